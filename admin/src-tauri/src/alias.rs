@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+
+use crate::netutil;
+use crate::sanitize;
+use crate::server::{clean_non_empty_owned, usable_mac, DeviceRecord};
+
+const MAX_TAGS: usize = 16;
+
+/// Operator naming applied to every agent the matcher selects. All criteria
+/// that are set must match; rules are tried in order and the first one wins.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(default)]
+pub struct AliasRule {
+    pub id: String,
+    /// Glob against the hostname, case-insensitive: `*` any run, `?` one character.
+    pub hostname_pattern: Option<String>,
+    /// e.g. `aa:bb:cc`; dashes are accepted.
+    pub mac_prefix: Option<String>,
+    pub subnet: Option<String>,
+    pub alias: Option<String>,
+    pub tags: Vec<String>,
+    pub location: Option<String>,
+    pub created_at: i64,
+}
+
+impl AliasRule {
+    pub fn normalize(mut self) -> Result<Self, String> {
+        let clean = |v: Option<String>| v.and_then(|v| clean_non_empty_owned(&sanitize::field(&v)));
+        self.hostname_pattern = clean(self.hostname_pattern).map(|p| p.to_lowercase());
+        self.alias = clean(self.alias);
+        self.location = clean(self.location);
+        self.mac_prefix = match clean(self.mac_prefix) {
+            Some(raw) => {
+                let prefix = raw.to_ascii_lowercase().replace('-', ":");
+                let valid = prefix
+                    .split(':')
+                    .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()));
+                if !valid {
+                    return Err(format!("invalid mac_prefix: {}", raw));
+                }
+                Some(prefix)
+            }
+            None => None,
+        };
+        self.subnet = match clean(self.subnet) {
+            Some(raw) => {
+                let network = netutil::network_address(&raw)
+                    .filter(|_| raw.contains('/'))
+                    .ok_or_else(|| format!("invalid subnet: {}", raw))?;
+                let prefix = raw.split_once('/').map(|(_, p)| p.trim()).unwrap_or("32");
+                Some(format!("{}/{}", network, prefix))
+            }
+            None => None,
+        };
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self
+            .tags
+            .iter()
+            .filter_map(|t| clean_non_empty_owned(&sanitize::field(t)))
+        {
+            if !tags.contains(&tag) && tags.len() < MAX_TAGS {
+                tags.push(tag);
+            }
+        }
+        self.tags = tags;
+
+        if self.hostname_pattern.is_none() && self.mac_prefix.is_none() && self.subnet.is_none() {
+            return Err("rule needs a hostname_pattern, mac_prefix or subnet".to_string());
+        }
+        if self.alias.is_none() && self.tags.is_empty() && self.location.is_none() {
+            return Err("rule needs an alias, tags or location".to_string());
+        }
+        Ok(self)
+    }
+
+    fn matches(&self, device: &DeviceRecord) -> bool {
+        if let Some(pattern) = &self.hostname_pattern {
+            if !glob_match(pattern, &device.hostname.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.mac_prefix {
+            match device.mac.as_deref().and_then(usable_mac) {
+                Some(mac) if mac.starts_with(prefix.as_str()) => {}
+                _ => return false,
+            }
+        }
+        if let Some(subnet) = &self.subnet {
+            match device.ip.as_deref() {
+                Some(ip) if netutil::contains(subnet, ip) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Sets alias, tags and location from the first matching rule, or clears
+/// them when none match. Returns whether anything changed.
+pub fn apply_alias_rules(rules: &[AliasRule], device: &mut DeviceRecord) -> bool {
+    let rule = rules.iter().find(|rule| rule.matches(device));
+    let rule_id = rule.map(|r| r.id.clone());
+    let alias = rule.and_then(|r| r.alias.clone());
+    let tags = rule.map(|r| r.tags.clone()).unwrap_or_default();
+    let location = rule.and_then(|r| r.location.clone());
+    let changed = device.alias_rule_id != rule_id
+        || device.alias != alias
+        || device.tags != tags
+        || device.location != location;
+    device.alias_rule_id = rule_id;
+    device.alias = alias;
+    device.tags = tags;
+    device.location = location;
+    changed
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        hostname: Option<&str>,
+        mac: Option<&str>,
+        subnet: Option<&str>,
+        alias: &str,
+    ) -> AliasRule {
+        AliasRule {
+            id: alias.to_string(),
+            hostname_pattern: hostname.map(str::to_string),
+            mac_prefix: mac.map(str::to_string),
+            subnet: subnet.map(str::to_string),
+            alias: Some(alias.to_string()),
+            tags: vec!["lab".to_string(), "lab".to_string()],
+            ..Default::default()
+        }
+        .normalize()
+        .unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins_and_unmatched_devices_are_cleared() {
+        let rules = vec![
+            rule(Some("LAB-A-*"), Some("AA-BB-CC"), None, "bench"),
+            rule(Some("lab-a-??"), None, None, "room-a"),
+            rule(None, None, Some("10.1.2.77/24"), "room-b"),
+        ];
+        assert_eq!(rules[0].mac_prefix.as_deref(), Some("aa:bb:cc"));
+        assert_eq!(rules[0].tags, vec!["lab"]);
+        assert_eq!(rules[2].subnet.as_deref(), Some("10.1.2.0/24"));
+
+        let mut device = DeviceRecord {
+            hostname: "lab-a-07".to_string(),
+            mac: Some("AA-BB-CC-00-11-22".to_string()),
+            ..Default::default()
+        };
+        assert!(apply_alias_rules(&rules, &mut device));
+        assert_eq!(device.alias.as_deref(), Some("bench"));
+        assert!(!apply_alias_rules(&rules, &mut device));
+
+        device.mac = Some("de:ad:be:ef:00:01".to_string());
+        apply_alias_rules(&rules, &mut device);
+        assert_eq!(device.alias.as_deref(), Some("room-a"));
+
+        device.hostname = "lab-a-107".to_string();
+        device.ip = Some("10.1.2.5".to_string());
+        apply_alias_rules(&rules, &mut device);
+        assert_eq!(device.alias.as_deref(), Some("room-b"));
+
+        device.ip = Some("10.9.9.9".to_string());
+        assert!(apply_alias_rules(&rules, &mut device));
+        assert_eq!(device.alias, None);
+        assert!(device.tags.is_empty());
+    }
+
+    #[test]
+    fn rules_need_a_matcher_and_something_to_apply() {
+        let only_alias = AliasRule {
+            alias: Some("x".to_string()),
+            ..Default::default()
+        };
+        assert!(only_alias.normalize().is_err());
+        let only_matcher = AliasRule {
+            hostname_pattern: Some("*".to_string()),
+            ..Default::default()
+        };
+        assert!(only_matcher.normalize().is_err());
+        let bad_mac = AliasRule {
+            mac_prefix: Some("zz:1".to_string()),
+            alias: Some("x".to_string()),
+            ..Default::default()
+        };
+        assert!(bad_mac.normalize().is_err());
+    }
+}
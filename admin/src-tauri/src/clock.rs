@@ -0,0 +1,46 @@
+use std::fmt::Debug;
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Where `ServerManager` reads the time from, so throttles, dedupe windows,
+/// the watchdog and timeouts can be driven deterministically in tests.
+pub trait Clock: Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> i64;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+}
+
+/// Stands still until moved with `set` or `advance`.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicI64);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start_ms: i64) -> Self {
+        Self(AtomicI64::new(start_ms))
+    }
+
+    pub fn set(&self, now_ms: i64) {
+        self.0.store(now_ms, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, ms: i64) {
+        self.0.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_ms(&self) -> i64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
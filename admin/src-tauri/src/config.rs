@@ -0,0 +1,612 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::discovery::{DEFAULT_MULTICAST_GROUP, DEFAULT_MULTICAST_PORT};
+use crate::webhook::WebhookConfig;
+
+/// Looked up in the working directory, next to `labscan.db`; the first one found wins.
+pub const CONFIG_FILES: [&str; 3] = ["labscan.config.json", "labscan.toml", "labscan.json"];
+pub const MIN_SERVER_PULSE_INTERVAL_MS: u64 = 1000;
+pub const MIN_PAIR_TOKEN_ROTATION_MS: u64 = 60_000;
+
+/// Everything the config file can set. Only `server` is applied at runtime;
+/// the other fields are read once when the server starts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LabScanConfig {
+    pub bind_address: String,
+    pub tls_enabled: bool,
+    pub heartbeat_timeout_ms: i64,
+    pub agent_ping_timeout_ms: u64,
+    pub provision_probe_step_ms: u64,
+    pub max_logs: usize,
+    pub max_activity: usize,
+    pub event_journal_capacity: usize,
+    pub udp_provision: bool,
+    pub multicast_beacon: bool,
+    /// Where the discovery beacon is sent; agents listen on the same group.
+    pub multicast_group: String,
+    pub multicast_port: u16,
+    pub server: ServerConfig,
+}
+
+impl Default for LabScanConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0".to_string(),
+            tls_enabled: true,
+            heartbeat_timeout_ms: 20_000,
+            agent_ping_timeout_ms: 3000,
+            provision_probe_step_ms: 3000,
+            max_logs: 400,
+            max_activity: 200,
+            event_journal_capacity: 5000,
+            udp_provision: true,
+            multicast_beacon: true,
+            multicast_group: DEFAULT_MULTICAST_GROUP.to_string(),
+            multicast_port: DEFAULT_MULTICAST_PORT,
+            server: ServerConfig::default(),
+        }
+    }
+}
+
+impl LabScanConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        self.bind_address
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("bind_address is not an IP address: {}", self.bind_address))?;
+        self.multicast_group_addr()?;
+        if self.multicast_port == 0 {
+            return Err("multicast_port must not be 0".to_string());
+        }
+        let positive = [
+            (
+                "heartbeat_timeout_ms",
+                self.heartbeat_timeout_ms.max(0) as u64,
+            ),
+            ("agent_ping_timeout_ms", self.agent_ping_timeout_ms),
+            ("provision_probe_step_ms", self.provision_probe_step_ms),
+            ("max_logs", self.max_logs as u64),
+            ("max_activity", self.max_activity as u64),
+            ("event_journal_capacity", self.event_journal_capacity as u64),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        self.server.validate()
+    }
+
+    pub fn multicast_group_addr(&self) -> Result<Ipv4Addr, String> {
+        self.multicast_group
+            .trim()
+            .parse::<Ipv4Addr>()
+            .ok()
+            .filter(Ipv4Addr::is_multicast)
+            .ok_or_else(|| {
+                format!(
+                    "multicast_group is not an IPv4 multicast address: {}",
+                    self.multicast_group
+                )
+            })
+    }
+
+    /// Startup-only settings that differ from `other`; these need a restart to apply.
+    pub fn restart_required(&self, other: &LabScanConfig) -> Vec<String> {
+        let fields = [
+            ("bind_address", self.bind_address != other.bind_address),
+            ("tls_enabled", self.tls_enabled != other.tls_enabled),
+            (
+                "heartbeat_timeout_ms",
+                self.heartbeat_timeout_ms != other.heartbeat_timeout_ms,
+            ),
+            (
+                "agent_ping_timeout_ms",
+                self.agent_ping_timeout_ms != other.agent_ping_timeout_ms,
+            ),
+            (
+                "provision_probe_step_ms",
+                self.provision_probe_step_ms != other.provision_probe_step_ms,
+            ),
+            ("max_logs", self.max_logs != other.max_logs),
+            ("max_activity", self.max_activity != other.max_activity),
+            (
+                "event_journal_capacity",
+                self.event_journal_capacity != other.event_journal_capacity,
+            ),
+            ("udp_provision", self.udp_provision != other.udp_provision),
+            (
+                "multicast_beacon",
+                self.multicast_beacon != other.multicast_beacon,
+            ),
+            (
+                "multicast_group",
+                self.multicast_group != other.multicast_group,
+            ),
+            (
+                "multicast_port",
+                self.multicast_port != other.multicast_port,
+            ),
+            (
+                "server.allow_mock_devices",
+                self.server.allow_mock_devices != other.server.allow_mock_devices,
+            ),
+        ];
+        fields
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+}
+
+/// Reads and validates the first config file found, or the defaults if there is none.
+pub fn load() -> Result<(LabScanConfig, Option<PathBuf>), String> {
+    match CONFIG_FILES.iter().map(Path::new).find(|p| p.exists()) {
+        Some(path) => Ok((load_from(path)?, Some(path.to_path_buf()))),
+        None => Ok((LabScanConfig::default(), None)),
+    }
+}
+
+pub fn load_from(path: &Path) -> Result<LabScanConfig, String> {
+    let raw = fs::read_to_string(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+    let config: LabScanConfig = if path.extension().and_then(|e| e.to_str()) == Some("json") {
+        serde_json::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))?
+    } else {
+        toml::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))?
+    };
+    config
+        .validate()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(config)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// Reject agent upgrades that carry an Origin header not in `allowed_ws_origins`.
+    /// Native agents send none, so this only turns away browsers. Off by default.
+    #[serde(default)]
+    pub validate_ws_origin: bool,
+    #[serde(default)]
+    pub allowed_ws_origins: Vec<String>,
+    #[serde(default)]
+    pub subnet_aggregation_threshold: usize,
+    #[serde(default = "default_subnet_aggregation_prefix")]
+    pub subnet_aggregation_prefix: u8,
+    #[serde(default)]
+    pub device_upsert_batch_ms: u64,
+    /// Send `device_patch` with only the fields changed since the last emit
+    /// instead of full `device_upsert` records. Batched upserts stay full.
+    #[serde(default)]
+    pub sparse_device_upserts: bool,
+    /// Lets release builds inject mock devices. Startup-only: live updates keep
+    /// the value the admin started with.
+    #[serde(default)]
+    pub allow_mock_devices: bool,
+    #[serde(default = "default_stale_reap_after_ms")]
+    pub stale_reap_after_ms: i64,
+    #[serde(default)]
+    pub reap_protected_agents: Vec<String>,
+    #[serde(default)]
+    pub confidence: ConfidenceWeights,
+    #[serde(default)]
+    pub require_agent_approval: bool,
+    #[serde(default)]
+    pub server_pulse_interval_ms: u64,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default = "default_activity_retention_ms")]
+    pub activity_retention_ms: i64,
+    #[serde(default)]
+    pub task_automation: TaskAutomationConfig,
+    /// Cap on open agent sockets, registered or not (0 = unlimited).
+    #[serde(default = "default_max_agent_connections")]
+    pub max_agent_connections: usize,
+    #[serde(default)]
+    pub secret_backoff: SecretBackoffConfig,
+    /// A changed topology is only committed once it has held this long (0 = immediately).
+    #[serde(default)]
+    pub topology_stability_ms: u64,
+    /// Committed topologies are checkpointed to SQLite for this long (0 = off).
+    #[serde(default = "default_topology_checkpoint_retention_ms")]
+    pub topology_checkpoint_retention_ms: i64,
+    #[serde(default)]
+    pub wire_trace: WireTraceConfig,
+    /// Rotate the pair token on this schedule (0 = only when asked).
+    #[serde(default)]
+    pub pair_token_rotation_ms: u64,
+    /// After a scheduled rotation the previous token still registers agents this long.
+    #[serde(default = "default_pair_token_grace_ms")]
+    pub pair_token_grace_ms: i64,
+    /// Alerts raised once each as a device stays offline longer (empty = off).
+    #[serde(default = "default_outage_thresholds")]
+    pub outage_thresholds: Vec<OutageThreshold>,
+    /// Ceiling on discovery/provisioning broadcast traffic; cycles over it are skipped (0 = unlimited).
+    #[serde(default)]
+    pub broadcast_budget_bytes_per_sec: u64,
+    /// Live listener ports; only changed while the server is stopped. Agents
+    /// listen for provisioning on `port_udp` (their `-provision-port`).
+    #[serde(default = "default_port_ws")]
+    pub port_ws: u16,
+    #[serde(default = "default_port_udp")]
+    pub port_udp: u16,
+}
+
+impl ServerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.port_ws == 0 || self.port_udp == 0 {
+            return Err("port_ws and port_udp must not be 0".to_string());
+        }
+        if self.subnet_aggregation_prefix > 32 {
+            return Err("subnet_aggregation_prefix must be between 0 and 32".to_string());
+        }
+        if self.stale_reap_after_ms < 0 {
+            return Err("stale_reap_after_ms must not be negative".to_string());
+        }
+        if self.topology_checkpoint_retention_ms < 0 {
+            return Err("topology_checkpoint_retention_ms must not be negative".to_string());
+        }
+        if self.activity_retention_ms < 0 {
+            return Err("activity_retention_ms must not be negative".to_string());
+        }
+        if self.server_pulse_interval_ms != 0
+            && self.server_pulse_interval_ms < MIN_SERVER_PULSE_INTERVAL_MS
+        {
+            return Err(format!(
+                "server_pulse_interval_ms must be 0 (off) or at least {}",
+                MIN_SERVER_PULSE_INTERVAL_MS
+            ));
+        }
+        if self.pair_token_rotation_ms != 0
+            && self.pair_token_rotation_ms < MIN_PAIR_TOKEN_ROTATION_MS
+        {
+            return Err(format!(
+                "pair_token_rotation_ms must be 0 (off) or at least {}",
+                MIN_PAIR_TOKEN_ROTATION_MS
+            ));
+        }
+        if self.pair_token_grace_ms < 0 {
+            return Err("pair_token_grace_ms must not be negative".to_string());
+        }
+        for threshold in &self.outage_thresholds {
+            threshold.validate()?;
+        }
+        self.confidence.validate()?;
+        self.secret_backoff.validate()?;
+        self.wire_trace.validate()?;
+        for hook in &self.webhooks {
+            hook.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Temporary bans for sources that keep presenting a wrong shared secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecretBackoffConfig {
+    /// Failures within `window_ms` before a source is banned (0 = off).
+    pub threshold: u32,
+    /// Sliding window the failures are counted over.
+    pub window_ms: i64,
+    /// First ban length; doubles with each further ban from the same source.
+    pub base_ban_ms: i64,
+    /// Longest ban; a source quiet this long after its last ban starts over.
+    pub max_ban_ms: i64,
+}
+
+impl Default for SecretBackoffConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            window_ms: 60_000,
+            base_ban_ms: 5_000,
+            max_ban_ms: 15 * 60 * 1000,
+        }
+    }
+}
+
+impl SecretBackoffConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold > 0 && (self.base_ban_ms <= 0 || self.max_ban_ms < self.base_ban_ms) {
+            return Err(
+                "secret_backoff needs base_ban_ms > 0 and max_ban_ms >= base_ban_ms".to_string(),
+            );
+        }
+        if self.threshold > 0 && self.window_ms <= 0 {
+            return Err("secret_backoff needs window_ms > 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Ban length after `failures` bad secrets inside the window, for a source
+    /// that has already served `prior_bans` bans, if any.
+    pub fn ban_ms(&self, failures: u32, prior_bans: u32) -> Option<i64> {
+        if self.threshold == 0 || failures < self.threshold {
+            return None;
+        }
+        Some(
+            self.base_ban_ms
+                .saturating_mul(1_i64 << prior_bans.min(32))
+                .min(self.max_ban_ms),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OutageThreshold {
+    pub after_ms: i64,
+    /// `warning` or `critical`.
+    pub severity: String,
+}
+
+impl OutageThreshold {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.after_ms <= 0 {
+            return Err("outage_thresholds.after_ms must be positive".to_string());
+        }
+        if !matches!(self.severity.as_str(), "warning" | "critical") {
+            return Err(format!(
+                "outage_thresholds.severity must be warning or critical, got {}",
+                self.severity
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> &'static str {
+        if self.severity == "critical" {
+            "ERROR"
+        } else {
+            "WARN"
+        }
+    }
+}
+
+/// Limits for `set_wire_trace`, which logs every frame of one agent's socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WireTraceConfig {
+    /// Agents that may be traced at once (0 = tracing disabled).
+    pub max_agents: usize,
+    /// A trace switches itself off after this long.
+    pub duration_ms: i64,
+}
+
+impl Default for WireTraceConfig {
+    fn default() -> Self {
+        Self {
+            max_agents: 4,
+            duration_ms: 10 * 60 * 1000,
+        }
+    }
+}
+
+impl WireTraceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.duration_ms <= 0 {
+            return Err("wire_trace.duration_ms must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct TaskAutomationConfig {
+    /// Re-dispatch a failed task to its failed agents up to this many times (0 = off).
+    pub auto_rerun_failed: u32,
+    /// Queue a `task_result` webhook event carrying the full results on completion.
+    pub webhook_on_complete: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfidenceWeights {
+    pub gateway_subnet: f64,
+    pub evidence: f64,
+    pub admin_heuristic: f64,
+    pub host_heuristic: f64,
+    pub hub_heuristic: f64,
+    pub nat_cap: f64,
+    pub configured: f64,
+    /// ARP-observed hosts without an agent, only shown on request.
+    pub unmanaged: f64,
+}
+
+impl Default for ConfidenceWeights {
+    fn default() -> Self {
+        Self {
+            gateway_subnet: 1.0,
+            evidence: 0.9,
+            admin_heuristic: 0.5,
+            host_heuristic: 0.45,
+            hub_heuristic: 0.5,
+            nat_cap: 0.3,
+            configured: 1.0,
+            unmanaged: 0.25,
+        }
+    }
+}
+
+impl ConfidenceWeights {
+    pub fn validate(&self) -> Result<(), String> {
+        let weights = [
+            ("gateway_subnet", self.gateway_subnet),
+            ("evidence", self.evidence),
+            ("admin_heuristic", self.admin_heuristic),
+            ("host_heuristic", self.host_heuristic),
+            ("hub_heuristic", self.hub_heuristic),
+            ("nat_cap", self.nat_cap),
+            ("configured", self.configured),
+            ("unmanaged", self.unmanaged),
+        ];
+        for (name, value) in weights {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("confidence.{} must be between 0 and 1", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            validate_ws_origin: false,
+            allowed_ws_origins: Vec::new(),
+            subnet_aggregation_threshold: 0,
+            subnet_aggregation_prefix: default_subnet_aggregation_prefix(),
+            device_upsert_batch_ms: 0,
+            sparse_device_upserts: false,
+            allow_mock_devices: false,
+            stale_reap_after_ms: default_stale_reap_after_ms(),
+            reap_protected_agents: Vec::new(),
+            confidence: ConfidenceWeights::default(),
+            require_agent_approval: false,
+            server_pulse_interval_ms: 0,
+            webhooks: Vec::new(),
+            activity_retention_ms: default_activity_retention_ms(),
+            task_automation: TaskAutomationConfig::default(),
+            max_agent_connections: default_max_agent_connections(),
+            secret_backoff: SecretBackoffConfig::default(),
+            topology_stability_ms: 0,
+            topology_checkpoint_retention_ms: default_topology_checkpoint_retention_ms(),
+            wire_trace: WireTraceConfig::default(),
+            pair_token_rotation_ms: 0,
+            pair_token_grace_ms: default_pair_token_grace_ms(),
+            outage_thresholds: default_outage_thresholds(),
+            broadcast_budget_bytes_per_sec: 0,
+            port_ws: default_port_ws(),
+            port_udp: default_port_udp(),
+        }
+    }
+}
+
+fn default_port_ws() -> u16 {
+    8148
+}
+
+fn default_port_udp() -> u16 {
+    8870
+}
+
+fn default_outage_thresholds() -> Vec<OutageThreshold> {
+    vec![
+        OutageThreshold {
+            after_ms: 5 * 60 * 1000,
+            severity: "warning".to_string(),
+        },
+        OutageThreshold {
+            after_ms: 60 * 60 * 1000,
+            severity: "critical".to_string(),
+        },
+    ]
+}
+
+fn default_pair_token_grace_ms() -> i64 {
+    60_000
+}
+
+fn default_topology_checkpoint_retention_ms() -> i64 {
+    7 * 24 * 60 * 60 * 1000
+}
+
+fn default_subnet_aggregation_prefix() -> u8 {
+    24
+}
+
+fn default_stale_reap_after_ms() -> i64 {
+    7 * 24 * 60 * 60 * 1000
+}
+
+fn default_activity_retention_ms() -> i64 {
+    30 * 24 * 60 * 60 * 1000
+}
+
+fn default_max_agent_connections() -> usize {
+    1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_file_keeps_defaults_and_reports_bad_values() {
+        let config: LabScanConfig = toml::from_str(
+            r#"
+            tls_enabled = false
+
+            [server]
+            port_ws = 9000
+            max_agent_connections = 64
+
+            [server.confidence]
+            evidence = 0.8
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port_ws, 9000);
+        assert_eq!(config.server.port_udp, 8870);
+        assert_eq!(config.server.max_agent_connections, 64);
+        assert_eq!(config.server.confidence.evidence, 0.8);
+        assert_eq!(config.server.confidence.configured, 1.0);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.restart_required(&LabScanConfig::default()),
+            vec!["tls_enabled"]
+        );
+
+        let bad = LabScanConfig {
+            heartbeat_timeout_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            bad.validate().unwrap_err(),
+            "heartbeat_timeout_ms must be greater than 0"
+        );
+        let mut bad_weight = LabScanConfig::default();
+        bad_weight.server.confidence.nat_cap = 1.5;
+        assert_eq!(
+            bad_weight.validate().unwrap_err(),
+            "confidence.nat_cap must be between 0 and 1"
+        );
+        let bad_group = LabScanConfig {
+            multicast_group: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        assert!(bad_group
+            .validate()
+            .unwrap_err()
+            .contains("multicast_group"));
+    }
+
+    #[test]
+    fn secret_backoff_bans_after_threshold_and_doubles_to_cap() {
+        let policy = SecretBackoffConfig {
+            threshold: 3,
+            window_ms: 60_000,
+            base_ban_ms: 1_000,
+            max_ban_ms: 5_000,
+        };
+        assert_eq!(policy.ban_ms(2, 4), None);
+        assert_eq!(policy.ban_ms(3, 0), Some(1_000));
+        assert_eq!(policy.ban_ms(3, 1), Some(2_000));
+        assert_eq!(policy.ban_ms(9, 2), Some(4_000));
+        assert_eq!(policy.ban_ms(3, 3), Some(5_000));
+        assert_eq!(policy.ban_ms(3, 500), Some(5_000));
+
+        let off = SecretBackoffConfig {
+            threshold: 0,
+            ..policy
+        };
+        assert_eq!(off.ban_ms(500, 0), None);
+    }
+}
@@ -1,7 +1,20 @@
-use rusqlite::{params, Connection, Result};
-use std::sync::Arc;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Params, Result};
+use std::{sync::Arc, thread, time::Duration};
 use tokio::sync::Mutex;
 
+/// Version of the JSON `DeviceRecord` stored in `devices.record`.
+pub const DEVICE_RECORD_VERSION: i64 = 1;
+
+/// Columns added after the first release, applied to older `labscan.db` files.
+const ADDED_COLUMNS: [(&str, &str, &str); 2] = [
+    ("devices", "record", "TEXT"),
+    ("devices", "record_version", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+const BUSY_TIMEOUT_MS: u64 = 2000;
+const BUSY_RETRY_ATTEMPTS: u32 = 5;
+const BUSY_RETRY_BASE_MS: u64 = 10;
+
 pub struct Database {
     pub conn: Connection,
 }
@@ -20,6 +33,8 @@ pub struct Device {
     pub registered_at: i64,
     pub last_seen: i64,
     pub is_online: bool,
+    pub record: Option<String>,
+    pub record_version: i64,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -33,6 +48,71 @@ pub struct Heartbeat {
     pub local_ports: Option<String>,
 }
 
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Availability {
+    /// Share of heartbeat windows with at least one heartbeat; `None` without samples.
+    pub uptime_pct: Option<f64>,
+    pub longest_online_ms: i64,
+    pub sample_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TopologySeedRow {
+    pub mac: Option<String>,
+    pub agent_id: Option<String>,
+    pub switch_name: String,
+    pub port: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ActivityRow {
+    pub id: String,
+    pub kind: String,
+    pub agent_id: Option<String>,
+    pub message: String,
+    pub ts: i64,
+    pub count: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JournalRow {
+    pub seq: i64,
+    pub ts: i64,
+    pub event: String,
+    pub payload: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TopologyCheckpointRow {
+    pub revision: i64,
+    pub updated_at: i64,
+    pub node_count: i64,
+    pub edge_count: i64,
+    /// Serialized `Vec<TopologyNode>` / `Vec<TopologyEdge>`.
+    pub nodes: String,
+    pub edges: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TopologyCheckpointInfo {
+    pub id: i64,
+    pub revision: i64,
+    pub updated_at: i64,
+    pub node_count: i64,
+    pub edge_count: i64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TaskRow {
+    pub id: String,
+    pub kind: String,
+    pub label: Option<String>,
+    pub metadata: Option<String>,
+    pub status: String,
+    pub created_at: i64,
+    pub record: String,
+}
+
 impl Database {
     pub fn new(conn: Connection) -> Self {
         Self { conn }
@@ -81,15 +161,183 @@ impl Database {
             [],
         )?;
 
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS agent_approvals (
+                agent_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS activity (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                agent_id TEXT,
+                message TEXT NOT NULL,
+                ts INTEGER NOT NULL,
+                count INTEGER
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_activity_ts ON activity (ts)",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS device_history (
+                device_id TEXT NOT NULL,
+                ts INTEGER NOT NULL,
+                facts TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_device_history_device_ts
+             ON device_history (device_id, ts)",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS topology_seed (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                mac TEXT,
+                agent_id TEXT,
+                switch_name TEXT NOT NULL,
+                port TEXT
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS agent_allowlist (
+                agent_id TEXT PRIMARY KEY,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS blocklist (
+                kind TEXT NOT NULL,
+                value TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (kind, value)
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS network_overrides (
+                agent_id TEXT PRIMARY KEY,
+                facts TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS alias_rules (
+                id TEXT PRIMARY KEY,
+                position INTEGER NOT NULL,
+                rule TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS tasks (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                label TEXT,
+                metadata TEXT,
+                status TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                record TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS event_journal (
+                seq INTEGER PRIMARY KEY,
+                ts INTEGER NOT NULL,
+                event TEXT NOT NULL,
+                payload TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS topology_checkpoints (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                revision INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                node_count INTEGER NOT NULL,
+                edge_count INTEGER NOT NULL,
+                nodes TEXT NOT NULL,
+                edges TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_topology_checkpoints_updated_at
+             ON topology_checkpoints(updated_at)",
+            [],
+        )?;
+
+        self.migrate()
+    }
+
+    fn migrate(&self) -> Result<()> {
+        for (table, column, decl) in ADDED_COLUMNS {
+            if !self.has_column(table, column)? {
+                self.conn.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+                    [],
+                )?;
+            }
+        }
         Ok(())
     }
 
+    fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("PRAGMA table_info({})", table))?;
+        let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+        for name in names {
+            if name? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// `Connection::execute`, retried while another connection holds the database.
+    fn execute<P: Params + Copy>(&self, sql: &str, params: P) -> Result<usize> {
+        retry_busy(|| self.conn.execute(sql, params))
+    }
+
     pub fn register_device(&mut self, device: Device) -> Result<()> {
-        self.conn.execute(
+        self.execute(
             "INSERT OR REPLACE INTO devices 
             (id, hostname, os, arch, agent_version, local_ip, mac_address, 
-             gateway_ip, dns_servers, registered_at, last_seen, is_online)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+             gateway_ip, dns_servers, registered_at, last_seen, is_online, record, record_version)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
             params![
                 device.id,
                 device.hostname,
@@ -103,13 +351,15 @@ impl Database {
                 device.registered_at,
                 device.last_seen,
                 device.is_online,
+                device.record,
+                device.record_version,
             ],
         )?;
         Ok(())
     }
 
     pub fn record_heartbeat(&mut self, heartbeat: Heartbeat) -> Result<()> {
-        self.conn.execute(
+        self.execute(
             "INSERT INTO heartbeats 
             (id, device_id, timestamp, gateway_reachable, dns_resolves, https_latency_ms, local_ports)
             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
@@ -127,17 +377,636 @@ impl Database {
     }
 
     pub fn update_device_last_seen(&mut self, device_id: &str, timestamp: i64) -> Result<()> {
-        self.conn.execute(
+        self.execute(
             "UPDATE devices SET last_seen = ?1, is_online = 1 WHERE id = ?2",
             params![timestamp, device_id],
         )?;
         Ok(())
     }
 
+    pub fn save_task(&mut self, task: TaskRow) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO tasks
+            (id, kind, label, metadata, status, created_at, record)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                task.id,
+                task.kind,
+                task.label,
+                task.metadata,
+                task.status,
+                task.created_at,
+                task.record,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn search_tasks(&self, query: &str, limit: usize) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT record FROM tasks
+             WHERE lower(id) LIKE ?1 ESCAPE '\\' OR lower(kind) LIKE ?1 ESCAPE '\\'
+                OR lower(COALESCE(label, '')) LIKE ?1 ESCAPE '\\'
+                OR lower(COALESCE(metadata, '')) LIKE ?1 ESCAPE '\\'
+             ORDER BY created_at DESC LIMIT ?2",
+        )?;
+        // `%` and `_` in the query are literal text, not wildcards.
+        let escaped = query
+            .to_lowercase()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let pattern = format!("%{}%", escaped);
+        let rows = stmt.query_map(params![pattern, limit as i64], |row| row.get(0))?;
+
+        let mut records = Vec::new();
+        for record in rows {
+            records.push(record?);
+        }
+        Ok(records)
+    }
+
+    /// The newest `limit` heartbeats since `since_ms`, oldest first.
+    pub fn get_heartbeats(
+        &self,
+        device_id: &str,
+        since_ms: i64,
+        limit: usize,
+    ) -> Result<Vec<Heartbeat>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, device_id, timestamp, gateway_reachable, dns_resolves, https_latency_ms, local_ports
+             FROM heartbeats
+             WHERE device_id = ?1 AND timestamp >= ?2
+             ORDER BY timestamp DESC LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![device_id, since_ms, limit as i64], |row| {
+            Ok(Heartbeat {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                timestamp: row.get(2)?,
+                gateway_reachable: row.get(3)?,
+                dns_resolves: row.get(4)?,
+                https_latency_ms: row.get(5)?,
+                local_ports: row.get(6)?,
+            })
+        })?;
+
+        let mut heartbeats = Vec::new();
+        for heartbeat in rows {
+            heartbeats.push(heartbeat?);
+        }
+        heartbeats.reverse();
+        Ok(heartbeats)
+    }
+
+    /// Task records created since `since_ms` whose JSON mentions the agent id;
+    /// callers still check `assigned_agents` since this is a text match.
+    pub fn get_task_records_mentioning(
+        &self,
+        agent_id: &str,
+        since_ms: i64,
+    ) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT record FROM tasks
+             WHERE created_at >= ?1 AND instr(record, ?2) > 0
+             ORDER BY created_at ASC",
+        )?;
+        let needle = format!("\"{}\"", agent_id);
+        let rows = stmt.query_map(params![since_ms, needle], |row| row.get(0))?;
+
+        let mut records = Vec::new();
+        for record in rows {
+            records.push(record?);
+        }
+        Ok(records)
+    }
+
+    /// Splits `[since_ms, until_ms)` into `window_ms` windows and counts those
+    /// holding a heartbeat. Heartbeats at most `window_ms` apart form one streak.
+    pub fn get_availability(
+        &self,
+        device_id: &str,
+        since_ms: i64,
+        until_ms: i64,
+        window_ms: i64,
+    ) -> Result<Availability> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp FROM heartbeats
+             WHERE device_id = ?1 AND timestamp >= ?2 AND timestamp < ?3
+             ORDER BY timestamp ASC",
+        )?;
+        let rows = stmt.query_map(params![device_id, since_ms, until_ms], |row| row.get(0))?;
+
+        let mut timestamps = Vec::new();
+        for ts in rows {
+            timestamps.push(ts?);
+        }
+        Ok(availability(&timestamps, since_ms, until_ms, window_ms))
+    }
+
+    pub fn get_latency_samples(&self, device_id: &str, since_ms: i64) -> Result<Vec<i64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT https_latency_ms FROM heartbeats
+             WHERE device_id = ?1 AND timestamp >= ?2 AND https_latency_ms IS NOT NULL
+             ORDER BY https_latency_ms ASC",
+        )?;
+        let rows = stmt.query_map(params![device_id, since_ms], |row| row.get(0))?;
+
+        let mut samples = Vec::new();
+        for sample in rows {
+            samples.push(sample?);
+        }
+        Ok(samples)
+    }
+
+    /// Inserts the event, or updates `ts`/`count` when a coalesced event is saved again.
+    pub fn save_activity(&mut self, row: &ActivityRow) -> Result<()> {
+        self.execute(
+            "INSERT INTO activity (id, kind, agent_id, message, ts, count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(id) DO UPDATE SET ts = excluded.ts, count = excluded.count",
+            params![
+                row.id,
+                row.kind,
+                row.agent_id,
+                row.message,
+                row.ts,
+                row.count
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn query_activity(
+        &self,
+        since_ms: i64,
+        kind: Option<&str>,
+        agent_id: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<ActivityRow>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, kind, agent_id, message, ts, count FROM activity
+             WHERE ts >= ?1
+               AND (?2 IS NULL OR kind = ?2)
+               AND (?3 IS NULL OR agent_id = ?3)
+             ORDER BY ts DESC
+             LIMIT ?4",
+        )?;
+        let rows = stmt.query_map(params![since_ms, kind, agent_id, limit as i64], |row| {
+            Ok(ActivityRow {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                agent_id: row.get(2)?,
+                message: row.get(3)?,
+                ts: row.get(4)?,
+                count: row.get(5)?,
+            })
+        })?;
+
+        let mut activity = Vec::new();
+        for row in rows {
+            activity.push(row?);
+        }
+        Ok(activity)
+    }
+
+    pub fn prune_activity(&mut self, before_ms: i64) -> Result<usize> {
+        self.execute("DELETE FROM activity WHERE ts < ?1", params![before_ms])
+    }
+
+    /// Appends a facts snapshot unless it matches the latest one stored for the device.
+    pub fn record_device_facts(&mut self, device_id: &str, ts: i64, facts: &str) -> Result<()> {
+        let latest = self.get_device_facts_at(device_id, i64::MAX)?;
+        if latest.as_ref().map(|(_, f)| f.as_str()) == Some(facts) {
+            return Ok(());
+        }
+        self.execute(
+            "INSERT INTO device_history (device_id, ts, facts) VALUES (?1, ?2, ?3)",
+            params![device_id, ts, facts],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_device_facts_at(&self, device_id: &str, ts: i64) -> Result<Option<(i64, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ts, facts FROM device_history
+             WHERE device_id = ?1 AND ts <= ?2
+             ORDER BY ts DESC, rowid DESC LIMIT 1",
+        )?;
+        let mut rows =
+            stmt.query_map(params![device_id, ts], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.next().transpose()
+    }
+
+    pub fn get_device_history(&self, device_id: &str, since_ms: i64) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ts, facts FROM device_history
+             WHERE device_id = ?1 AND ts >= ?2
+             ORDER BY ts ASC, rowid ASC",
+        )?;
+        let rows = stmt.query_map(params![device_id, since_ms], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+        let mut history = Vec::new();
+        for row in rows {
+            history.push(row?);
+        }
+        Ok(history)
+    }
+
+    /// Appends an event and drops the oldest so at most `capacity` remain.
+    pub fn append_event(&mut self, row: &JournalRow, capacity: usize) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO event_journal (seq, ts, event, payload)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![row.seq, row.ts, row.event, row.payload],
+        )?;
+        self.execute(
+            "DELETE FROM event_journal WHERE seq <= ?1",
+            params![row.seq - capacity as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_events_after(&self, seq: i64, limit: usize) -> Result<Vec<JournalRow>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, ts, event, payload FROM event_journal
+             WHERE seq > ?1 ORDER BY seq ASC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![seq, limit as i64], |row| {
+            Ok(JournalRow {
+                seq: row.get(0)?,
+                ts: row.get(1)?,
+                event: row.get(2)?,
+                payload: row.get(3)?,
+            })
+        })?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            events.push(row?);
+        }
+        Ok(events)
+    }
+
+    /// Oldest and newest retained sequence numbers, if any.
+    pub fn event_journal_bounds(&self) -> Result<Option<(i64, i64)>> {
+        self.conn
+            .query_row("SELECT MIN(seq), MAX(seq) FROM event_journal", [], |row| {
+                let min: Option<i64> = row.get(0)?;
+                let max: Option<i64> = row.get(1)?;
+                Ok(min.zip(max))
+            })
+    }
+
+    /// Stores a checkpoint and drops those last updated before `keep_since_ms`.
+    pub fn insert_topology_checkpoint(
+        &mut self,
+        row: &TopologyCheckpointRow,
+        keep_since_ms: i64,
+    ) -> Result<()> {
+        self.execute(
+            "INSERT INTO topology_checkpoints
+                (revision, updated_at, node_count, edge_count, nodes, edges)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                row.revision,
+                row.updated_at,
+                row.node_count,
+                row.edge_count,
+                row.nodes,
+                row.edges
+            ],
+        )?;
+        self.execute(
+            "DELETE FROM topology_checkpoints WHERE updated_at < ?1",
+            params![keep_since_ms],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_topology_checkpoints(
+        &self,
+        since_ms: i64,
+        limit: usize,
+    ) -> Result<Vec<TopologyCheckpointInfo>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, revision, updated_at, node_count, edge_count FROM topology_checkpoints
+             WHERE updated_at >= ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![since_ms, limit as i64], |row| {
+            Ok(TopologyCheckpointInfo {
+                id: row.get(0)?,
+                revision: row.get(1)?,
+                updated_at: row.get(2)?,
+                node_count: row.get(3)?,
+                edge_count: row.get(4)?,
+            })
+        })?;
+
+        let mut checkpoints = Vec::new();
+        for row in rows {
+            checkpoints.push(row?);
+        }
+        // Newest `limit` rows, oldest first.
+        checkpoints.reverse();
+        Ok(checkpoints)
+    }
+
+    /// Highest checkpointed revision, so a restart keeps counting from it.
+    pub fn max_topology_revision(&self) -> Result<Option<i64>> {
+        self.conn.query_row(
+            "SELECT MAX(revision) FROM topology_checkpoints",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn set_agent_approval(&mut self, agent_id: &str, state: &str, ts: i64) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO agent_approvals (agent_id, state, updated_at)
+            VALUES (?1, ?2, ?3)",
+            params![agent_id, state, ts],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_token(&mut self, token: &str, created_at: i64, expires_at: i64) -> Result<()> {
+        self.execute(
+            "INSERT INTO tokens (token, created_at, expires_at, used) VALUES (?1, ?2, ?3, 0)",
+            params![token, created_at, expires_at],
+        )?;
+        Ok(())
+    }
+
+    /// Marks an unused, unexpired token as used in one statement, so two
+    /// registrations can't both redeem it. Returns whether it was valid.
+    pub fn consume_token(&mut self, token: &str, now: i64) -> Result<bool> {
+        let changed = self.execute(
+            "UPDATE tokens SET used = 1 WHERE token = ?1 AND used = 0 AND expires_at > ?2",
+            params![token, now],
+        )?;
+        Ok(changed == 1)
+    }
+
+    /// Whether `token` is unused and unexpired, without redeeming it.
+    pub fn token_valid(&self, token: &str, now: i64) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT COUNT(*) > 0 FROM tokens WHERE token = ?1 AND used = 0 AND expires_at > ?2",
+            params![token, now],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether `token` is an unused enrollment token that is past its expiry.
+    pub fn token_expired(&self, token: &str, now: i64) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT COUNT(*) > 0 FROM tokens WHERE token = ?1 AND used = 0 AND expires_at <= ?2",
+            params![token, now],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn delete_agent_approval(&mut self, agent_id: &str) -> Result<()> {
+        self.execute(
+            "DELETE FROM agent_approvals WHERE agent_id = ?1",
+            params![agent_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_agent_approvals(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT agent_id, state FROM agent_approvals")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut approvals = Vec::new();
+        for row in rows {
+            approvals.push(row?);
+        }
+        Ok(approvals)
+    }
+
+    pub fn replace_topology_seed(&mut self, rows: &[TopologySeedRow]) -> Result<()> {
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            tx.execute("DELETE FROM topology_seed", [])?;
+            for row in rows {
+                tx.execute(
+                    "INSERT INTO topology_seed (mac, agent_id, switch_name, port) VALUES (?1, ?2, ?3, ?4)",
+                    params![row.mac, row.agent_id, row.switch_name, row.port],
+                )?;
+            }
+            tx.commit()
+        })
+    }
+
+    pub fn get_topology_seed(&self) -> Result<Vec<TopologySeedRow>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT mac, agent_id, switch_name, port FROM topology_seed ORDER BY id ASC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(TopologySeedRow {
+                mac: row.get(0)?,
+                agent_id: row.get(1)?,
+                switch_name: row.get(2)?,
+                port: row.get(3)?,
+            })
+        })?;
+
+        let mut seed = Vec::new();
+        for row in rows {
+            seed.push(row?);
+        }
+        Ok(seed)
+    }
+
+    pub fn add_block(&mut self, kind: &str, value: &str, ts: i64) -> Result<()> {
+        self.execute(
+            "INSERT OR IGNORE INTO blocklist (kind, value, created_at) VALUES (?1, ?2, ?3)",
+            params![kind, value, ts],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_block(&mut self, kind: &str, value: &str) -> Result<()> {
+        self.execute(
+            "DELETE FROM blocklist WHERE kind = ?1 AND value = ?2",
+            params![kind, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn add_allowed_agent(&mut self, agent_id: &str, ts: i64) -> Result<()> {
+        self.execute(
+            "INSERT OR IGNORE INTO agent_allowlist (agent_id, created_at) VALUES (?1, ?2)",
+            params![agent_id, ts],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_allowed_agent(&mut self, agent_id: &str) -> Result<()> {
+        self.execute(
+            "DELETE FROM agent_allowlist WHERE agent_id = ?1",
+            params![agent_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_allowed_agents(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT agent_id FROM agent_allowlist ORDER BY created_at ASC")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut agents = Vec::new();
+        for row in rows {
+            agents.push(row?);
+        }
+        Ok(agents)
+    }
+
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn set_setting(&mut self, key: &str, value: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_network_override(&mut self, agent_id: &str, facts: &str, ts: i64) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO network_overrides (agent_id, facts, updated_at)
+             VALUES (?1, ?2, ?3)",
+            params![agent_id, facts, ts],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_network_override(&mut self, agent_id: &str) -> Result<()> {
+        self.execute(
+            "DELETE FROM network_overrides WHERE agent_id = ?1",
+            params![agent_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn replace_network_overrides(&mut self, rows: &[(String, String)], ts: i64) -> Result<()> {
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            tx.execute("DELETE FROM network_overrides", [])?;
+            for (agent_id, facts) in rows {
+                tx.execute(
+                    "INSERT INTO network_overrides (agent_id, facts, updated_at) VALUES (?1, ?2, ?3)",
+                    params![agent_id, facts, ts],
+                )?;
+            }
+            tx.commit()
+        })
+    }
+
+    /// `(agent_id, facts JSON)` pairs.
+    pub fn get_network_overrides(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT agent_id, facts FROM network_overrides")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut overrides = Vec::new();
+        for row in rows {
+            overrides.push(row?);
+        }
+        Ok(overrides)
+    }
+
+    /// Appends after every existing rule.
+    pub fn insert_alias_rule(&mut self, id: &str, rule: &str, ts: i64) -> Result<()> {
+        self.execute(
+            "INSERT INTO alias_rules (id, position, rule, created_at)
+             VALUES (?1, (SELECT COALESCE(MAX(position), 0) + 1 FROM alias_rules), ?2, ?3)",
+            params![id, rule, ts],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_alias_rule(&mut self, id: &str) -> Result<()> {
+        self.execute("DELETE FROM alias_rules WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// `(id, rule JSON, created_at)` in evaluation order.
+    pub fn replace_alias_rules(&mut self, rows: &[(String, String, i64)]) -> Result<()> {
+        retry_busy(|| {
+            let tx = self.conn.transaction()?;
+            tx.execute("DELETE FROM alias_rules", [])?;
+            for (position, (id, rule, created_at)) in rows.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO alias_rules (id, position, rule, created_at) VALUES (?1, ?2, ?3, ?4)",
+                    params![id, position as i64 + 1, rule, created_at],
+                )?;
+            }
+            tx.commit()
+        })
+    }
+
+    /// Rule JSON in evaluation order.
+    pub fn get_alias_rules(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rule FROM alias_rules ORDER BY position ASC")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut rules = Vec::new();
+        for row in rows {
+            rules.push(row?);
+        }
+        Ok(rules)
+    }
+
+    pub fn get_blocks(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT kind, value FROM blocklist ORDER BY created_at ASC")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut blocks = Vec::new();
+        for row in rows {
+            blocks.push(row?);
+        }
+        Ok(blocks)
+    }
+
+    pub fn delete_device(&mut self, device_id: &str) -> Result<()> {
+        self.execute(
+            "DELETE FROM heartbeats WHERE device_id = ?1",
+            params![device_id],
+        )?;
+        self.execute(
+            "DELETE FROM device_history WHERE device_id = ?1",
+            params![device_id],
+        )?;
+        self.execute("DELETE FROM devices WHERE id = ?1", params![device_id])?;
+        Ok(())
+    }
+
     pub fn get_all_devices(&self) -> Result<Vec<Device>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, hostname, os, arch, agent_version, local_ip, mac_address, 
-                    gateway_ip, dns_servers, registered_at, last_seen, is_online 
+                    gateway_ip, dns_servers, registered_at, last_seen, is_online,
+                    record, record_version
              FROM devices ORDER BY last_seen DESC",
         )?;
 
@@ -176,6 +1045,8 @@ impl Database {
                 registered_at: row.get(9)?,
                 last_seen: row.get(10)?,
                 is_online: row.get(11)?,
+                record: row.get(12)?,
+                record_version: row.get(13)?,
             })
         })?;
 
@@ -187,11 +1058,413 @@ impl Database {
     }
 }
 
+impl Database {
+    /// Writes a consistent, compacted copy of the database to `path`.
+    pub fn backup_to(&self, path: &std::path::Path) -> Result<()> {
+        self.execute(
+            "VACUUM INTO ?1",
+            params![path.to_string_lossy().to_string()],
+        )?;
+        Ok(())
+    }
+}
+
+fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(e, _)
+            if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+/// Runs `op` again with exponential backoff while SQLite reports busy/locked,
+/// giving up after `BUSY_RETRY_ATTEMPTS` retries (~310ms in total).
+pub fn retry_busy<T>(mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(err) if is_busy(&err) && attempt < BUSY_RETRY_ATTEMPTS => {
+                thread::sleep(Duration::from_millis(BUSY_RETRY_BASE_MS << attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub fn init_database() -> Result<Database> {
     let conn = Connection::open("labscan.db")?;
+    conn.busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS))?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
     let db = Database::new(conn);
     db.create_tables()?;
     Ok(db)
 }
 
 pub type DbPool = Arc<Mutex<Database>>;
+
+fn availability(timestamps: &[i64], since_ms: i64, until_ms: i64, window_ms: i64) -> Availability {
+    let sample_count = timestamps.len();
+    if sample_count == 0 || window_ms <= 0 || until_ms <= since_ms {
+        return Availability {
+            uptime_pct: None,
+            longest_online_ms: 0,
+            sample_count,
+        };
+    }
+
+    let windows = (until_ms - since_ms + window_ms - 1) / window_ms;
+    let mut covered = 0;
+    let mut last_window = None;
+    for ts in timestamps {
+        let window = (ts - since_ms) / window_ms;
+        if last_window != Some(window) {
+            covered += 1;
+            last_window = Some(window);
+        }
+    }
+
+    let mut longest_online_ms = 0;
+    let mut streak_start = timestamps[0];
+    for pair in timestamps.windows(2) {
+        if pair[1] - pair[0] > window_ms {
+            streak_start = pair[1];
+        }
+        longest_online_ms = longest_online_ms.max(pair[1] - streak_start);
+    }
+
+    Availability {
+        uptime_pct: Some(covered as f64 * 100.0 / windows as f64),
+        longest_online_ms,
+        sample_count,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_tables_upgrades_old_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE devices (
+                id TEXT PRIMARY KEY,
+                hostname TEXT NOT NULL,
+                os TEXT NOT NULL,
+                arch TEXT NOT NULL,
+                agent_version TEXT NOT NULL,
+                local_ip TEXT NOT NULL,
+                mac_address TEXT,
+                gateway_ip TEXT,
+                dns_servers TEXT,
+                registered_at INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                is_online BOOLEAN NOT NULL DEFAULT 1
+            );
+            INSERT INTO devices VALUES
+                ('agent-1', 'lab-pc-01', 'windows', '', '0.1.0', '192.168.1.20',
+                 'aa:bb:cc:dd:ee:01', '192.168.1.1', '', 1000, 2000, 1);",
+        )
+        .unwrap();
+
+        let db = Database::new(conn);
+        db.create_tables().unwrap();
+        // Running again must be a no-op.
+        db.create_tables().unwrap();
+
+        let devices = db.get_all_devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].hostname, "lab-pc-01");
+        assert_eq!(devices[0].gateway_ip.as_deref(), Some("192.168.1.1"));
+        assert_eq!(devices[0].dns_servers, None);
+        assert_eq!(devices[0].record, None);
+        assert_eq!(devices[0].record_version, 0);
+    }
+
+    #[test]
+    fn device_facts_history_dedupes_and_looks_up_by_time() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+
+        db.record_device_facts("agent-1", 100, r#"{"status":"online"}"#)
+            .unwrap();
+        db.record_device_facts("agent-1", 200, r#"{"status":"online"}"#)
+            .unwrap();
+        db.record_device_facts("agent-1", 300, r#"{"status":"offline"}"#)
+            .unwrap();
+
+        assert_eq!(db.get_device_facts_at("agent-1", 50).unwrap(), None);
+        assert_eq!(
+            db.get_device_facts_at("agent-1", 250).unwrap(),
+            Some((100, r#"{"status":"online"}"#.to_string()))
+        );
+        assert_eq!(
+            db.get_device_facts_at("agent-1", 300)
+                .unwrap()
+                .map(|(ts, _)| ts),
+            Some(300)
+        );
+    }
+
+    #[test]
+    fn availability_counts_heartbeat_windows_and_streaks() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        assert_eq!(
+            db.get_availability("gw", 0, 100, 10).unwrap().uptime_pct,
+            None
+        );
+
+        db.conn
+            .execute(
+                "INSERT INTO devices (id, hostname, os, arch, agent_version, local_ip, registered_at, last_seen)
+                 VALUES ('gw', 'gw', '', '', '', '', 0, 0)",
+                [],
+            )
+            .unwrap();
+        // Windows 0-3 and 7-8 of ten; the gap from 35 to 72 breaks the streak.
+        for ts in [1, 8, 15, 25, 35, 72, 80, 85] {
+            db.record_heartbeat(Heartbeat {
+                id: ts.to_string(),
+                device_id: "gw".to_string(),
+                timestamp: ts,
+                gateway_reachable: true,
+                dns_resolves: true,
+                https_latency_ms: None,
+                local_ports: None,
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            db.get_availability("gw", 0, 100, 10).unwrap(),
+            Availability {
+                uptime_pct: Some(60.0),
+                longest_online_ms: 34,
+                sample_count: 8,
+            }
+        );
+        let newest: Vec<i64> = db
+            .get_heartbeats("gw", 0, 3)
+            .unwrap()
+            .iter()
+            .map(|h| h.timestamp)
+            .collect();
+        assert_eq!(newest, vec![72, 80, 85]);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        db.insert_token("old", 100, 200).unwrap();
+
+        assert!(!db.consume_token("old", 200).unwrap());
+        assert!(db.token_expired("old", 200).unwrap());
+        assert!(!db.token_expired("unknown", 200).unwrap());
+    }
+
+    #[test]
+    fn valid_tokens_are_consumed_once() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        db.insert_token("fresh", 100, 1_000).unwrap();
+
+        assert!(db.consume_token("fresh", 500).unwrap());
+        let used: bool = db
+            .conn
+            .query_row("SELECT used FROM tokens WHERE token = 'fresh'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(used);
+        assert!(!db.consume_token("fresh", 600).unwrap());
+        assert!(!db.token_expired("fresh", 2_000).unwrap());
+    }
+
+    #[test]
+    fn writes_retry_until_a_competing_lock_is_released() {
+        let path = std::env::temp_dir().join(format!("labscan-busy-{}.db", uuid::Uuid::new_v4()));
+        let setup = Database::new(Connection::open(&path).unwrap());
+        setup.create_tables().unwrap();
+
+        let holder = Connection::open(&path).unwrap();
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+        let contender = Connection::open(&path).unwrap();
+        contender.busy_timeout(Duration::ZERO).unwrap();
+        let mut db = Database::new(contender);
+        let raw = db.conn.execute(
+            "INSERT INTO blocklist (kind, value, created_at) VALUES ('agent', 'x', 0)",
+            [],
+        );
+        assert!(raw.as_ref().is_err_and(is_busy));
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(40));
+            holder.execute_batch("COMMIT").unwrap();
+        });
+        db.add_block("agent", "agent-1", 1).unwrap();
+        release.join().unwrap();
+
+        assert_eq!(
+            db.get_blocks().unwrap(),
+            vec![("agent".to_string(), "agent-1".to_string())]
+        );
+        drop((db, setup));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn event_journal_keeps_the_newest_events_in_order() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        assert_eq!(db.event_journal_bounds().unwrap(), None);
+
+        for seq in 1..=5 {
+            db.append_event(
+                &JournalRow {
+                    seq,
+                    ts: seq * 10,
+                    event: "log_event".to_string(),
+                    payload: format!(r#"{{"event_seq":{}}}"#, seq),
+                },
+                3,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(db.event_journal_bounds().unwrap(), Some((3, 5)));
+        let after = db.get_events_after(3, 10).unwrap();
+        assert_eq!(after.iter().map(|r| r.seq).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(db.get_events_after(0, 1).unwrap()[0].seq, 3);
+    }
+
+    #[test]
+    fn topology_checkpoints_are_listed_and_pruned_by_age() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        for revision in 1..=3 {
+            db.insert_topology_checkpoint(
+                &TopologyCheckpointRow {
+                    revision,
+                    updated_at: revision * 1_000,
+                    node_count: revision,
+                    edge_count: revision - 1,
+                    nodes: "[]".to_string(),
+                    edges: "[]".to_string(),
+                },
+                1_500,
+            )
+            .unwrap();
+        }
+
+        let all = db.get_topology_checkpoints(0, 10).unwrap();
+        assert_eq!(
+            all.iter().map(|c| c.revision).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        let recent = db.get_topology_checkpoints(2_500, 10).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].edge_count, 2);
+        let newest = db.get_topology_checkpoints(0, 1).unwrap();
+        assert_eq!(newest[0].revision, 3);
+        assert_eq!(db.max_topology_revision().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn task_records_mentioning_matches_quoted_agent_id() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+
+        for (id, agents, created_at) in [
+            ("t1", r#"["agent-1","agent-2"]"#, 100),
+            ("t2", r#"["agent-10"]"#, 200),
+            ("t3", r#"["agent-1"]"#, 50),
+        ] {
+            db.save_task(TaskRow {
+                id: id.to_string(),
+                kind: "ping".to_string(),
+                label: None,
+                metadata: None,
+                status: "done".to_string(),
+                created_at,
+                record: format!(r#"{{"task_id":"{}","assigned_agents":{}}}"#, id, agents),
+            })
+            .unwrap();
+        }
+
+        let records = db.get_task_records_mentioning("agent-1", 75).unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].contains(r#""task_id":"t1""#));
+    }
+
+    #[test]
+    fn task_search_treats_wildcards_as_text() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+
+        for (id, label) in [
+            ("t1", "disk 100% full"),
+            ("t2", "disk_check"),
+            ("t3", "disk 1000"),
+        ] {
+            db.save_task(TaskRow {
+                id: id.to_string(),
+                kind: "exec".to_string(),
+                label: Some(label.to_string()),
+                metadata: None,
+                status: "done".to_string(),
+                created_at: 0,
+                record: id.to_string(),
+            })
+            .unwrap();
+        }
+
+        assert_eq!(db.search_tasks("100%", 10).unwrap(), vec!["t1"]);
+        assert_eq!(db.search_tasks("disk_", 10).unwrap(), vec!["t2"]);
+        assert_eq!(db.search_tasks("DISK", 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn activity_upserts_coalesced_counts_and_filters() {
+        let mut db = Database::new(Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+
+        let mut row = ActivityRow {
+            id: "evt-1".to_string(),
+            kind: "device_disconnected".to_string(),
+            agent_id: Some("agent-1".to_string()),
+            message: "lab-pc-01 disconnected".to_string(),
+            ts: 1000,
+            count: None,
+        };
+        db.save_activity(&row).unwrap();
+        row.ts = 2000;
+        row.count = Some(2);
+        db.save_activity(&row).unwrap();
+        db.save_activity(&ActivityRow {
+            id: "evt-2".to_string(),
+            kind: "task_started".to_string(),
+            agent_id: None,
+            message: "Task started".to_string(),
+            ts: 1500,
+            count: None,
+        })
+        .unwrap();
+
+        let all = db.query_activity(0, None, None, 10).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].ts, all[0].count), (2000, Some(2)));
+
+        let by_agent = db.query_activity(0, None, Some("agent-1"), 10).unwrap();
+        assert_eq!(by_agent.len(), 1);
+        let by_kind = db
+            .query_activity(0, Some("task_started"), None, 10)
+            .unwrap();
+        assert_eq!(by_kind[0].id, "evt-2");
+
+        assert_eq!(db.prune_activity(1800).unwrap(), 1);
+        assert_eq!(db.query_activity(0, None, None, 10).unwrap().len(), 1);
+    }
+}
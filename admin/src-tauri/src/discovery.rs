@@ -2,11 +2,11 @@ use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::time;
 
-const MULTICAST_GROUP: &str = "239.255.77.77";
-const MULTICAST_PORT: u16 = 47777;
-const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+pub const DEFAULT_MULTICAST_GROUP: &str = "239.255.77.77";
+pub const DEFAULT_MULTICAST_PORT: u16 = 47777;
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+const SERVICE_TYPE: &str = "_netmon._tcp.local.";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BeaconPayload {
@@ -19,48 +19,58 @@ pub struct BeaconPayload {
     pub issued_at_unix: i64,
 }
 
+/// Multicast beacon sender. The token and fingerprint are passed per send so
+/// a rotated pair token or regenerated certificate goes out on the next beacon.
 pub struct DiscoveryService {
     socket: UdpSocket,
     admin_ip: Ipv4Addr,
-    tls_fingerprint: String,
+    target: SocketAddr,
 }
 
 impl DiscoveryService {
-    pub async fn new(admin_ip: Ipv4Addr, tls_fingerprint: String) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", MULTICAST_PORT)).await?;
-        
-        let multi_addr = MULTICAST_GROUP.parse::<Ipv4Addr>()?;
+    pub async fn new(
+        admin_ip: Ipv4Addr,
+        group: Ipv4Addr,
+        port: u16,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+
         let interface = Ipv4Addr::UNSPECIFIED;
-        
-        socket.join_multicast_v4(multi_addr, interface)?;
+
+        socket.join_multicast_v4(group, interface)?;
         socket.set_multicast_loop_v4(true)?;
-        
+
         Ok(Self {
             socket,
             admin_ip,
-            tls_fingerprint,
+            target: SocketAddr::new(IpAddr::V4(group), port),
         })
     }
 
-    pub async fn start_beacon(&self, join_token: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let multi_addr: Ipv4Addr = MULTICAST_GROUP.parse()?;
-        let broadcast_addr = SocketAddr::new(IpAddr::V4(multi_addr), MULTICAST_PORT);
-        
+    /// An empty fingerprint means the WS listener is plain, so the URL is `http`.
+    pub async fn send_beacon(
+        &self,
+        ws_port: u16,
+        join_token: &str,
+        tls_fingerprint: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let scheme = if tls_fingerprint.is_empty() {
+            "http"
+        } else {
+            "https"
+        };
         let payload = BeaconPayload {
             beacon_type: "NETMON_ADMIN".to_string(),
             version: "1".to_string(),
-            admin_https_url: format!("https://{}:8443", self.admin_ip),
-            tls_fingerprint_sha256: self.tls_fingerprint.clone(),
-            join_token,
+            admin_https_url: format!("{}://{}:{}", scheme, self.admin_ip, ws_port),
+            tls_fingerprint_sha256: tls_fingerprint.to_string(),
+            join_token: join_token.to_string(),
             issued_at_unix: chrono::Utc::now().timestamp(),
         };
 
         let json_payload = serde_json::to_vec(&payload)?;
-        
-        loop {
-            self.socket.send_to(&json_payload, broadcast_addr).await?;
-            time::sleep(BEACON_INTERVAL).await;
-        }
+        self.socket.send_to(&json_payload, self.target).await?;
+        Ok(())
     }
 }
 
@@ -75,20 +85,25 @@ impl MDNSService {
         Ok(Self { service })
     }
 
-    pub fn advertise(&self, name: &str, port: u16, tls_fingerprint: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let service_type = "_netmon._tcp.local.";
-        let hostname = format!("{}.{}", name, service_type);
-        
+    pub fn advertise(
+        &self,
+        name: &str,
+        host_ip: Ipv4Addr,
+        port: u16,
+        tls_fingerprint: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let hostname = format!("{}.{}", name, SERVICE_TYPE);
+
         let mut txt = std::collections::HashMap::new();
         txt.insert("v".to_string(), "1".to_string());
         txt.insert("fp".to_string(), tls_fingerprint.to_string());
         txt.insert("name".to_string(), name.to_string());
 
         let my_service = mdns_sd::ServiceInfo::new(
-            service_type,
+            SERVICE_TYPE,
             name,
             &hostname,
-            "",
+            host_ip.to_string().as_str(),
             port,
             Some(txt),
         )?;
@@ -96,4 +111,10 @@ impl MDNSService {
         self.service.register(my_service)?;
         Ok(())
     }
+
+    pub fn withdraw(&self, name: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.service
+            .unregister(&format!("{}.{}", name, SERVICE_TYPE))?;
+        Ok(())
+    }
 }
@@ -0,0 +1,78 @@
+use thiserror::Error;
+
+/// Errors from `ServerManager`. Commands hand them to the frontend as their
+/// display string, see `From<ServerError> for String`.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("bind {addr} failed: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{context} failed: {source}")]
+    Database {
+        context: &'static str,
+        #[source]
+        source: rusqlite::Error,
+    },
+    #[error("serialization failed: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Tls(String),
+    #[error("{0}")]
+    Export(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("{0}")]
+    NotFound(String),
+    /// The request clashes with current state, e.g. a limit is reached.
+    #[error("{0}")]
+    Conflict(String),
+    /// Refused by policy: blocked, pending approval, disabled in this build.
+    #[error("{0}")]
+    NotPermitted(String),
+    /// An agent or subsystem needed for the request is not connected or running.
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+impl ServerError {
+    /// For `map_err`: labels a database failure with what was being done.
+    pub fn db(context: &'static str) -> impl FnOnce(rusqlite::Error) -> ServerError {
+        move |source| ServerError::Database { context, source }
+    }
+}
+
+impl From<ServerError> for String {
+    fn from(err: ServerError) -> Self {
+        err.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_errors_name_the_failed_operation() {
+        let err = ServerError::db("journal query")(rusqlite::Error::InvalidQuery);
+        assert!(matches!(err, ServerError::Database { .. }));
+        assert_eq!(
+            String::from(err),
+            "journal query failed: Query is not read-only"
+        );
+        assert_eq!(
+            String::from(ServerError::NotFound("unknown agent lab-1".to_string())),
+            "unknown agent lab-1"
+        );
+    }
+}
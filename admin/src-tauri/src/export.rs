@@ -0,0 +1,116 @@
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportResult {
+    pub path: String,
+    pub bytes: u64,
+    pub compressed: bool,
+}
+
+/// Final file name for an export; compressed exports always end in `.gz`.
+pub fn export_path(path: &str, compress: bool) -> PathBuf {
+    let path = PathBuf::from(path.trim());
+    if compress && path.extension().and_then(|e| e.to_str()) != Some("gz") {
+        let mut name = path.clone().into_os_string();
+        name.push(".gz");
+        return PathBuf::from(name);
+    }
+    path
+}
+
+/// Streams `write_body` into the export file, through a gzip encoder when
+/// `compress` is set, so large exports never sit in memory in full.
+pub fn write_export(
+    path: &str,
+    compress: bool,
+    write_body: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<ExportResult, String> {
+    if path.trim().is_empty() {
+        return Err("export path is required".to_string());
+    }
+    let target = export_path(path, compress);
+    let file = File::create(&target).map_err(|e| format!("create {}: {}", target.display(), e))?;
+    let written = if compress {
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        write_body(&mut encoder)
+            .and_then(|_| encoder.finish())
+            .and_then(|mut inner| inner.flush())
+    } else {
+        let mut writer = BufWriter::new(file);
+        write_body(&mut writer).and_then(|_| writer.flush())
+    };
+    written.map_err(|e| format!("write {}: {}", target.display(), e))?;
+
+    let bytes = fs::metadata(&target)
+        .map_err(|e| format!("stat {}: {}", target.display(), e))?
+        .len();
+    Ok(ExportResult {
+        path: target.display().to_string(),
+        bytes,
+        compressed: compress,
+    })
+}
+
+pub fn copy_file_export(source: &Path, path: &str, compress: bool) -> Result<ExportResult, String> {
+    let mut input = File::open(source).map_err(|e| format!("open {}: {}", source.display(), e))?;
+    write_export(path, compress, |out| io::copy(&mut input, out).map(|_| ()))
+}
+
+/// Writes `items` as a JSON array one element at a time.
+pub fn write_json_array<T: Serialize>(out: &mut dyn Write, items: &[T]) -> io::Result<()> {
+    out.write_all(b"[\n")?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut *out, item)?;
+    }
+    out.write_all(b"\n]\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compressed_export_round_trips_with_gz_extension() {
+        let dir = std::env::temp_dir().join(format!("labscan-export-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let rows: Vec<serde_json::Value> = (0..500)
+            .map(|i| serde_json::json!({ "agent_id": format!("agent-{}", i), "ip": "10.0.0.1" }))
+            .collect();
+
+        let plain_path = dir.join("devices.json");
+        let plain = write_export(plain_path.to_str().unwrap(), false, |out| {
+            write_json_array(out, &rows)
+        })
+        .unwrap();
+        let packed = write_export(plain_path.to_str().unwrap(), true, |out| {
+            write_json_array(out, &rows)
+        })
+        .unwrap();
+
+        assert!(packed.path.ends_with("devices.json.gz"));
+        assert!(packed.compressed);
+        assert!(packed.bytes < plain.bytes);
+
+        let mut text = String::new();
+        GzDecoder::new(File::open(&packed.path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        let decoded: Vec<serde_json::Value> = serde_json::from_str(&text).unwrap();
+        assert_eq!(decoded, rows);
+        assert_eq!(fs::read_to_string(&plain.path).unwrap(), text);
+
+        assert_eq!(export_path("/tmp/x.gz", true), PathBuf::from("/tmp/x.gz"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,9 +2,24 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 // #![windows_subsystem = "console"]
 
+mod alias;
+mod clock;
+mod config;
+mod database;
+mod discovery;
+mod error;
+mod export;
 mod logger;
+mod metrics;
+mod netutil;
+mod quality;
+mod sanitize;
 mod server;
+mod tls;
+mod topology;
+mod webhook;
 
+use std::sync::Arc;
 use tauri::{AppHandle, Manager};
 
 #[tokio::main]
@@ -12,27 +27,107 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     tracing::info!("Tauri backend startup");
 
-    let manager = server::ServerManager::new();
+    let settings = match config::load() {
+        Ok((settings, path)) => {
+            if let Some(path) = path {
+                tracing::info!("Loaded config from {}", path.display());
+            }
+            settings
+        }
+        Err(err) => {
+            tracing::error!("Invalid config, using defaults: {}", err);
+            config::LabScanConfig::default()
+        }
+    };
+
+    let db = database::init_database()?;
 
     tauri::Builder::default()
-        .manage(manager)
-        .setup(|app| {
+        .setup(move |app| {
+            let data_dir = app.path().app_data_dir()?;
+            let manager =
+                server::ServerManager::new(Arc::new(tokio::sync::Mutex::new(db)), settings)?
+                    .with_data_dir(&data_dir);
+            app.manage(manager.clone());
             let app_handle = app.handle().clone();
-            let manager = app.state::<server::ServerManager>().inner().clone();
             tauri::async_runtime::spawn(async move {
-                manager.start_runtime(app_handle).await;
+                manager.start_runtime(Arc::new(app_handle)).await;
             });
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             get_server_status,
+            get_config,
+            set_config,
             get_devices_snapshot,
+            get_devices_digest,
             get_topology_snapshot,
             get_tasks_snapshot,
             get_activity_snapshot,
             dispatch_task,
+            search_tasks,
             get_pair_token,
             rotate_pair_token,
+            notify_update,
+            inject_mock_devices,
+            clear_mock_devices,
+            test_provisioning,
+            send_agent_control,
+            approve_agent,
+            reject_agent,
+            get_discovery_stats,
+            get_device_latency_percentiles,
+            follow_agent,
+            get_blocklist,
+            get_task_failure_stats,
+            get_webhook_stats,
+            set_loop_enabled,
+            get_topology_seed,
+            import_topology_seed,
+            retry_failed,
+            get_tls_fingerprint,
+            regenerate_tls_certificate,
+            diff_device,
+            query_activity,
+            prune_activity,
+            render_device_metrics,
+            force_full_refresh,
+            get_device_audit,
+            export_device_inventory,
+            export_database,
+            get_devices_page,
+            measure_all_agents,
+            get_events_since,
+            get_effective_config,
+            reload_config,
+            dispatch_mesh_ping,
+            get_topology_quality,
+            set_wire_trace,
+            get_topology_checkpoints,
+            get_device,
+            validate_target,
+            override_device_network,
+            clear_device_network_override,
+            add_alias_rule,
+            list_alias_rules,
+            remove_alias_rule,
+            export_runtime_state,
+            import_runtime_state,
+            await_task,
+            cancel_task,
+            mint_enrollment_token,
+            stop_server,
+            start_server,
+            remove_device,
+            get_device_uptime,
+            block_agent,
+            unblock_agent,
+            block_source,
+            unblock_source,
+            get_agent_allowlist,
+            set_agent_allowlist_enabled,
+            allow_agent,
+            deny_agent,
             logger::log_debug,
             logger::log_info,
             logger::log_warn,
@@ -51,6 +146,22 @@ async fn get_server_status(
     Ok(state.get_status().await)
 }
 
+#[tauri::command]
+async fn get_config(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<config::ServerConfig, String> {
+    Ok(state.get_config().await)
+}
+
+#[tauri::command]
+async fn set_config(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    config: config::ServerConfig,
+) -> Result<config::ServerConfig, String> {
+    state.set_config(&app, config).await.map_err(String::from)
+}
+
 #[tauri::command]
 async fn get_devices_snapshot(
     state: tauri::State<'_, server::ServerManager>,
@@ -58,10 +169,21 @@ async fn get_devices_snapshot(
     Ok(state.get_devices_snapshot().await)
 }
 
+#[tauri::command]
+async fn get_devices_digest(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<String, String> {
+    Ok(state.get_devices_digest().await)
+}
+
 #[tauri::command]
 async fn get_topology_snapshot(
     state: tauri::State<'_, server::ServerManager>,
+    include_unmanaged: Option<bool>,
 ) -> Result<server::TopologySnapshot, String> {
+    if include_unmanaged.unwrap_or(false) {
+        return Ok(state.get_topology_with_unmanaged().await);
+    }
     Ok(state.get_topology_snapshot().await)
 }
 
@@ -86,8 +208,20 @@ async fn dispatch_task(
     agents: Vec<String>,
     kind: String,
     params: serde_json::Value,
+    options: Option<server::TaskOptions>,
 ) -> Result<server::TaskRecord, String> {
-    state.dispatch_task(app, agents, kind, params).await
+    state
+        .dispatch_task(&app, agents, kind, params, options.unwrap_or_default())
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn search_tasks(
+    state: tauri::State<'_, server::ServerManager>,
+    query: String,
+) -> Result<Vec<server::TaskRecord>, String> {
+    state.search_tasks(query).await.map_err(String::from)
 }
 
 #[tauri::command]
@@ -100,5 +234,620 @@ async fn rotate_pair_token(
     app: AppHandle,
     state: tauri::State<'_, server::ServerManager>,
 ) -> Result<String, String> {
-    state.rotate_pair_token(app).await
+    state.rotate_pair_token(&app).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn notify_update(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    min_version: String,
+    download_url: String,
+) -> Result<usize, String> {
+    state
+        .notify_update(&app, min_version, download_url)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn inject_mock_devices(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    count: usize,
+) -> Result<usize, String> {
+    state
+        .inject_mock_devices(&app, count)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn clear_mock_devices(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<usize, String> {
+    state.clear_mock_devices(&app).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn test_provisioning(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::ProvisioningReport, String> {
+    state.test_provisioning(&app).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn send_agent_control(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    action: String,
+) -> Result<String, String> {
+    state
+        .send_agent_control(&app, agent_id, action)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn approve_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<(), String> {
+    state
+        .approve_agent(&app, agent_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn reject_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    block: Option<bool>,
+) -> Result<(), String> {
+    state
+        .reject_agent(&app, agent_id, block.unwrap_or(false))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_discovery_stats(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::DiscoveryStats, String> {
+    Ok(state.get_discovery_stats().await)
+}
+
+#[tauri::command]
+async fn get_device_latency_percentiles(
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    since_ms: i64,
+) -> Result<server::LatencyPercentiles, String> {
+    state
+        .get_device_latency_percentiles(agent_id, since_ms)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn follow_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    enabled: bool,
+) -> Result<Vec<String>, String> {
+    state
+        .follow_agent(&app, agent_id, enabled)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_blocklist(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::Blocklist, String> {
+    Ok(state.get_blocklist().await)
+}
+
+#[tauri::command]
+async fn block_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<server::Blocklist, String> {
+    state
+        .block_agent(&app, agent_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn unblock_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<server::Blocklist, String> {
+    state
+        .unblock_agent(&app, agent_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn block_source(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    cidr: String,
+) -> Result<server::Blocklist, String> {
+    state.block_source(&app, cidr).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn unblock_source(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    cidr: String,
+) -> Result<server::Blocklist, String> {
+    state.unblock_source(&app, cidr).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_agent_allowlist(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::AgentAllowlist, String> {
+    Ok(state.get_agent_allowlist().await)
+}
+
+#[tauri::command]
+async fn set_agent_allowlist_enabled(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    enabled: bool,
+) -> Result<server::AgentAllowlist, String> {
+    state
+        .set_agent_allowlist_enabled(&app, enabled)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn allow_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<server::AgentAllowlist, String> {
+    state
+        .allow_agent(&app, agent_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn deny_agent(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<server::AgentAllowlist, String> {
+    state.deny_agent(&app, agent_id).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_task_failure_stats(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::TaskFailureStats, String> {
+    Ok(state.get_task_failure_stats().await)
+}
+
+#[tauri::command]
+async fn get_webhook_stats(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<webhook::WebhookStats, String> {
+    Ok(state.get_webhook_stats().await)
+}
+
+#[tauri::command]
+async fn set_loop_enabled(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    loop_name: String,
+    enabled: bool,
+) -> Result<server::LoopStatus, String> {
+    state
+        .set_loop_enabled(&app, loop_name, enabled)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_topology_seed(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<Vec<topology::TopologySeedEntry>, String> {
+    Ok(state.get_topology_seed().await)
+}
+
+#[tauri::command]
+async fn import_topology_seed(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    json: String,
+) -> Result<usize, String> {
+    state
+        .import_topology_seed(&app, json)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn retry_failed(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    task_id: String,
+) -> Result<server::TaskRecord, String> {
+    state
+        .retry_failed(&app, task_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_tls_fingerprint(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<String, String> {
+    state.get_tls_fingerprint().await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn regenerate_tls_certificate(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<String, String> {
+    state
+        .regenerate_tls_certificate(&app)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn diff_device(
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    ts_a: i64,
+    ts_b: i64,
+) -> Result<server::DeviceDiff, String> {
+    state
+        .diff_device(agent_id, ts_a, ts_b)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn query_activity(
+    state: tauri::State<'_, server::ServerManager>,
+    since_ms: i64,
+    kind: Option<String>,
+    agent_id: Option<String>,
+    limit: Option<usize>,
+) -> Result<Vec<server::ActivityEvent>, String> {
+    state
+        .query_activity(since_ms, kind, agent_id, limit)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn prune_activity(
+    state: tauri::State<'_, server::ServerManager>,
+    before_ms: i64,
+) -> Result<usize, String> {
+    state.prune_activity(before_ms).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn render_device_metrics(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<String, String> {
+    Ok(state.render_device_metrics().await)
+}
+
+#[tauri::command]
+async fn force_full_refresh(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<(), String> {
+    state.force_full_refresh(&app).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_device_audit(
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    since_ms: i64,
+) -> Result<server::DeviceAudit, String> {
+    state
+        .get_device_audit(agent_id, since_ms)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn export_device_inventory(
+    state: tauri::State<'_, server::ServerManager>,
+    path: String,
+    compress: Option<bool>,
+) -> Result<export::ExportResult, String> {
+    state
+        .export_device_inventory(path, compress.unwrap_or(false))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn export_database(
+    state: tauri::State<'_, server::ServerManager>,
+    path: String,
+    compress: Option<bool>,
+) -> Result<export::ExportResult, String> {
+    state
+        .export_database(path, compress.unwrap_or(false))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_devices_page(
+    state: tauri::State<'_, server::ServerManager>,
+    offset: usize,
+    limit: usize,
+    sort_by: Option<String>,
+    descending: Option<bool>,
+) -> Result<server::DevicesPage, String> {
+    state
+        .get_devices_page(offset, limit, sort_by, descending.unwrap_or(false))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn measure_all_agents(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::AgentRttReport, String> {
+    Ok(state.measure_all_agents().await)
+}
+
+#[tauri::command]
+async fn get_events_since(
+    state: tauri::State<'_, server::ServerManager>,
+    seq: u64,
+) -> Result<server::EventReplay, String> {
+    state.get_events_since(seq).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_effective_config(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<config::LabScanConfig, String> {
+    Ok(state.get_effective_config().await)
+}
+
+#[tauri::command]
+async fn reload_config(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::ConfigReload, String> {
+    state.reload_config(&app).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn dispatch_mesh_ping(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agents: Vec<String>,
+) -> Result<server::MeshPingMatrix, String> {
+    state
+        .dispatch_mesh_ping(&app, agents)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_topology_quality(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<topology::TopologyQuality, String> {
+    Ok(state.get_topology_quality().await)
+}
+
+#[tauri::command]
+async fn set_wire_trace(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    enabled: bool,
+) -> Result<server::WireTraceStatus, String> {
+    state
+        .set_wire_trace(&app, agent_id, enabled)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_topology_checkpoints(
+    state: tauri::State<'_, server::ServerManager>,
+    since_ms: Option<i64>,
+) -> Result<Vec<database::TopologyCheckpointInfo>, String> {
+    state
+        .get_topology_checkpoints(since_ms.unwrap_or(0))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_device(
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<server::DeviceRecord, String> {
+    state.get_device(&agent_id).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn validate_target(input: String) -> Result<netutil::TargetValidation, String> {
+    Ok(netutil::validate_target(&input))
+}
+
+#[tauri::command]
+async fn override_device_network(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    facts: server::NetworkFactsPayload,
+) -> Result<server::DeviceRecord, String> {
+    state
+        .override_device_network(&app, agent_id, facts)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn clear_device_network_override(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<server::DeviceRecord, String> {
+    state
+        .clear_device_network_override(&app, agent_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn add_alias_rule(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    rule: alias::AliasRule,
+) -> Result<alias::AliasRule, String> {
+    state.add_alias_rule(&app, rule).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn list_alias_rules(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<Vec<alias::AliasRule>, String> {
+    Ok(state.list_alias_rules().await)
+}
+
+#[tauri::command]
+async fn remove_alias_rule(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    id: String,
+) -> Result<(), String> {
+    state
+        .remove_alias_rule(&app, id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn export_runtime_state(
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::RuntimeStateDocument, String> {
+    Ok(state.export_runtime_state().await)
+}
+
+#[tauri::command]
+async fn import_runtime_state(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    doc: server::RuntimeStateDocument,
+    confirm: bool,
+) -> Result<server::RuntimeStateImport, String> {
+    state
+        .import_runtime_state(&app, doc, confirm)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn await_task(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agents: Vec<String>,
+    kind: String,
+    params: serde_json::Value,
+    timeout_ms: u64,
+) -> Result<server::AwaitedTask, String> {
+    state
+        .await_task(&app, agents, kind, params, timeout_ms)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn cancel_task(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    task_id: String,
+) -> Result<server::TaskRecord, String> {
+    state.cancel_task(&app, task_id).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn mint_enrollment_token(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    ttl_secs: u64,
+) -> Result<server::EnrollmentToken, String> {
+    state
+        .mint_enrollment_token(&app, ttl_secs)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn stop_server(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::ServerStatus, String> {
+    state.stop_server(&app).await.map_err(String::from)
+}
+
+#[tauri::command]
+async fn start_server(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+) -> Result<server::ServerStatus, String> {
+    state
+        .start_server(Arc::new(app))
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn remove_device(
+    app: AppHandle,
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+) -> Result<(), String> {
+    state
+        .remove_device(&app, agent_id)
+        .await
+        .map_err(String::from)
+}
+
+#[tauri::command]
+async fn get_device_uptime(
+    state: tauri::State<'_, server::ServerManager>,
+    agent_id: String,
+    window_hours: u32,
+) -> Result<database::Availability, String> {
+    state
+        .get_device_uptime(agent_id, window_hours)
+        .await
+        .map_err(String::from)
 }
@@ -0,0 +1,138 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::server::{DeviceRecord, TaskRecord};
+
+/// Devices beyond this are left out so a runaway fleet can't explode series count.
+pub const MAX_METRIC_DEVICES: usize = 1000;
+const MAX_LABEL_CHARS: usize = 64;
+
+/// Renders Prometheus text exposition for the given devices and tasks.
+pub fn render_device_metrics(devices: &[DeviceRecord], tasks: &[TaskRecord]) -> String {
+    let mut devices: Vec<&DeviceRecord> = devices.iter().collect();
+    devices.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+    devices.truncate(MAX_METRIC_DEVICES);
+
+    let mut out = String::new();
+    gauge_family(
+        &mut out,
+        "labscan_device_online",
+        "1 when the agent is connected and heartbeating.",
+        &devices,
+        |d| Some(if d.status == "offline" { 0.0 } else { 1.0 }),
+    );
+    gauge_family(
+        &mut out,
+        "labscan_device_latency_ms",
+        "Last reported HTTPS probe latency in milliseconds.",
+        &devices,
+        |d| d.latency_ms.map(|v| v as f64),
+    );
+    gauge_family(
+        &mut out,
+        "labscan_device_internet_reachable",
+        "1 when the agent last reported internet reachability.",
+        &devices,
+        |d| d.internet_reachable.map(|v| if v { 1.0 } else { 0.0 }),
+    );
+
+    let mut by_status: BTreeMap<&str, usize> = ["queued", "running", "done", "failed"]
+        .into_iter()
+        .map(|status| (status, 0))
+        .collect();
+    for task in tasks {
+        *by_status.entry(task.status.as_str()).or_insert(0) += 1;
+    }
+    let _ = writeln!(
+        out,
+        "# HELP labscan_tasks Tasks currently tracked, by status."
+    );
+    let _ = writeln!(out, "# TYPE labscan_tasks gauge");
+    for (status, count) in by_status {
+        let _ = writeln!(
+            out,
+            "labscan_tasks{{status=\"{}\"}} {}",
+            escape_label(status),
+            count
+        );
+    }
+    out
+}
+
+fn gauge_family(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    devices: &[&DeviceRecord],
+    value: impl Fn(&DeviceRecord) -> Option<f64>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for device in devices {
+        if let Some(v) = value(device) {
+            let _ = writeln!(
+                out,
+                "{}{{agent_id=\"{}\",hostname=\"{}\"}} {}",
+                name,
+                escape_label(&device.agent_id),
+                escape_label(&device.hostname),
+                v
+            );
+        }
+    }
+}
+
+fn escape_label(raw: &str) -> String {
+    let mut escaped = String::new();
+    for c in raw.chars().take(MAX_LABEL_CHARS) {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_gauges_with_escaped_labels() {
+        let devices = vec![
+            DeviceRecord {
+                agent_id: "agent-b".to_string(),
+                hostname: "lab \"pc\"\\2".to_string(),
+                status: "online".to_string(),
+                latency_ms: Some(42),
+                internet_reachable: Some(true),
+                ..Default::default()
+            },
+            DeviceRecord {
+                agent_id: "agent-a".to_string(),
+                hostname: "pc1".to_string(),
+                status: "offline".to_string(),
+                ..Default::default()
+            },
+        ];
+        let text = render_device_metrics(&devices, &[]);
+
+        assert!(text.contains("# TYPE labscan_device_online gauge"));
+        assert!(text.contains(
+            r#"labscan_device_latency_ms{agent_id="agent-b",hostname="lab \"pc\"\\2"} 42"#
+        ));
+        assert!(text.contains(r#"labscan_device_online{agent_id="agent-a",hostname="pc1"} 0"#));
+        assert!(!text.contains(r#"labscan_device_latency_ms{agent_id="agent-a""#));
+        assert!(text.contains(r#"labscan_tasks{status="failed"} 0"#));
+
+        let online = text.find(r#"online{agent_id="agent-a""#).unwrap();
+        assert!(online < text.find(r#"online{agent_id="agent-b""#).unwrap());
+    }
+
+    #[test]
+    fn escape_label_bounds_length_and_escapes_newlines() {
+        assert_eq!(escape_label("a\nb"), "a\\nb");
+        assert_eq!(escape_label(&"x".repeat(200)).len(), MAX_LABEL_CHARS);
+    }
+}
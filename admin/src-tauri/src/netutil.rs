@@ -0,0 +1,375 @@
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub fn ip_to_u32(value: &str) -> Option<u32> {
+    let ip: Ipv4Addr = value.trim().parse().ok()?;
+    Some(u32::from(ip))
+}
+
+pub fn parse_cidr(cidr: &str) -> Option<(Ipv4Addr, u8)> {
+    let (addr, prefix) = match cidr.trim().split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.trim().parse::<u8>().ok()?),
+        None => (cidr, 32),
+    };
+    if prefix > 32 {
+        return None;
+    }
+    let addr: Ipv4Addr = addr.trim().parse().ok()?;
+    Some((addr, prefix))
+}
+
+pub fn network_address(cidr: &str) -> Option<Ipv4Addr> {
+    let (addr, prefix) = parse_cidr(cidr)?;
+    Some(Ipv4Addr::from(u32::from(addr) & prefix_mask(prefix)))
+}
+
+pub fn host_count(cidr: &str) -> Option<u64> {
+    let (_, prefix) = parse_cidr(cidr)?;
+    Some(match prefix {
+        32 => 1,
+        31 => 2,
+        _ => (1_u64 << (32 - u32::from(prefix))) - 2,
+    })
+}
+
+pub fn contains(cidr: &str, ip: &str) -> bool {
+    match (parse_cidr(cidr), ip_to_u32(ip)) {
+        (Some((network, prefix)), Some(addr)) => {
+            let mask = prefix_mask(prefix);
+            u32::from(network) & mask == addr & mask
+        }
+        _ => false,
+    }
+}
+
+/// Loopback, link-local (APIPA, fe80::/10) or unspecified: never a host's LAN address.
+pub fn is_unroutable_host_ip(value: &str) -> bool {
+    match value.trim().parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        Ok(IpAddr::V6(ip)) => {
+            ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+        Err(_) => false,
+    }
+}
+
+/// RFC 1123 host name: dot-separated labels of letters, digits and inner
+/// hyphens. All-numeric names are left to the IPv4 parser.
+pub fn is_hostname(value: &str) -> bool {
+    let value = value.strip_suffix('.').unwrap_or(value);
+    !value.is_empty()
+        && value.len() <= 253
+        && !value.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+pub fn same_subnet(ip_a: &str, ip_b: &str, cidr: &str) -> bool {
+    contains(cidr, ip_a) && contains(cidr, ip_b)
+}
+
+/// `ip` masked down to its `prefix_len` network, as `network/prefix`.
+pub fn network_cidr(ip: &str, prefix_len: u8) -> Option<String> {
+    let network = network_address(&format!("{}/{}", ip.trim(), prefix_len))?;
+    Some(format!("{}/{}", network, prefix_len))
+}
+
+/// IPv6 counterpart of `network_cidr`; accepts `addr` or `addr/prefix` and
+/// uses `/64` when no prefix is given.
+pub fn network_cidr_v6(cidr: &str) -> Option<String> {
+    let (addr, prefix) = match cidr.trim().split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.trim().parse::<u8>().ok()?),
+        None => (cidr.trim(), 64),
+    };
+    if prefix > 128 {
+        return None;
+    }
+    let addr: Ipv6Addr = addr.trim().parse().ok()?;
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u128::MAX << (128 - u32::from(prefix))
+    };
+    Some(format!(
+        "{}/{}",
+        Ipv6Addr::from(u128::from(addr) & mask),
+        prefix
+    ))
+}
+
+/// Last resort when nothing reported a prefix length.
+pub fn guess_subnet(ip: &str) -> Option<String> {
+    network_cidr(ip, 24)
+}
+
+/// Directed broadcast for an interface address, or `None` for loopback and
+/// point-to-point (/31, /32) links where there is nothing to broadcast to.
+pub fn directed_broadcast(ip: Ipv4Addr, netmask: Ipv4Addr) -> Option<Ipv4Addr> {
+    let mask = u32::from(netmask);
+    if ip.is_loopback() || mask.count_ones() >= 31 || mask.leading_ones() != mask.count_ones() {
+        return None;
+    }
+    Some(Ipv4Addr::from(u32::from(ip) | !mask))
+}
+
+/// What an operator-typed target turned out to be, for inline validation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TargetValidation {
+    Ip {
+        ip: Ipv4Addr,
+    },
+    Cidr {
+        /// Normalized to the network address, e.g. `10.0.0.7/24` becomes `10.0.0.0/24`.
+        cidr: String,
+        prefix: u8,
+        network: Ipv4Addr,
+        /// `None` for /31 and /32.
+        broadcast: Option<Ipv4Addr>,
+        host_count: u64,
+        /// The input had bits set past the prefix.
+        had_host_bits: bool,
+    },
+    Invalid {
+        reason: String,
+    },
+}
+
+pub fn validate_target(input: &str) -> TargetValidation {
+    let invalid = |reason: String| TargetValidation::Invalid { reason };
+    let trimmed = input.trim();
+    if trimmed.is_empty() {
+        return invalid("enter an IPv4 address or CIDR".to_string());
+    }
+    let (addr, prefix) = match trimmed.split_once('/') {
+        Some((addr, prefix)) => (addr.trim(), Some(prefix.trim())),
+        None => (trimmed, None),
+    };
+    let ip: Ipv4Addr = match addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip,
+        Ok(IpAddr::V6(_)) => return invalid("IPv6 targets are not supported".to_string()),
+        Err(_) => return invalid(format!("'{}' is not a valid IPv4 address", addr)),
+    };
+    let Some(prefix) = prefix else {
+        return TargetValidation::Ip { ip };
+    };
+    let prefix = match prefix.parse::<u8>() {
+        Ok(p) if p <= 32 => p,
+        Ok(_) => return invalid("prefix length must be between 0 and 32".to_string()),
+        Err(_) => return invalid(format!("'{}' is not a prefix length", prefix)),
+    };
+
+    let mask = prefix_mask(prefix);
+    let network = Ipv4Addr::from(u32::from(ip) & mask);
+    let cidr = format!("{}/{}", network, prefix);
+    TargetValidation::Cidr {
+        broadcast: (prefix < 31).then(|| Ipv4Addr::from(u32::from(network) | !mask)),
+        host_count: host_count(&cidr).unwrap_or(0),
+        had_host_bits: network != ip,
+        cidr,
+        prefix,
+        network,
+    }
+}
+
+fn prefix_mask(prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        u32::MAX << (32 - u32::from(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directed_broadcast_per_netmask() {
+        let ip = Ipv4Addr::new(10, 20, 5, 17);
+        assert_eq!(
+            directed_broadcast(ip, Ipv4Addr::new(255, 255, 255, 0)),
+            Some(Ipv4Addr::new(10, 20, 5, 255))
+        );
+        assert_eq!(
+            directed_broadcast(ip, Ipv4Addr::new(255, 255, 252, 0)),
+            Some(Ipv4Addr::new(10, 20, 7, 255))
+        );
+        assert_eq!(
+            directed_broadcast(ip, Ipv4Addr::new(255, 255, 255, 255)),
+            None
+        );
+        assert_eq!(directed_broadcast(ip, Ipv4Addr::new(255, 0, 255, 0)), None);
+        assert_eq!(
+            directed_broadcast(Ipv4Addr::LOCALHOST, Ipv4Addr::new(255, 0, 0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_cidr_accepts_valid_and_rejects_malformed() {
+        assert_eq!(
+            parse_cidr("192.168.1.0/24"),
+            Some((Ipv4Addr::new(192, 168, 1, 0), 24))
+        );
+        assert_eq!(
+            parse_cidr(" 10.0.0.5 "),
+            Some((Ipv4Addr::new(10, 0, 0, 5), 32))
+        );
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("10.0.0.0/"), None);
+        assert_eq!(parse_cidr("10.0.0/24"), None);
+        assert_eq!(parse_cidr("fe80::1/64"), None);
+        assert_eq!(parse_cidr(""), None);
+    }
+
+    #[test]
+    fn network_address_masks_host_bits() {
+        assert_eq!(
+            network_address("192.168.1.77/24"),
+            Some(Ipv4Addr::new(192, 168, 1, 0))
+        );
+        assert_eq!(
+            network_address("10.20.30.40/12"),
+            Some(Ipv4Addr::new(10, 16, 0, 0))
+        );
+        assert_eq!(network_address("1.2.3.4/0"), Some(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(network_address("garbage"), None);
+    }
+
+    #[test]
+    fn unroutable_host_ips() {
+        for ip in [
+            "127.0.0.1",
+            "169.254.10.20",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            " ::",
+        ] {
+            assert!(is_unroutable_host_ip(ip), "{}", ip);
+        }
+        for ip in ["192.168.1.10", "10.0.0.1", "2001:db8::1", "not-an-ip"] {
+            assert!(!is_unroutable_host_ip(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn hostnames() {
+        for name in ["router", "lab-a-07.school.lan", "example.com."] {
+            assert!(is_hostname(name), "{}", name);
+        }
+        for name in [
+            "",
+            "-lab",
+            "lab-",
+            "a..b",
+            "bad_name",
+            "10.0.0.300",
+            "host name",
+        ] {
+            assert!(!is_hostname(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn host_count_handles_edge_prefixes() {
+        assert_eq!(host_count("192.168.1.0/24"), Some(254));
+        assert_eq!(host_count("10.0.0.0/30"), Some(2));
+        assert_eq!(host_count("10.0.0.0/31"), Some(2));
+        assert_eq!(host_count("10.0.0.1/32"), Some(1));
+        assert_eq!(host_count("0.0.0.0/0"), Some(4_294_967_294));
+        assert_eq!(host_count("10.0.0.0/40"), None);
+    }
+
+    #[test]
+    fn contains_and_same_subnet() {
+        assert!(contains("192.168.1.0/24", "192.168.1.200"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.1"));
+        assert!(contains("0.0.0.0/0", "8.8.8.8"));
+        assert!(!contains("192.168.1.0/24", "not-an-ip"));
+        assert!(!contains("bad/24", "192.168.1.1"));
+
+        assert!(same_subnet("10.1.0.4", "10.1.255.9", "10.1.0.0/16"));
+        assert!(!same_subnet("10.1.0.4", "10.2.0.4", "10.1.0.0/16"));
+    }
+
+    #[test]
+    fn validate_target_explains_each_outcome() {
+        assert_eq!(
+            validate_target(" 10.0.0.5 "),
+            TargetValidation::Ip {
+                ip: Ipv4Addr::new(10, 0, 0, 5)
+            }
+        );
+        assert_eq!(
+            validate_target("192.168.1.77/24"),
+            TargetValidation::Cidr {
+                cidr: "192.168.1.0/24".to_string(),
+                prefix: 24,
+                network: Ipv4Addr::new(192, 168, 1, 0),
+                broadcast: Some(Ipv4Addr::new(192, 168, 1, 255)),
+                host_count: 254,
+                had_host_bits: true,
+            }
+        );
+        assert!(matches!(
+            validate_target("10.0.0.1/32"),
+            TargetValidation::Cidr {
+                broadcast: None,
+                host_count: 1,
+                had_host_bits: false,
+                ..
+            }
+        ));
+
+        let reason = |input: &str| match validate_target(input) {
+            TargetValidation::Invalid { reason } => reason,
+            other => panic!("{} accepted as {:?}", input, other),
+        };
+        assert!(reason("").contains("enter"));
+        assert!(reason("fe80::1/64").contains("IPv6"));
+        assert!(reason("10.0.0/24").contains("not a valid IPv4"));
+        assert!(reason("10.0.0.0/33").contains("between 0 and 32"));
+        assert!(reason("10.0.0.0/x").contains("not a prefix length"));
+    }
+
+    #[test]
+    fn network_cidr_masks_with_the_given_prefix() {
+        let cidr = |ip: &str, prefix: u8| network_cidr(ip, prefix);
+        assert_eq!(cidr("192.168.1.6", 30), Some("192.168.1.4/30".to_string()));
+        assert_eq!(cidr("10.1.7.200", 22), Some("10.1.4.0/22".to_string()));
+        assert_eq!(cidr("172.16.99.3", 16), Some("172.16.0.0/16".to_string()));
+        assert_eq!(cidr("172.16.99.3", 33), None);
+        assert_eq!(cidr("nope", 16), None);
+    }
+
+    #[test]
+    fn network_cidr_v6_masks_and_defaults_to_64() {
+        assert_eq!(
+            network_cidr_v6("2001:db8:1:2:aa::5/48"),
+            Some("2001:db8:1::/48".to_string())
+        );
+        assert_eq!(
+            network_cidr_v6("2001:db8:1:2::5"),
+            Some("2001:db8:1:2::/64".to_string())
+        );
+        assert_eq!(network_cidr_v6("2001:db8::/129"), None);
+        assert_eq!(network_cidr_v6("10.0.0.1/24"), None);
+    }
+
+    #[test]
+    fn guess_subnet_validates_octets() {
+        assert_eq!(
+            guess_subnet("192.168.4.23"),
+            Some("192.168.4.0/24".to_string())
+        );
+        assert_eq!(guess_subnet("192.168.4"), None);
+        assert_eq!(guess_subnet("300.1.1.1"), None);
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+
+/// Agents heartbeat every 8s unless configured otherwise.
+pub const EXPECTED_HEARTBEAT_MS: i64 = 8_000;
+/// Flaps and missed heartbeats older than this stop counting against a device.
+pub const QUALITY_WINDOW_MS: i64 = 60 * 60 * 1000;
+const LATENCY_SAMPLES: usize = 20;
+
+/// Inputs to [`connection_quality`], all measured over [`QUALITY_WINDOW_MS`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QualitySignals {
+    pub flaps: usize,
+    pub missed_heartbeats: usize,
+    pub clock_skew_ms: i64,
+    pub latency_jitter_ms: f64,
+}
+
+/// Scores 0–100 by subtracting a capped penalty per signal:
+/// 8 per flap (max 40), 5 per missed heartbeat (max 25),
+/// 2 per second of clock skew beyond the first (max 20), and
+/// 1 per 10 ms of latency jitter beyond 20 ms (max 15).
+pub fn connection_quality(signals: &QualitySignals) -> u8 {
+    let flap_penalty = (signals.flaps as f64 * 8.0).min(40.0);
+    let missed_penalty = (signals.missed_heartbeats as f64 * 5.0).min(25.0);
+    let skew_s = (signals.clock_skew_ms.unsigned_abs() as f64 / 1000.0 - 1.0).max(0.0);
+    let skew_penalty = (skew_s * 2.0).min(20.0);
+    let jitter_penalty = ((signals.latency_jitter_ms - 20.0).max(0.0) / 10.0).min(15.0);
+    let score = 100.0 - flap_penalty - missed_penalty - skew_penalty - jitter_penalty;
+    score.round().clamp(0.0, 100.0) as u8
+}
+
+pub fn quality_band(score: u8) -> &'static str {
+    match score {
+        80..=100 => "good",
+        50..=79 => "fair",
+        _ => "poor",
+    }
+}
+
+/// Per-agent history the score is derived from.
+#[derive(Debug, Default)]
+pub struct ConnectionSignals {
+    flaps: VecDeque<i64>,
+    missed: VecDeque<(i64, usize)>,
+    latencies: VecDeque<i64>,
+    last_heartbeat_ms: Option<i64>,
+    clock_skew_ms: i64,
+}
+
+impl ConnectionSignals {
+    pub fn record_flap(&mut self, now: i64) {
+        self.flaps.push_back(now);
+        self.last_heartbeat_ms = None;
+    }
+
+    pub fn record_heartbeat(&mut self, now: i64, agent_ts: i64, latency_ms: Option<i64>) {
+        if let Some(last) = self.last_heartbeat_ms {
+            let gap = now - last;
+            if gap * 2 > EXPECTED_HEARTBEAT_MS * 3 {
+                let missed = ((gap as f64 / EXPECTED_HEARTBEAT_MS as f64).round() as usize)
+                    .saturating_sub(1)
+                    .max(1);
+                self.missed.push_back((now, missed));
+            }
+        }
+        self.last_heartbeat_ms = Some(now);
+        if agent_ts > 0 {
+            self.clock_skew_ms = agent_ts - now;
+        }
+        if let Some(latency) = latency_ms {
+            self.latencies.push_back(latency);
+            if self.latencies.len() > LATENCY_SAMPLES {
+                self.latencies.pop_front();
+            }
+        }
+    }
+
+    pub fn signals(&mut self, now: i64) -> QualitySignals {
+        let cutoff = now - QUALITY_WINDOW_MS;
+        while self.flaps.front().is_some_and(|ts| *ts < cutoff) {
+            self.flaps.pop_front();
+        }
+        while self.missed.front().is_some_and(|(ts, _)| *ts < cutoff) {
+            self.missed.pop_front();
+        }
+        QualitySignals {
+            flaps: self.flaps.len(),
+            missed_heartbeats: self.missed.iter().map(|(_, n)| n).sum(),
+            clock_skew_ms: self.clock_skew_ms,
+            latency_jitter_ms: std_dev(&self.latencies),
+        }
+    }
+}
+
+fn std_dev(samples: &VecDeque<i64>) -> f64 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<i64>() as f64 / n;
+    let variance = samples
+        .iter()
+        .map(|s| (*s as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    variance.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_penalties_are_capped_per_signal() {
+        assert_eq!(connection_quality(&QualitySignals::default()), 100);
+
+        let flappy = QualitySignals {
+            flaps: 2,
+            ..Default::default()
+        };
+        assert_eq!(connection_quality(&flappy), 84);
+        assert_eq!(quality_band(84), "good");
+
+        let skewed = QualitySignals {
+            clock_skew_ms: -6_000,
+            ..Default::default()
+        };
+        assert_eq!(connection_quality(&skewed), 90);
+
+        let worst = QualitySignals {
+            flaps: 50,
+            missed_heartbeats: 50,
+            clock_skew_ms: 600_000,
+            latency_jitter_ms: 5_000.0,
+        };
+        assert_eq!(connection_quality(&worst), 0);
+        assert_eq!(quality_band(0), "poor");
+
+        let middling = QualitySignals {
+            flaps: 3,
+            missed_heartbeats: 2,
+            latency_jitter_ms: 70.0,
+            ..Default::default()
+        };
+        assert_eq!(connection_quality(&middling), 61);
+        assert_eq!(quality_band(61), "fair");
+    }
+
+    #[test]
+    fn signals_track_gaps_skew_jitter_and_expire() {
+        let mut signals = ConnectionSignals::default();
+        let t0 = 1_000_000;
+        signals.record_heartbeat(t0, t0 + 2_000, Some(10));
+        signals.record_heartbeat(t0 + 8_000, t0 + 10_000, Some(30));
+        // Three intervals elapsed: two heartbeats were missed.
+        signals.record_heartbeat(t0 + 32_000, t0 + 34_000, Some(10));
+        signals.record_flap(t0 + 40_000);
+        // The first heartbeat after a reconnect doesn't count the outage as missed.
+        signals.record_heartbeat(t0 + 100_000, t0 + 102_000, Some(30));
+
+        let now = t0 + 100_000;
+        let snapshot = signals.signals(now);
+        assert_eq!(snapshot.flaps, 1);
+        assert_eq!(snapshot.missed_heartbeats, 2);
+        assert_eq!(snapshot.clock_skew_ms, 2_000);
+        assert_eq!(snapshot.latency_jitter_ms, 10.0);
+
+        let later = signals.signals(now + QUALITY_WINDOW_MS);
+        assert_eq!(later.flaps, 0);
+        assert_eq!(later.missed_heartbeats, 0);
+    }
+}
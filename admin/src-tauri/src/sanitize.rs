@@ -0,0 +1,102 @@
+/// Longest hostname/os/version/ssid kept from an agent.
+pub const MAX_FIELD_CHARS: usize = 128;
+/// Longest agent-supplied error or log/activity message kept.
+pub const MAX_MESSAGE_CHARS: usize = 1024;
+/// Longest time zone name or locale tag kept.
+pub const MAX_IDENTIFIER_CHARS: usize = 64;
+
+/// Makes an agent-supplied string safe to show in logs and the UI: control
+/// characters (newlines, escape sequences) and bidi overrides become spaces,
+/// and the result is trimmed and cut to `max_chars` characters.
+pub fn display(raw: &str, max_chars: usize) -> String {
+    let cleaned: String = raw
+        .chars()
+        .map(|c| if is_unsafe(c) { ' ' } else { c })
+        .collect();
+    cleaned
+        .trim()
+        .chars()
+        .take(max_chars)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+pub fn field(raw: &str) -> String {
+    display(raw, MAX_FIELD_CHARS)
+}
+
+pub fn message(raw: &str) -> String {
+    display(raw, MAX_MESSAGE_CHARS)
+}
+
+/// Accepts short machine identifiers such as `Europe/Berlin`, `Etc/GMT+5` or
+/// `en_US.UTF-8`; anything empty, too long or with other characters is dropped.
+pub fn identifier(raw: &str) -> Option<String> {
+    let trimmed = raw.trim();
+    let valid = !trimmed.is_empty()
+        && trimmed.chars().count() <= MAX_IDENTIFIER_CHARS
+        && trimmed
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '/' | '.' | '@'));
+    valid.then(|| trimmed.to_string())
+}
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_newlines_and_terminal_escapes() {
+        assert_eq!(
+            field("lab-pc\n[ERROR] forged entry"),
+            "lab-pc [ERROR] forged entry"
+        );
+        assert_eq!(field("\u{1b}[31mred\u{1b}[0m"), "[31mred [0m");
+        assert_eq!(field("tab\there\r\n"), "tab here");
+        assert_eq!(field("\u{7f}\u{9b}x"), "x");
+    }
+
+    #[test]
+    fn strips_bidi_overrides() {
+        assert_eq!(field("evil\u{202E}gpj.exe"), "evil gpj.exe");
+        assert_eq!(field("\u{2066}name\u{2069}"), "name");
+    }
+
+    #[test]
+    fn bounds_length_on_char_boundaries() {
+        let long = "é".repeat(500);
+        let out = field(&long);
+        assert_eq!(out.chars().count(), MAX_FIELD_CHARS);
+        assert!(message(&"x".repeat(5000)).len() <= MAX_MESSAGE_CHARS);
+        assert_eq!(display("abc   def", 5), "abc");
+    }
+
+    #[test]
+    fn identifier_accepts_zone_and_locale_names() {
+        assert_eq!(
+            identifier(" America/Argentina/Buenos_Aires "),
+            Some("America/Argentina/Buenos_Aires".to_string())
+        );
+        assert_eq!(identifier("Etc/GMT+5"), Some("Etc/GMT+5".to_string()));
+        assert_eq!(
+            identifier("sr_RS.UTF-8@latin"),
+            Some("sr_RS.UTF-8@latin".to_string())
+        );
+        assert_eq!(identifier("Europe/Berlin\n[WARN]"), None);
+        assert_eq!(identifier("en US"), None);
+        assert_eq!(identifier(""), None);
+        assert_eq!(identifier(&"a".repeat(65)), None);
+    }
+
+    #[test]
+    fn keeps_ordinary_unicode() {
+        assert_eq!(field("  Café-PC 実験室 "), "Café-PC 実験室");
+        assert_eq!(field(""), "");
+        assert_eq!(field("\n\n"), "");
+    }
+}
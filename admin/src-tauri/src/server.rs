@@ -1,59 +1,333 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
         ConnectInfo, State,
     },
-    response::IntoResponse,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, VecDeque},
-    net::Ipv4Addr,
-    net::SocketAddr,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    path::Path,
     process::Command,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
-use tauri::{AppHandle, Emitter};
+use tauri::{AppHandle, Emitter, Manager};
 use tokio::{
     net::{TcpListener, UdpSocket},
-    sync::{mpsc, Mutex},
-    time::sleep,
+    sync::{broadcast, mpsc, oneshot, Mutex, Notify},
+    task::JoinHandle,
+    time::{sleep, timeout},
 };
+use tokio_tungstenite::{tungstenite::Message as ClientMessage, Connector};
 use uuid::Uuid;
 
-const WS_PORT: u16 = 8148;
-const UDP_PORT: u16 = 8870;
-const HEARTBEAT_TIMEOUT_MS: i64 = 20_000;
+use crate::alias::{apply_alias_rules, AliasRule};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{self, LabScanConfig, ServerConfig};
+use crate::database::{
+    ActivityRow, Availability, Database, DbPool, Device, Heartbeat, JournalRow, TaskRow,
+    TopologyCheckpointInfo, TopologyCheckpointRow, TopologySeedRow, DEVICE_RECORD_VERSION,
+};
+use crate::discovery::{DiscoveryService, MDNSService, BEACON_INTERVAL};
+use crate::error::{Result, ServerError};
+use crate::export::{self, ExportResult};
+use crate::metrics;
+use crate::netutil::{self, ip_to_u32};
+use crate::quality::{self, ConnectionSignals};
+use crate::sanitize;
+use crate::tls;
+use crate::topology::{
+    build_topology_snapshot, topology_diff, topology_key, topology_quality, with_unmanaged_hosts,
+    TopologyQuality, TopologySeedEntry,
+};
+use crate::webhook::{self, WebhookConfig, WebhookDelivery, WebhookEvent, WebhookStats};
+
 const DEVICE_EMIT_THROTTLE_MS: i64 = 1_000;
 const DEVICE_ACTIVITY_RATE_MS: i64 = 5_000;
+const MAX_FOLLOWED_AGENTS: usize = 8;
+const TASK_ERROR_CODES: [&str; 4] = ["timeout", "unreachable", "permission_denied", "unsupported"];
+const TASK_ERROR_CODE_OTHER: &str = "other";
+const MAX_RECENT_TASK_FAILURES: usize = 50;
+const CONNECTION_CAP_ALERT_INTERVAL_MS: i64 = 10_000;
+const BLOCK_KIND_AGENT: &str = "agent";
+const BLOCK_KIND_SOURCE: &str = "source";
+const SETTING_AGENT_ALLOWLIST_ENABLED: &str = "agent_allowlist_enabled";
+/// Why a `device_remove` event was sent.
+const REMOVE_REASON_MANUAL: &str = "manual";
+const REMOVE_REASON_REAPED: &str = "reaped";
+const REMOVE_REASON_EVICTED: &str = "evicted";
+const REMOVE_REASON_BLOCKED: &str = "blocked";
+const REMOVE_REASON_MERGED: &str = "merged";
+const TASK_COMPLETION_CAPACITY: usize = 256;
 const ACTIVITY_DEDUPE_MS: i64 = 30_000;
-const MAX_LOGS: usize = 400;
-const MAX_ACTIVITY: usize = 200;
+const MAX_ACTIVITY_QUERY: usize = 1000;
+const ACTIVITY_PRUNE_INTERVAL_MS: i64 = 60 * 60 * 1000;
+const MAX_ROUTE_TABLE_ENTRIES: usize = 512;
+const MAX_MOCK_DEVICES: usize = 500;
+const MAX_LOCAL_PORTS: usize = 256;
+const MAX_PERIPHERALS: usize = 64;
+const KNOWN_METRICS: [&str; 4] = [
+    "internet_reachable",
+    "dns_ok",
+    "gateway_reachable",
+    "latency_ms",
+];
+const MAX_EXTRA_METRICS: usize = 32;
+/// Longest serialized value kept per extra metric.
+const MAX_EXTRA_METRIC_BYTES: usize = 256;
+const MAX_TASK_SEARCH_RESULTS: usize = 200;
+const MAX_MESH_AGENTS: usize = 16;
+const MESH_PING_TIMEOUT_MS: u64 = 15_000;
+const MAX_PING_TARGETS: usize = 64;
+const MAX_AWAIT_TASK_MS: u64 = 10 * 60 * 1000;
+const MAX_ENROLLMENT_TOKEN_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_UPTIME_WINDOW_HOURS: u32 = 365 * 24;
+/// How long `stop_server` waits for the WS listener to drain before aborting it.
+const WS_SHUTDOWN_GRACE_MS: u64 = 5_000;
+const MDNS_INSTANCE_NAME: &str = "labscan-admin";
+const WIRE_MAX_SKEW_MS: i64 = 30_000;
+const DEFAULT_TASK_TIMEOUT_MS: i64 = 60_000;
+const MAX_TASK_TIMEOUT_MS: i64 = 60 * 60 * 1000;
+const TASK_TIMEOUT_SWEEP_MS: u64 = 2_000;
+const MIN_LATENCY_SAMPLES: usize = 5;
+const LATENCY_BUCKETS_MS: [i64; 7] = [10, 25, 50, 100, 250, 500, 1000];
+const MAX_DEVICES_PAGE: usize = 500;
+const MAX_EVENT_REPLAY: usize = 1000;
+pub const RUNTIME_STATE_VERSION: u32 = 1;
+/// IPv4 + UDP headers, counted against the broadcast budget per datagram.
+const UDP_HEADER_BYTES: usize = 28;
+const BROADCAST_THROTTLE_LOG_MS: i64 = 60_000;
+const MAX_TOPOLOGY_CHECKPOINTS: usize = 1000;
+const AGENT_PING_CONCURRENCY: usize = 16;
+const AUDIT_HEARTBEAT_BUCKET_MS: i64 = 5 * 60 * 1000;
+const AUDIT_ACTIVITY_LIMIT: usize = 1000;
+// About two days of heartbeats at the agent's default 8 s interval.
+const AUDIT_HEARTBEAT_LIMIT: usize = 20_000;
+const DEVICE_FACT_FIELDS: [&str; 6] = [
+    "ip",
+    "subnet_cidr",
+    "default_gateway_ip",
+    "dns_ok",
+    "local_ports",
+    "status",
+];
 
 const EVENT_SERVER_STATUS: &str = "server_status";
+const EVENT_SERVER_PULSE: &str = "server_pulse";
 const EVENT_DEVICES_SNAPSHOT: &str = "devices_snapshot";
 const EVENT_DEVICE_UPSERT: &str = "device_upsert";
+const EVENT_DEVICE_UPSERT_BATCH: &str = "device_upsert_batch";
+const EVENT_DEVICE_PATCH: &str = "device_patch";
 const EVENT_DEVICE_REMOVE: &str = "device_remove";
 const EVENT_LOG: &str = "log_event";
 const EVENT_TASK_UPDATE: &str = "task_update";
+const EVENT_TASKS_SNAPSHOT: &str = "tasks_snapshot";
 const EVENT_ACTIVITY: &str = "activity_event";
+const EVENT_ACTIVITY_SNAPSHOT: &str = "activity_snapshot";
+const EVENT_FOLLOWED_DEVICE: &str = "followed_device_event";
 const EVENT_TOPOLOGY_SNAPSHOT: &str = "topology_snapshot";
 const EVENT_TOPOLOGY_CHANGED: &str = "topology_changed";
+const EVENT_TOPOLOGY_DIFF: &str = "topology_diff";
+
+pub trait EventSink: Send + Sync {
+    fn emit_value(&self, event: &str, payload: Value);
+    fn clone_sink(&self) -> Arc<dyn EventSink>;
+}
+
+impl dyn EventSink + '_ {
+    fn emit<T: Serialize>(&self, event: &str, payload: T) {
+        self.emit_value(event, serde_json::to_value(payload).unwrap_or(Value::Null));
+    }
+}
+
+impl EventSink for AppHandle {
+    fn emit_value(&self, event: &str, mut payload: Value) {
+        if let Some(manager) = self.try_state::<ServerManager>() {
+            manager.journal_event(event, &mut payload);
+        }
+        let _ = Emitter::emit(self, event, payload);
+    }
+
+    fn clone_sink(&self) -> Arc<dyn EventSink> {
+        Arc::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CaptureSink {
+    events: Arc<std::sync::Mutex<Vec<(String, Value)>>>,
+    journal: Option<ServerManager>,
+}
+
+#[cfg(test)]
+impl CaptureSink {
+    /// Journals through `manager` like the Tauri sink does.
+    pub fn journaled(manager: &ServerManager) -> Self {
+        Self {
+            journal: Some(manager.clone()),
+            ..Self::default()
+        }
+    }
+
+    pub fn events(&self) -> Vec<(String, Value)> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.events().into_iter().map(|(name, _)| name).collect()
+    }
+}
+
+#[cfg(test)]
+impl EventSink for CaptureSink {
+    fn emit_value(&self, event: &str, mut payload: Value) {
+        if let Some(manager) = &self.journal {
+            manager.journal_event(event, &mut payload);
+        }
+        self.events
+            .lock()
+            .unwrap()
+            .push((event.to_string(), payload));
+    }
+
+    fn clone_sink(&self) -> Arc<dyn EventSink> {
+        Arc::new(self.clone())
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerStatus {
     pub online: bool,
     pub port_ws: u16,
     pub port_udp: u16,
+    pub loops: LoopStatus,
+    pub connection_count: usize,
+    pub max_connections: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoopStatus {
+    pub udp_provision: bool,
+    pub multicast_beacon: bool,
+    pub watchdog: bool,
+    pub topology_rebuild: bool,
+}
+
+#[derive(Debug)]
+struct LoopFlags {
+    udp_provision: AtomicBool,
+    multicast_beacon: AtomicBool,
+    watchdog: AtomicBool,
+    topology_rebuild: AtomicBool,
+}
+
+impl LoopFlags {
+    fn new(settings: &LabScanConfig) -> Self {
+        Self {
+            udp_provision: AtomicBool::new(settings.udp_provision),
+            multicast_beacon: AtomicBool::new(settings.multicast_beacon),
+            watchdog: AtomicBool::new(true),
+            topology_rebuild: AtomicBool::new(true),
+        }
+    }
+
+    fn flag(&self, name: &str) -> Option<&AtomicBool> {
+        match name {
+            "udp_provision" => Some(&self.udp_provision),
+            "multicast_beacon" => Some(&self.multicast_beacon),
+            "watchdog" => Some(&self.watchdog),
+            "topology_rebuild" => Some(&self.topology_rebuild),
+            _ => None,
+        }
+    }
+
+    fn enabled(flag: &AtomicBool) -> bool {
+        flag.load(AtomicOrdering::Relaxed)
+    }
+
+    fn status(&self) -> LoopStatus {
+        LoopStatus {
+            udp_provision: Self::enabled(&self.udp_provision),
+            multicast_beacon: Self::enabled(&self.multicast_beacon),
+            watchdog: Self::enabled(&self.watchdog),
+            topology_rebuild: Self::enabled(&self.topology_rebuild),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerPulse {
+    pub seq: u64,
+    pub ts: i64,
+    pub uptime_ms: i64,
+    pub device_count: usize,
+    pub online_count: usize,
+    pub offline_count: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SecretFailures {
+    /// Failure times inside `secret_backoff.window_ms`, oldest first.
+    recent: VecDeque<i64>,
+    /// Bans served so far; survives the window so a steady source escalates
+    /// towards `max_ban_ms`, and resets after a clean `max_ban_ms`.
+    bans: u32,
+    banned_until_ms: i64,
+    last_failure_ms: i64,
+}
+
+/// Token bucket shared by the broadcast loops, refilled at
+/// `broadcast_budget_bytes_per_sec` and holding at most one second's worth.
+#[derive(Debug, Clone, Default)]
+struct BroadcastBudget {
+    available: f64,
+    refilled_ms: i64,
+}
+
+impl BroadcastBudget {
+    /// Spends `bytes` if the budget allows it. A send larger than one second's
+    /// budget waits until the bucket has filled up to its size, so the
+    /// average rate still holds.
+    fn try_spend(&mut self, bytes: u64, limit_per_sec: u64, now: i64) -> bool {
+        if limit_per_sec == 0 {
+            return true;
+        }
+        let capacity = limit_per_sec.max(bytes) as f64;
+        if self.refilled_ms == 0 {
+            self.available = capacity;
+        } else {
+            let elapsed = (now - self.refilled_ms).max(0) as f64 / 1000.0;
+            self.available = (self.available + elapsed * limit_per_sec as f64).min(capacity);
+        }
+        self.refilled_ms = now;
+        if self.available < bytes as f64 {
+            return false;
+        }
+        self.available -= bytes as f64;
+        true
+    }
+}
+
+// Struct-level default keeps records persisted by older builds readable as fields are added.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct DeviceRecord {
     pub device_key: String,
     pub agent_id: String,
@@ -81,6 +355,119 @@ pub struct DeviceRecord {
     pub ssid: Option<String>,
     #[serde(default)]
     pub arp_snapshot: Vec<ArpEntry>,
+    #[serde(default)]
+    pub update_notified_version: Option<String>,
+    #[serde(default)]
+    pub update_notified_ms: Option<i64>,
+    #[serde(default)]
+    pub remote_ip: Option<String>,
+    #[serde(default)]
+    pub behind_nat: bool,
+    #[serde(default)]
+    pub gateway_arp_conflict: bool,
+    #[serde(default)]
+    pub local_ports: Option<Vec<u16>>,
+    #[serde(default)]
+    pub duplicate_mac: bool,
+    #[serde(default)]
+    pub last_control_action: Option<String>,
+    #[serde(default)]
+    pub last_control_sent_ms: Option<i64>,
+    #[serde(default)]
+    pub last_control_ack_ms: Option<i64>,
+    #[serde(default)]
+    pub last_control_ok: Option<bool>,
+    #[serde(default)]
+    pub pending_approval: bool,
+    /// Global unicast v6 address of a dual-stack or v6-only host.
+    #[serde(default)]
+    pub ipv6: Option<String>,
+    #[serde(default)]
+    pub ipv6_cidr: Option<String>,
+    /// IANA zone name reported by the agent, for correlating with on-host logs.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub vlan_id: Option<u16>,
+    /// 0–100 from flaps, missed heartbeats, clock skew and latency jitter.
+    #[serde(default)]
+    pub connection_quality: Option<u8>,
+    /// `good`, `fair` or `poor`.
+    #[serde(default)]
+    pub connection_band: Option<String>,
+    /// USB devices, monitors etc.; `None` until the agent reports any.
+    #[serde(default)]
+    pub peripherals: Option<Vec<Peripheral>>,
+    /// Network fields set by `override_device_network` rather than the agent.
+    #[serde(default)]
+    pub overridden_network_fields: Vec<String>,
+    /// From the first matching alias rule, see `add_alias_rule`.
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub alias_rule_id: Option<String>,
+    /// Heartbeat metrics beyond the built-in ones, as last reported.
+    #[serde(default)]
+    pub extra_metrics: serde_json::Map<String, Value>,
+    /// Declared by the agent at register; describes keys in `extra_metrics`.
+    #[serde(default)]
+    pub metric_schema: Vec<MetricSchema>,
+    /// The agent reported a loopback, link-local or unspecified address.
+    #[serde(default)]
+    pub bad_network_detection: bool,
+    /// Injected by `inject_mock_devices`; never persisted, reaped or alerted on.
+    #[serde(default)]
+    pub is_mock: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct DeviceFacts {
+    pub ip: Option<String>,
+    pub subnet_cidr: Option<String>,
+    pub default_gateway_ip: Option<String>,
+    pub dns_ok: Option<bool>,
+    pub local_ports: Option<Vec<u16>>,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldDiff {
+    pub field: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceDiff {
+    pub agent_id: String,
+    pub ts_a: i64,
+    pub ts_b: i64,
+    pub facts_a: Option<DeviceFacts>,
+    pub facts_b: Option<DeviceFacts>,
+    pub changes: Vec<FieldDiff>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceAuditEvent {
+    pub ts: i64,
+    /// `status`, `network`, `heartbeat`, `task`, `activity` or `alert`.
+    pub kind: String,
+    pub message: String,
+    pub data: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceAudit {
+    pub agent_id: String,
+    pub since_ms: i64,
+    pub events: Vec<DeviceAuditEvent>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -88,6 +475,46 @@ pub struct DevicesSnapshot {
     pub devices: Vec<DeviceRecord>,
 }
 
+/// Everything `import_runtime_state` needs to rebuild an admin elsewhere.
+/// `topology` is informational: it is rebuilt from the devices on import.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeStateDocument {
+    pub version: u32,
+    pub exported_at: i64,
+    pub devices: Vec<DeviceRecord>,
+    pub tasks: Vec<TaskRecord>,
+    pub activity: Vec<ActivityEvent>,
+    pub topology: TopologySnapshot,
+    pub topology_seed: Vec<TopologySeedEntry>,
+    pub network_overrides: BTreeMap<String, NetworkFactsPayload>,
+    pub alias_rules: Vec<AliasRule>,
+    pub config: ServerConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeStateImport {
+    pub devices: usize,
+    /// Imported records skipped because the agent is connected right now.
+    pub live_kept: Vec<String>,
+    pub tasks: usize,
+    pub activity: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireTraceStatus {
+    pub agent_id: String,
+    pub enabled: bool,
+    pub expires_at: Option<i64>,
+    pub traced_agents: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevicesPage {
+    pub devices: Vec<DeviceRecord>,
+    pub total: usize,
+    pub offset: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TopologySnapshot {
     pub revision: u64,
@@ -107,6 +534,19 @@ pub struct TopologyNode {
     pub agent_id: Option<String>,
     pub interface_type: Option<String>,
     pub attached_count: Option<usize>,
+    #[serde(default)]
+    pub utilization: Option<SubnetUtilization>,
+    /// Hosts whose connection came from outside their reported subnet; their
+    /// attachment is a guess.
+    #[serde(default)]
+    pub behind_nat: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubnetUtilization {
+    pub managed_hosts: usize,
+    pub observed_ips: usize,
+    pub capacity: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -116,6 +556,28 @@ pub struct TopologyEdge {
     pub parent_id: String,
     pub method: String,
     pub confidence: f64,
+    #[serde(default)]
+    pub port: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(default)]
+pub struct Peripheral {
+    /// e.g. `usb`, `monitor`.
+    pub kind: String,
+    pub name: String,
+    pub id: String,
+}
+
+/// How an agent describes one of its custom heartbeat metrics.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct MetricSchema {
+    pub name: String,
+    /// e.g. `celsius`, `percent`.
+    pub unit: Option<String>,
+    /// How the UI should render it, e.g. `gauge`, `bool`, `text`.
+    pub display: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -136,6 +598,13 @@ pub struct NetworkFactsPayload {
     pub ssid: Option<String>,
     #[serde(default)]
     pub arp_snapshot: Vec<ArpEntry>,
+    /// 802.1Q tag of the agent's access port, when the agent can see it.
+    #[serde(default)]
+    pub vlan_id: Option<u16>,
+    #[serde(default)]
+    pub ipv6: Option<String>,
+    #[serde(default)]
+    pub ipv6_cidr: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -144,9 +613,29 @@ pub struct TaskResultRecord {
     pub ok: bool,
     pub result: Value,
     pub error: Option<String>,
+    #[serde(default)]
+    pub error_code: Option<String>,
+    pub ts: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskFailure {
+    pub task_id: String,
+    pub kind: String,
+    pub agent_id: String,
+    pub error_code: String,
+    pub error: Option<String>,
     pub ts: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskFailureStats {
+    pub total_failures: usize,
+    pub by_code: BTreeMap<String, usize>,
+    pub by_kind: BTreeMap<String, BTreeMap<String, usize>>,
+    pub recent: Vec<TaskFailure>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaskRecord {
     pub task_id: String,
@@ -158,6 +647,176 @@ pub struct TaskRecord {
     pub started_at: Option<i64>,
     pub ended_at: Option<i64>,
     pub results: Vec<TaskResultRecord>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub metadata: Value,
+    #[serde(default)]
+    pub origin_task_id: Option<String>,
+    /// How many retries precede this task in its `origin_task_id` chain.
+    #[serde(default)]
+    pub rerun_depth: u32,
+    #[serde(default)]
+    pub delivered_to: Vec<String>,
+    #[serde(default)]
+    pub acked_by: Vec<String>,
+    /// Assigned agents that haven't reported a result yet.
+    #[serde(default)]
+    pub pending_agents: Vec<String>,
+    /// How long after `started_at` missing results are given up on.
+    #[serde(default = "default_task_timeout_ms")]
+    pub timeout_ms: i64,
+}
+
+fn default_task_timeout_ms() -> i64 {
+    DEFAULT_TASK_TIMEOUT_MS
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyBucket {
+    pub le_ms: Option<i64>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyPercentiles {
+    pub agent_id: String,
+    pub since_ms: i64,
+    pub sample_count: usize,
+    pub sufficient_samples: bool,
+    pub p50: Option<i64>,
+    pub p90: Option<i64>,
+    pub p99: Option<i64>,
+    pub buckets: Vec<LatencyBucket>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigReload {
+    pub path: Option<String>,
+    pub config: LabScanConfig,
+    pub restart_required: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournaledEvent {
+    pub seq: u64,
+    pub ts: i64,
+    pub event: String,
+    pub payload: Value,
+}
+
+/// Events after the requested `seq`. When that `seq` has already been pruned,
+/// `snapshot_required` is set and the client should refetch snapshots instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventReplay {
+    pub events: Vec<JournaledEvent>,
+    pub latest_seq: u64,
+    pub has_more: bool,
+    pub snapshot_required: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshPingCell {
+    pub source: String,
+    pub destination: String,
+    pub destination_ip: String,
+    /// The source's ping task; `None` when the source was offline.
+    pub task_id: Option<String>,
+    /// `None` when the source never reported back.
+    pub reachable: Option<bool>,
+    pub latency_ms: Option<i64>,
+    pub error: Option<String>,
+}
+
+/// Single-use alternative to the shared pair token, handed to one machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrollmentToken {
+    pub token: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+/// Optional settings for `dispatch_task`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct TaskOptions {
+    pub label: Option<String>,
+    pub metadata: Option<Value>,
+    /// Defaults to `DEFAULT_TASK_TIMEOUT_MS`.
+    pub timeout_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AwaitedTask {
+    /// Final record, or the partial one as of the timeout.
+    pub task: TaskRecord,
+    pub timed_out: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshPingMatrix {
+    pub mesh_id: String,
+    pub agents: Vec<String>,
+    pub cells: Vec<MeshPingCell>,
+    /// Pairs where one direction succeeds and the other does not.
+    pub asymmetric: Vec<(String, String)>,
+    pub complete: bool,
+}
+
+/// Admin-measured WebSocket ping round trips, one per connected agent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentRttReport {
+    pub measured_at: i64,
+    pub rtt_ms: BTreeMap<String, f64>,
+    pub unreachable: BTreeMap<String, String>,
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Blocklist {
+    pub agents: Vec<String>,
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AgentAllowlist {
+    pub enabled: bool,
+    pub agents: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DiscoveryMechanismStats {
+    pub running: bool,
+    pub last_send_ms: Option<i64>,
+    pub send_count: u64,
+    pub ack_count: u64,
+    pub last_ack_ms: Option<i64>,
+    pub last_error: Option<String>,
+    /// Cycles skipped by `broadcast_budget_bytes_per_sec`.
+    pub throttled_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DiscoveryStats {
+    pub udp_broadcast: DiscoveryMechanismStats,
+    pub multicast_beacon: DiscoveryMechanismStats,
+    pub mdns: DiscoveryMechanismStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningCheck {
+    pub step: String,
+    pub ok: bool,
+    pub detail: String,
+    pub elapsed_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningReport {
+    pub ok: bool,
+    pub agent_id: String,
+    pub checks: Vec<ProvisioningCheck>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -194,9 +853,29 @@ struct DeviceUpsertEvent {
     device: DeviceRecord,
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct DevicePatchEvent {
+    agent_id: String,
+    changes: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DeviceUpsertBatchEvent {
+    devices: Vec<DeviceRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FollowedDeviceEvent {
+    agent_id: String,
+    kind: String,
+    ts: i64,
+    payload: Value,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct DeviceRemoveEvent {
     agent_id: String,
+    reason: &'static str,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -210,8 +889,12 @@ struct ProvisionBroadcast {
     message_type: String,
     v: u8,
     admin_ip: String,
+    ws_port: u16,
     secret: String,
     nonce: String,
+    /// Present when the WS listener serves TLS; agents then dial `wss` and pin it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -232,6 +915,15 @@ struct WireMessage {
     ts: i64,
     agent_id: String,
     payload: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sig: Option<String>,
+}
+
+/// The `payload` text exactly as the agent sent it, which is what `sig` covers.
+#[derive(Deserialize)]
+struct WirePayloadText<'a> {
+    #[serde(borrow)]
+    payload: &'a serde_json::value::RawValue,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -250,6 +942,12 @@ struct RegisterPayload {
     started_at: i64,
     #[serde(default)]
     network: NetworkFactsPayload,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    locale: Option<String>,
+    #[serde(default)]
+    metric_schema: Vec<MetricSchema>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -259,13 +957,37 @@ struct HeartbeatPayload {
     metrics: Option<Value>,
     #[serde(default)]
     network: NetworkFactsPayload,
+    #[serde(default)]
+    local_ports: Option<Vec<u16>>,
+    #[serde(default)]
+    peripherals: Option<Vec<Peripheral>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TaskResultPayload {
-    task_id: String,
-    ok: bool,
-    result: Value,
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct PingAlivePayload {
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    last_seen: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TaskResultPayload {
+    task_id: String,
+    ok: bool,
+    result: Value,
+    error: Option<String>,
+    #[serde(default)]
+    error_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ControlAckPayload {
+    action: String,
+    #[serde(default)]
+    request_id: String,
+    ok: bool,
+    #[serde(default)]
     error: Option<String>,
 }
 
@@ -274,12 +996,41 @@ struct TaskDispatchPayload {
     task_id: String,
     kind: String,
     params: Value,
+    timeout_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TaskAckPayload {
+    task_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct RouteEntry {
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    gateway: String,
+    #[serde(default)]
+    interface: String,
+    #[serde(default)]
+    metric: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UpdateAvailablePayload {
+    min_version: String,
+    download_url: String,
 }
 
 #[derive(Debug)]
 struct RuntimeState {
     online: bool,
+    online_since_ms: Option<i64>,
+    pulse_seq: u64,
     pair_token: String,
+    /// The token replaced by a scheduled rotation, accepted until the given time.
+    previous_pair_token: Option<(String, i64)>,
+    config: ServerConfig,
     devices: HashMap<String, DeviceRecord>,
     device_order: Vec<String>,
     fingerprint_index: HashMap<String, String>,
@@ -288,84 +1039,595 @@ struct RuntimeState {
     activity: VecDeque<ActivityEvent>,
     connections: HashMap<String, mpsc::UnboundedSender<Message>>,
     last_device_emit_ms: HashMap<String, i64>,
+    pending_upserts: HashMap<String, DeviceRecord>,
+    /// Last record sent per device, the baseline for sparse `device_patch` events.
+    emitted_devices: HashMap<String, Value>,
+    pending_pings: HashMap<u64, (String, oneshot::Sender<Instant>)>,
+    next_ping_nonce: u64,
+    upsert_flush_scheduled: bool,
+    mock_agents: HashSet<String>,
+    approvals: HashMap<String, String>,
+    discovery: DiscoveryStats,
+    followed_agents: HashSet<String>,
+    blocked_agents: HashSet<String>,
+    blocked_sources: Vec<String>,
+    secret_failures: HashMap<IpAddr, SecretFailures>,
+    /// Every open agent socket and its source, registered or not, so blocking
+    /// a source can close them.
+    socket_sources: HashMap<u64, (IpAddr, Arc<Notify>)>,
+    next_socket_id: u64,
+    /// Only enforced while `allowlist_enabled`; an enabled, empty list admits nobody.
+    allowed_agents: HashSet<String>,
+    allowlist_enabled: bool,
+    webhook_stats: WebhookStats,
+    topology_seed: Vec<TopologySeedEntry>,
+    tls_fingerprint: Option<String>,
+    /// Set only while the WS listener is serving this certificate.
+    tls_config: Option<RustlsConfig>,
+    last_activity_prune_ms: i64,
+    connection_signals: HashMap<String, ConnectionSignals>,
+    capacity_rejections: u64,
+    last_capacity_alert_ms: i64,
     last_activity_emit_ms: HashMap<String, i64>,
     topology_snapshot: TopologySnapshot,
+    /// Key of the committed (emitted) topology.
     topology_key: String,
+    /// A differing graph waiting out `topology_stability_ms`, and since when.
+    topology_candidate_key: Option<String>,
+    topology_candidate_since_ms: i64,
+    topology_recheck_scheduled: bool,
     admin_network: NetworkFactsPayload,
+    /// Agents whose frames are logged verbatim, and when each trace expires.
+    wire_traces: HashMap<String, i64>,
+    /// Operator corrections that win over agent-reported network facts.
+    network_overrides: HashMap<String, NetworkFactsPayload>,
+    /// How many `outage_thresholds` each offline device has already alerted for.
+    outage_levels: HashMap<String, usize>,
+    /// Evaluated in order at registration and on fact changes; first match wins.
+    alias_rules: Vec<AliasRule>,
+    broadcast_budget: BroadcastBudget,
+    /// The WS listener and UDP loop, present while the server is started.
+    listeners: Option<Listeners>,
+}
+
+impl RuntimeState {
+    /// The fingerprint agents may pin: only while the listener presents it.
+    fn served_tls_fingerprint(&self) -> Option<String> {
+        self.tls_fingerprint
+            .clone()
+            .filter(|_| self.tls_config.is_some())
+    }
+}
+
+#[derive(Debug)]
+struct Listeners {
+    ws_shutdown: oneshot::Sender<()>,
+    ws: JoinHandle<()>,
+    udp: JoinHandle<()>,
 }
 
+type DbJob = Box<dyn FnOnce(&mut Database) -> rusqlite::Result<()> + Send>;
+
 #[derive(Clone)]
 pub struct ServerManager {
     inner: Arc<Mutex<RuntimeState>>,
+    db: DbPool,
+    db_jobs: mpsc::UnboundedSender<DbJob>,
+    /// Writes that failed even after busy retries; drained into alerts once running.
+    db_failures: Arc<Mutex<Option<mpsc::UnboundedReceiver<String>>>>,
+    webhook_jobs: mpsc::UnboundedSender<WebhookDelivery>,
+    /// Deliveries queued before `start_runtime` wait here for the sender loop.
+    webhook_queue: Arc<Mutex<Option<mpsc::UnboundedReceiver<WebhookDelivery>>>>,
+    task_completions: broadcast::Sender<TaskRecord>,
+    /// Wakes the beacon loop so a new pair token goes out without waiting a cycle.
+    beacon_refresh: Arc<Notify>,
+    loops: Arc<LoopFlags>,
+    open_sockets: Arc<AtomicUsize>,
+    /// Size of `wire_traces`, so untraced frames skip the state lock.
+    wire_traced: Arc<AtomicUsize>,
+    event_seq: Arc<AtomicU64>,
+    /// Startup settings; only `server` is replaced at runtime, inside `RuntimeState`.
+    settings: Arc<LabScanConfig>,
+    cert_paths: Arc<tls::CertPaths>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Holds one of the `max_agent_connections` slots until the socket closes.
+struct SocketSlot(Arc<AtomicUsize>);
+
+impl Drop for SocketSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, AtomicOrdering::Relaxed);
+    }
 }
 
 #[derive(Clone)]
 struct HttpState {
     manager: ServerManager,
-    app: AppHandle,
+    app: Arc<dyn EventSink>,
 }
 
 impl ServerManager {
-    pub fn new() -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(RuntimeState {
-                online: false,
-                pair_token: Uuid::new_v4().to_string(),
-                devices: HashMap::new(),
-                device_order: Vec::new(),
-                fingerprint_index: HashMap::new(),
-                tasks: HashMap::new(),
-                logs: VecDeque::new(),
-                activity: VecDeque::new(),
-                connections: HashMap::new(),
-                last_device_emit_ms: HashMap::new(),
-                last_activity_emit_ms: HashMap::new(),
-                topology_snapshot: TopologySnapshot {
-                    revision: 0,
-                    updated_at: now_ms(),
-                    nodes: Vec::new(),
-                    edges: Vec::new(),
-                },
-                topology_key: String::new(),
-                admin_network: detect_admin_network_facts(),
-            })),
+    pub fn new(db: DbPool, settings: LabScanConfig) -> Result<Self> {
+        Self::with_clock(db, settings, Arc::new(SystemClock))
+    }
+
+    /// Loads the saved approvals, blocks and topology state and starts the
+    /// database writer. Background loops wait for `start_runtime`.
+    pub fn with_clock(db: DbPool, settings: LabScanConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        let (
+            approvals,
+            blocks,
+            allowed,
+            allowlist_setting,
+            seed_rows,
+            journal_bounds,
+            last_revision,
+            override_rows,
+            alias_rows,
+        ) = {
+            // Nothing else holds the pool before the manager exists, so a busy
+            // lock means a caller bug; starting without saved state would hide it.
+            let db = db.try_lock().map_err(|_| {
+                ServerError::Unavailable("database is locked during startup".to_string())
+            })?;
+            (
+                db.get_agent_approvals()
+                    .map_err(ServerError::db("approval load"))?,
+                db.get_blocks()
+                    .map_err(ServerError::db("block list load"))?,
+                db.get_allowed_agents()
+                    .map_err(ServerError::db("allowlist load"))?,
+                db.get_setting(SETTING_AGENT_ALLOWLIST_ENABLED)
+                    .map_err(ServerError::db("allowlist load"))?,
+                db.get_topology_seed()
+                    .map_err(ServerError::db("topology seed load"))?,
+                db.event_journal_bounds()
+                    .map_err(ServerError::db("journal query"))?,
+                db.max_topology_revision()
+                    .map_err(ServerError::db("checkpoint query"))?,
+                db.get_network_overrides()
+                    .map_err(ServerError::db("network override load"))?,
+                db.get_alias_rules()
+                    .map_err(ServerError::db("alias rule load"))?,
+            )
+        };
+        let alias_rules = alias_rows
+            .iter()
+            .filter_map(|rule| serde_json::from_str::<AliasRule>(rule).ok())
+            .collect::<Vec<_>>();
+        let network_overrides = override_rows
+            .into_iter()
+            .filter_map(|(agent_id, facts)| {
+                serde_json::from_str::<NetworkFactsPayload>(&facts)
+                    .ok()
+                    .map(|facts| (agent_id, facts))
+            })
+            .collect::<HashMap<_, _>>();
+        let topology_seed = seed_rows
+            .into_iter()
+            .map(|row| TopologySeedEntry {
+                mac: row.mac,
+                agent_id: row.agent_id,
+                switch: row.switch_name,
+                port: row.port,
+            })
+            .collect::<Vec<_>>();
+        let approvals = approvals.into_iter().collect::<HashMap<_, _>>();
+        let blocked_agents = blocks
+            .iter()
+            .filter(|(kind, _)| kind == BLOCK_KIND_AGENT)
+            .map(|(_, value)| value.clone())
+            .collect::<HashSet<_>>();
+        let blocked_sources = blocks
+            .into_iter()
+            .filter(|(kind, _)| kind == BLOCK_KIND_SOURCE)
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>();
+        let (db_jobs, mut db_rx) = mpsc::unbounded_channel::<DbJob>();
+        let (db_failures, db_failure_rx) = mpsc::unbounded_channel::<String>();
+        let writer_db = db.clone();
+        // Writes may sleep through busy retries, so they get their own thread
+        // rather than a runtime worker. It ends once the manager is dropped.
+        thread::Builder::new()
+            .name("labscan-db-writer".to_string())
+            .spawn(move || {
+                while let Some(job) = db_rx.blocking_recv() {
+                    let mut guard = writer_db.blocking_lock();
+                    if let Err(err) = job(&mut guard) {
+                        tracing::warn!("[DB] write failed: {}", err);
+                        let _ = db_failures.send(err.to_string());
+                    }
+                }
+            })
+            .map_err(|err| ServerError::Internal(format!("database writer thread: {}", err)))?;
+
+        let inner = Arc::new(Mutex::new(RuntimeState {
+            online: false,
+            online_since_ms: None,
+            pulse_seq: 0,
+            pair_token: Uuid::new_v4().to_string(),
+            previous_pair_token: None,
+            config: settings.server.clone(),
+            devices: HashMap::new(),
+            device_order: Vec::new(),
+            fingerprint_index: HashMap::new(),
+            tasks: HashMap::new(),
+            logs: VecDeque::new(),
+            activity: VecDeque::new(),
+            connections: HashMap::new(),
+            last_device_emit_ms: HashMap::new(),
+            pending_upserts: HashMap::new(),
+            emitted_devices: HashMap::new(),
+            pending_pings: HashMap::new(),
+            next_ping_nonce: 0,
+            upsert_flush_scheduled: false,
+            mock_agents: HashSet::new(),
+            approvals,
+            discovery: DiscoveryStats::default(),
+            followed_agents: HashSet::new(),
+            blocked_agents,
+            blocked_sources,
+            secret_failures: HashMap::new(),
+            socket_sources: HashMap::new(),
+            next_socket_id: 0,
+            // Databases from before the flag enforced any non-empty list.
+            allowlist_enabled: allowlist_setting.map_or(!allowed.is_empty(), |v| v == "1"),
+            allowed_agents: allowed.into_iter().collect(),
+            webhook_stats: WebhookStats::default(),
+            topology_seed,
+            tls_fingerprint: None,
+            tls_config: None,
+            last_activity_prune_ms: 0,
+            connection_signals: HashMap::new(),
+            capacity_rejections: 0,
+            last_capacity_alert_ms: 0,
+            last_activity_emit_ms: HashMap::new(),
+            topology_snapshot: TopologySnapshot {
+                // Continues after the last checkpoint so revisions stay unique.
+                revision: last_revision.unwrap_or(0).max(0) as u64,
+                updated_at: clock.now_ms(),
+                nodes: Vec::new(),
+                edges: Vec::new(),
+            },
+            topology_key: String::new(),
+            topology_candidate_key: None,
+            topology_candidate_since_ms: 0,
+            topology_recheck_scheduled: false,
+            admin_network: detect_admin_network_facts(),
+            wire_traces: HashMap::new(),
+            network_overrides,
+            outage_levels: HashMap::new(),
+            alias_rules,
+            broadcast_budget: BroadcastBudget::default(),
+            listeners: None,
+        }));
+
+        let (webhook_jobs, webhook_rx) = mpsc::unbounded_channel::<WebhookDelivery>();
+
+        let (task_completions, _) = broadcast::channel(TASK_COMPLETION_CAPACITY);
+
+        Ok(Self {
+            db,
+            db_jobs,
+            db_failures: Arc::new(Mutex::new(Some(db_failure_rx))),
+            webhook_jobs,
+            webhook_queue: Arc::new(Mutex::new(Some(webhook_rx))),
+            task_completions,
+            beacon_refresh: Arc::new(Notify::new()),
+            loops: Arc::new(LoopFlags::new(&settings)),
+            open_sockets: Arc::new(AtomicUsize::new(0)),
+            wire_traced: Arc::new(AtomicUsize::new(0)),
+            event_seq: Arc::new(AtomicU64::new(
+                journal_bounds.map_or(0, |(_, max)| max.max(0) as u64),
+            )),
+            settings: Arc::new(settings),
+            cert_paths: Arc::new(tls::CertPaths::in_dir(Path::new("."))),
+            inner,
+            clock,
+        })
+    }
+
+    /// Keeps the TLS certificate and key in `dir` instead of the working directory.
+    pub fn with_data_dir(mut self, dir: &Path) -> Self {
+        self.cert_paths = Arc::new(tls::CertPaths::in_dir(dir));
+        self
+    }
+
+    fn now(&self) -> i64 {
+        self.clock.now_ms()
+    }
+
+    /// Seeds devices saved by earlier runs, offline until their agents reconnect,
+    /// so a returning agent keeps its `first_seen_ms` and position.
+    async fn load_persisted_devices(&self, app: &dyn EventSink) {
+        let rows = match self.db.lock().await.get_all_devices() {
+            Ok(rows) => rows,
+            Err(err) => {
+                self.emit_log(
+                    app,
+                    None,
+                    "WARN",
+                    format!("Loading saved devices failed: {}", err),
+                )
+                .await;
+                return;
+            }
+        };
+        let mut records = rows.into_iter().map(device_from_row).collect::<Vec<_>>();
+        records.sort_by_key(|device| device.first_seen_ms);
+
+        let loaded = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let mut loaded = 0;
+            for mut device in records {
+                let agent_id = device.agent_id.clone();
+                if agent_id.is_empty() || state.devices.contains_key(&agent_id) {
+                    continue;
+                }
+                device.status = "offline".to_string();
+                apply_network_override(&mut device, state.network_overrides.get(&agent_id));
+                apply_alias_rules(&state.alias_rules, &mut device);
+                if let Some(fp) = &device.fingerprint {
+                    state.fingerprint_index.insert(fp.clone(), agent_id.clone());
+                }
+                state.device_order.push(agent_id.clone());
+                state.devices.insert(agent_id, device);
+                loaded += 1;
+            }
+            loaded
+        };
+        if loaded > 0 {
+            self.emit_log(
+                app,
+                None,
+                "INFO",
+                format!("Loaded {} saved device(s)", loaded),
+            )
+            .await;
+            self.emit_devices_snapshot(app).await;
         }
     }
 
-    pub async fn start_runtime(&self, app: AppHandle) {
+    pub async fn start_runtime(&self, app: Arc<dyn EventSink>) {
         let online = { self.inner.lock().await.online };
         if online {
             return;
         }
 
-        self.rebuild_topology_if_changed(&app).await;
+        self.load_persisted_devices(&*app).await;
+        self.rebuild_topology_if_changed(&*app).await;
+        self.spawn_listeners(app.clone()).await;
 
         let manager = self.clone();
-        let app_for_ws = app.clone();
+        let app_for_beacon = app.clone();
         tokio::spawn(async move {
-            manager.run_ws_server(app_for_ws).await;
+            manager.multicast_beacon_loop(app_for_beacon).await;
         });
 
         let manager = self.clone();
-        let app_for_udp = app.clone();
+        let app_for_watchdog = app.clone();
         tokio::spawn(async move {
-            manager.run_udp_provision_loop(app_for_udp).await;
+            manager.heartbeat_watchdog(app_for_watchdog).await;
         });
 
         let manager = self.clone();
-        let app_for_watchdog = app.clone();
+        let app_for_timeouts = app.clone();
         tokio::spawn(async move {
-            manager.heartbeat_watchdog(app_for_watchdog).await;
+            manager.task_timeout_sweep(app_for_timeouts).await;
+        });
+
+        let manager = self.clone();
+        let app_for_pulse = app.clone();
+        tokio::spawn(async move {
+            manager.server_pulse_loop(app_for_pulse).await;
+        });
+
+        if let Some(failures) = self.db_failures.lock().await.take() {
+            let manager = self.clone();
+            let app_for_db = app.clone();
+            tokio::spawn(async move {
+                manager.db_failure_alert_loop(app_for_db, failures).await;
+            });
+        }
+
+        if let Some(deliveries) = self.webhook_queue.lock().await.take() {
+            let manager = self.clone();
+            tokio::spawn(async move {
+                manager.webhook_delivery_loop(deliveries).await;
+            });
+        }
+
+        let manager = self.clone();
+        let app_for_rotation = app.clone();
+        tokio::spawn(async move {
+            manager.pair_token_rotation_loop(app_for_rotation).await;
+        });
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            manager.task_automation_loop(app).await;
+        });
+    }
+
+    async fn webhook_delivery_loop(
+        &self,
+        mut deliveries: mpsc::UnboundedReceiver<WebhookDelivery>,
+    ) {
+        let client = webhook::client();
+        while let Some(delivery) = deliveries.recv().await {
+            let outcome = webhook::deliver(&client, &delivery).await;
+            let now = self.now();
+            let mut state = self.inner.lock().await;
+            let stats = &mut state.webhook_stats;
+            stats.retries += u64::from(outcome.attempts.saturating_sub(1));
+            match outcome.result {
+                Ok(()) => {
+                    stats.delivered += 1;
+                    stats.last_delivery_ms = Some(now);
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "[WEBHOOK] delivery to {} failed after {} attempt(s): {}",
+                        delivery.hook.url,
+                        outcome.attempts,
+                        err
+                    );
+                    stats.failed += 1;
+                    stats.last_error = Some(format!("{}: {}", delivery.hook.url, err));
+                }
+            }
+        }
+    }
+
+    async fn db_failure_alert_loop(
+        &self,
+        app: Arc<dyn EventSink>,
+        mut failures: mpsc::UnboundedReceiver<String>,
+    ) {
+        while let Some(first) = failures.recv().await {
+            // Collapse a burst into one alert rather than one per dropped write.
+            let mut count = 1;
+            while failures.try_recv().is_ok() {
+                count += 1;
+            }
+            let message = if count == 1 {
+                format!("Database write dropped: {}", first)
+            } else {
+                format!("{} database writes dropped: {}", count, first)
+            };
+            self.emit_log(&*app, None, "ERROR", message).await;
+        }
+    }
+
+    /// Every task that reaches `done` or `failed` is published here once.
+    pub fn subscribe_task_completions(&self) -> broadcast::Receiver<TaskRecord> {
+        self.task_completions.subscribe()
+    }
+
+    async fn spawn_listeners(&self, app: Arc<dyn EventSink>) {
+        let (ws_shutdown, shutdown_rx) = oneshot::channel();
+        let manager = self.clone();
+        let app_for_ws = app.clone();
+        let ws = tokio::spawn(async move {
+            manager.run_ws_server(app_for_ws, shutdown_rx).await;
+        });
+
+        let manager = self.clone();
+        let udp = tokio::spawn(async move {
+            manager.run_udp_provision_loop(app).await;
         });
+
+        self.inner.lock().await.listeners = Some(Listeners {
+            ws_shutdown,
+            ws,
+            udp,
+        });
+    }
+
+    /// Re-binds the WS listener and restarts the UDP loop after `stop_server`.
+    pub async fn start_server(&self, app: Arc<dyn EventSink>) -> Result<ServerStatus> {
+        {
+            let mut state = self.inner.lock().await;
+            match state.listeners.take() {
+                Some(listeners) if !listeners.ws.is_finished() => {
+                    state.listeners = Some(listeners);
+                    return Err(ServerError::Conflict(
+                        "server is already running".to_string(),
+                    ));
+                }
+                // The listener died on its own, e.g. the port was taken.
+                Some(listeners) => listeners.udp.abort(),
+                None => {}
+            }
+        }
+        self.emit_log(&*app, None, "INFO", "Starting server".to_string())
+            .await;
+        self.spawn_listeners(app).await;
+        Ok(self.get_status().await)
+    }
+
+    /// Stops the WS listener and UDP loop and closes agent sockets, freeing
+    /// the ports; background loops and in-memory state are kept.
+    pub async fn stop_server(&self, app: &dyn EventSink) -> Result<ServerStatus> {
+        let (listeners, closed) = {
+            let mut state = self.inner.lock().await;
+            let listeners = state
+                .listeners
+                .take()
+                .ok_or_else(|| ServerError::Conflict("server is not running".to_string()))?;
+            let ids: Vec<String> = state.connections.keys().cloned().collect();
+            for id in &ids {
+                disconnect_agent(&mut state, id);
+            }
+            state.discovery.udp_broadcast.running = false;
+            (listeners, ids.len())
+        };
+
+        listeners.udp.abort();
+        let _ = listeners.ws_shutdown.send(());
+        let ws = listeners.ws;
+        let abort = ws.abort_handle();
+        if timeout(Duration::from_millis(WS_SHUTDOWN_GRACE_MS), ws)
+            .await
+            .is_err()
+        {
+            abort.abort();
+        }
+
+        self.set_online(app, false).await;
+        self.emit_log(
+            app,
+            None,
+            "INFO",
+            format!("Server stopped; closed {} agent connection(s)", closed),
+        )
+        .await;
+        Ok(self.get_status().await)
     }
 
     pub async fn get_status(&self) -> ServerStatus {
         let state = self.inner.lock().await;
         ServerStatus {
             online: state.online,
-            port_ws: WS_PORT,
-            port_udp: UDP_PORT,
+            port_ws: state.config.port_ws,
+            port_udp: state.config.port_udp,
+            loops: self.loops.status(),
+            connection_count: self.open_sockets.load(AtomicOrdering::Relaxed),
+            max_connections: state.config.max_agent_connections,
+        }
+    }
+
+    pub async fn set_loop_enabled(
+        &self,
+        app: &dyn EventSink,
+        loop_name: String,
+        enabled: bool,
+    ) -> Result<LoopStatus> {
+        let flag = self
+            .loops
+            .flag(&loop_name)
+            .ok_or_else(|| ServerError::InvalidInput(format!("unknown loop: {}", loop_name)))?;
+        let previous = flag.swap(enabled, AtomicOrdering::Relaxed);
+        if previous != enabled {
+            self.emit_log(
+                app,
+                None,
+                "INFO",
+                format!(
+                    "Loop {} {}",
+                    loop_name,
+                    if enabled { "resumed" } else { "paused" }
+                ),
+            )
+            .await;
+            self.emit_server_status(app).await;
+            if enabled && loop_name == "topology_rebuild" && self.commit_topology(app, true).await {
+                self.emit_topology_snapshot(app).await;
+            }
         }
+        Ok(self.loops.status())
     }
 
     pub async fn get_devices_snapshot(&self) -> DevicesSnapshot {
@@ -380,1436 +1642,8809 @@ impl ServerManager {
         DevicesSnapshot { devices }
     }
 
-    pub async fn get_topology_snapshot(&self) -> TopologySnapshot {
+    pub async fn get_device(&self, agent_id: &str) -> Result<DeviceRecord> {
         let state = self.inner.lock().await;
-        state.topology_snapshot.clone()
+        state
+            .devices
+            .get(agent_id)
+            .cloned()
+            .ok_or_else(|| ServerError::NotFound(format!("unknown agent {}", agent_id)))
     }
 
-    pub async fn get_tasks_snapshot(&self) -> TasksSnapshot {
-        let mut tasks = {
-            let state = self.inner.lock().await;
-            state.tasks.values().cloned().collect::<Vec<_>>()
+    pub async fn override_device_network(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        facts: NetworkFactsPayload,
+    ) -> Result<DeviceRecord> {
+        validate_network_override(&facts)?;
+        let facts = NetworkFactsPayload {
+            arp_snapshot: Vec::new(),
+            ..facts
         };
-        tasks.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-        TasksSnapshot { tasks }
-    }
-
-    pub async fn get_activity_snapshot(&self) -> ActivitySnapshot {
-        let events = {
-            let state = self.inner.lock().await;
-            state.activity.iter().cloned().collect::<Vec<_>>()
+        let device = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let device = state
+                .devices
+                .get_mut(&agent_id)
+                .ok_or_else(|| ServerError::NotFound(format!("unknown agent {}", agent_id)))?;
+            apply_network_override(device, Some(&facts));
+            let device = device.clone();
+            state
+                .network_overrides
+                .insert(agent_id.clone(), facts.clone());
+            device
         };
-        ActivitySnapshot { events }
-    }
 
-    pub async fn get_pair_token(&self) -> String {
-        self.inner.lock().await.pair_token.clone()
+        let stored = serde_json::to_string(&facts)?;
+        let (db_id, ts) = (agent_id.clone(), self.now());
+        self.persist(move |db| db.set_network_override(&db_id, &stored, ts));
+        self.persist_facts(&device);
+        self.emit_log(
+            app,
+            Some(agent_id),
+            "INFO",
+            format!(
+                "Network facts overridden: {}",
+                device.overridden_network_fields.join(", ")
+            ),
+        )
+        .await;
+        self.emit_device_upsert_if_needed(app, device.clone(), true)
+            .await;
+        self.rebuild_topology_if_changed(app).await;
+        Ok(device)
     }
 
-    pub async fn rotate_pair_token(&self, app: AppHandle) -> Result<String, String> {
-        let token = {
-            let mut state = self.inner.lock().await;
-            state.pair_token = Uuid::new_v4().to_string();
-            state.pair_token.clone()
+    /// Drops the correction; the agent's own facts return with its next heartbeat.
+    pub async fn clear_device_network_override(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+    ) -> Result<DeviceRecord> {
+        let device = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            if state.network_overrides.remove(&agent_id).is_none() {
+                return Err(ServerError::NotFound(format!(
+                    "no network override for {}",
+                    agent_id
+                )));
+            }
+            state.devices.get_mut(&agent_id).map(|device| {
+                device.overridden_network_fields.clear();
+                device.clone()
+            })
         };
-        self.emit_server_status(&app).await;
-        self.emit_log(&app, None, "INFO", "Pair token rotated".to_string())
+
+        let db_id = agent_id.clone();
+        self.persist(move |db| db.delete_network_override(&db_id));
+        self.emit_log(
+            app,
+            Some(agent_id.clone()),
+            "INFO",
+            "Network override cleared".to_string(),
+        )
+        .await;
+        let device =
+            device.ok_or_else(|| ServerError::NotFound(format!("unknown agent {}", agent_id)))?;
+        self.emit_device_upsert_if_needed(app, device.clone(), true)
             .await;
-        Ok(token)
+        self.rebuild_topology_if_changed(app).await;
+        Ok(device)
     }
 
-    pub async fn dispatch_task(
-        &self,
-        app: AppHandle,
-        agents: Vec<String>,
-        kind: String,
-        params: Value,
-    ) -> Result<TaskRecord, String> {
-        if agents.is_empty() {
-            return Err("at least one agent is required".to_string());
+    pub async fn list_alias_rules(&self) -> Vec<AliasRule> {
+        self.inner.lock().await.alias_rules.clone()
+    }
+
+    /// Appends the rule after existing ones and re-resolves every known device.
+    pub async fn add_alias_rule(&self, app: &dyn EventSink, rule: AliasRule) -> Result<AliasRule> {
+        let mut rule = rule.normalize().map_err(ServerError::InvalidInput)?;
+        if rule.id.is_empty() {
+            rule.id = Uuid::new_v4().to_string();
         }
-        if !matches!(kind.as_str(), "ping" | "port_scan" | "arp_snapshot") {
-            return Err("unsupported task kind".to_string());
+        rule.created_at = self.now();
+        {
+            let mut state = self.inner.lock().await;
+            if state
+                .alias_rules
+                .iter()
+                .any(|existing| existing.id == rule.id)
+            {
+                return Err(ServerError::Conflict(format!(
+                    "alias rule {} already exists",
+                    rule.id
+                )));
+            }
+            state.alias_rules.push(rule.clone());
         }
 
-        let task = TaskRecord {
-            task_id: Uuid::new_v4().to_string(),
-            kind,
-            params,
-            assigned_agents: agents,
-            status: "queued".to_string(),
-            created_at: now_ms(),
-            started_at: None,
-            ended_at: None,
-            results: Vec::new(),
-        };
+        let stored = serde_json::to_string(&rule)?;
+        let (db_id, ts) = (rule.id.clone(), rule.created_at);
+        self.persist(move |db| db.insert_alias_rule(&db_id, &stored, ts));
+        self.emit_log(app, None, "INFO", format!("Alias rule {} added", rule.id))
+            .await;
+        self.reapply_alias_rules(app).await;
+        Ok(rule)
+    }
 
+    pub async fn remove_alias_rule(&self, app: &dyn EventSink, id: String) -> Result<()> {
         {
-            self.inner
-                .lock()
-                .await
-                .tasks
-                .insert(task.task_id.clone(), task.clone());
+            let mut state = self.inner.lock().await;
+            let before = state.alias_rules.len();
+            state.alias_rules.retain(|rule| rule.id != id);
+            if state.alias_rules.len() == before {
+                return Err(ServerError::NotFound(format!("unknown alias rule {}", id)));
+            }
         }
 
-        let updated = self.dispatch_task_now(task).await;
-        self.emit_task_update(&app, updated.clone()).await;
-        self.emit_activity(
-            &app,
-            "task_started",
-            None,
-            format!("Task started: {} ({})", updated.kind, updated.task_id),
-        )
-        .await;
-        Ok(updated)
+        let db_id = id.clone();
+        self.persist(move |db| db.delete_alias_rule(&db_id));
+        self.emit_log(app, None, "INFO", format!("Alias rule {} removed", id))
+            .await;
+        self.reapply_alias_rules(app).await;
+        Ok(())
     }
 
-    async fn run_ws_server(&self, app: AppHandle) {
-        let bind_addr = format!("0.0.0.0:{}", WS_PORT);
-        tracing::info!("[WS] binding addr={}", bind_addr);
-        self.emit_log(
-            &app,
-            None,
-            "INFO",
-            format!("Starting WS server on {}", bind_addr),
-        )
-        .await;
-
-        let listener = match TcpListener::bind(&bind_addr).await {
-            Ok(listener) => listener,
-            Err(err) => {
-                tracing::error!("[WS] bind failed: {}", err);
-                self.set_online(&app, false).await;
-                self.emit_log(&app, None, "ERROR", format!("ws: bind failed: {}", err))
-                    .await;
-                return;
+    async fn reapply_alias_rules(&self, app: &dyn EventSink) {
+        let changed = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            state
+                .devices
+                .values_mut()
+                .filter_map(|device| {
+                    apply_alias_rules(&state.alias_rules, device).then(|| device.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+        for device in changed {
+            self.persist_device(&device);
+            self.emit_device_upsert_if_needed(app, device, true).await;
+        }
+    }
+
+    pub async fn get_devices_page(
+        &self,
+        offset: usize,
+        limit: usize,
+        sort_by: Option<String>,
+        descending: bool,
+    ) -> Result<DevicesPage> {
+        let mut devices = self.get_devices_snapshot().await.devices;
+        match sort_by.as_deref().unwrap_or("order") {
+            "order" => {}
+            "hostname" => devices.sort_by(|a, b| a.hostname.cmp(&b.hostname)),
+            "last_seen" => devices.sort_by_key(|d| d.last_seen_ms),
+            "ip" => devices.sort_by_key(|d| d.ip.as_deref().and_then(ip_to_u32)),
+            "connection_quality" => devices.sort_by_key(|d| d.connection_quality),
+            other => {
+                return Err(ServerError::InvalidInput(format!(
+                    "unknown sort key: {}",
+                    other
+                )))
             }
+        }
+        if descending {
+            devices.reverse();
+        }
+        let total = devices.len();
+        let devices = devices
+            .into_iter()
+            .skip(offset)
+            .take(limit.clamp(1, MAX_DEVICES_PAGE))
+            .collect();
+        Ok(DevicesPage {
+            devices,
+            total,
+            offset,
+        })
+    }
+
+    pub async fn get_devices_digest(&self) -> String {
+        let key = {
+            let state = self.inner.lock().await;
+            devices_key(&state.devices)
         };
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
 
-        self.set_online(&app, true).await;
-        tracing::info!("[WS] listening addr={}", bind_addr);
-        self.emit_log(
-            &app,
-            None,
-            "INFO",
-            format!("WS server listening on {}", bind_addr),
-        )
-        .await;
+    pub async fn diff_device(&self, agent_id: String, ts_a: i64, ts_b: i64) -> Result<DeviceDiff> {
+        let (facts_a, facts_b) = {
+            let db = self.db.lock().await;
+            let at = |ts| {
+                db.get_device_facts_at(&agent_id, ts)
+                    .map_err(ServerError::db("device history query"))
+                    .map(|row| {
+                        row.and_then(|(_, raw)| serde_json::from_str::<DeviceFacts>(&raw).ok())
+                    })
+            };
+            (at(ts_a)?, at(ts_b)?)
+        };
+        if facts_a.is_none() && facts_b.is_none() {
+            return Err(ServerError::NotFound(format!(
+                "no history for {} at either timestamp",
+                agent_id
+            )));
+        }
 
-        let router = Router::new()
-            .route("/ws/agent", get(ws_agent_handler))
-            .with_state(HttpState {
-                manager: self.clone(),
-                app: app.clone(),
-            });
+        let changes = fact_changes(facts_a.as_ref(), facts_b.as_ref());
+        Ok(DeviceDiff {
+            agent_id,
+            ts_a,
+            ts_b,
+            facts_a,
+            facts_b,
+            changes,
+        })
+    }
 
-        if let Err(err) = axum::serve(
-            listener,
-            router.into_make_service_with_connect_info::<SocketAddr>(),
-        )
+    pub async fn export_device_inventory(
+        &self,
+        path: String,
+        compress: bool,
+    ) -> Result<ExportResult> {
+        let devices = self.get_devices_snapshot().await.devices;
+        tokio::task::spawn_blocking(move || {
+            export::write_export(&path, compress, |out| {
+                export::write_json_array(out, &devices)
+            })
+        })
         .await
+        .map_err(|e| ServerError::Internal(format!("export task failed: {}", e)))?
+        .map_err(ServerError::Export)
+    }
+
+    pub async fn export_database(&self, path: String, compress: bool) -> Result<ExportResult> {
+        let snapshot = std::env::temp_dir().join(format!("labscan-export-{}.db", Uuid::new_v4()));
         {
-            self.set_online(&app, false).await;
-            self.emit_log(&app, None, "ERROR", format!("WS server stopped: {}", err))
-                .await;
+            let db = self.db.lock().await;
+            db.backup_to(&snapshot)
+                .map_err(ServerError::db("database snapshot"))?;
         }
+        tokio::task::spawn_blocking(move || {
+            let result = export::copy_file_export(&snapshot, &path, compress);
+            let _ = std::fs::remove_file(&snapshot);
+            result
+        })
+        .await
+        .map_err(|e| ServerError::Internal(format!("export task failed: {}", e)))?
+        .map_err(ServerError::Export)
     }
 
-    async fn run_udp_provision_loop(&self, app: AppHandle) {
-        let send_socket = match UdpSocket::bind("0.0.0.0:0").await {
-            Ok(s) => s,
-            Err(err) => {
-                self.emit_log(
-                    &app,
-                    None,
-                    "ERROR",
-                    format!("UDP broadcast socket error: {}", err),
-                )
-                .await;
-                return;
+    pub async fn get_device_audit(&self, agent_id: String, since_ms: i64) -> Result<DeviceAudit> {
+        let (history, heartbeats, task_records, activity) = {
+            let db = self.db.lock().await;
+            let baseline = db
+                .get_device_facts_at(&agent_id, since_ms)
+                .map_err(ServerError::db("device history query"))?;
+            let mut history = db
+                .get_device_history(&agent_id, since_ms)
+                .map_err(ServerError::db("device history query"))?;
+            if let Some(row) = baseline.filter(|(ts, _)| *ts < since_ms) {
+                history.insert(0, row);
             }
-        };
-        if let Err(err) = send_socket.set_broadcast(true) {
-            self.emit_log(
-                &app,
-                None,
-                "ERROR",
-                format!("UDP broadcast enable failed: {}", err),
+            (
+                history,
+                db.get_heartbeats(&agent_id, since_ms, AUDIT_HEARTBEAT_LIMIT)
+                    .map_err(ServerError::db("heartbeat query"))?,
+                db.get_task_records_mentioning(&agent_id, since_ms)
+                    .map_err(ServerError::db("task query"))?,
+                db.query_activity(since_ms, None, Some(&agent_id), AUDIT_ACTIVITY_LIMIT)
+                    .map_err(ServerError::db("activity query"))?,
             )
-            .await;
-            return;
-        }
+        };
+        // Logs are memory-only, so alerts reach back only as far as the ring buffer.
+        let alerts: Vec<LogEvent> = {
+            let state = self.inner.lock().await;
+            state
+                .logs
+                .iter()
+                .filter(|log| {
+                    log.ts >= since_ms
+                        && log.agent_id.as_deref() == Some(agent_id.as_str())
+                        && matches!(log.level.as_str(), "WARN" | "ERROR")
+                })
+                .cloned()
+                .collect()
+        };
 
-        let ack_socket = UdpSocket::bind(format!("0.0.0.0:{}", UDP_PORT)).await.ok();
-        let admin_ip = detect_local_ipv4()
-            .map(|ip| ip.to_string())
-            .unwrap_or_else(|| "127.0.0.1".to_string());
-        let destination = format!("255.255.255.255:{}", UDP_PORT);
-        self.emit_log(
-            &app,
-            None,
-            "INFO",
-            format!(
-                "provision: broadcasting on UDP {}, admin_ip={}",
-                UDP_PORT, admin_ip
-            ),
-        )
-        .await;
+        let mut events = Vec::new();
 
-        let mut ack_buffer = [0_u8; 2048];
-        loop {
-            if !self.inner.lock().await.online {
-                sleep(Duration::from_secs(1)).await;
+        let mut previous: Option<DeviceFacts> = None;
+        for (ts, raw) in history {
+            let Ok(facts) = serde_json::from_str::<DeviceFacts>(&raw) else {
                 continue;
-            }
-
-            let secret = self.inner.lock().await.pair_token.clone();
-            let payload = ProvisionBroadcast {
-                message_type: "LABSCAN_PROVISION".to_string(),
-                v: 1,
-                admin_ip: admin_ip.clone(),
-                secret,
-                nonce: Uuid::new_v4().to_string(),
             };
-            if let Ok(raw) = serde_json::to_vec(&payload) {
-                let _ = send_socket.send_to(&raw, &destination).await;
-            }
-
-            if let Some(socket) = &ack_socket {
-                if let Ok(result) = tokio::time::timeout(
-                    Duration::from_millis(300),
-                    socket.recv_from(&mut ack_buffer),
-                )
-                .await
-                {
-                    if let Ok((len, sender)) = result {
-                        if let Ok(ack) = serde_json::from_slice::<ProvisionAck>(&ack_buffer[..len])
-                        {
-                            if ack.message_type == "LABSCAN_PROVISION_ACK" && ack.v == 1 {
-                                self.emit_log(
-                                    &app,
-                                    Some(ack.agent_id),
-                                    "INFO",
-                                    format!(
-                                        "Provision ACK from {} ({}) nonce={} ts={}",
-                                        ack.hostname, sender, ack.nonce, ack.ts
-                                    ),
-                                )
-                                .await;
-                            }
-                        }
+            if let Some(before) = &previous {
+                let changes = fact_changes(Some(before), Some(&facts));
+                let (status, network): (Vec<FieldDiff>, Vec<FieldDiff>) =
+                    changes.into_iter().partition(|c| c.field == "status");
+                if ts >= since_ms {
+                    if !status.is_empty() {
+                        events.push(DeviceAuditEvent {
+                            ts,
+                            kind: "status".to_string(),
+                            message: format!("Status {} -> {}", before.status, facts.status),
+                            data: json!(status),
+                        });
+                    }
+                    if !network.is_empty() {
+                        events.push(DeviceAuditEvent {
+                            ts,
+                            kind: "network".to_string(),
+                            message: format!(
+                                "Changed {}",
+                                network
+                                    .iter()
+                                    .map(|c| c.field.as_str())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                            data: json!(network),
+                        });
                     }
                 }
             }
-            sleep(Duration::from_secs(1)).await;
+            previous = Some(facts);
         }
-    }
 
-    async fn heartbeat_watchdog(&self, app: AppHandle) {
-        loop {
-            sleep(Duration::from_secs(3)).await;
-            let now = now_ms();
-            let mut ids = Vec::new();
-            {
-                let mut state = self.inner.lock().await;
-                for d in state.devices.values_mut() {
-                    if d.status != "offline" && now - d.last_seen_ms > HEARTBEAT_TIMEOUT_MS {
-                        d.status = "offline".to_string();
-                        ids.push(d.agent_id.clone());
-                    }
-                }
-            }
-            for id in ids {
-                if let Some(device) = self.inner.lock().await.devices.get(&id).cloned() {
-                    self.emit_device_upsert_if_needed(&app, device.clone(), true)
-                        .await;
-                    self.emit_activity(
-                        &app,
-                        "device_disconnected",
-                        Some(id),
-                        format!("{} disconnected (heartbeat timeout)", device.hostname),
-                    )
-                    .await;
-                }
-            }
+        for window in heartbeats.chunk_by(|a, b| {
+            a.timestamp / AUDIT_HEARTBEAT_BUCKET_MS == b.timestamp / AUDIT_HEARTBEAT_BUCKET_MS
+        }) {
+            let latencies: Vec<i64> = window.iter().filter_map(|h| h.https_latency_ms).collect();
+            let avg_latency_ms = (!latencies.is_empty())
+                .then(|| latencies.iter().sum::<i64>() / latencies.len() as i64);
+            let gateway_failures = window.iter().filter(|h| !h.gateway_reachable).count();
+            let dns_failures = window.iter().filter(|h| !h.dns_resolves).count();
+            events.push(DeviceAuditEvent {
+                ts: window[0].timestamp,
+                kind: "heartbeat".to_string(),
+                message: format!(
+                    "{} heartbeat(s), avg latency {}",
+                    window.len(),
+                    avg_latency_ms
+                        .map(|ms| format!("{} ms", ms))
+                        .unwrap_or_else(|| "n/a".to_string())
+                ),
+                data: json!({
+                    "count": window.len(),
+                    "until": window[window.len() - 1].timestamp,
+                    "avg_latency_ms": avg_latency_ms,
+                    "gateway_failures": gateway_failures,
+                    "dns_failures": dns_failures,
+                }),
+            });
         }
-    }
 
-    async fn dispatch_task_now(&self, task: TaskRecord) -> TaskRecord {
-        let payload = TaskDispatchPayload {
-            task_id: task.task_id.clone(),
-            kind: task.kind.clone(),
-            params: task.params.clone(),
-        };
-        let mut started = false;
-        {
-            let state = self.inner.lock().await;
-            for agent in &task.assigned_agents {
-                if let Some(sender) = state.connections.get(agent) {
-                    let msg = WireMessage {
-                        message_type: "task".to_string(),
-                        ts: now_ms(),
-                        agent_id: agent.clone(),
-                        payload: serde_json::to_value(&payload).unwrap_or_else(|_| json!({})),
-                    };
-                    if let Ok(raw) = serde_json::to_string(&msg) {
-                        let _ = sender.send(Message::Text(raw.into()));
-                        started = true;
-                    }
-                }
+        for raw in task_records {
+            let Ok(task) = serde_json::from_str::<TaskRecord>(&raw) else {
+                continue;
+            };
+            if !task.assigned_agents.contains(&agent_id) {
+                continue;
             }
+            let result = task.results.iter().find(|r| r.agent_id == agent_id);
+            events.push(DeviceAuditEvent {
+                ts: task.created_at,
+                kind: "task".to_string(),
+                message: format!(
+                    "Task {} ({}) {}",
+                    task.kind,
+                    task.task_id,
+                    match result {
+                        Some(r) if r.ok => "ok".to_string(),
+                        Some(r) => format!(
+                            "failed: {}",
+                            r.error
+                                .clone()
+                                .unwrap_or_else(|| "unknown error".to_string())
+                        ),
+                        None => "no result".to_string(),
+                    }
+                ),
+                data: json!({
+                    "task_id": task.task_id,
+                    "kind": task.kind,
+                    "status": task.status,
+                    "result": result,
+                }),
+            });
         }
-        let mut state = self.inner.lock().await;
-        if let Some(existing) = state.tasks.get_mut(&task.task_id) {
-            if started {
-                existing.status = "running".to_string();
-                existing.started_at = Some(now_ms());
-            }
-            existing.clone()
-        } else {
-            task
+
+        for row in activity {
+            events.push(DeviceAuditEvent {
+                ts: row.ts,
+                kind: "activity".to_string(),
+                message: row.message,
+                data: json!({ "activity_kind": row.kind, "count": row.count }),
+            });
         }
-    }
 
-    async fn set_online(&self, app: &AppHandle, online: bool) {
-        self.inner.lock().await.online = online;
-        self.emit_server_status(app).await;
-    }
+        for log in alerts {
+            events.push(DeviceAuditEvent {
+                ts: log.ts,
+                kind: "alert".to_string(),
+                message: log.message,
+                data: json!({ "level": log.level }),
+            });
+        }
 
-    async fn emit_server_status(&self, app: &AppHandle) {
-        let _ = app.emit(EVENT_SERVER_STATUS, self.get_status().await);
+        events.sort_by_key(|e| e.ts);
+        Ok(DeviceAudit {
+            agent_id,
+            since_ms,
+            events,
+        })
     }
 
-    async fn emit_devices_snapshot(&self, app: &AppHandle) {
-        let _ = app.emit(EVENT_DEVICES_SNAPSHOT, self.get_devices_snapshot().await);
+    pub async fn query_activity(
+        &self,
+        since_ms: i64,
+        kind: Option<String>,
+        agent_id: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ActivityEvent>> {
+        let limit = limit
+            .unwrap_or(self.settings.max_activity)
+            .clamp(1, MAX_ACTIVITY_QUERY);
+        let kind = kind.and_then(|v| clean_non_empty_owned(&v));
+        let agent_id = agent_id.and_then(|v| clean_non_empty_owned(&v));
+        let rows = {
+            let db = self.db.lock().await;
+            db.query_activity(since_ms, kind.as_deref(), agent_id.as_deref(), limit)
+                .map_err(ServerError::db("activity query"))?
+        };
+        Ok(rows
+            .into_iter()
+            .map(|row| ActivityEvent {
+                id: row.id,
+                kind: row.kind,
+                agent_id: row.agent_id,
+                message: row.message,
+                ts: row.ts,
+                count: row.count,
+            })
+            .collect())
     }
 
-    async fn emit_topology_snapshot(&self, app: &AppHandle) {
-        let snapshot = self.get_topology_snapshot().await;
-        let _ = app.emit(EVENT_TOPOLOGY_SNAPSHOT, snapshot.clone());
-        let _ = app.emit(EVENT_TOPOLOGY_CHANGED, snapshot);
+    pub async fn prune_activity(&self, before_ms: i64) -> Result<usize> {
+        let mut db = self.db.lock().await;
+        db.prune_activity(before_ms)
+            .map_err(ServerError::db("activity prune"))
     }
 
-    async fn rebuild_topology_if_changed(&self, app: &AppHandle) {
-        let changed = {
-            let mut state = self.inner.lock().await;
-            let candidate = build_topology_snapshot(
-                &state.devices,
-                &state.device_order,
-                &state.admin_network,
-                state.topology_snapshot.revision + 1,
-            );
-            let key = topology_key(&candidate);
-            if key != state.topology_key {
-                state.topology_key = key;
-                state.topology_snapshot = candidate;
-                true
-            } else {
-                false
-            }
+    pub async fn render_device_metrics(&self) -> String {
+        let (devices, tasks) = {
+            let state = self.inner.lock().await;
+            (
+                state.devices.values().cloned().collect::<Vec<_>>(),
+                state.tasks.values().cloned().collect::<Vec<_>>(),
+            )
         };
-        if changed {
-            self.emit_topology_snapshot(app).await;
+        metrics::render_device_metrics(&devices, &tasks)
+    }
+
+    /// Availability over the last `window_hours`, judged per heartbeat-timeout
+    /// window: one without any heartbeat is when the watchdog would call it offline.
+    pub async fn get_device_uptime(
+        &self,
+        agent_id: String,
+        window_hours: u32,
+    ) -> Result<Availability> {
+        if window_hours == 0 || window_hours > MAX_UPTIME_WINDOW_HOURS {
+            return Err(ServerError::InvalidInput(format!(
+                "window_hours must be between 1 and {}",
+                MAX_UPTIME_WINDOW_HOURS
+            )));
         }
+        let until_ms = self.now();
+        let since_ms = until_ms - i64::from(window_hours) * 60 * 60 * 1000;
+        let db = self.db.lock().await;
+        db.get_availability(
+            &agent_id,
+            since_ms,
+            until_ms,
+            self.settings.heartbeat_timeout_ms,
+        )
+        .map_err(ServerError::db("availability query"))
     }
 
-    async fn emit_device_upsert_if_needed(
+    pub async fn get_device_latency_percentiles(
         &self,
-        app: &AppHandle,
-        device: DeviceRecord,
-        force: bool,
-    ) {
-        let now = now_ms();
-        let should = {
-            let mut state = self.inner.lock().await;
-            let last = state
-                .last_device_emit_ms
-                .get(&device.agent_id)
-                .copied()
-                .unwrap_or(0);
-            if force || now - last >= DEVICE_EMIT_THROTTLE_MS {
-                state
-                    .last_device_emit_ms
-                    .insert(device.agent_id.clone(), now);
-                true
+        agent_id: String,
+        since_ms: i64,
+    ) -> Result<LatencyPercentiles> {
+        let samples = {
+            let db = self.db.lock().await;
+            db.get_latency_samples(&agent_id, since_ms)
+                .map_err(ServerError::db("latency query"))?
+        };
+
+        let mut buckets: Vec<LatencyBucket> = LATENCY_BUCKETS_MS
+            .iter()
+            .map(|le| LatencyBucket {
+                le_ms: Some(*le),
+                count: 0,
+            })
+            .chain(std::iter::once(LatencyBucket {
+                le_ms: None,
+                count: 0,
+            }))
+            .collect();
+        for sample in &samples {
+            let idx = LATENCY_BUCKETS_MS
+                .iter()
+                .position(|le| sample <= le)
+                .unwrap_or(LATENCY_BUCKETS_MS.len());
+            buckets[idx].count += 1;
+        }
+
+        let sufficient_samples = samples.len() >= MIN_LATENCY_SAMPLES;
+        let pick = |pct: usize| {
+            if sufficient_samples {
+                nearest_rank(&samples, pct)
             } else {
-                false
+                None
             }
         };
-        if should {
-            let _ = app.emit(EVENT_DEVICE_UPSERT, DeviceUpsertEvent { device });
-            self.emit_devices_snapshot(app).await;
-        }
+        Ok(LatencyPercentiles {
+            agent_id,
+            since_ms,
+            sample_count: samples.len(),
+            sufficient_samples,
+            p50: pick(50),
+            p90: pick(90),
+            p99: pick(99),
+            buckets,
+        })
     }
 
-    async fn emit_device_remove(&self, app: &AppHandle, agent_id: String) {
-        let _ = app.emit(EVENT_DEVICE_REMOVE, DeviceRemoveEvent { agent_id });
-        self.emit_devices_snapshot(app).await;
+    /// Stamps an outgoing event with the next `event_seq` and journals it.
+    /// Pulses are not journaled; they carry their own `seq` and no state.
+    /// Neither are snapshots: a client too far behind fetches fresh ones.
+    fn journal_event(&self, event: &str, payload: &mut Value) {
+        if matches!(
+            event,
+            EVENT_SERVER_PULSE
+                | EVENT_DEVICES_SNAPSHOT
+                | EVENT_TASKS_SNAPSHOT
+                | EVENT_ACTIVITY_SNAPSHOT
+                | EVENT_TOPOLOGY_SNAPSHOT
+        ) {
+            return;
+        }
+        let Value::Object(fields) = payload else {
+            return;
+        };
+        let seq = self.event_seq.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+        fields.insert("event_seq".to_string(), json!(seq));
+        let row = JournalRow {
+            seq: seq as i64,
+            ts: self.now(),
+            event: event.to_string(),
+            payload: payload.to_string(),
+        };
+        let capacity = self.settings.event_journal_capacity;
+        self.persist(move |db| db.append_event(&row, capacity));
     }
 
-    async fn emit_task_update(&self, app: &AppHandle, task: TaskRecord) {
-        let _ = app.emit(EVENT_TASK_UPDATE, TaskUpdateEvent { task });
+    pub async fn get_events_since(&self, seq: u64) -> Result<EventReplay> {
+        let db = self.db.lock().await;
+        let bounds = db
+            .event_journal_bounds()
+            .map_err(ServerError::db("journal query"))?;
+        let latest_seq = self.event_seq.load(AtomicOrdering::SeqCst);
+        let pruned = bounds.is_some_and(|(oldest, _)| seq.saturating_add(1) < oldest as u64);
+        if pruned || seq > latest_seq {
+            return Ok(EventReplay {
+                events: Vec::new(),
+                latest_seq,
+                has_more: false,
+                snapshot_required: true,
+            });
+        }
+
+        let rows = db
+            .get_events_after(seq as i64, MAX_EVENT_REPLAY + 1)
+            .map_err(ServerError::db("journal query"))?;
+        let has_more = rows.len() > MAX_EVENT_REPLAY;
+        let events = rows
+            .into_iter()
+            .take(MAX_EVENT_REPLAY)
+            .map(|row| JournaledEvent {
+                seq: row.seq as u64,
+                ts: row.ts,
+                event: row.event,
+                payload: serde_json::from_str(&row.payload).unwrap_or(Value::Null),
+            })
+            .collect();
+        Ok(EventReplay {
+            events,
+            latest_seq,
+            has_more,
+            snapshot_required: false,
+        })
     }
 
-    async fn emit_log(
-        &self,
-        app: &AppHandle,
-        agent_id: Option<String>,
-        level: &str,
-        message: String,
-    ) {
-        let event = LogEvent {
-            id: Uuid::new_v4().to_string(),
-            agent_id,
-            level: level.to_string(),
-            message,
-            ts: now_ms(),
-        };
-        {
+    /// Round trip of a WebSocket ping over the agent's control connection.
+    pub async fn ping_agent(&self, agent_id: &str) -> Result<Duration> {
+        let (sent_at, nonce, answered) = {
             let mut state = self.inner.lock().await;
-            state.logs.push_front(event.clone());
-            while state.logs.len() > MAX_LOGS {
-                state.logs.pop_back();
+            let tx = state.connections.get(agent_id).cloned().ok_or_else(|| {
+                ServerError::Unavailable(format!("agent {} is not connected", agent_id))
+            })?;
+            state.next_ping_nonce += 1;
+            let nonce = state.next_ping_nonce;
+            let (done, answered) = oneshot::channel();
+            state
+                .pending_pings
+                .insert(nonce, (agent_id.to_string(), done));
+            let sent_at = Instant::now();
+            if tx
+                .send(Message::Ping(nonce.to_be_bytes().to_vec()))
+                .is_err()
+            {
+                state.pending_pings.remove(&nonce);
+                return Err(ServerError::Unavailable(format!(
+                    "agent {} connection closed",
+                    agent_id
+                )));
+            }
+            (sent_at, nonce, answered)
+        };
+
+        let wait_ms = self.settings.agent_ping_timeout_ms;
+        match timeout(Duration::from_millis(wait_ms), answered).await {
+            Ok(Ok(received_at)) => Ok(received_at.duration_since(sent_at)),
+            _ => {
+                self.inner.lock().await.pending_pings.remove(&nonce);
+                Err(ServerError::Timeout(format!(
+                    "no pong within {}ms",
+                    wait_ms
+                )))
             }
         }
-        let _ = app.emit(EVENT_LOG, event);
     }
 
-    async fn emit_activity(
-        &self,
-        app: &AppHandle,
-        kind: &str,
-        agent_id: Option<String>,
-        message: String,
-    ) {
-        let now = now_ms();
-        if let Some(ref id) = agent_id {
-            let drop_event = {
-                let state = self.inner.lock().await;
-                now - state.last_activity_emit_ms.get(id).copied().unwrap_or(0)
-                    < DEVICE_ACTIVITY_RATE_MS
-            };
-            if drop_event {
-                return;
+    async fn resolve_ping(&self, agent_id: &str, payload: &[u8], received_at: Instant) {
+        let Ok(bytes) = <[u8; 8]>::try_from(payload) else {
+            return;
+        };
+        let nonce = u64::from_be_bytes(bytes);
+        let mut state = self.inner.lock().await;
+        if state
+            .pending_pings
+            .get(&nonce)
+            .is_some_and(|(owner, _)| owner == agent_id)
+        {
+            if let Some((_, done)) = state.pending_pings.remove(&nonce) {
+                let _ = done.send(received_at);
             }
         }
+    }
 
-        let event = {
-            let mut state = self.inner.lock().await;
-            if let Some(ref id) = agent_id {
-                state.last_activity_emit_ms.insert(id.clone(), now);
-            }
+    pub async fn measure_all_agents(&self) -> AgentRttReport {
+        let mut agent_ids: Vec<String> = {
+            let state = self.inner.lock().await;
+            state.connections.keys().cloned().collect()
+        };
+        agent_ids.sort();
+
+        let outcomes: Vec<(String, Result<Duration>)> = futures_util::stream::iter(agent_ids)
+            .map(|agent_id| async move {
+                let outcome = self.ping_agent(&agent_id).await;
+                (agent_id, outcome)
+            })
+            .buffer_unordered(AGENT_PING_CONCURRENCY)
+            .collect()
+            .await;
 
-            if let Some(front) = state.activity.front_mut() {
-                if front.kind == kind
-                    && front.agent_id == agent_id
-                    && now - front.ts <= ACTIVITY_DEDUPE_MS
-                {
-                    front.ts = now;
-                    front.count = Some(front.count.unwrap_or(1) + 1);
-                    front.clone()
-                } else {
-                    let e = ActivityEvent {
-                        id: Uuid::new_v4().to_string(),
-                        kind: kind.to_string(),
-                        agent_id,
-                        message,
-                        ts: now,
-                        count: None,
-                    };
-                    state.activity.push_front(e.clone());
-                    while state.activity.len() > MAX_ACTIVITY {
-                        state.activity.pop_back();
-                    }
-                    e
+        let mut rtt_ms = BTreeMap::new();
+        let mut unreachable = BTreeMap::new();
+        let mut samples_us = Vec::new();
+        for (agent_id, outcome) in outcomes {
+            match outcome {
+                Ok(rtt) => {
+                    let us = rtt.as_micros() as i64;
+                    samples_us.push(us);
+                    rtt_ms.insert(agent_id, us as f64 / 1000.0);
+                }
+                Err(e) => {
+                    unreachable.insert(agent_id, e.to_string());
                 }
-            } else {
-                let e = ActivityEvent {
-                    id: Uuid::new_v4().to_string(),
-                    kind: kind.to_string(),
-                    agent_id,
-                    message,
-                    ts: now,
-                    count: None,
-                };
-                state.activity.push_front(e.clone());
-                e
             }
-        };
-
-        let _ = app.emit(EVENT_ACTIVITY, event);
+        }
+        samples_us.sort_unstable();
+        let pick = |pct| nearest_rank(&samples_us, pct).map(|us| us as f64 / 1000.0);
+        AgentRttReport {
+            measured_at: self.now(),
+            p50_ms: pick(50),
+            p95_ms: pick(95),
+            rtt_ms,
+            unreachable,
+        }
     }
 
-    async fn on_agent_disconnect(&self, app: &AppHandle, agent_id: String) {
-        let device = {
-            let mut state = self.inner.lock().await;
-            state.connections.remove(&agent_id);
-            if let Some(d) = state.devices.get_mut(&agent_id) {
-                d.status = "offline".to_string();
-                d.last_seen_ms = now_ms();
-                Some(d.clone())
-            } else {
-                None
+    pub async fn get_task_failure_stats(&self) -> TaskFailureStats {
+        let state = self.inner.lock().await;
+        let mut by_code = BTreeMap::new();
+        let mut by_kind: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+        let mut failures = Vec::new();
+        for task in state.tasks.values() {
+            for result in task.results.iter().filter(|r| !r.ok) {
+                let code = result
+                    .error_code
+                    .clone()
+                    .unwrap_or_else(|| TASK_ERROR_CODE_OTHER.to_string());
+                *by_code.entry(code.clone()).or_insert(0) += 1;
+                *by_kind
+                    .entry(task.kind.clone())
+                    .or_default()
+                    .entry(code.clone())
+                    .or_insert(0) += 1;
+                failures.push(TaskFailure {
+                    task_id: task.task_id.clone(),
+                    kind: task.kind.clone(),
+                    agent_id: result.agent_id.clone(),
+                    error_code: code,
+                    error: result.error.clone(),
+                    ts: result.ts,
+                });
             }
-        };
-
-        if let Some(device) = device {
-            self.emit_device_upsert_if_needed(app, device.clone(), true)
-                .await;
-            self.emit_activity(
-                app,
-                "device_disconnected",
-                Some(device.agent_id.clone()),
-                format!("{} disconnected", device.hostname),
-            )
-            .await;
-        } else {
-            self.emit_device_remove(app, agent_id).await;
         }
-        self.rebuild_topology_if_changed(app).await;
+        failures.sort_by_key(|f| std::cmp::Reverse(f.ts));
+        let total_failures = failures.len();
+        failures.truncate(MAX_RECENT_TASK_FAILURES);
+        TaskFailureStats {
+            total_failures,
+            by_code,
+            by_kind,
+            recent: failures,
+        }
     }
-}
 
-async fn ws_agent_handler(
-    ws: WebSocketUpgrade,
-    ConnectInfo(remote): ConnectInfo<SocketAddr>,
-    State(state): State<HttpState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_agent_socket(socket, state, remote))
-}
+    pub async fn get_topology_seed(&self) -> Vec<TopologySeedEntry> {
+        self.inner.lock().await.topology_seed.clone()
+    }
 
-async fn handle_agent_socket(socket: WebSocket, state: HttpState, remote: SocketAddr) {
-    tracing::info!("[WS] connect remote={} path=/ws/agent", remote);
-    state
-        .manager
-        .emit_log(
-            &state.app,
+    pub async fn import_topology_seed(&self, app: &dyn EventSink, json: String) -> Result<usize> {
+        let entries: Vec<TopologySeedEntry> = serde_json::from_str(&json)
+            .map_err(|e| ServerError::InvalidInput(format!("invalid topology seed: {}", e)))?;
+        let entries = entries
+            .into_iter()
+            .map(TopologySeedEntry::normalize)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(ServerError::InvalidInput)?;
+        let count = entries.len();
+
+        let rows = entries
+            .iter()
+            .map(|entry| TopologySeedRow {
+                mac: entry.mac.clone(),
+                agent_id: entry.agent_id.clone(),
+                switch_name: entry.switch.clone(),
+                port: entry.port.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.inner.lock().await.topology_seed = entries;
+        self.persist(move |db| db.replace_topology_seed(&rows));
+
+        self.emit_log(
+            app,
             None,
             "INFO",
-            format!("[WS] connect remote={} path=/ws/agent", remote),
+            format!("Topology seed imported ({} entries)", count),
         )
         .await;
+        self.rebuild_topology_if_changed(app).await;
+        Ok(count)
+    }
 
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
-    let write_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(msg).await.is_err() {
-                break;
-            }
+    pub async fn export_runtime_state(&self) -> RuntimeStateDocument {
+        let state = self.inner.lock().await;
+        let mut tasks = state.tasks.values().cloned().collect::<Vec<_>>();
+        tasks.sort_by_key(|task| task.created_at);
+        RuntimeStateDocument {
+            version: RUNTIME_STATE_VERSION,
+            exported_at: self.now(),
+            devices: state
+                .device_order
+                .iter()
+                .filter_map(|id| state.devices.get(id).cloned())
+                .collect(),
+            tasks,
+            activity: state.activity.iter().cloned().collect(),
+            topology: state.topology_snapshot.clone(),
+            topology_seed: state.topology_seed.clone(),
+            network_overrides: state
+                .network_overrides
+                .iter()
+                .map(|(id, facts)| (id.clone(), facts.clone()))
+                .collect(),
+            alias_rules: state.alias_rules.clone(),
+            config: state.config.clone(),
         }
-    });
+    }
 
-    let mut registered_agent_id: Option<String> = None;
+    /// Replaces config, overrides, alias rules and topology seed with the
+    /// document's, and merges devices, tasks and activity into the live state.
+    /// Connected agents keep their live records; everything else imported
+    /// comes back offline until its agent reconnects.
+    pub async fn import_runtime_state(
+        &self,
+        app: &dyn EventSink,
+        doc: RuntimeStateDocument,
+        confirm: bool,
+    ) -> Result<RuntimeStateImport> {
+        if !confirm {
+            return Err(ServerError::NotPermitted(
+                "importing runtime state overwrites live state; pass confirm to proceed"
+                    .to_string(),
+            ));
+        }
+        if doc.version != RUNTIME_STATE_VERSION {
+            return Err(ServerError::InvalidInput(format!(
+                "unsupported runtime state version {} (expected {})",
+                doc.version, RUNTIME_STATE_VERSION
+            )));
+        }
+        doc.config.validate().map_err(ServerError::Config)?;
+        let topology_seed = doc
+            .topology_seed
+            .into_iter()
+            .map(TopologySeedEntry::normalize)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(ServerError::InvalidInput)?;
+        let alias_rules = doc
+            .alias_rules
+            .into_iter()
+            .map(|rule| {
+                let mut rule = rule.normalize()?;
+                if rule.id.is_empty() {
+                    rule.id = Uuid::new_v4().to_string();
+                }
+                Ok(rule)
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(ServerError::InvalidInput)?;
+        for facts in doc.network_overrides.values() {
+            validate_network_override(facts)?;
+        }
 
-    while let Some(incoming) = receiver.next().await {
-        let message = match incoming {
-            Ok(m) => m,
-            Err(_) => break,
-        };
-        let text = match message {
-            Message::Text(t) => t,
-            _ => continue,
-        };
+        let (devices, live_kept, tasks, activity) = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            // The listeners keep running, so the live ports stay.
+            state.config = ServerConfig {
+                port_ws: state.config.port_ws,
+                port_udp: state.config.port_udp,
+                allow_mock_devices: self.settings.server.allow_mock_devices,
+                ..doc.config
+            };
+            state.topology_seed = topology_seed.clone();
+            state.alias_rules = alias_rules.clone();
+            state.network_overrides = doc.network_overrides.clone().into_iter().collect();
+
+            let mut devices = Vec::new();
+            let mut live_kept = Vec::new();
+            for mut device in doc.devices {
+                let agent_id = device.agent_id.clone();
+                if agent_id.is_empty() {
+                    continue;
+                }
+                if state.connections.contains_key(&agent_id) {
+                    live_kept.push(agent_id);
+                    continue;
+                }
+                device.status = "offline".to_string();
+                apply_network_override(&mut device, state.network_overrides.get(&agent_id));
+                apply_alias_rules(&state.alias_rules, &mut device);
+                if let Some(fp) = &device.fingerprint {
+                    state.fingerprint_index.insert(fp.clone(), agent_id.clone());
+                }
+                if !state.device_order.contains(&agent_id) {
+                    state.device_order.push(agent_id.clone());
+                }
+                state.devices.insert(agent_id, device.clone());
+                devices.push(device);
+            }
 
-        let wire = match serde_json::from_str::<WireMessage>(&text) {
-            Ok(v) => v,
-            Err(_) => continue,
+            let mut tasks = Vec::new();
+            for task in doc.tasks {
+                if !state.tasks.contains_key(&task.task_id) {
+                    state.tasks.insert(task.task_id.clone(), task.clone());
+                    tasks.push(task);
+                }
+            }
+
+            let known = state
+                .activity
+                .iter()
+                .map(|event| event.id.clone())
+                .collect::<HashSet<_>>();
+            let activity = doc
+                .activity
+                .into_iter()
+                .filter(|event| !known.contains(&event.id))
+                .collect::<Vec<_>>();
+            let mut merged = state
+                .activity
+                .drain(..)
+                .chain(activity.clone())
+                .collect::<Vec<_>>();
+            merged.sort_by_key(|event| std::cmp::Reverse(event.ts));
+            merged.truncate(self.settings.max_activity);
+            state.activity = merged.into();
+            (devices, live_kept, tasks, activity)
         };
 
-        if wire.message_type == "register" {
-            let payload = match serde_json::from_value::<RegisterPayload>(wire.payload) {
-                Ok(v) => v,
-                Err(_) => continue,
+        let seed_rows = topology_seed
+            .iter()
+            .map(|entry| TopologySeedRow {
+                mac: entry.mac.clone(),
+                agent_id: entry.agent_id.clone(),
+                switch_name: entry.switch.clone(),
+                port: entry.port.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.persist(move |db| db.replace_topology_seed(&seed_rows));
+        let mut rule_rows = Vec::new();
+        for rule in &alias_rules {
+            rule_rows.push((
+                rule.id.clone(),
+                serde_json::to_string(rule)?,
+                rule.created_at,
+            ));
+        }
+        self.persist(move |db| db.replace_alias_rules(&rule_rows));
+        let mut override_rows = Vec::new();
+        for (agent_id, facts) in &doc.network_overrides {
+            override_rows.push((agent_id.clone(), serde_json::to_string(facts)?));
+        }
+        let ts = self.now();
+        self.persist(move |db| db.replace_network_overrides(&override_rows, ts));
+        for device in &devices {
+            self.persist_device(device);
+        }
+        for task in &tasks {
+            self.persist_task(task);
+        }
+        for event in &activity {
+            let row = ActivityRow {
+                id: event.id.clone(),
+                kind: event.kind.clone(),
+                agent_id: event.agent_id.clone(),
+                message: event.message.clone(),
+                ts: event.ts,
+                count: event.count,
             };
+            self.persist(move |db| db.save_activity(&row));
+        }
 
-            let secret_ok = {
-                let guard = state.manager.inner.lock().await;
-                payload.secret == guard.pair_token
-            };
+        let summary = RuntimeStateImport {
+            devices: devices.len(),
+            live_kept,
+            tasks: tasks.len(),
+            activity: activity.len(),
+        };
+        self.emit_log(
+            app,
+            None,
+            "WARN",
+            format!(
+                "Runtime state imported from export at {}: {} devices, {} tasks, {} activity events ({} live agents kept)",
+                doc.exported_at,
+                summary.devices,
+                summary.tasks,
+                summary.activity,
+                summary.live_kept.len()
+            ),
+        )
+        .await;
+        self.emit_devices_snapshot(app).await;
+        app.emit(EVENT_TASKS_SNAPSHOT, self.get_tasks_snapshot().await);
+        app.emit(EVENT_ACTIVITY_SNAPSHOT, self.get_activity_snapshot().await);
+        self.rebuild_topology_if_changed(app).await;
+        Ok(summary)
+    }
 
-            tracing::info!(
-                "[WS] message type=register agent_id={} ok={}",
-                payload.agent_id,
-                secret_ok
-            );
+    pub async fn get_tls_fingerprint(&self) -> Result<String> {
+        self.inner
+            .lock()
+            .await
+            .tls_fingerprint
+            .clone()
+            .ok_or_else(|| ServerError::Unavailable("TLS certificate not loaded".to_string()))
+    }
 
-            if !secret_ok {
-                let _ = tx.send(Message::Text(
-                    json!({
-                        "type": "registered",
-                        "ts": now_ms(),
-                        "agent_id": payload.agent_id,
-                        "payload": {"ok": false, "error": "invalid shared secret", "server_time": now_ms()}
-                    })
-                    .to_string()
-                    .into(),
-                ));
-                break;
+    /// Swaps a fresh certificate into the running listener; agents that pinned
+    /// the old one must be re-provisioned.
+    pub async fn regenerate_tls_certificate(&self, app: &dyn EventSink) -> Result<String> {
+        let config = self.inner.lock().await.tls_config.clone().ok_or_else(|| {
+            ServerError::Unavailable("WS listener is not serving TLS".to_string())
+        })?;
+        let fingerprint = tls::regenerate(&self.cert_paths).map_err(ServerError::Tls)?;
+        config
+            .reload_from_pem_file(&self.cert_paths.cert, &self.cert_paths.key)
+            .await
+            .map_err(|e| ServerError::Tls(format!("reload certificate: {}", e)))?;
+        self.set_tls_fingerprint(app, fingerprint.clone()).await;
+        Ok(fingerprint)
+    }
+
+    /// Loads the certificate the WS listener is about to serve. Without one the
+    /// listener falls back to plain WS and no fingerprint is advertised.
+    async fn load_tls_config(&self, app: &dyn EventSink) -> Option<RustlsConfig> {
+        let loaded = match tls::load_or_generate(&self.cert_paths) {
+            Ok(fingerprint) => {
+                RustlsConfig::from_pem_file(&self.cert_paths.cert, &self.cert_paths.key)
+                    .await
+                    .map(|config| (config, fingerprint))
+                    .map_err(|e| e.to_string())
+            }
+            Err(err) => Err(err),
+        };
+        match loaded {
+            Ok((config, fingerprint)) => {
+                self.inner.lock().await.tls_config = Some(config.clone());
+                self.set_tls_fingerprint(app, fingerprint).await;
+                Some(config)
+            }
+            Err(err) => {
+                {
+                    let mut state = self.inner.lock().await;
+                    state.tls_config = None;
+                    state.tls_fingerprint = None;
+                }
+                self.emit_log(
+                    app,
+                    None,
+                    "WARN",
+                    format!("TLS certificate unavailable, serving plain WS: {}", err),
+                )
+                .await;
+                None
             }
+        }
+    }
 
-            let now = now_ms();
-            let (device, was_new, old_status, adopted_old_agent) = {
-                let mut guard = state.manager.inner.lock().await;
-                let fingerprint = clean_non_empty_owned(&payload.fingerprint);
+    async fn set_tls_fingerprint(&self, app: &dyn EventSink, fingerprint: String) {
+        let previous = self
+            .inner
+            .lock()
+            .await
+            .tls_fingerprint
+            .replace(fingerprint.clone());
+        if previous.as_deref() != Some(fingerprint.as_str()) {
+            self.emit_log(
+                app,
+                None,
+                "INFO",
+                format!("TLS certificate fingerprint (sha256): {}", fingerprint),
+            )
+            .await;
+        }
+    }
 
-                let adopt_key = fingerprint.as_ref().and_then(|fp| {
-                    guard.fingerprint_index.get(fp).cloned().or_else(|| {
-                        guard
-                            .devices
-                            .iter()
-                            .find(|(_, d)| d.fingerprint.as_deref() == Some(fp.as_str()))
-                            .map(|(key, _)| key.clone())
-                    })
-                });
+    pub async fn get_webhook_stats(&self) -> WebhookStats {
+        self.inner.lock().await.webhook_stats.clone()
+    }
 
-                let mut adopted_old_agent: Option<String> = None;
-                if let Some(old_key) = adopt_key {
-                    if old_key != payload.agent_id {
-                        if let Some(mut adopted) = guard.devices.remove(&old_key) {
-                            adopted_old_agent = Some(adopted.agent_id.clone());
-                            adopted.agent_id = payload.agent_id.clone();
-                            guard.devices.insert(payload.agent_id.clone(), adopted);
-                            guard.connections.remove(&old_key);
-                            guard.last_device_emit_ms.remove(&old_key);
-                            if let Some(slot) =
-                                guard.device_order.iter_mut().find(|id| **id == old_key)
-                            {
-                                *slot = payload.agent_id.clone();
-                            }
-                        }
-                    }
-                }
+    pub async fn get_discovery_stats(&self) -> DiscoveryStats {
+        self.inner.lock().await.discovery.clone()
+    }
 
-                guard
-                    .connections
-                    .insert(payload.agent_id.clone(), tx.clone());
-                let was_new = !guard.devices.contains_key(&payload.agent_id);
-                if was_new {
-                    guard.device_order.push(payload.agent_id.clone());
+    pub async fn get_topology_snapshot(&self) -> TopologySnapshot {
+        let state = self.inner.lock().await;
+        state.topology_snapshot.clone()
+    }
+
+    pub async fn set_wire_trace(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        enabled: bool,
+    ) -> Result<WireTraceStatus> {
+        let now = self.now();
+        let status = {
+            let mut state = self.inner.lock().await;
+            state.wire_traces.retain(|_, expires_at| *expires_at > now);
+            if !enabled {
+                state.wire_traces.remove(&agent_id);
+                self.wire_traced
+                    .store(state.wire_traces.len(), AtomicOrdering::Relaxed);
+                WireTraceStatus {
+                    agent_id: agent_id.clone(),
+                    enabled: false,
+                    expires_at: None,
+                    traced_agents: state.wire_traces.keys().cloned().collect(),
+                }
+            } else {
+                if !state.devices.contains_key(&agent_id) {
+                    return Err(ServerError::NotFound(format!("unknown agent {}", agent_id)));
+                }
+                let limits = state.config.wire_trace.clone();
+                if !state.wire_traces.contains_key(&agent_id)
+                    && state.wire_traces.len() >= limits.max_agents
+                {
+                    return Err(ServerError::Conflict(format!(
+                        "at most {} agents can be traced at once",
+                        limits.max_agents
+                    )));
+                }
+                let expires_at = now + limits.duration_ms;
+                state.wire_traces.insert(agent_id.clone(), expires_at);
+                self.wire_traced
+                    .store(state.wire_traces.len(), AtomicOrdering::Relaxed);
+                WireTraceStatus {
+                    agent_id: agent_id.clone(),
+                    enabled: true,
+                    expires_at: Some(expires_at),
+                    traced_agents: state.wire_traces.keys().cloned().collect(),
                 }
+            }
+        };
 
-                let fp_for_index = fingerprint.clone();
-                let device = {
-                    let entry = guard
-                        .devices
-                        .entry(payload.agent_id.clone())
-                        .or_insert(DeviceRecord {
-                            device_key: fingerprint
-                                .as_ref()
-                                .map(|fp| format!("fp:{}", fp))
-                                .unwrap_or_else(|| format!("agent:{}", payload.agent_id)),
-                            agent_id: payload.agent_id.clone(),
-                            fingerprint: fingerprint.clone(),
-                            hostname: payload.hostname.clone(),
-                            ips: payload.ips.clone(),
-                            os: payload.os.clone(),
-                            version: payload.version.clone(),
-                            status: "online".to_string(),
-                            last_seen_ms: now,
-                            internet_reachable: None,
-                            dns_ok: None,
-                            gateway_reachable: None,
-                            latency_ms: None,
-                            last_internet_change_ms: None,
-                            last_dns_change_ms: None,
-                            first_seen_ms: now,
-                            ip: None,
-                            subnet_cidr: None,
-                            default_gateway_ip: None,
-                            interface_type: None,
-                            mac: None,
-                            gateway_mac: None,
-                            dhcp_server_ip: None,
-                            ssid: None,
-                            arp_snapshot: Vec::new(),
-                        });
-
-                    if entry.device_key.is_empty() {
-                        entry.device_key = fingerprint
-                            .as_ref()
-                            .map(|fp| format!("fp:{}", fp))
-                            .unwrap_or_else(|| format!("agent:{}", payload.agent_id));
-                    }
-                    if let Some(fp) = fingerprint.clone() {
-                        entry.fingerprint = Some(fp);
-                    }
-
-                    let old_status = entry.status.clone();
-                    entry.hostname = payload.hostname;
-                    entry.ips = payload.ips;
-                    entry.os = payload.os;
-                    entry.version = payload.version;
-                    entry.status = "online".to_string();
-                    entry.last_seen_ms = now;
-                    apply_network_payload(entry, &payload.network);
-                    (entry.clone(), old_status)
-                };
+        let message = match status.expires_at {
+            Some(expires_at) => {
+                self.schedule_wire_trace_expiry(app, agent_id.clone(), expires_at);
+                format!("[WIRE] trace enabled until {}", expires_at)
+            }
+            None => "[WIRE] trace disabled".to_string(),
+        };
+        self.emit_log(app, Some(agent_id), "INFO", message).await;
+        Ok(status)
+    }
 
-                if let Some(fp) = fp_for_index {
-                    guard.fingerprint_index.insert(fp, payload.agent_id.clone());
+    fn schedule_wire_trace_expiry(&self, app: &dyn EventSink, agent_id: String, expires_at: i64) {
+        let manager = self.clone();
+        let app = app.clone_sink();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(
+                (expires_at - manager.now()).max(0) as u64
+            ))
+            .await;
+            let expired = {
+                let mut state = manager.inner.lock().await;
+                // A later enable extends the trace; only the matching timer ends it.
+                if state.wire_traces.get(&agent_id) == Some(&expires_at) {
+                    state.wire_traces.remove(&agent_id);
+                    manager
+                        .wire_traced
+                        .store(state.wire_traces.len(), AtomicOrdering::Relaxed);
+                    true
+                } else {
+                    false
                 }
-                (device.0, was_new, device.1, adopted_old_agent)
             };
+            if expired {
+                manager
+                    .emit_log(
+                        &*app,
+                        Some(agent_id),
+                        "INFO",
+                        "[WIRE] trace expired".to_string(),
+                    )
+                    .await;
+            }
+        });
+    }
 
-            registered_agent_id = Some(device.agent_id.clone());
+    async fn trace_wire(&self, app: &dyn EventSink, agent_id: &str, direction: &str, raw: &str) {
+        if self.wire_traced.load(AtomicOrdering::Relaxed) == 0 {
+            return;
+        }
+        let traced = {
+            let state = self.inner.lock().await;
+            state
+                .wire_traces
+                .get(agent_id)
+                .is_some_and(|expires_at| *expires_at > self.now())
+        };
+        if !traced {
+            return;
+        }
+        let mut frame = serde_json::from_str::<Value>(raw).ok();
+        let message_type = frame
+            .as_ref()
+            .and_then(|v| v.get("type").and_then(Value::as_str).map(sanitize::field))
+            .unwrap_or_else(|| "?".to_string());
+        // Any register frame, not just the first, carries the pair or enrollment token.
+        let shown = match frame.as_mut() {
+            Some(frame) if message_type == "register" => {
+                if let Some(secret) = frame.pointer_mut("/payload/secret") {
+                    *secret = json!("[redacted]");
+                }
+                frame.to_string()
+            }
+            _ => raw.to_string(),
+        };
+        let header = format!(
+            "[WIRE] {} type={} bytes={} ",
+            direction,
+            message_type,
+            raw.len()
+        );
+        // Cut here rather than leave it to `sanitize::message`, so the ellipsis survives.
+        let room = sanitize::MAX_MESSAGE_CHARS.saturating_sub(header.chars().count() + 1);
+        let body = if shown.chars().count() > room {
+            format!("{}…", shown.chars().take(room).collect::<String>())
+        } else {
+            shown
+        };
+        self.emit_log(
+            app,
+            Some(agent_id.to_string()),
+            "INFO",
+            format!("{}{}", header, body),
+        )
+        .await;
+    }
 
-            let _ = tx.send(Message::Text(
-                json!({
-                    "type": "registered",
-                    "ts": now_ms(),
-                    "agent_id": device.agent_id,
-                    "payload": {"ok": true, "server_time": now_ms(), "canonical_id": device.device_key}
-                })
-                .to_string()
-                .into(),
-            ));
+    pub async fn get_topology_checkpoints(
+        &self,
+        since_ms: i64,
+    ) -> Result<Vec<TopologyCheckpointInfo>> {
+        let db = self.db.lock().await;
+        db.get_topology_checkpoints(since_ms, MAX_TOPOLOGY_CHECKPOINTS)
+            .map_err(ServerError::db("checkpoint query"))
+    }
 
-            state
-                .manager
-                .emit_device_upsert_if_needed(&state.app, device.clone(), true)
-                .await;
+    pub async fn get_topology_quality(&self) -> TopologyQuality {
+        let state = self.inner.lock().await;
+        topology_quality(&state.topology_snapshot, &state.devices)
+    }
 
-            if let Some(old_agent) = adopted_old_agent {
-                state
-                    .manager
-                    .emit_device_remove(&state.app, old_agent)
-                    .await;
-            }
+    /// The cached graph plus ARP-observed hosts that run no agent. Never cached
+    /// or emitted, so the default graph stays free of them.
+    pub async fn get_topology_with_unmanaged(&self) -> TopologySnapshot {
+        let state = self.inner.lock().await;
+        with_unmanaged_hosts(
+            state.topology_snapshot.clone(),
+            &state.devices,
+            &state.admin_network,
+            &state.config.confidence,
+        )
+    }
 
-            if was_new {
-                state
-                    .manager
-                    .emit_activity(
-                        &state.app,
-                        "device_connected",
-                        Some(device.agent_id.clone()),
-                        format!("{} connected", device.hostname),
-                    )
-                    .await;
-            }
-            if old_status != "online" {
-                state
-                    .manager
-                    .emit_activity(
-                        &state.app,
-                        "device_status_changed",
-                        Some(device.agent_id.clone()),
-                        format!("{} status {} -> online", device.hostname, old_status),
-                    )
-                    .await;
-            }
-            state.manager.rebuild_topology_if_changed(&state.app).await;
-            continue;
+    pub async fn get_tasks_snapshot(&self) -> TasksSnapshot {
+        let mut tasks = {
+            let state = self.inner.lock().await;
+            state.tasks.values().cloned().collect::<Vec<_>>()
+        };
+        tasks.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        TasksSnapshot { tasks }
+    }
+
+    pub async fn get_activity_snapshot(&self) -> ActivitySnapshot {
+        let events = {
+            let state = self.inner.lock().await;
+            state.activity.iter().cloned().collect::<Vec<_>>()
+        };
+        ActivitySnapshot { events }
+    }
+
+    /// Re-emits every snapshot at once, ignoring per-device throttles, so a
+    /// reconnected frontend can resync without waiting out the windows.
+    pub async fn force_full_refresh(&self, app: &dyn EventSink) {
+        {
+            let mut state = self.inner.lock().await;
+            state.last_device_emit_ms.clear();
+            state.pending_upserts.clear();
+            state.emitted_devices.clear();
         }
+        self.commit_topology(app, true).await;
+        self.emit_devices_snapshot(app).await;
+        self.emit_topology_snapshot(app).await;
+        app.emit(EVENT_TASKS_SNAPSHOT, self.get_tasks_snapshot().await);
+        app.emit(EVENT_ACTIVITY_SNAPSHOT, self.get_activity_snapshot().await);
+    }
 
-        let agent_id = match &registered_agent_id {
-            Some(id) => id.clone(),
-            None => continue,
+    fn claim_socket_slot(&self, max: usize) -> Option<SocketSlot> {
+        self.open_sockets
+            .fetch_update(AtomicOrdering::Relaxed, AtomicOrdering::Relaxed, |open| {
+                (max == 0 || open < max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| SocketSlot(self.open_sockets.clone()))
+    }
+
+    async fn alert_connection_cap(&self, app: &dyn EventSink, remote: SocketAddr, max: usize) {
+        tracing::warn!(
+            "[WS] rejected upgrade remote={} reason=connection limit {}",
+            remote,
+            max
+        );
+        let rejected = {
+            let mut state = self.inner.lock().await;
+            state.capacity_rejections += 1;
+            let now = self.now();
+            if now - state.last_capacity_alert_ms < CONNECTION_CAP_ALERT_INTERVAL_MS {
+                return;
+            }
+            state.last_capacity_alert_ms = now;
+            std::mem::take(&mut state.capacity_rejections)
         };
+        self.emit_log(
+            app,
+            None,
+            "WARN",
+            format!(
+                "[WS] rejected upgrade remote={} reason=connection limit reached (max {}); {} rejected since last alert",
+                remote, max, rejected
+            ),
+        )
+        .await;
+    }
 
-        match wire.message_type.as_str() {
-            "heartbeat" => {
-                if let Ok(payload) = serde_json::from_value::<HeartbeatPayload>(wire.payload) {
-                    tracing::debug!("[WS] heartbeat agent_id={}", agent_id);
-                    let now = now_ms();
-                    let (device_opt, status_changed, internet_changed, dns_changed) = {
-                        let mut guard = state.manager.inner.lock().await;
-                        if let Some(device) = guard.devices.get_mut(&agent_id) {
-                            let old_status = device.status.clone();
-                            let old_internet = device.internet_reachable;
-                            let old_dns = device.dns_ok;
+    pub async fn get_pair_token(&self) -> String {
+        self.inner.lock().await.pair_token.clone()
+    }
 
-                            device.last_seen_ms = if payload.last_seen > 0 {
-                                payload.last_seen
-                            } else {
-                                now
-                            };
-                            if !payload.status.is_empty() {
-                                device.status = payload.status;
-                            }
-                            if let Some(metrics) = payload.metrics {
-                                if let Some(v) = metrics.get("internet_reachable") {
-                                    device.internet_reachable = v.as_bool();
-                                }
-                                if let Some(v) = metrics.get("dns_ok") {
-                                    device.dns_ok = v.as_bool();
-                                }
-                                if let Some(v) = metrics.get("gateway_reachable") {
-                                    device.gateway_reachable = v.as_bool();
-                                }
-                                if let Some(v) = metrics.get("latency_ms") {
-                                    device.latency_ms = v.as_i64();
-                                }
-                            }
+    pub async fn rotate_pair_token(&self, app: &dyn EventSink) -> Result<String> {
+        let token = self.replace_pair_token(0).await;
+        self.emit_server_status(app).await;
+        self.emit_log(app, None, "INFO", "Pair token rotated".to_string())
+            .await;
+        Ok(token)
+    }
 
-                            apply_network_payload(device, &payload.network);
+    pub async fn mint_enrollment_token(
+        &self,
+        app: &dyn EventSink,
+        ttl_secs: u64,
+    ) -> Result<EnrollmentToken> {
+        if ttl_secs == 0 || ttl_secs > MAX_ENROLLMENT_TOKEN_TTL_SECS {
+            return Err(ServerError::InvalidInput(format!(
+                "ttl_secs must be between 1 and {}",
+                MAX_ENROLLMENT_TOKEN_TTL_SECS
+            )));
+        }
+        let created_at = self.now();
+        let token = EnrollmentToken {
+            token: Uuid::new_v4().to_string(),
+            created_at,
+            expires_at: created_at + ttl_secs as i64 * 1000,
+        };
+        self.db
+            .lock()
+            .await
+            .insert_token(&token.token, token.created_at, token.expires_at)
+            .map_err(ServerError::db("token insert"))?;
+        self.emit_log(
+            app,
+            None,
+            "INFO",
+            format!("Enrollment token minted, valid for {}s", ttl_secs),
+        )
+        .await;
+        Ok(token)
+    }
 
-                            let status_changed = if old_status != device.status {
-                                Some((old_status, device.status.clone()))
-                            } else {
-                                None
-                            };
+    async fn enrollment_token_expired(&self, token: &str) -> bool {
+        let now = self.now();
+        !token.is_empty()
+            && self
+                .db
+                .lock()
+                .await
+                .token_expired(token, now)
+                .unwrap_or(false)
+    }
 
-                            let internet_changed = if old_internet != device.internet_reachable {
-                                device.last_internet_change_ms = Some(now);
-                                Some((old_internet, device.internet_reachable))
-                            } else {
-                                None
-                            };
+    async fn enrollment_token_valid(&self, token: &str) -> bool {
+        if token.is_empty() {
+            return false;
+        }
+        let now = self.now();
+        match self.db.lock().await.token_valid(token, now) {
+            Ok(valid) => valid,
+            Err(err) => {
+                tracing::warn!("[DB] enrollment token lookup failed: {}", err);
+                false
+            }
+        }
+    }
 
-                            let dns_changed = if old_dns != device.dns_ok {
-                                device.last_dns_change_ms = Some(now);
-                                Some((old_dns, device.dns_ok))
-                            } else {
-                                None
-                            };
+    async fn consume_enrollment_token(&self, token: &str) -> bool {
+        if token.is_empty() {
+            return false;
+        }
+        let now = self.now();
+        match self.db.lock().await.consume_token(token, now) {
+            Ok(valid) => valid,
+            Err(err) => {
+                tracing::warn!("[DB] enrollment token lookup failed: {}", err);
+                false
+            }
+        }
+    }
 
-                            (
-                                Some(device.clone()),
-                                status_changed,
-                                internet_changed,
-                                dns_changed,
-                            )
-                        } else {
-                            (None, None, None, None)
-                        }
-                    };
+    /// Mints a new token; the old one keeps working for `grace_ms` (0 = revoked now).
+    async fn replace_pair_token(&self, grace_ms: i64) -> String {
+        let mut state = self.inner.lock().await;
+        let token = Uuid::new_v4().to_string();
+        let previous = std::mem::replace(&mut state.pair_token, token.clone());
+        state.previous_pair_token = (grace_ms > 0).then(|| (previous, self.now() + grace_ms));
+        self.beacon_refresh.notify_one();
+        token
+    }
 
-                    if let Some(device) = device_opt {
-                        state
-                            .manager
-                            .emit_device_upsert_if_needed(&state.app, device.clone(), false)
-                            .await;
+    async fn pair_token_rotation_loop(&self, app: Arc<dyn EventSink>) {
+        loop {
+            let interval_ms = self.inner.lock().await.config.pair_token_rotation_ms;
+            if interval_ms == 0 {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            sleep(Duration::from_millis(interval_ms)).await;
+            let grace_ms = {
+                let state = self.inner.lock().await;
+                if state.config.pair_token_rotation_ms == 0 {
+                    continue;
+                }
+                state.config.pair_token_grace_ms
+            };
+            self.replace_pair_token(grace_ms).await;
+            self.emit_server_status(&*app).await;
+            self.emit_activity(
+                &*app,
+                "pair_token_rotated",
+                None,
+                format!(
+                    "Pair token rotated on schedule; the previous token is accepted for {}s",
+                    grace_ms / 1000
+                ),
+            )
+            .await;
+        }
+    }
 
-                        if let Some((old, new)) = status_changed {
-                            state
-                                .manager
-                                .emit_activity(
-                                    &state.app,
-                                    "device_status_changed",
-                                    Some(device.agent_id.clone()),
-                                    format!("{} status {} -> {}", device.hostname, old, new),
-                                )
-                                .await;
-                        }
-                        if let Some((old, new)) = internet_changed {
-                            state
-                                .manager
-                                .emit_log(
-                                    &state.app,
-                                    Some(device.agent_id.clone()),
-                                    "INFO",
-                                    format!("internet_reachable changed {:?} -> {:?}", old, new),
-                                )
-                                .await;
-                            state
-                                .manager
-                                .emit_activity(
-                                    &state.app,
-                                    "internet_status_changed",
-                                    Some(device.agent_id.clone()),
-                                    format!("{} internet {:?} -> {:?}", device.hostname, old, new),
-                                )
-                                .await;
-                        }
-                        if let Some((old, new)) = dns_changed {
-                            state
-                                .manager
-                                .emit_log(
-                                    &state.app,
-                                    Some(device.agent_id.clone()),
-                                    "INFO",
-                                    format!("dns_ok changed {:?} -> {:?}", old, new),
-                                )
-                                .await;
-                            state
-                                .manager
-                                .emit_activity(
-                                    &state.app,
-                                    "dns_status_changed",
-                                    Some(device.agent_id.clone()),
-                                    format!("{} dns {:?} -> {:?}", device.hostname, old, new),
-                                )
-                                .await;
+    pub async fn get_config(&self) -> ServerConfig {
+        self.inner.lock().await.config.clone()
+    }
+
+    /// Startup settings with the live runtime config in `server`.
+    pub async fn get_effective_config(&self) -> LabScanConfig {
+        let server = self.get_config().await;
+        LabScanConfig {
+            server,
+            ..(*self.settings).clone()
+        }
+    }
+
+    /// Re-reads the config file and applies its `server` section. Startup-only
+    /// settings that changed are listed in `restart_required` instead.
+    pub async fn reload_config(&self, app: &dyn EventSink) -> Result<ConfigReload> {
+        let (file, path) = config::load().map_err(ServerError::Config)?;
+        let restart_required = file.restart_required(&self.settings);
+        // Ports only change through `set_config` while the server is stopped.
+        let live = self.get_config().await;
+        let server = self
+            .set_config(
+                app,
+                ServerConfig {
+                    port_ws: live.port_ws,
+                    port_udp: live.port_udp,
+                    ..file.server.clone()
+                },
+            )
+            .await?;
+        if !restart_required.is_empty() {
+            self.emit_log(
+                app,
+                None,
+                "WARN",
+                format!(
+                    "Config reloaded; restart to apply: {}",
+                    restart_required.join(", ")
+                ),
+            )
+            .await;
+        }
+        Ok(ConfigReload {
+            path: path.map(|p| p.display().to_string()),
+            config: LabScanConfig {
+                server,
+                ..(*self.settings).clone()
+            },
+            restart_required,
+        })
+    }
+
+    pub async fn set_config(
+        &self,
+        app: &dyn EventSink,
+        config: ServerConfig,
+    ) -> Result<ServerConfig> {
+        config.validate().map_err(ServerError::Config)?;
+        let config = ServerConfig {
+            allow_mock_devices: self.settings.server.allow_mock_devices,
+            ..config
+        };
+        {
+            let mut state = self.inner.lock().await;
+            let ports_changed =
+                (config.port_ws, config.port_udp) != (state.config.port_ws, state.config.port_udp);
+            if ports_changed && state.listeners.is_some() {
+                return Err(ServerError::Conflict(
+                    "stop the server before changing port_ws or port_udp".to_string(),
+                ));
+            }
+            state.config = config.clone();
+        }
+        self.emit_log(app, None, "INFO", "Server config updated".to_string())
+            .await;
+        self.rebuild_topology_if_changed(app).await;
+        Ok(config)
+    }
+
+    pub async fn dispatch_task(
+        &self,
+        app: &dyn EventSink,
+        agents: Vec<String>,
+        kind: String,
+        params: Value,
+        options: TaskOptions,
+    ) -> Result<TaskRecord> {
+        if agents.is_empty() {
+            return Err(ServerError::InvalidInput(
+                "at least one agent is required".to_string(),
+            ));
+        }
+        if !matches!(
+            kind.as_str(),
+            "ping" | "port_scan" | "arp_snapshot" | "route_table" | "traceroute" | "dns_lookup"
+        ) {
+            return Err(ServerError::InvalidInput(
+                "unsupported task kind".to_string(),
+            ));
+        }
+        validate_task_params(&kind, &params)?;
+        let timeout_ms = options.timeout_ms.unwrap_or(DEFAULT_TASK_TIMEOUT_MS);
+        if !(1..=MAX_TASK_TIMEOUT_MS).contains(&timeout_ms) {
+            return Err(ServerError::InvalidInput(format!(
+                "timeout_ms must be between 1 and {}",
+                MAX_TASK_TIMEOUT_MS
+            )));
+        }
+
+        let task = TaskRecord {
+            task_id: Uuid::new_v4().to_string(),
+            kind,
+            params,
+            assigned_agents: agents,
+            status: "queued".to_string(),
+            created_at: self.now(),
+            started_at: None,
+            ended_at: None,
+            results: Vec::new(),
+            label: options.label.and_then(|v| clean_non_empty_owned(&v)),
+            metadata: options.metadata.unwrap_or(Value::Null),
+            origin_task_id: None,
+            rerun_depth: 0,
+            delivered_to: Vec::new(),
+            acked_by: Vec::new(),
+            pending_agents: Vec::new(),
+            timeout_ms,
+        };
+        self.launch_task(app, task).await
+    }
+
+    /// Has every selected agent ping every other one and collects the results
+    /// into a source x destination matrix, waiting up to `MESH_PING_TIMEOUT_MS`.
+    pub async fn dispatch_mesh_ping(
+        &self,
+        app: &dyn EventSink,
+        agents: Vec<String>,
+    ) -> Result<MeshPingMatrix> {
+        let mut agents: Vec<String> = agents
+            .iter()
+            .filter_map(|id| clean_non_empty_owned(id))
+            .collect();
+        agents.sort();
+        agents.dedup();
+        if agents.len() < 2 {
+            return Err(ServerError::InvalidInput(
+                "mesh ping needs at least two agents".to_string(),
+            ));
+        }
+        if agents.len() > MAX_MESH_AGENTS {
+            return Err(ServerError::InvalidInput(format!(
+                "mesh ping supports at most {} agents",
+                MAX_MESH_AGENTS
+            )));
+        }
+
+        let (ips, online): (HashMap<String, String>, HashSet<String>) = {
+            let state = self.inner.lock().await;
+            let mut ips = HashMap::new();
+            let mut missing = Vec::new();
+            for id in &agents {
+                match state
+                    .devices
+                    .get(id)
+                    .and_then(|d| d.ip.clone().or_else(|| d.ips.first().cloned()))
+                {
+                    Some(ip) => {
+                        ips.insert(id.clone(), ip);
+                    }
+                    None => missing.push(id.clone()),
+                }
+            }
+            if !missing.is_empty() {
+                return Err(ServerError::NotFound(format!(
+                    "no known IP for: {}",
+                    missing.join(", ")
+                )));
+            }
+            let online = agents
+                .iter()
+                .filter(|id| state.connections.contains_key(*id))
+                .cloned()
+                .collect();
+            (ips, online)
+        };
+
+        let mesh_id = Uuid::new_v4().to_string();
+        let mut completions = self.subscribe_task_completions();
+        // One multi-target ping per source. Offline sources would only sit in
+        // the queue until the deadline, so their row fails straight away.
+        let mut source_tasks: HashMap<String, String> = HashMap::new();
+        for source in agents.iter().filter(|id| online.contains(*id)) {
+            let targets: Vec<&String> = agents
+                .iter()
+                .filter(|d| *d != source)
+                .map(|d| &ips[d])
+                .collect();
+            let task = self
+                .dispatch_task(
+                    app,
+                    vec![source.clone()],
+                    "ping".to_string(),
+                    json!({ "targets": targets }),
+                    TaskOptions {
+                        label: Some(format!("mesh ping from {}", source)),
+                        metadata: Some(json!({ "mesh_id": mesh_id, "source": source })),
+                        timeout_ms: Some(MESH_PING_TIMEOUT_MS as i64),
+                    },
+                )
+                .await?;
+            source_tasks.insert(source.clone(), task.task_id);
+        }
+
+        let mut pending: HashSet<String> = source_tasks.values().cloned().collect();
+        let mut finished: HashMap<String, TaskRecord> = HashMap::new();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(MESH_PING_TIMEOUT_MS);
+        while !pending.is_empty() {
+            match tokio::time::timeout_at(deadline, completions.recv()).await {
+                Ok(Ok(task)) => {
+                    if pending.remove(&task.task_id) {
+                        finished.insert(task.task_id.clone(), task);
+                    }
+                }
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                    let state = self.inner.lock().await;
+                    for id in pending.iter() {
+                        if let Some(task) = state.tasks.get(id).filter(|t| t.ended_at.is_some()) {
+                            finished.insert(id.clone(), task.clone());
                         }
-                        state.manager.rebuild_topology_if_changed(&state.app).await;
                     }
+                    pending.retain(|id| !finished.contains_key(id));
                 }
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
             }
-            "task_result" => {
-                if let Ok(payload) = serde_json::from_value::<TaskResultPayload>(wire.payload) {
-                    let maybe_task = {
-                        let mut guard = state.manager.inner.lock().await;
-                        if let Some(task) = guard.tasks.get_mut(&payload.task_id) {
-                            task.results.retain(|r| r.agent_id != agent_id);
-                            task.results.push(TaskResultRecord {
-                                agent_id: agent_id.clone(),
-                                ok: payload.ok,
-                                result: payload.result,
-                                error: payload.error,
-                                ts: now_ms(),
+        }
+
+        let mut cells: Vec<MeshPingCell> = Vec::new();
+        for source in &agents {
+            let task_id = source_tasks.get(source);
+            let result = task_id
+                .and_then(|id| finished.get(id))
+                .and_then(|t| t.results.iter().find(|r| r.agent_id == *source));
+            for destination in agents.iter().filter(|d| *d != source) {
+                let mut cell = MeshPingCell {
+                    source: source.clone(),
+                    destination: destination.clone(),
+                    destination_ip: ips[destination].clone(),
+                    task_id: task_id.cloned(),
+                    reachable: None,
+                    latency_ms: None,
+                    error: None,
+                };
+                match (task_id, result) {
+                    (None, _) => cell.error = Some("agent offline".to_string()),
+                    (Some(_), None) => cell.error = Some("no result before timeout".to_string()),
+                    (Some(_), Some(result)) if !result.ok => {
+                        cell.reachable = Some(false);
+                        cell.error = result.error.clone();
+                    }
+                    (Some(_), Some(result)) => {
+                        let entry = result
+                            .result
+                            .get("results")
+                            .and_then(Value::as_array)
+                            .and_then(|rows| {
+                                rows.iter().find(|row| {
+                                    row.get("target").and_then(Value::as_str)
+                                        == Some(cell.destination_ip.as_str())
+                                })
                             });
-                            if task.results.len() == task.assigned_agents.len() {
-                                task.ended_at = Some(now_ms());
-                                task.status = if task.results.iter().all(|r| r.ok) {
-                                    "done".to_string()
-                                } else {
-                                    "failed".to_string()
-                                };
+                        match entry {
+                            Some(entry) => {
+                                cell.reachable =
+                                    Some(entry.get("ok").and_then(Value::as_bool).unwrap_or(false));
+                                cell.latency_ms = entry.get("latency_ms").and_then(Value::as_i64);
                             }
-                            Some(task.clone())
-                        } else {
-                            None
+                            None => cell.error = Some("no result for this target".to_string()),
                         }
-                    };
+                    }
+                }
+                cells.push(cell);
+            }
+        }
 
-                    if let Some(task) = maybe_task {
-                        state
-                            .manager
-                            .emit_task_update(&state.app, task.clone())
-                            .await;
-                        let kind = if task.status == "failed" {
-                            "task_failed"
-                        } else if task.status == "done" {
-                            "task_completed"
-                        } else {
-                            "task_started"
-                        };
-                        state
-                            .manager
-                            .emit_activity(
-                                &state.app,
-                                kind,
-                                Some(agent_id),
-                                format!("Task {} status {}", task.task_id, task.status),
-                            )
-                            .await;
+        let reach: HashMap<(&str, &str), Option<bool>> = cells
+            .iter()
+            .map(|c| ((c.source.as_str(), c.destination.as_str()), c.reachable))
+            .collect();
+        let asymmetric = cells
+            .iter()
+            .filter(|c| c.source < c.destination)
+            .filter(|c| {
+                let back = reach
+                    .get(&(c.destination.as_str(), c.source.as_str()))
+                    .copied()
+                    .flatten();
+                matches!((c.reachable, back), (Some(a), Some(b)) if a != b)
+            })
+            .map(|c| (c.source.clone(), c.destination.clone()))
+            .collect();
+
+        Ok(MeshPingMatrix {
+            mesh_id,
+            agents,
+            complete: pending.is_empty(),
+            cells,
+            asymmetric,
+        })
+    }
+
+    /// Tells agents that haven't answered yet to stop, and ends the task as `cancelled`.
+    pub async fn cancel_task(&self, app: &dyn EventSink, task_id: String) -> Result<TaskRecord> {
+        let now = self.now();
+        let (task, notified) = {
+            let mut guard = self.inner.lock().await;
+            let runtime = &mut *guard;
+            let task = runtime
+                .tasks
+                .get_mut(&task_id)
+                .ok_or_else(|| ServerError::NotFound(format!("task {} not found", task_id)))?;
+            if task.ended_at.is_some() {
+                return Err(ServerError::Conflict(format!(
+                    "task {} is already {}",
+                    task_id, task.status
+                )));
+            }
+            refresh_pending_agents(task);
+            let mut notified = 0;
+            for agent in &task.pending_agents {
+                let Some(sender) = runtime.connections.get(agent) else {
+                    continue;
+                };
+                if let Some(msg) =
+                    wire_frame("task_cancel", agent, json!({ "task_id": task_id }), now)
+                {
+                    if sender.send(msg).is_ok() {
+                        notified += 1;
+                    }
+                }
+            }
+            task.status = "cancelled".to_string();
+            task.ended_at = Some(now);
+            (task.clone(), notified)
+        };
+
+        self.emit_task_update(app, task.clone()).await;
+        self.emit_activity(
+            app,
+            "task_cancelled",
+            None,
+            format!(
+                "Task cancelled: {} ({}), {} agent(s) notified",
+                task.kind, task.task_id, notified
+            ),
+        )
+        .await;
+        let _ = self.task_completions.send(task.clone());
+        Ok(task)
+    }
+
+    /// Dispatches a task and waits for it to finish, up to `timeout_ms`.
+    pub async fn await_task(
+        &self,
+        app: &dyn EventSink,
+        agents: Vec<String>,
+        kind: String,
+        params: Value,
+        timeout_ms: u64,
+    ) -> Result<AwaitedTask> {
+        if timeout_ms == 0 || timeout_ms > MAX_AWAIT_TASK_MS {
+            return Err(ServerError::InvalidInput(format!(
+                "timeout_ms must be between 1 and {}",
+                MAX_AWAIT_TASK_MS
+            )));
+        }
+        // Subscribe first so a fast result can't slip past between dispatch and wait.
+        let mut completions = self.subscribe_task_completions();
+        let task = self
+            .dispatch_task(
+                app,
+                agents,
+                kind,
+                params,
+                TaskOptions {
+                    timeout_ms: Some(timeout_ms as i64),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        if task.ended_at.is_some() {
+            return Ok(AwaitedTask {
+                task,
+                timed_out: false,
+            });
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(timeout_ms);
+        loop {
+            match tokio::time::timeout_at(deadline, completions.recv()).await {
+                Ok(Ok(done)) if done.task_id == task.task_id => {
+                    return Ok(AwaitedTask {
+                        task: done,
+                        timed_out: false,
+                    });
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                    let state = self.inner.lock().await;
+                    if let Some(done) = state
+                        .tasks
+                        .get(&task.task_id)
+                        .filter(|t| t.ended_at.is_some())
+                    {
+                        return Ok(AwaitedTask {
+                            task: done.clone(),
+                            timed_out: false,
+                        });
+                    }
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+            }
+        }
+
+        let current = self.inner.lock().await.tasks.get(&task.task_id).cloned();
+        Ok(AwaitedTask {
+            task: current.unwrap_or(task),
+            timed_out: true,
+        })
+    }
+
+    pub async fn retry_failed(&self, app: &dyn EventSink, task_id: String) -> Result<TaskRecord> {
+        let original = self
+            .find_task(&task_id)
+            .await?
+            .ok_or_else(|| ServerError::NotFound(format!("task {} not found", task_id)))?;
+        if original.ended_at.is_none() {
+            return Err(ServerError::Conflict(format!(
+                "task {} is still {}; retry it once it has finished",
+                task_id, original.status
+            )));
+        }
+
+        // Agents that never responded count as failed.
+        let failed: Vec<String> = original
+            .assigned_agents
+            .iter()
+            .filter(|id| !original.results.iter().any(|r| &r.agent_id == *id && r.ok))
+            .cloned()
+            .collect();
+        if failed.is_empty() {
+            return Err(ServerError::Conflict(format!(
+                "all {} agent(s) succeeded in task {}; nothing to retry",
+                original.assigned_agents.len(),
+                task_id
+            )));
+        }
+
+        let task = TaskRecord {
+            task_id: Uuid::new_v4().to_string(),
+            kind: original.kind,
+            params: original.params,
+            assigned_agents: failed,
+            status: "queued".to_string(),
+            created_at: self.now(),
+            started_at: None,
+            ended_at: None,
+            results: Vec::new(),
+            label: original.label,
+            metadata: original.metadata,
+            origin_task_id: Some(original.task_id),
+            rerun_depth: original.rerun_depth + 1,
+            delivered_to: Vec::new(),
+            acked_by: Vec::new(),
+            pending_agents: Vec::new(),
+            timeout_ms: original.timeout_ms,
+        };
+        self.launch_task(app, task).await
+    }
+
+    async fn find_task(&self, task_id: &str) -> Result<Option<TaskRecord>> {
+        if let Some(task) = self.inner.lock().await.tasks.get(task_id).cloned() {
+            return Ok(Some(task));
+        }
+        let stored = {
+            let db = self.db.lock().await;
+            db.search_tasks(&task_id.to_lowercase(), MAX_TASK_SEARCH_RESULTS)
+                .map_err(ServerError::db("task lookup"))?
+        };
+        Ok(stored
+            .iter()
+            .filter_map(|raw| serde_json::from_str::<TaskRecord>(raw).ok())
+            .find(|task| task.task_id == task_id))
+    }
+
+    async fn launch_task(&self, app: &dyn EventSink, mut task: TaskRecord) -> Result<TaskRecord> {
+        refresh_pending_agents(&mut task);
+        {
+            let state = self.inner.lock().await;
+            if let Some(pending) = task.assigned_agents.iter().find(|id| {
+                state
+                    .devices
+                    .get(*id)
+                    .is_some_and(|device| device.pending_approval)
+            }) {
+                return Err(ServerError::NotPermitted(format!(
+                    "agent {} is pending approval",
+                    pending
+                )));
+            }
+        }
+
+        {
+            self.inner
+                .lock()
+                .await
+                .tasks
+                .insert(task.task_id.clone(), task.clone());
+        }
+
+        let updated = self.dispatch_task_now(task).await;
+        self.emit_task_update(app, updated.clone()).await;
+        let message = match &updated.origin_task_id {
+            Some(origin) => format!(
+                "Task started: {} ({}), retrying {} agent(s) from {}",
+                updated.kind,
+                updated.task_id,
+                updated.assigned_agents.len(),
+                origin
+            ),
+            None => format!("Task started: {} ({})", updated.kind, updated.task_id),
+        };
+        self.emit_activity(app, "task_started", None, message).await;
+        Ok(updated)
+    }
+
+    pub async fn search_tasks(&self, query: String) -> Result<Vec<TaskRecord>> {
+        let needle = query.trim().to_lowercase();
+        let mut tasks: Vec<TaskRecord> = {
+            let state = self.inner.lock().await;
+            state
+                .tasks
+                .values()
+                .filter(|t| task_matches(t, &needle))
+                .cloned()
+                .collect()
+        };
+
+        let stored = {
+            let db = self.db.lock().await;
+            db.search_tasks(&needle, MAX_TASK_SEARCH_RESULTS)
+                .map_err(ServerError::db("task search"))?
+        };
+        for raw in stored {
+            if let Ok(task) = serde_json::from_str::<TaskRecord>(&raw) {
+                if !tasks.iter().any(|t| t.task_id == task.task_id) {
+                    tasks.push(task);
+                }
+            }
+        }
+
+        tasks.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+        tasks.truncate(MAX_TASK_SEARCH_RESULTS);
+        Ok(tasks)
+    }
+
+    pub async fn notify_update(
+        &self,
+        app: &dyn EventSink,
+        min_version: String,
+        download_url: String,
+    ) -> Result<usize> {
+        let min_version = min_version.trim().to_string();
+        let download_url = download_url.trim().to_string();
+        if min_version.is_empty() {
+            return Err(ServerError::InvalidInput(
+                "min_version is required".to_string(),
+            ));
+        }
+        if download_url.is_empty() {
+            return Err(ServerError::InvalidInput(
+                "download_url is required".to_string(),
+            ));
+        }
+
+        let payload = UpdateAvailablePayload {
+            min_version: min_version.clone(),
+            download_url,
+        };
+        let payload = serde_json::to_value(&payload).unwrap_or_else(|_| json!({}));
+        let now = self.now();
+        let notified = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let mut notified = Vec::new();
+            for (agent_id, sender) in &state.connections {
+                if let Some(device) = state.devices.get_mut(agent_id) {
+                    if compare_versions(&device.version, &min_version) != Ordering::Less {
+                        continue;
+                    }
+                    if let Some(msg) =
+                        wire_frame("update_available", agent_id, payload.clone(), now)
+                    {
+                        if sender.send(msg).is_ok() {
+                            device.update_notified_version = Some(min_version.clone());
+                            device.update_notified_ms = Some(now);
+                            notified.push(device.clone());
+                        }
                     }
                 }
             }
-            _ => {}
-        }
+            notified
+        };
+
+        for device in &notified {
+            self.emit_device_upsert_if_needed(app, device.clone(), true)
+                .await;
+        }
+        self.emit_log(
+            app,
+            None,
+            "INFO",
+            format!(
+                "Update {} notified to {} agent(s)",
+                min_version,
+                notified.len()
+            ),
+        )
+        .await;
+        Ok(notified.len())
+    }
+
+    pub async fn approve_agent(&self, app: &dyn EventSink, agent_id: String) -> Result<()> {
+        let device = {
+            let mut state = self.inner.lock().await;
+            if !state.devices.contains_key(&agent_id) {
+                return Err(ServerError::NotFound("agent not found".to_string()));
+            }
+            state
+                .approvals
+                .insert(agent_id.clone(), "approved".to_string());
+            state.devices.get_mut(&agent_id).map(|device| {
+                device.pending_approval = false;
+                device.clone()
+            })
+        };
+        let (db_id, ts) = (agent_id.clone(), self.now());
+        self.persist(move |db| db.set_agent_approval(&db_id, "approved", ts));
+
+        if let Some(device) = device {
+            self.emit_device_upsert_if_needed(app, device.clone(), true)
+                .await;
+            self.emit_activity(
+                app,
+                "agent_approved",
+                Some(agent_id.clone()),
+                format!("{} approved", device.hostname),
+            )
+            .await;
+        }
+        self.emit_log(app, Some(agent_id), "INFO", "Agent approved".to_string())
+            .await;
+        Ok(())
+    }
+
+    /// Forgets a decommissioned machine. Connected agents must disconnect first.
+    pub async fn remove_device(&self, app: &dyn EventSink, agent_id: String) -> Result<()> {
+        let removed = {
+            let mut state = self.inner.lock().await;
+            if state.connections.contains_key(&agent_id) {
+                return Err(ServerError::Conflict(format!(
+                    "agent {} is connected; disconnect it before removing",
+                    agent_id
+                )));
+            }
+            let removed = state
+                .devices
+                .remove(&agent_id)
+                .ok_or_else(|| ServerError::NotFound(format!("agent {} not found", agent_id)))?;
+            state.device_order.retain(|id| *id != agent_id);
+            state.last_device_emit_ms.remove(&agent_id);
+            state.last_activity_emit_ms.remove(&agent_id);
+            state.pending_upserts.remove(&agent_id);
+            state.emitted_devices.remove(&agent_id);
+            state.followed_agents.remove(&agent_id);
+            state.connection_signals.remove(&agent_id);
+            state.outage_levels.remove(&agent_id);
+            if let Some(fp) = removed.fingerprint.as_ref() {
+                state.fingerprint_index.remove(fp);
+            }
+            removed
+        };
+
+        let db_id = agent_id.clone();
+        self.persist(move |db| db.delete_device(&db_id));
+        self.emit_device_remove(app, agent_id.clone(), REMOVE_REASON_MANUAL)
+            .await;
+        self.emit_log(
+            app,
+            Some(agent_id),
+            "INFO",
+            format!("Removed device {}", removed.hostname),
+        )
+        .await;
+        self.rebuild_topology_if_changed(app).await;
+        Ok(())
+    }
+
+    pub async fn reject_agent(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        block: bool,
+    ) -> Result<()> {
+        let removed = {
+            let mut state = self.inner.lock().await;
+            state.approvals.remove(&agent_id);
+            if block {
+                state.blocked_agents.insert(agent_id.clone());
+            }
+            if let Some(sender) = state.connections.remove(&agent_id) {
+                let _ = sender.send(Message::Close(None));
+            }
+            state.last_device_emit_ms.remove(&agent_id);
+            state.last_activity_emit_ms.remove(&agent_id);
+            state.pending_upserts.remove(&agent_id);
+            state.emitted_devices.remove(&agent_id);
+            state.followed_agents.remove(&agent_id);
+            state.device_order.retain(|id| *id != agent_id);
+            let removed = state.devices.remove(&agent_id);
+            if let Some(fp) = removed.as_ref().and_then(|d| d.fingerprint.as_ref()) {
+                state.fingerprint_index.remove(fp);
+            }
+            removed
+        };
+
+        let (db_id, ts) = (agent_id.clone(), self.now());
+        self.persist(move |db| {
+            if block {
+                db.add_block(BLOCK_KIND_AGENT, &db_id, ts)?;
+            }
+            db.delete_agent_approval(&db_id)?;
+            db.delete_device(&db_id)
+        });
+
+        if removed.is_some() {
+            let reason = if block {
+                REMOVE_REASON_BLOCKED
+            } else {
+                REMOVE_REASON_MANUAL
+            };
+            self.emit_device_remove(app, agent_id.clone(), reason).await;
+            self.rebuild_topology_if_changed(app).await;
+        }
+        self.emit_log(
+            app,
+            Some(agent_id),
+            "WARN",
+            if block {
+                "Agent rejected and blocked".to_string()
+            } else {
+                "Agent rejected".to_string()
+            },
+        )
+        .await;
+        Ok(())
+    }
+
+    pub async fn get_blocklist(&self) -> Blocklist {
+        let state = self.inner.lock().await;
+        let mut agents: Vec<String> = state.blocked_agents.iter().cloned().collect();
+        agents.sort();
+        Blocklist {
+            agents,
+            sources: state.blocked_sources.clone(),
+        }
+    }
+
+    pub async fn block_agent(&self, app: &dyn EventSink, agent_id: String) -> Result<Blocklist> {
+        let agent_id = agent_id.trim().to_string();
+        if agent_id.is_empty() {
+            return Err(ServerError::InvalidInput(
+                "agent_id is required".to_string(),
+            ));
+        }
+        let disconnected = {
+            let mut state = self.inner.lock().await;
+            state.blocked_agents.insert(agent_id.clone());
+            disconnect_agent(&mut state, &agent_id)
+        };
+        let (db_id, ts) = (agent_id.clone(), self.now());
+        self.persist(move |db| db.add_block(BLOCK_KIND_AGENT, &db_id, ts));
+
+        self.emit_log(
+            app,
+            Some(agent_id),
+            "WARN",
+            if disconnected {
+                "Agent blocked and disconnected".to_string()
+            } else {
+                "Agent blocked".to_string()
+            },
+        )
+        .await;
+        Ok(self.get_blocklist().await)
+    }
+
+    pub async fn unblock_agent(&self, app: &dyn EventSink, agent_id: String) -> Result<Blocklist> {
+        let removed = self.inner.lock().await.blocked_agents.remove(&agent_id);
+        if !removed {
+            return Err(ServerError::NotFound("agent is not blocked".to_string()));
+        }
+        let db_id = agent_id.clone();
+        self.persist(move |db| db.remove_block(BLOCK_KIND_AGENT, &db_id));
+        self.emit_log(app, Some(agent_id), "INFO", "Agent unblocked".to_string())
+            .await;
+        Ok(self.get_blocklist().await)
+    }
+
+    pub async fn get_agent_allowlist(&self) -> AgentAllowlist {
+        let state = self.inner.lock().await;
+        let mut agents: Vec<String> = state.allowed_agents.iter().cloned().collect();
+        agents.sort();
+        AgentAllowlist {
+            enabled: state.allowlist_enabled,
+            agents,
+        }
+    }
+
+    /// Turning the allowlist on disconnects every connected agent that is not listed.
+    pub async fn set_agent_allowlist_enabled(
+        &self,
+        app: &dyn EventSink,
+        enabled: bool,
+    ) -> Result<AgentAllowlist> {
+        let disconnected = {
+            let mut state = self.inner.lock().await;
+            if state.allowlist_enabled == enabled {
+                drop(state);
+                return Ok(self.get_agent_allowlist().await);
+            }
+            state.allowlist_enabled = enabled;
+            let mut unlisted: Vec<String> = if enabled {
+                state
+                    .connections
+                    .keys()
+                    .filter(|id| !state.allowed_agents.contains(*id))
+                    .cloned()
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            unlisted.retain(|agent_id| disconnect_agent(&mut state, agent_id));
+            unlisted.sort();
+            unlisted
+        };
+        let value = if enabled { "1" } else { "0" };
+        self.persist(move |db| db.set_setting(SETTING_AGENT_ALLOWLIST_ENABLED, value));
+
+        let message = if !enabled {
+            "Agent allowlist disabled; any agent may register".to_string()
+        } else if disconnected.is_empty() {
+            "Agent allowlist enabled; only listed agents may register".to_string()
+        } else {
+            format!(
+                "Agent allowlist enabled; disconnected unlisted agents: {}",
+                disconnected.join(", ")
+            )
+        };
+        self.emit_log(app, None, "WARN", message).await;
+        Ok(self.get_agent_allowlist().await)
+    }
+
+    pub async fn allow_agent(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+    ) -> Result<AgentAllowlist> {
+        let agent_id = agent_id.trim().to_string();
+        if agent_id.is_empty() {
+            return Err(ServerError::InvalidInput(
+                "agent_id is required".to_string(),
+            ));
+        }
+        let inserted = self
+            .inner
+            .lock()
+            .await
+            .allowed_agents
+            .insert(agent_id.clone());
+        if inserted {
+            let (db_id, ts) = (agent_id.clone(), self.now());
+            self.persist(move |db| db.add_allowed_agent(&db_id, ts));
+            self.emit_log(
+                app,
+                Some(agent_id),
+                "INFO",
+                "Agent added to allowlist".to_string(),
+            )
+            .await;
+        }
+        Ok(self.get_agent_allowlist().await)
+    }
+
+    pub async fn deny_agent(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+    ) -> Result<AgentAllowlist> {
+        let agent_id = agent_id.trim().to_string();
+        let (removed, disconnected) = {
+            let mut state = self.inner.lock().await;
+            let removed = state.allowed_agents.remove(&agent_id);
+            let disconnected =
+                removed && state.allowlist_enabled && disconnect_agent(&mut state, &agent_id);
+            (removed, disconnected)
+        };
+        if !removed {
+            return Err(ServerError::NotFound(
+                "agent is not on the allowlist".to_string(),
+            ));
+        }
+        let db_id = agent_id.clone();
+        self.persist(move |db| db.remove_allowed_agent(&db_id));
+
+        let message = if disconnected {
+            "Agent removed from allowlist and disconnected".to_string()
+        } else {
+            "Agent removed from allowlist".to_string()
+        };
+        self.emit_log(app, Some(agent_id), "WARN", message).await;
+        Ok(self.get_agent_allowlist().await)
+    }
+
+    pub async fn block_source(&self, app: &dyn EventSink, cidr: String) -> Result<Blocklist> {
+        let (_, prefix) = netutil::parse_cidr(&cidr)
+            .ok_or_else(|| ServerError::InvalidInput(format!("invalid CIDR: {}", cidr)))?;
+        let network = netutil::network_address(&cidr)
+            .ok_or_else(|| ServerError::InvalidInput(format!("invalid CIDR: {}", cidr)))?;
+        let source = format!("{}/{}", network, prefix);
+
+        let closed = {
+            let mut guard = self.inner.lock().await;
+            if !guard.blocked_sources.contains(&source) {
+                guard.blocked_sources.push(source.clone());
+            }
+            let matching: Vec<String> = guard
+                .devices
+                .values()
+                .filter(|d| {
+                    d.remote_ip
+                        .as_deref()
+                        .is_some_and(|ip| netutil::contains(&source, ip))
+                })
+                .map(|d| d.agent_id.clone())
+                .collect();
+            for agent_id in matching {
+                disconnect_agent(&mut guard, &agent_id);
+            }
+            // Also drops sockets that haven't registered yet.
+            guard
+                .socket_sources
+                .values()
+                .filter(|(ip, _)| netutil::contains(&source, &ip.to_string()))
+                .map(|(_, close)| close.notify_one())
+                .count()
+        };
+        let (db_source, ts) = (source.clone(), self.now());
+        self.persist(move |db| db.add_block(BLOCK_KIND_SOURCE, &db_source, ts));
+
+        self.emit_log(
+            app,
+            None,
+            "WARN",
+            format!("Source {} blocked; closed {} connection(s)", source, closed),
+        )
+        .await;
+        Ok(self.get_blocklist().await)
+    }
+
+    pub async fn unblock_source(&self, app: &dyn EventSink, cidr: String) -> Result<Blocklist> {
+        let removed = {
+            let mut state = self.inner.lock().await;
+            let before = state.blocked_sources.len();
+            state.blocked_sources.retain(|s| s != cidr.trim());
+            before != state.blocked_sources.len()
+        };
+        if !removed {
+            return Err(ServerError::NotFound("source is not blocked".to_string()));
+        }
+        let db_source = cidr.trim().to_string();
+        self.persist(move |db| db.remove_block(BLOCK_KIND_SOURCE, &db_source));
+        self.emit_log(
+            app,
+            None,
+            "INFO",
+            format!("Source {} unblocked", cidr.trim()),
+        )
+        .await;
+        Ok(self.get_blocklist().await)
+    }
+
+    async fn track_socket(&self, remote: &SocketAddr, close: Arc<Notify>) -> u64 {
+        let mut state = self.inner.lock().await;
+        state.next_socket_id += 1;
+        let socket_id = state.next_socket_id;
+        state
+            .socket_sources
+            .insert(socket_id, (remote.ip().to_canonical(), close));
+        socket_id
+    }
+
+    async fn untrack_socket(&self, socket_id: u64) {
+        self.inner.lock().await.socket_sources.remove(&socket_id);
+    }
+
+    async fn is_source_blocked(&self, remote: &SocketAddr) -> Option<String> {
+        let ip = remote.ip().to_canonical().to_string();
+        let state = self.inner.lock().await;
+        state
+            .blocked_sources
+            .iter()
+            .find(|cidr| netutil::contains(cidr, &ip))
+            .cloned()
+    }
+
+    /// Remaining ban for a source that tripped the invalid-secret threshold.
+    async fn secret_ban_remaining(&self, remote: &SocketAddr) -> Option<i64> {
+        let ip = remote.ip().to_canonical();
+        let state = self.inner.lock().await;
+        let remaining = state.secret_failures.get(&ip)?.banned_until_ms - self.now();
+        (remaining > 0).then_some(remaining)
+    }
+
+    async fn record_secret_failure(&self, app: &dyn EventSink, remote: &SocketAddr) {
+        let ip = remote.ip().to_canonical();
+        let now = self.now();
+        let tripped = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let policy = &state.config.secret_backoff;
+            let window_ms = policy.window_ms.max(0);
+            let clean_ms = policy.max_ban_ms.max(0);
+            // Forget sources that have been quiet for a full clean period.
+            state.secret_failures.retain(|_, f| {
+                f.recent.retain(|t| now - t <= window_ms);
+                now - f.last_failure_ms.max(f.banned_until_ms) <= clean_ms
+            });
+            let failures = state.secret_failures.entry(ip).or_default();
+            failures.recent.push_back(now);
+            failures.last_failure_ms = now;
+            let count = failures.recent.len() as u32;
+            match policy.ban_ms(count, failures.bans) {
+                Some(ban) if failures.banned_until_ms <= now => {
+                    failures.bans = failures.bans.saturating_add(1);
+                    failures.banned_until_ms = now + ban;
+                    // The next ban needs a fresh run of failures after this one ends.
+                    failures.recent.clear();
+                    Some((count, ban))
+                }
+                _ => None,
+            }
+        };
+
+        if let Some((count, ban)) = tripped {
+            self.emit_log(
+                app,
+                None,
+                "WARN",
+                format!(
+                    "[WS] source {} sent {} invalid shared secrets; refusing upgrades for {}s",
+                    ip,
+                    count,
+                    ban / 1000
+                ),
+            )
+            .await;
+            self.emit_activity(
+                app,
+                "auth_rate_limited",
+                None,
+                format!(
+                    "{} was refused for {}s after {} failed registrations",
+                    ip,
+                    (ban + 999) / 1000,
+                    count
+                ),
+            )
+            .await;
+        }
+    }
+
+    async fn clear_secret_failures(&self, app: &dyn EventSink, remote: &SocketAddr) {
+        let ip = remote.ip().to_canonical();
+        let cleared = {
+            let mut state = self.inner.lock().await;
+            state.secret_failures.remove(&ip)
+        };
+        if let Some(failures) = cleared {
+            self.emit_log(
+                app,
+                None,
+                "INFO",
+                format!(
+                    "[WS] source {} presented a valid secret after {} ban(s) and {} recent failures",
+                    ip,
+                    failures.bans,
+                    failures.recent.len()
+                ),
+            )
+            .await;
+        }
+    }
+
+    pub async fn follow_agent(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        enabled: bool,
+    ) -> Result<Vec<String>> {
+        let followed = {
+            let mut state = self.inner.lock().await;
+            if enabled {
+                if !state.devices.contains_key(&agent_id) {
+                    return Err(ServerError::NotFound("agent not found".to_string()));
+                }
+                if !state.followed_agents.contains(&agent_id)
+                    && state.followed_agents.len() >= MAX_FOLLOWED_AGENTS
+                {
+                    return Err(ServerError::Conflict(format!(
+                        "at most {} agents can be followed at once",
+                        MAX_FOLLOWED_AGENTS
+                    )));
+                }
+                state.followed_agents.insert(agent_id.clone());
+            } else {
+                state.followed_agents.remove(&agent_id);
+            }
+            let mut followed: Vec<String> = state.followed_agents.iter().cloned().collect();
+            followed.sort();
+            followed
+        };
+        self.emit_log(
+            app,
+            Some(agent_id),
+            "INFO",
+            if enabled {
+                "Follow mode enabled".to_string()
+            } else {
+                "Follow mode disabled".to_string()
+            },
+        )
+        .await;
+        Ok(followed)
+    }
+
+    pub async fn send_agent_control(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        action: String,
+    ) -> Result<String> {
+        let action = action.trim().to_lowercase();
+        if !matches!(action.as_str(), "reconnect" | "restart" | "refresh") {
+            return Err(ServerError::InvalidInput(format!(
+                "unsupported control action: {}",
+                action
+            )));
+        }
+
+        let request_id = Uuid::new_v4().to_string();
+        let now = self.now();
+        let frame = wire_frame(
+            "control",
+            &agent_id,
+            json!({ "action": action, "request_id": request_id }),
+            now,
+        )
+        .ok_or_else(|| ServerError::Internal("control frame could not be encoded".to_string()))?;
+        let device = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let sender = state
+                .connections
+                .get(&agent_id)
+                .ok_or_else(|| ServerError::Unavailable("agent is not connected".to_string()))?;
+            sender
+                .send(frame)
+                .map_err(|_| ServerError::Unavailable("agent connection is closing".to_string()))?;
+            state.devices.get_mut(&agent_id).map(|device| {
+                device.last_control_action = Some(action.clone());
+                device.last_control_sent_ms = Some(now);
+                device.last_control_ack_ms = None;
+                device.last_control_ok = None;
+                device.clone()
+            })
+        };
+
+        if let Some(device) = device {
+            self.emit_device_upsert_if_needed(app, device, true).await;
+        }
+        self.emit_log(
+            app,
+            Some(agent_id),
+            "INFO",
+            format!("Control action '{}' sent ({})", action, request_id),
+        )
+        .await;
+        Ok(request_id)
+    }
+
+    pub async fn inject_mock_devices(&self, app: &dyn EventSink, count: usize) -> Result<usize> {
+        self.ensure_mock_devices_allowed().await?;
+        if count == 0 || count > MAX_MOCK_DEVICES {
+            return Err(ServerError::InvalidInput(format!(
+                "count must be between 1 and {}",
+                MAX_MOCK_DEVICES
+            )));
+        }
+
+        let subnets = [
+            ("10.10.1", "wired"),
+            ("10.10.2", "wired"),
+            ("192.168.50", "wifi"),
+        ];
+        let statuses = ["online", "online", "online", "degraded", "offline"];
+        let now = self.now();
+        let devices = {
+            let mut state = self.inner.lock().await;
+            let mut next = state.mock_agents.len();
+            let mut devices = Vec::new();
+            for _ in 0..count {
+                // Never reuse an id that a real agent (or an earlier mock) holds.
+                let (i, agent_id) = loop {
+                    let i = next;
+                    next += 1;
+                    let agent_id = format!("mock-{:04}", i + 1);
+                    if !state.devices.contains_key(&agent_id)
+                        && !state.connections.contains_key(&agent_id)
+                    {
+                        break (i, agent_id);
+                    }
+                };
+                let (prefix, interface) = subnets[i % subnets.len()];
+                let host = 10 + (i / subnets.len()) % 240;
+                let status = statuses[i % statuses.len()];
+                let device = DeviceRecord {
+                    device_key: format!("agent:{}", agent_id),
+                    agent_id: agent_id.clone(),
+                    hostname: format!("mock-lab-{:03}", i + 1),
+                    ips: vec![format!("{}.{}", prefix, host)],
+                    os: if i % 3 == 0 { "linux" } else { "windows" }.to_string(),
+                    version: "0.0.0-mock".to_string(),
+                    status: status.to_string(),
+                    last_seen_ms: now,
+                    internet_reachable: Some(status != "offline" && i % 7 != 0),
+                    dns_ok: Some(status != "offline" && i % 11 != 0),
+                    gateway_reachable: Some(status != "offline"),
+                    latency_ms: Some(5 + ((i * 37) % 180) as i64),
+                    first_seen_ms: now,
+                    ip: Some(format!("{}.{}", prefix, host)),
+                    subnet_cidr: Some(format!("{}.0/24", prefix)),
+                    default_gateway_ip: Some(format!("{}.1", prefix)),
+                    interface_type: Some(interface.to_string()),
+                    is_mock: true,
+                    ..Default::default()
+                };
+                state.mock_agents.insert(agent_id.clone());
+                state.device_order.push(agent_id.clone());
+                state.devices.insert(agent_id, device.clone());
+                devices.push(device);
+            }
+            devices
+        };
+
+        for device in &devices {
+            self.emit_device_upsert_if_needed(app, device.clone(), true)
+                .await;
+        }
+        self.emit_log(
+            app,
+            None,
+            "INFO",
+            format!("Injected {} mock device(s)", devices.len()),
+        )
+        .await;
+        self.rebuild_topology_if_changed(app).await;
+        Ok(devices.len())
+    }
+
+    pub async fn clear_mock_devices(&self, app: &dyn EventSink) -> Result<usize> {
+        self.ensure_mock_devices_allowed().await?;
+        let removed = {
+            let mut state = self.inner.lock().await;
+            let ids: Vec<String> = state.mock_agents.drain().collect();
+            for id in &ids {
+                if state.devices.get(id).is_some_and(|d| !d.is_mock) {
+                    continue;
+                }
+                state.devices.remove(id);
+                state.last_device_emit_ms.remove(id);
+                state.last_activity_emit_ms.remove(id);
+                state.pending_upserts.remove(id);
+                state.emitted_devices.remove(id);
+            }
+            state.device_order.retain(|id| !ids.contains(id));
+            ids
+        };
+
+        for agent_id in &removed {
+            // Rows written before mocks were kept out of the database.
+            let db_id = agent_id.clone();
+            self.persist(move |db| db.delete_device(&db_id));
+            self.emit_device_remove(app, agent_id.clone(), REMOVE_REASON_MANUAL)
+                .await;
+        }
+        self.emit_log(
+            app,
+            None,
+            "INFO",
+            format!("Cleared {} mock device(s)", removed.len()),
+        )
+        .await;
+        self.rebuild_topology_if_changed(app).await;
+        Ok(removed.len())
+    }
+
+    pub async fn test_provisioning(&self, app: &dyn EventSink) -> Result<ProvisioningReport> {
+        let agent_id = format!("selftest-{}", Uuid::new_v4());
+        let mut checks = Vec::new();
+        let outcome = self.run_provisioning_probe(&agent_id, &mut checks).await;
+        self.forget_probe_agent(app, &agent_id).await;
+
+        let (level, message) = match &outcome {
+            Ok(()) => ("INFO", "Provisioning self-test passed".to_string()),
+            Err(err) => ("WARN", format!("Provisioning self-test failed: {}", err)),
+        };
+        self.emit_log(app, None, level, message).await;
+        Ok(ProvisioningReport {
+            ok: outcome.is_ok(),
+            agent_id,
+            checks,
+        })
+    }
+
+    async fn run_provisioning_probe(
+        &self,
+        agent_id: &str,
+        checks: &mut Vec<ProvisioningCheck>,
+    ) -> Result<()> {
+        let step_timeout = Duration::from_millis(self.settings.provision_probe_step_ms);
+        let (online, secret, ws_port, pinned) = {
+            let state = self.inner.lock().await;
+            (
+                state.online,
+                state.pair_token.clone(),
+                state.config.port_ws,
+                state.served_tls_fingerprint(),
+            )
+        };
+        let started = self.now();
+        record_check(
+            checks,
+            "server_online",
+            started,
+            self.now(),
+            if online {
+                Ok(format!("WS server listening on {}", ws_port))
+            } else {
+                Err("WS server is not online".to_string())
+            },
+        )?;
+
+        // Connects the way an agent does: pinned to the advertised fingerprint.
+        let (url, connector) = match pinned {
+            Some(fingerprint) => (
+                format!("wss://127.0.0.1:{}/ws/agent", ws_port),
+                Some(Connector::Rustls(tls::pinned_client_config(&fingerprint))),
+            ),
+            None => (format!("ws://127.0.0.1:{}/ws/agent", ws_port), None),
+        };
+        let started = self.now();
+        let connect =
+            tokio_tungstenite::connect_async_tls_with_config(url.as_str(), None, false, connector);
+        let mut socket = match timeout(step_timeout, connect).await {
+            Ok(Ok((socket, _))) => socket,
+            Ok(Err(err)) => {
+                return record_check(
+                    checks,
+                    "ws_connect",
+                    started,
+                    self.now(),
+                    Err(format!("connect failed: {}", err)),
+                )
+            }
+            Err(_) => {
+                return record_check(
+                    checks,
+                    "ws_connect",
+                    started,
+                    self.now(),
+                    Err("connect timed out".to_string()),
+                )
+            }
+        };
+        record_check(checks, "ws_connect", started, self.now(), Ok(url))?;
+
+        let started = self.now();
+        let register = json!({
+            "type": "register",
+            "ts": self.now(),
+            "agent_id": agent_id,
+            "payload": {
+                "agent_id": agent_id,
+                "secret": secret,
+                "hostname": "labscan-selftest",
+                "ips": ["127.0.0.1"],
+                "os": std::env::consts::OS,
+                "version": env!("CARGO_PKG_VERSION"),
+            }
+        });
+        if let Err(err) = socket.send(ClientMessage::Text(register.to_string())).await {
+            return record_check(
+                checks,
+                "register",
+                started,
+                self.now(),
+                Err(err.to_string()),
+            );
+        }
+        let reply = timeout(step_timeout, async {
+            while let Some(Ok(msg)) = socket.next().await {
+                if let ClientMessage::Text(text) = msg {
+                    if let Ok(wire) = serde_json::from_str::<WireMessage>(&text) {
+                        if wire.message_type == "registered" {
+                            return Some(wire.payload);
+                        }
+                    }
+                }
+            }
+            None
+        })
+        .await;
+        let result = match reply {
+            Ok(Some(payload)) if payload.get("ok").and_then(|v| v.as_bool()) == Some(true) => {
+                Ok("registered with current pair token".to_string())
+            }
+            Ok(Some(payload)) => Err(payload
+                .get("error")
+                .and_then(|v| v.as_str())
+                .unwrap_or("registration rejected")
+                .to_string()),
+            Ok(None) => Err("connection closed before registration reply".to_string()),
+            Err(_) => Err("no registration reply".to_string()),
+        };
+        record_check(checks, "register", started, self.now(), result)?;
+
+        let started = self.now();
+        let ts = self.now();
+        let payload = json!({
+            "status": "online",
+            "last_seen": ts,
+            "metrics": {"latency_ms": 0},
+        });
+        let heartbeat = json!({
+            "type": "heartbeat",
+            "ts": ts,
+            "agent_id": agent_id,
+            "sig": wire_signature(&secret, "heartbeat", ts, agent_id, &payload.to_string()),
+            "payload": payload,
+        });
+        if let Err(err) = socket
+            .send(ClientMessage::Text(heartbeat.to_string()))
+            .await
+        {
+            return record_check(
+                checks,
+                "heartbeat",
+                started,
+                self.now(),
+                Err(err.to_string()),
+            );
+        }
+        let applied = timeout(step_timeout, async {
+            loop {
+                let seen = self
+                    .inner
+                    .lock()
+                    .await
+                    .devices
+                    .get(agent_id)
+                    .is_some_and(|d| d.latency_ms.is_some());
+                if seen {
+                    break;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        record_check(
+            checks,
+            "heartbeat",
+            started,
+            self.now(),
+            applied
+                .map(|_| "heartbeat applied to device state".to_string())
+                .map_err(|_| "heartbeat was not applied".to_string()),
+        )?;
+
+        let started = self.now();
+        let _ = socket.close(None).await;
+        let released = timeout(step_timeout, async {
+            while self.inner.lock().await.connections.contains_key(agent_id) {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        record_check(
+            checks,
+            "disconnect",
+            started,
+            self.now(),
+            released
+                .map(|_| "connection released".to_string())
+                .map_err(|_| "server did not observe the disconnect".to_string()),
+        )
+    }
+
+    async fn forget_probe_agent(&self, app: &dyn EventSink, agent_id: &str) {
+        let removed = {
+            let mut state = self.inner.lock().await;
+            state.last_device_emit_ms.remove(agent_id);
+            state.last_activity_emit_ms.remove(agent_id);
+            state.pending_upserts.remove(agent_id);
+            state.emitted_devices.remove(agent_id);
+            state.device_order.retain(|id| id != agent_id);
+            state.devices.remove(agent_id).is_some()
+        };
+        if removed {
+            let db_id = agent_id.to_string();
+            self.persist(move |db| db.delete_device(&db_id));
+            self.emit_device_remove(app, agent_id.to_string(), REMOVE_REASON_MANUAL)
+                .await;
+            self.rebuild_topology_if_changed(app).await;
+        }
+    }
+
+    /// `allow_mock_devices` is read from the startup config only, so it can't be
+    /// switched on later through `set_config` or an import.
+    async fn ensure_mock_devices_allowed(&self) -> Result<()> {
+        if cfg!(debug_assertions) || self.settings.server.allow_mock_devices {
+            Ok(())
+        } else {
+            Err(ServerError::NotPermitted(
+                "mock devices are disabled in release builds".to_string(),
+            ))
+        }
+    }
+
+    fn persist<F>(&self, job: F)
+    where
+        F: FnOnce(&mut Database) -> rusqlite::Result<()> + Send + 'static,
+    {
+        if self.db_jobs.send(Box::new(job)).is_err() {
+            // Only happens if the writer thread panicked; say so rather than
+            // losing the write without a trace.
+            tracing::error!("[DB] writer thread has stopped; write dropped");
+        }
+    }
+
+    fn persist_device(&self, device: &DeviceRecord) {
+        if device.is_mock {
+            return;
+        }
+        let row = device_row(device);
+        self.persist(move |db| db.register_device(row));
+        self.persist_facts(device);
+    }
+
+    fn persist_facts(&self, device: &DeviceRecord) {
+        if device.is_mock {
+            return;
+        }
+        let Ok(facts) = serde_json::to_string(&device_facts(device)) else {
+            return;
+        };
+        let device_id = device.agent_id.clone();
+        let ts = device.last_seen_ms;
+        self.persist(move |db| db.record_device_facts(&device_id, ts, &facts));
+    }
+
+    fn persist_task(&self, task: &TaskRecord) {
+        let record = match serde_json::to_string(task) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let row = TaskRow {
+            id: task.task_id.clone(),
+            kind: task.kind.clone(),
+            label: task.label.clone(),
+            metadata: (!task.metadata.is_null()).then(|| task.metadata.to_string()),
+            status: task.status.clone(),
+            created_at: task.created_at,
+            record,
+        };
+        self.persist(move |db| db.save_task(row));
+    }
+
+    fn persist_heartbeat(&self, device: &DeviceRecord) {
+        if device.is_mock {
+            return;
+        }
+        let device_id = device.agent_id.clone();
+        let timestamp = device.last_seen_ms;
+        let heartbeat = Heartbeat {
+            id: Uuid::new_v4().to_string(),
+            device_id: device_id.clone(),
+            timestamp,
+            gateway_reachable: device.gateway_reachable.unwrap_or(false),
+            dns_resolves: device.dns_ok.unwrap_or(false),
+            https_latency_ms: device.latency_ms,
+            local_ports: device
+                .local_ports
+                .as_ref()
+                .and_then(|ports| serde_json::to_string(ports).ok()),
+        };
+        self.persist(move |db| {
+            db.record_heartbeat(heartbeat)?;
+            db.update_device_last_seen(&device_id, timestamp)
+        });
+        self.persist_facts(device);
+    }
+
+    async fn run_ws_server(&self, app: Arc<dyn EventSink>, shutdown: oneshot::Receiver<()>) {
+        let ws_port = self.inner.lock().await.config.port_ws;
+        let bind_addr = format!("{}:{}", self.settings.bind_address, ws_port);
+        tracing::info!("[WS] binding addr={}", bind_addr);
+        self.emit_log(
+            &*app,
+            None,
+            "INFO",
+            format!("Starting WS server on {}", bind_addr),
+        )
+        .await;
+
+        let bound = TcpListener::bind(&bind_addr)
+            .await
+            .map_err(|source| ServerError::Bind {
+                addr: bind_addr.clone(),
+                source,
+            });
+        let listener = match bound {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!("[WS] {}", err);
+                self.set_online(&*app, false).await;
+                self.emit_log(&*app, None, "ERROR", format!("ws: {}", err))
+                    .await;
+                return;
+            }
+        };
+
+        // Loaded before going online so the beacon never advertises a stale fingerprint.
+        let tls_config = match self.settings.tls_enabled {
+            true => self.load_tls_config(&*app).await,
+            false => None,
+        };
+        let scheme = if tls_config.is_some() { "wss" } else { "ws" };
+
+        self.set_online(&*app, true).await;
+        tracing::info!("[WS] listening addr={} scheme={}", bind_addr, scheme);
+        self.emit_log(
+            &*app,
+            None,
+            "INFO",
+            format!("WS server listening on {}://{}", scheme, bind_addr),
+        )
+        .await;
+
+        let router = Router::new()
+            .route("/ws/agent", get(ws_agent_handler))
+            .with_state(HttpState {
+                manager: self.clone(),
+                app: app.clone(),
+            });
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+
+        let served = match tls_config {
+            Some(config) => match listener.into_std() {
+                Ok(listener) => {
+                    let handle = axum_server::Handle::new();
+                    let stopper = handle.clone();
+                    tokio::spawn(async move {
+                        let _ = shutdown.await;
+                        stopper.graceful_shutdown(Some(Duration::from_secs(2)));
+                    });
+                    axum_server::from_tcp_rustls(listener, config)
+                        .handle(handle)
+                        .serve(service)
+                        .await
+                }
+                Err(err) => Err(err),
+            },
+            None => {
+                axum::serve(listener, service)
+                    .with_graceful_shutdown(async {
+                        let _ = shutdown.await;
+                    })
+                    .await
+            }
+        };
+        // Nothing is serving the certificate any more.
+        self.inner.lock().await.tls_config = None;
+        if let Err(err) = served {
+            self.set_online(&*app, false).await;
+            self.emit_log(&*app, None, "ERROR", format!("WS server stopped: {}", err))
+                .await;
+        }
+    }
+
+    /// Multicasts the beacon with the current pair token and keeps the mDNS
+    /// record on the live WS port while the server is online.
+    async fn multicast_beacon_loop(&self, app: Arc<dyn EventSink>) {
+        let group = match self.settings.multicast_group_addr() {
+            Ok(group) => group,
+            Err(err) => {
+                self.inner
+                    .lock()
+                    .await
+                    .discovery
+                    .multicast_beacon
+                    .last_error = Some(err.clone());
+                self.emit_log(&*app, None, "ERROR", format!("beacon: {}", err))
+                    .await;
+                return;
+            }
+        };
+        let port = self.settings.multicast_port;
+        let mdns = match MDNSService::new() {
+            Ok(mdns) => Some(mdns),
+            Err(err) => {
+                self.inner.lock().await.discovery.mdns.last_error = Some(err.to_string());
+                self.emit_log(&*app, None, "WARN", format!("mDNS unavailable: {}", err))
+                    .await;
+                None
+            }
+        };
+        let mut beacon: Option<DiscoveryService> = None;
+        let mut advertised: Option<(u16, String)> = None;
+        loop {
+            let (online, ws_port, join_token, fingerprint) = {
+                let state = self.inner.lock().await;
+                (
+                    state.online,
+                    state.config.port_ws,
+                    state.pair_token.clone(),
+                    state.served_tls_fingerprint().unwrap_or_default(),
+                )
+            };
+            let enabled = online && LoopFlags::enabled(&self.loops.multicast_beacon);
+            let Some(admin_ip) = detect_local_ipv4().filter(|_| enabled) else {
+                if let (Some(mdns), Some(_)) = (&mdns, advertised.take()) {
+                    let _ = mdns.withdraw(MDNS_INSTANCE_NAME);
+                }
+                {
+                    let mut state = self.inner.lock().await;
+                    state.discovery.multicast_beacon.running = false;
+                    state.discovery.mdns.running = false;
+                    if enabled {
+                        state.discovery.multicast_beacon.last_error =
+                            Some("no local IPv4 address".to_string());
+                    }
+                }
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            };
+
+            if beacon.is_none() {
+                match DiscoveryService::new(admin_ip, group, port).await {
+                    Ok(service) => {
+                        beacon = Some(service);
+                        self.emit_log(
+                            &*app,
+                            None,
+                            "INFO",
+                            format!(
+                                "beacon: multicasting to {}:{}, admin_ip={}",
+                                group, port, admin_ip
+                            ),
+                        )
+                        .await;
+                    }
+                    Err(err) => {
+                        let message = err.to_string();
+                        let first = {
+                            let mut state = self.inner.lock().await;
+                            let stats = &mut state.discovery.multicast_beacon;
+                            let first = stats.last_error.as_deref() != Some(message.as_str());
+                            stats.last_error = Some(message.clone());
+                            first
+                        };
+                        if first {
+                            self.emit_log(
+                                &*app,
+                                None,
+                                "ERROR",
+                                format!("beacon: cannot bind {}:{}: {}", group, port, message),
+                            )
+                            .await;
+                        }
+                    }
+                }
+            }
+            if let Some(service) = &beacon {
+                let sent = service
+                    .send_beacon(ws_port, &join_token, &fingerprint)
+                    .await;
+                let mut state = self.inner.lock().await;
+                let stats = &mut state.discovery.multicast_beacon;
+                stats.running = true;
+                match sent {
+                    Ok(()) => {
+                        stats.send_count += 1;
+                        stats.last_send_ms = Some(self.now());
+                        stats.last_error = None;
+                    }
+                    Err(err) => stats.last_error = Some(err.to_string()),
+                }
+            }
+            let current = (ws_port, fingerprint.clone());
+            if let Some(mdns) = mdns
+                .as_ref()
+                .filter(|_| advertised.as_ref() != Some(&current))
+            {
+                let registered =
+                    mdns.advertise(MDNS_INSTANCE_NAME, admin_ip, ws_port, &fingerprint);
+                let mut state = self.inner.lock().await;
+                let stats = &mut state.discovery.mdns;
+                match registered {
+                    Ok(()) => {
+                        advertised = Some(current);
+                        stats.running = true;
+                        stats.send_count += 1;
+                        stats.last_send_ms = Some(self.now());
+                        stats.last_error = None;
+                    }
+                    Err(err) => stats.last_error = Some(err.to_string()),
+                }
+            }
+
+            tokio::select! {
+                _ = sleep(BEACON_INTERVAL) => {}
+                _ = self.beacon_refresh.notified() => {}
+            }
+        }
+    }
+
+    async fn run_udp_provision_loop(&self, app: Arc<dyn EventSink>) {
+        let send_socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(s) => s,
+            Err(err) => {
+                self.inner.lock().await.discovery.udp_broadcast.last_error = Some(err.to_string());
+                self.emit_log(
+                    &*app,
+                    None,
+                    "ERROR",
+                    format!("UDP broadcast socket error: {}", err),
+                )
+                .await;
+                return;
+            }
+        };
+        if let Err(err) = send_socket.set_broadcast(true) {
+            self.inner.lock().await.discovery.udp_broadcast.last_error = Some(err.to_string());
+            self.emit_log(
+                &*app,
+                None,
+                "ERROR",
+                format!("UDP broadcast enable failed: {}", err),
+            )
+            .await;
+            return;
+        }
+
+        let (udp_port, ws_port) = {
+            let state = self.inner.lock().await;
+            (state.config.port_udp, state.config.port_ws)
+        };
+        let ack_socket = UdpSocket::bind(format!("{}:{}", self.settings.bind_address, udp_port))
+            .await
+            .ok();
+        let admin_ip = detect_local_ipv4()
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "127.0.0.1".to_string());
+        self.emit_log(
+            &*app,
+            None,
+            "INFO",
+            format!(
+                "provision: broadcasting on UDP {}, admin_ip={}",
+                udp_port, admin_ip
+            ),
+        )
+        .await;
+
+        self.inner.lock().await.discovery.udp_broadcast.running = true;
+        let mut ack_buffer = [0_u8; 2048];
+        let mut logged_targets = Vec::new();
+        let mut enumeration_warned = false;
+        let mut last_throttle_log_ms = 0_i64;
+        loop {
+            if !self.inner.lock().await.online || !LoopFlags::enabled(&self.loops.udp_provision) {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+
+            let (secret, tls_fingerprint) = {
+                let state = self.inner.lock().await;
+                (state.pair_token.clone(), state.served_tls_fingerprint())
+            };
+            let payload = ProvisionBroadcast {
+                message_type: "LABSCAN_PROVISION".to_string(),
+                v: 1,
+                admin_ip: admin_ip.clone(),
+                ws_port,
+                secret,
+                nonce: Uuid::new_v4().to_string(),
+                tls_fingerprint,
+            };
+            let (targets, enumeration_error) = broadcast_targets();
+            if let Some(err) = enumeration_error.filter(|_| !enumeration_warned) {
+                enumeration_warned = true;
+                self.emit_log(
+                    &*app,
+                    None,
+                    "WARN",
+                    format!(
+                        "provision: interface enumeration unavailable ({}), using global broadcast only",
+                        err
+                    ),
+                )
+                .await;
+            }
+            if targets != logged_targets {
+                self.emit_log(
+                    &*app,
+                    None,
+                    "INFO",
+                    format!(
+                        "provision: broadcast targets {}",
+                        targets
+                            .iter()
+                            .map(|ip| ip.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                )
+                .await;
+                logged_targets = targets.clone();
+            }
+            if let Ok(raw) = serde_json::to_vec(&payload) {
+                let cost = ((raw.len() + UDP_HEADER_BYTES) * targets.len()) as u64;
+                let throttled = {
+                    let mut guard = self.inner.lock().await;
+                    let state = &mut *guard;
+                    let limit = state.config.broadcast_budget_bytes_per_sec;
+                    let allowed = state.broadcast_budget.try_spend(cost, limit, self.now());
+                    if !allowed {
+                        state.discovery.udp_broadcast.throttled_count += 1;
+                    }
+                    (!allowed).then_some(limit)
+                };
+                if let Some(limit) = throttled {
+                    let now = self.now();
+                    if now - last_throttle_log_ms >= BROADCAST_THROTTLE_LOG_MS {
+                        last_throttle_log_ms = now;
+                        self.emit_log(
+                            &*app,
+                            None,
+                            "WARN",
+                            format!(
+                                "provision: backing off, {} byte cycle exceeds broadcast budget of {} B/s",
+                                cost, limit
+                            ),
+                        )
+                        .await;
+                    }
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                let mut delivered = false;
+                let mut last_error = None;
+                for target in &targets {
+                    match send_socket.send_to(&raw, (*target, udp_port)).await {
+                        Ok(_) => delivered = true,
+                        Err(err) => last_error = Some(format!("{}: {}", target, err)),
+                    }
+                }
+                let mut state = self.inner.lock().await;
+                let stats = &mut state.discovery.udp_broadcast;
+                if delivered {
+                    stats.send_count += 1;
+                    stats.last_send_ms = Some(self.now());
+                }
+                stats.last_error = last_error;
+            }
+
+            if let Some(socket) = &ack_socket {
+                if let Ok(result) = tokio::time::timeout(
+                    Duration::from_millis(300),
+                    socket.recv_from(&mut ack_buffer),
+                )
+                .await
+                {
+                    if let Ok((len, sender)) = result {
+                        if let Ok(ack) = serde_json::from_slice::<ProvisionAck>(&ack_buffer[..len])
+                        {
+                            if ack.message_type == "LABSCAN_PROVISION_ACK" && ack.v == 1 {
+                                {
+                                    let mut state = self.inner.lock().await;
+                                    let stats = &mut state.discovery.udp_broadcast;
+                                    stats.ack_count += 1;
+                                    stats.last_ack_ms = Some(self.now());
+                                }
+                                self.emit_log(
+                                    &*app,
+                                    Some(ack.agent_id),
+                                    "INFO",
+                                    format!(
+                                        "Provision ACK from {} ({}) nonce={} ts={}",
+                                        ack.hostname, sender, ack.nonce, ack.ts
+                                    ),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                }
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn heartbeat_watchdog(&self, app: Arc<dyn EventSink>) {
+        loop {
+            sleep(Duration::from_secs(3)).await;
+            if !LoopFlags::enabled(&self.loops.watchdog) {
+                continue;
+            }
+            let now = self.now();
+            let mut ids = Vec::new();
+            {
+                let mut guard = self.inner.lock().await;
+                let state = &mut *guard;
+                // Mocks never heartbeat; they keep their simulated status.
+                for d in state.devices.values_mut().filter(|d| !d.is_mock) {
+                    if d.status != "offline"
+                        && now - d.last_seen_ms > self.settings.heartbeat_timeout_ms
+                    {
+                        d.status = "offline".to_string();
+                        ids.push(d.agent_id.clone());
+                        state
+                            .connection_signals
+                            .entry(d.agent_id.clone())
+                            .or_default()
+                            .record_flap(now);
+                    }
+                }
+            }
+            for id in ids {
+                if let Some(device) = self.inner.lock().await.devices.get(&id).cloned() {
+                    self.persist_facts(&device);
+                    self.emit_device_upsert_if_needed(&*app, device.clone(), true)
+                        .await;
+                    self.emit_activity(
+                        &*app,
+                        "device_disconnected",
+                        Some(id),
+                        format!("{} disconnected (heartbeat timeout)", device.hostname),
+                    )
+                    .await;
+                }
+            }
+            self.reap_stale_devices(&*app).await;
+            self.escalate_outages(&*app).await;
+            self.prune_activity_if_due().await;
+        }
+    }
+
+    async fn task_timeout_sweep(&self, app: Arc<dyn EventSink>) {
+        loop {
+            sleep(Duration::from_millis(TASK_TIMEOUT_SWEEP_MS)).await;
+            self.expire_overdue_tasks(&*app).await;
+        }
+    }
+
+    /// Gives up on running tasks whose missing results are overdue, recording
+    /// a `timeout` failure for every agent that never answered.
+    async fn expire_overdue_tasks(&self, app: &dyn EventSink) {
+        let now = self.now();
+        let expired: Vec<TaskRecord> = {
+            let mut guard = self.inner.lock().await;
+            let mut expired = Vec::new();
+            for task in guard.tasks.values_mut() {
+                // A task whose agents were all offline never starts; time it from creation.
+                let started_at = task.started_at.unwrap_or(task.created_at);
+                if task.ended_at.is_some() || started_at + task.timeout_ms >= now {
+                    continue;
+                }
+                refresh_pending_agents(task);
+                for agent_id in std::mem::take(&mut task.pending_agents) {
+                    task.results.push(TaskResultRecord {
+                        agent_id,
+                        ok: false,
+                        result: Value::Null,
+                        error: Some("timeout".to_string()),
+                        error_code: Some("timeout".to_string()),
+                        ts: now,
+                    });
+                }
+                task.status = "timeout".to_string();
+                task.ended_at = Some(now);
+                expired.push(task.clone());
+            }
+            expired
+        };
+
+        for task in expired {
+            self.emit_task_update(app, task.clone()).await;
+            self.emit_activity(
+                app,
+                "task_failed",
+                None,
+                format!(
+                    "Task {} ({}) timed out after {} ms",
+                    task.task_id, task.kind, task.timeout_ms
+                ),
+            )
+            .await;
+            let _ = self.task_completions.send(task);
+        }
+    }
+
+    async fn escalate_outages(&self, app: &dyn EventSink) {
+        let now = self.now();
+        let mut alerts = Vec::new();
+        let mut recovered = Vec::new();
+        {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let mut thresholds = state.config.outage_thresholds.clone();
+            thresholds.sort_by_key(|t| t.after_ms);
+            let devices = &state.devices;
+            state.outage_levels.retain(|id, level| {
+                let back = devices.get(id).is_none_or(|d| d.status != "offline");
+                if back && *level > 0 {
+                    if let Some(d) = devices.get(id) {
+                        recovered.push(d.clone());
+                    }
+                }
+                !back
+            });
+            for device in state
+                .devices
+                .values()
+                .filter(|d| d.status == "offline" && !d.is_mock)
+            {
+                let offline_for = now - device.last_seen_ms;
+                let crossed = thresholds
+                    .iter()
+                    .take_while(|t| offline_for >= t.after_ms)
+                    .count();
+                let level = state
+                    .outage_levels
+                    .entry(device.agent_id.clone())
+                    .or_insert(0);
+                // Thresholds can shrink on reload; never re-alert for ones already sent.
+                for threshold in thresholds.iter().take(crossed).skip(*level) {
+                    alerts.push((device.clone(), threshold.clone(), offline_for));
+                }
+                *level = (*level).max(crossed);
+            }
+        }
+
+        for (device, threshold, offline_for) in alerts {
+            let message = format!(
+                "{} has been offline for {} ({})",
+                device.hostname,
+                format_duration_ms(offline_for),
+                threshold.severity
+            );
+            self.emit_log(
+                app,
+                Some(device.agent_id.clone()),
+                threshold.log_level(),
+                message.clone(),
+            )
+            .await;
+            self.emit_activity(
+                app,
+                &format!("outage_{}", threshold.severity),
+                Some(device.agent_id),
+                message,
+            )
+            .await;
+        }
+        for device in recovered {
+            self.emit_activity(
+                app,
+                "outage_cleared",
+                Some(device.agent_id),
+                format!(
+                    "{} is back online after an extended outage",
+                    device.hostname
+                ),
+            )
+            .await;
+        }
+    }
+
+    async fn prune_activity_if_due(&self) {
+        let now = self.now();
+        let retention = {
+            let mut state = self.inner.lock().await;
+            let retention = state.config.activity_retention_ms;
+            if retention <= 0 || now - state.last_activity_prune_ms < ACTIVITY_PRUNE_INTERVAL_MS {
+                return;
+            }
+            state.last_activity_prune_ms = now;
+            retention
+        };
+        self.persist(move |db| db.prune_activity(now - retention).map(|_| ()));
+    }
+
+    async fn reap_stale_devices(&self, app: &dyn EventSink) {
+        let now = self.now();
+        let reaped: Vec<DeviceRecord> = {
+            let mut state = self.inner.lock().await;
+            let threshold = state.config.stale_reap_after_ms;
+            if threshold <= 0 {
+                return;
+            }
+            let ids: Vec<String> = state
+                .devices
+                .values()
+                .filter(|d| !d.is_mock && d.status == "offline")
+                .filter(|d| now - d.last_seen_ms > threshold)
+                .filter(|d| {
+                    !state
+                        .config
+                        .reap_protected_agents
+                        .iter()
+                        .any(|p| *p == d.agent_id || *p == d.device_key)
+                })
+                .map(|d| d.agent_id.clone())
+                .collect();
+            let mut reaped = Vec::new();
+            for id in &ids {
+                if let Some(device) = state.devices.remove(id) {
+                    if let Some(fp) = &device.fingerprint {
+                        state.fingerprint_index.remove(fp);
+                    }
+                    reaped.push(device);
+                }
+                state.connections.remove(id);
+                state.last_device_emit_ms.remove(id);
+                state.last_activity_emit_ms.remove(id);
+                state.pending_upserts.remove(id);
+                state.emitted_devices.remove(id);
+                state.mock_agents.remove(id);
+                state.followed_agents.remove(id);
+            }
+            state.device_order.retain(|id| !ids.contains(id));
+            reaped
+        };
+        if reaped.is_empty() {
+            return;
+        }
+
+        for device in &reaped {
+            let agent_id = device.agent_id.clone();
+            let db_id = agent_id.clone();
+            self.persist(move |db| db.delete_device(&db_id));
+            self.emit_device_remove(app, agent_id.clone(), REMOVE_REASON_REAPED)
+                .await;
+            self.emit_activity(
+                app,
+                "device_reaped",
+                Some(agent_id),
+                format!(
+                    "{} removed after being offline for {}h",
+                    device.hostname,
+                    (now - device.last_seen_ms) / 3_600_000
+                ),
+            )
+            .await;
+        }
+        self.rebuild_topology_if_changed(app).await;
+    }
+
+    async fn dispatch_task_now(&self, task: TaskRecord) -> TaskRecord {
+        let mut guard = self.inner.lock().await;
+        let runtime = &mut *guard;
+        if let Some(existing) = runtime.tasks.get_mut(&task.task_id) {
+            let now = self.now();
+            if send_task_to_agents(existing, &runtime.connections, None, now) > 0 {
+                existing.status = "running".to_string();
+                existing.started_at = Some(now);
+            }
+            existing.clone()
+        } else {
+            task
+        }
+    }
+
+    // Resends open tasks a reconnecting agent never acknowledged; acknowledged
+    // ones are not resent so a task runs at most once per agent.
+    async fn redeliver_pending_tasks(&self, app: &dyn EventSink, agent_id: &str) {
+        let updated: Vec<TaskRecord> = {
+            let mut guard = self.inner.lock().await;
+            let runtime = &mut *guard;
+            let mut updated = Vec::new();
+            let now = self.now();
+            for task in runtime.tasks.values_mut() {
+                if !matches!(task.status.as_str(), "queued" | "running")
+                    || !task.assigned_agents.iter().any(|a| a == agent_id)
+                {
+                    continue;
+                }
+                if send_task_to_agents(task, &runtime.connections, Some(agent_id), now) > 0 {
+                    if task.status == "queued" {
+                        task.status = "running".to_string();
+                        task.started_at = Some(now);
+                    }
+                    updated.push(task.clone());
+                }
+            }
+            updated
+        };
+        if !updated.is_empty() {
+            tracing::info!(
+                "[WS] redelivered {} pending task(s) to agent_id={}",
+                updated.len(),
+                agent_id
+            );
+        }
+        for task in updated {
+            self.emit_task_update(app, task).await;
+        }
+    }
+
+    async fn set_online(&self, app: &dyn EventSink, online: bool) {
+        {
+            let mut state = self.inner.lock().await;
+            state.online = online;
+            state.online_since_ms = if online { Some(self.now()) } else { None };
+        }
+        self.emit_server_status(app).await;
+    }
+
+    async fn task_automation_loop(&self, app: Arc<dyn EventSink>) {
+        let mut completions = self.subscribe_task_completions();
+        loop {
+            let task = match completions.recv().await {
+                Ok(task) => task,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("[TASK] automation skipped {} completion(s)", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let automation = self.inner.lock().await.config.task_automation.clone();
+            let reruns = task.rerun_depth;
+
+            if automation.webhook_on_complete {
+                self.queue_webhooks(WebhookEvent {
+                    kind: "task_result".to_string(),
+                    ts: self.now(),
+                    agent_id: None,
+                    level: None,
+                    message: format!("Task {} ({}) {}", task.task_id, task.kind, task.status),
+                    data: serde_json::to_value(&task).unwrap_or(Value::Null),
+                })
+                .await;
+            }
+
+            if matches!(task.status.as_str(), "failed" | "timeout")
+                && reruns < automation.auto_rerun_failed
+            {
+                match self.retry_failed(&*app, task.task_id.clone()).await {
+                    Ok(rerun) => {
+                        self.emit_log(
+                            &*app,
+                            None,
+                            "INFO",
+                            format!(
+                                "Auto-rerun {}/{} of task {} as {}",
+                                reruns + 1,
+                                automation.auto_rerun_failed,
+                                task.task_id,
+                                rerun.task_id
+                            ),
+                        )
+                        .await
+                    }
+                    Err(err) => {
+                        self.emit_log(
+                            &*app,
+                            None,
+                            "WARN",
+                            format!("Auto-rerun of task {} failed: {}", task.task_id, err),
+                        )
+                        .await
+                    }
+                }
+            }
+        }
+    }
+
+    async fn server_pulse_loop(&self, app: Arc<dyn EventSink>) {
+        loop {
+            let interval_ms = self.inner.lock().await.config.server_pulse_interval_ms;
+            if interval_ms == 0 {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            sleep(Duration::from_millis(interval_ms)).await;
+            if let Some(pulse) = self.next_server_pulse().await {
+                app.emit(EVENT_SERVER_PULSE, pulse);
+            }
+        }
+    }
+
+    async fn next_server_pulse(&self) -> Option<ServerPulse> {
+        let mut state = self.inner.lock().await;
+        if !state.online || state.config.server_pulse_interval_ms == 0 {
+            return None;
+        }
+        state.pulse_seq += 1;
+        let now = self.now();
+        let online_count = state
+            .devices
+            .values()
+            .filter(|d| d.status != "offline")
+            .count();
+        Some(ServerPulse {
+            seq: state.pulse_seq,
+            ts: now,
+            uptime_ms: state.online_since_ms.map(|t| now - t).unwrap_or(0),
+            device_count: state.devices.len(),
+            online_count,
+            offline_count: state.devices.len() - online_count,
+        })
+    }
+
+    async fn emit_server_status(&self, app: &dyn EventSink) {
+        app.emit(EVENT_SERVER_STATUS, self.get_status().await);
+    }
+
+    async fn emit_devices_snapshot(&self, app: &dyn EventSink) {
+        app.emit(EVENT_DEVICES_SNAPSHOT, self.get_devices_snapshot().await);
+    }
+
+    async fn emit_topology_snapshot(&self, app: &dyn EventSink) {
+        let snapshot = self.get_topology_snapshot().await;
+        app.emit(EVENT_TOPOLOGY_SNAPSHOT, snapshot.clone());
+        app.emit(EVENT_TOPOLOGY_CHANGED, snapshot);
+    }
+
+    async fn refresh_duplicate_macs(&self, app: &dyn EventSink) {
+        let (changed, raised) = {
+            let mut guard = self.inner.lock().await;
+            let runtime = &mut *guard;
+            let mut by_mac: HashMap<String, Vec<String>> = HashMap::new();
+            for id in &runtime.device_order {
+                if let Some(mac) = runtime
+                    .devices
+                    .get(id)
+                    .and_then(|d| d.mac.as_deref())
+                    .and_then(usable_mac)
+                {
+                    by_mac.entry(mac).or_default().push(id.clone());
+                }
+            }
+
+            let mut changed: Vec<DeviceRecord> = Vec::new();
+            let mut raised: Vec<(String, Vec<String>)> = Vec::new();
+            for (mac, ids) in &by_mac {
+                if ids.len() < 2 {
+                    continue;
+                }
+                let mut newly_flagged = false;
+                for id in ids {
+                    if let Some(device) = runtime.devices.get_mut(id) {
+                        if !device.duplicate_mac {
+                            device.duplicate_mac = true;
+                            newly_flagged = true;
+                            changed.push(device.clone());
+                        }
+                    }
+                }
+                if newly_flagged {
+                    let hosts = ids
+                        .iter()
+                        .filter_map(|id| runtime.devices.get(id))
+                        .map(|d| format!("{} ({})", d.hostname, d.agent_id))
+                        .collect();
+                    raised.push((mac.clone(), hosts));
+                }
+            }
+
+            for device in runtime.devices.values_mut() {
+                if !device.duplicate_mac {
+                    continue;
+                }
+                let still_duplicated = device
+                    .mac
+                    .as_deref()
+                    .and_then(usable_mac)
+                    .and_then(|mac| by_mac.get(&mac))
+                    .is_some_and(|ids| ids.len() > 1);
+                if !still_duplicated {
+                    device.duplicate_mac = false;
+                    changed.push(device.clone());
+                }
+            }
+            (changed, raised)
+        };
+
+        for (mac, hosts) in raised {
+            self.emit_log(
+                app,
+                None,
+                "WARN",
+                format!(
+                    "Duplicate MAC {} reported by {} (cloned image or spoofing?)",
+                    mac,
+                    hosts.join(", ")
+                ),
+            )
+            .await;
+        }
+        for device in changed {
+            if !device.duplicate_mac {
+                self.emit_log(
+                    app,
+                    Some(device.agent_id.clone()),
+                    "INFO",
+                    format!(
+                        "{} no longer shares its MAC with another device",
+                        device.hostname
+                    ),
+                )
+                .await;
+            }
+            self.emit_device_upsert_if_needed(app, device, true).await;
+        }
+    }
+
+    async fn rebuild_topology_if_changed(&self, app: &dyn EventSink) {
+        if !LoopFlags::enabled(&self.loops.topology_rebuild) {
+            return;
+        }
+        if self.commit_topology(app, false).await {
+            self.emit_topology_snapshot(app).await;
+        }
+    }
+
+    /// Rebuilds the graph and commits it if it differs from the committed one and
+    /// has stayed that way for `topology_stability_ms`. `force` skips the wait.
+    /// Returns whether a new revision was committed; commits emit a `topology_diff`
+    /// against the previous revision.
+    async fn commit_topology(&self, app: &dyn EventSink, force: bool) -> bool {
+        let now = self.now();
+        let mut checkpoint = None;
+        let mut diff = None;
+        let (committed, recheck_in_ms) = {
+            let mut guard = self.inner.lock().await;
+            let state = &mut *guard;
+            let candidate = build_topology_snapshot(
+                &state.devices,
+                &state.device_order,
+                &state.admin_network,
+                &state.config,
+                &state.topology_seed,
+                state.topology_snapshot.revision + 1,
+                now,
+            );
+            let key = topology_key(&candidate);
+            let window = state.config.topology_stability_ms as i64;
+            if key == state.topology_key {
+                // Whatever was pending reverted in time.
+                state.topology_candidate_key = None;
+                (false, None)
+            } else {
+                if state.topology_candidate_key.as_ref() != Some(&key) {
+                    state.topology_candidate_key = Some(key.clone());
+                    state.topology_candidate_since_ms = now;
+                }
+                let held_ms = now - state.topology_candidate_since_ms;
+                if force || window == 0 || state.topology_key.is_empty() || held_ms >= window {
+                    state.topology_key = key;
+                    let retention_ms = state.config.topology_checkpoint_retention_ms;
+                    if retention_ms > 0 {
+                        checkpoint = Some((candidate.clone(), now - retention_ms));
+                    }
+                    diff = Some(topology_diff(&state.topology_snapshot, &candidate));
+                    state.topology_snapshot = candidate;
+                    state.topology_candidate_key = None;
+                    (true, None)
+                } else if !std::mem::replace(&mut state.topology_recheck_scheduled, true) {
+                    (false, Some(window - held_ms))
+                } else {
+                    (false, None)
+                }
+            }
+        };
+
+        if let Some(diff) = diff {
+            app.emit(EVENT_TOPOLOGY_DIFF, diff);
+        }
+        if let Some((snapshot, keep_since_ms)) = checkpoint {
+            let row = TopologyCheckpointRow {
+                revision: snapshot.revision as i64,
+                updated_at: snapshot.updated_at,
+                node_count: snapshot.nodes.len() as i64,
+                edge_count: snapshot.edges.len() as i64,
+                nodes: serde_json::to_string(&snapshot.nodes).unwrap_or_else(|_| "[]".to_string()),
+                edges: serde_json::to_string(&snapshot.edges).unwrap_or_else(|_| "[]".to_string()),
+            };
+            self.persist(move |db| db.insert_topology_checkpoint(&row, keep_since_ms));
+        }
+        if let Some(delay) = recheck_in_ms {
+            self.schedule_topology_recheck(app.clone_sink(), delay.max(0) as u64);
+        }
+        committed
+    }
+
+    // Heartbeats may stop arriving, so make sure a pending candidate is looked at again.
+    fn schedule_topology_recheck(&self, app: Arc<dyn EventSink>, delay_ms: u64) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(delay_ms)).await;
+            manager.inner.lock().await.topology_recheck_scheduled = false;
+            manager.rebuild_topology_if_changed(&*app).await;
+        });
+    }
+
+    async fn emit_device_upsert_if_needed(
+        &self,
+        app: &dyn EventSink,
+        device: DeviceRecord,
+        force: bool,
+    ) {
+        let now = self.now();
+        let (should, batch_ms, followed, patch) = {
+            let mut state = self.inner.lock().await;
+            let followed = state.followed_agents.contains(&device.agent_id);
+            let last = state
+                .last_device_emit_ms
+                .get(&device.agent_id)
+                .copied()
+                .unwrap_or(0);
+            if force || followed || now - last >= DEVICE_EMIT_THROTTLE_MS {
+                state
+                    .last_device_emit_ms
+                    .insert(device.agent_id.clone(), now);
+                let batch_ms = state.config.device_upsert_batch_ms;
+                let patch = if state.config.sparse_device_upserts && batch_ms == 0 {
+                    let current = serde_json::to_value(&device).unwrap_or(Value::Null);
+                    state
+                        .emitted_devices
+                        .insert(device.agent_id.clone(), current.clone())
+                        .map(|previous| changed_fields(&previous, &current))
+                } else {
+                    state.emitted_devices.remove(&device.agent_id);
+                    None
+                };
+                (true, batch_ms, followed, patch)
+            } else {
+                (false, 0, false, None)
+            }
+        };
+        if !should {
+            return;
+        }
+        if followed {
+            emit_followed(app, &device.agent_id, "device", &device, now);
+        }
+        if let Some(changes) = patch {
+            // Clients merging patches stay in sync without a full snapshot.
+            if !changes.is_empty() {
+                app.emit(
+                    EVENT_DEVICE_PATCH,
+                    DevicePatchEvent {
+                        agent_id: device.agent_id,
+                        changes,
+                    },
+                );
+            }
+            return;
+        }
+        if batch_ms == 0 {
+            app.emit(EVENT_DEVICE_UPSERT, DeviceUpsertEvent { device });
+            self.emit_devices_snapshot(app).await;
+            return;
+        }
+
+        let schedule_flush = {
+            let mut state = self.inner.lock().await;
+            state
+                .pending_upserts
+                .insert(device.agent_id.clone(), device);
+            !std::mem::replace(&mut state.upsert_flush_scheduled, true)
+        };
+        if schedule_flush {
+            let manager = self.clone();
+            let app = app.clone_sink();
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(batch_ms)).await;
+                manager.flush_device_upserts(&*app).await;
+            });
+        }
+    }
+
+    async fn flush_device_upserts(&self, app: &dyn EventSink) {
+        let devices = {
+            let mut state = self.inner.lock().await;
+            state.upsert_flush_scheduled = false;
+            let mut pending = std::mem::take(&mut state.pending_upserts);
+            let mut devices: Vec<DeviceRecord> = state
+                .device_order
+                .iter()
+                .filter_map(|id| pending.remove(id))
+                .collect();
+            let mut rest: Vec<DeviceRecord> = pending.into_values().collect();
+            rest.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+            devices.extend(rest);
+            devices
+        };
+        if devices.is_empty() {
+            return;
+        }
+        app.emit(
+            EVENT_DEVICE_UPSERT_BATCH,
+            DeviceUpsertBatchEvent { devices },
+        );
+        self.emit_devices_snapshot(app).await;
+    }
+
+    async fn emit_device_remove(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        reason: &'static str,
+    ) {
+        app.emit(EVENT_DEVICE_REMOVE, DeviceRemoveEvent { agent_id, reason });
+        self.emit_devices_snapshot(app).await;
+    }
+
+    async fn emit_task_update(&self, app: &dyn EventSink, task: TaskRecord) {
+        self.persist_task(&task);
+        app.emit(EVENT_TASK_UPDATE, TaskUpdateEvent { task });
+    }
+
+    async fn emit_log(
+        &self,
+        app: &dyn EventSink,
+        agent_id: Option<String>,
+        level: &str,
+        message: String,
+    ) {
+        let event = LogEvent {
+            id: Uuid::new_v4().to_string(),
+            agent_id,
+            level: level.to_string(),
+            message: sanitize::message(&message),
+            ts: self.now(),
+        };
+        let followed = {
+            let mut state = self.inner.lock().await;
+            state.logs.push_front(event.clone());
+            while state.logs.len() > self.settings.max_logs {
+                state.logs.pop_back();
+            }
+            event
+                .agent_id
+                .as_ref()
+                .is_some_and(|id| state.followed_agents.contains(id))
+        };
+        if followed {
+            if let Some(id) = &event.agent_id {
+                emit_followed(app, id, "log", &event, event.ts);
+            }
+        }
+        if matches!(event.level.as_str(), "WARN" | "ERROR") {
+            self.queue_webhooks(WebhookEvent {
+                kind: "alert".to_string(),
+                ts: event.ts,
+                agent_id: event.agent_id.clone(),
+                level: Some(event.level.clone()),
+                message: event.message.clone(),
+                data: Value::Null,
+            })
+            .await;
+        }
+        app.emit(EVENT_LOG, event);
+    }
+
+    async fn queue_webhooks(&self, event: WebhookEvent) {
+        let mut state = self.inner.lock().await;
+        let hooks: Vec<WebhookConfig> = state
+            .config
+            .webhooks
+            .iter()
+            .filter(|hook| hook.matches(&event.kind))
+            .cloned()
+            .collect();
+        for hook in hooks {
+            let delivery = WebhookDelivery {
+                hook,
+                event: event.clone(),
+            };
+            if self.webhook_jobs.send(delivery).is_ok() {
+                state.webhook_stats.queued += 1;
+            }
+        }
+    }
+
+    async fn report_bad_network_detection(&self, app: &dyn EventSink, device: &DeviceRecord) {
+        self.emit_log(
+            app,
+            Some(device.agent_id.clone()),
+            "INFO",
+            format!(
+                "{} reported a loopback/link-local address (ips {:?}); using {:?} instead",
+                device.hostname, device.ips, device.ip
+            ),
+        )
+        .await;
+        self.emit_activity(
+            app,
+            "bad_network_detection",
+            Some(device.agent_id.clone()),
+            format!("{} network detection looks broken", device.hostname),
+        )
+        .await;
+    }
+
+    async fn emit_activity(
+        &self,
+        app: &dyn EventSink,
+        kind: &str,
+        agent_id: Option<String>,
+        message: String,
+    ) {
+        let now = self.now();
+        let message = sanitize::message(&message);
+        let mut followed = false;
+        if let Some(ref id) = agent_id {
+            let drop_event = {
+                let state = self.inner.lock().await;
+                followed = state.followed_agents.contains(id);
+                !followed
+                    && now - state.last_activity_emit_ms.get(id).copied().unwrap_or(0)
+                        < DEVICE_ACTIVITY_RATE_MS
+            };
+            if drop_event {
+                return;
+            }
+        }
+
+        let event = {
+            let mut state = self.inner.lock().await;
+            if let Some(ref id) = agent_id {
+                state.last_activity_emit_ms.insert(id.clone(), now);
+            }
+
+            if let Some(front) = state.activity.front_mut().filter(|_| !followed) {
+                if front.kind == kind
+                    && front.agent_id == agent_id
+                    && now - front.ts <= ACTIVITY_DEDUPE_MS
+                {
+                    front.ts = now;
+                    front.count = Some(front.count.unwrap_or(1) + 1);
+                    front.clone()
+                } else {
+                    let e = ActivityEvent {
+                        id: Uuid::new_v4().to_string(),
+                        kind: kind.to_string(),
+                        agent_id,
+                        message,
+                        ts: now,
+                        count: None,
+                    };
+                    state.activity.push_front(e.clone());
+                    while state.activity.len() > self.settings.max_activity {
+                        state.activity.pop_back();
+                    }
+                    e
+                }
+            } else {
+                let e = ActivityEvent {
+                    id: Uuid::new_v4().to_string(),
+                    kind: kind.to_string(),
+                    agent_id,
+                    message,
+                    ts: now,
+                    count: None,
+                };
+                state.activity.push_front(e.clone());
+                e
+            }
+        };
+
+        let row = ActivityRow {
+            id: event.id.clone(),
+            kind: event.kind.clone(),
+            agent_id: event.agent_id.clone(),
+            message: event.message.clone(),
+            ts: event.ts,
+            count: event.count,
+        };
+        self.persist(move |db| db.save_activity(&row));
+
+        if followed {
+            if let Some(id) = &event.agent_id {
+                emit_followed(app, id, "activity", &event, now);
+            }
+        }
+        if event.count.is_none() {
+            self.queue_webhooks(WebhookEvent {
+                kind: event.kind.clone(),
+                ts: event.ts,
+                agent_id: event.agent_id.clone(),
+                level: None,
+                message: event.message.clone(),
+                data: Value::Null,
+            })
+            .await;
+        }
+        app.emit(EVENT_ACTIVITY, event);
+    }
+
+    async fn on_agent_disconnect(
+        &self,
+        app: &dyn EventSink,
+        agent_id: String,
+        sender: &mpsc::UnboundedSender<Message>,
+    ) {
+        let device = {
+            let mut state = self.inner.lock().await;
+            match state.connections.get(&agent_id) {
+                // A newer socket took over this agent; it stays online.
+                Some(current) if !current.same_channel(sender) => return,
+                Some(_) => {
+                    state.connections.remove(&agent_id);
+                }
+                None => {}
+            }
+            let was_online = state
+                .devices
+                .get(&agent_id)
+                .is_some_and(|d| d.status != "offline");
+            if was_online {
+                state
+                    .connection_signals
+                    .entry(agent_id.clone())
+                    .or_default()
+                    .record_flap(self.now());
+            }
+            if let Some(d) = state.devices.get_mut(&agent_id) {
+                d.status = "offline".to_string();
+                d.last_seen_ms = self.now();
+                Some(d.clone())
+            } else {
+                None
+            }
+        };
+
+        if let Some(device) = device {
+            self.persist_facts(&device);
+            self.emit_device_upsert_if_needed(app, device.clone(), true)
+                .await;
+            self.emit_activity(
+                app,
+                "device_disconnected",
+                Some(device.agent_id.clone()),
+                format!("{} disconnected", device.hostname),
+            )
+            .await;
+        } else {
+            // The record was dropped while the socket was still open.
+            self.emit_device_remove(app, agent_id, REMOVE_REASON_EVICTED)
+                .await;
+        }
+        self.rebuild_topology_if_changed(app).await;
+    }
+}
+
+async fn ws_agent_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<HttpState>,
+) -> Response {
+    let config = state.manager.get_config().await;
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let origin = origin.to_str().unwrap_or_default();
+        if config.validate_ws_origin && !origin_allowed(origin, &config.allowed_ws_origins) {
+            tracing::warn!("[WS] rejected upgrade remote={} origin={}", remote, origin);
+            state
+                .manager
+                .emit_log(
+                    &*state.app,
+                    None,
+                    "WARN",
+                    format!(
+                        "[WS] rejected upgrade remote={} reason=origin not allowed ({})",
+                        remote, origin
+                    ),
+                )
+                .await;
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    if let Some(cidr) = state.manager.is_source_blocked(&remote).await {
+        tracing::warn!("[WS] rejected upgrade remote={} blocked={}", remote, cidr);
+        state
+            .manager
+            .emit_log(
+                &*state.app,
+                None,
+                "WARN",
+                format!(
+                    "[WS] rejected upgrade remote={} reason=source blocked ({})",
+                    remote, cidr
+                ),
+            )
+            .await;
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    // Dropped quietly: a looping client would otherwise flood the log.
+    if let Some(remaining) = state.manager.secret_ban_remaining(&remote).await {
+        tracing::debug!("[WS] rejected upgrade remote={} secret backoff", remote);
+        let retry_after = (remaining + 999) / 1000;
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response();
+    }
+
+    let Some(slot) = state
+        .manager
+        .claim_socket_slot(config.max_agent_connections)
+    else {
+        state
+            .manager
+            .alert_connection_cap(&*state.app, remote, config.max_agent_connections)
+            .await;
+        return ws.on_upgrade(reject_at_capacity);
+    };
+
+    ws.on_upgrade(move |socket| async move {
+        handle_agent_socket(socket, state, remote).await;
+        drop(slot);
+    })
+}
+
+async fn reject_at_capacity(mut socket: WebSocket) {
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::AGAIN,
+            reason: "server at connection capacity".into(),
+        })))
+        .await;
+}
+
+fn origin_allowed(origin: &str, allowed: &[String]) -> bool {
+    let origin = origin.trim().trim_end_matches('/');
+    allowed.iter().any(|candidate| {
+        candidate
+            .trim()
+            .trim_end_matches('/')
+            .eq_ignore_ascii_case(origin)
+    })
+}
+
+async fn handle_agent_socket(socket: WebSocket, state: HttpState, remote: SocketAddr) {
+    tracing::info!("[WS] connect remote={} path=/ws/agent", remote);
+    state
+        .manager
+        .emit_log(
+            &*state.app,
+            None,
+            "INFO",
+            format!("[WS] connect remote={} path=/ws/agent", remote),
+        )
+        .await;
+
+    let close = Arc::new(Notify::new());
+    let socket_id = state.manager.track_socket(&remote, close.clone()).await;
+    let (mut sender, receiver) = socket.split();
+    let mut receiver = receiver.take_until(Box::pin(close.notified_owned()));
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    // Set once the agent registers so outbound frames can be wire-traced.
+    let trace_id = Arc::new(std::sync::OnceLock::<String>::new());
+    let writer_trace_id = trace_id.clone();
+    let writer_state = state.clone();
+    let write_task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let (Message::Text(text), Some(agent_id)) = (&msg, writer_trace_id.get()) {
+                writer_state
+                    .manager
+                    .trace_wire(&*writer_state.app, agent_id, "out", text)
+                    .await;
+            }
+            if sender.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut registered_agent_id: Option<String> = None;
+    let mut session_key = String::new();
+
+    while let Some(incoming) = receiver.next().await {
+        let message = match incoming {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        let text = match message {
+            Message::Text(t) => t,
+            Message::Pong(data) => {
+                if let Some(agent_id) = &registered_agent_id {
+                    state
+                        .manager
+                        .resolve_ping(agent_id, &data, Instant::now())
+                        .await;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if let Some(agent_id) = &registered_agent_id {
+            state
+                .manager
+                .trace_wire(&*state.app, agent_id, "in", &text)
+                .await;
+        }
+
+        let wire = match serde_json::from_str::<WireMessage>(&text) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        if wire.message_type == "register" {
+            let mut payload = match serde_json::from_value::<RegisterPayload>(wire.payload) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            payload.hostname = sanitize::field(&payload.hostname);
+            payload.os = sanitize::field(&payload.os);
+            payload.version = sanitize::field(&payload.version);
+
+            let (pair_token_ok, blocked, allowed) = {
+                let guard = state.manager.inner.lock().await;
+                (
+                    accepts_pair_token(&guard, &payload.secret, state.manager.now()),
+                    guard.blocked_agents.contains(&payload.agent_id),
+                    !guard.allowlist_enabled || guard.allowed_agents.contains(&payload.agent_id),
+                )
+            };
+            // Enrollment tokens are only redeemed once registration goes ahead.
+            let enrollment_token_ok =
+                !pair_token_ok && state.manager.enrollment_token_valid(&payload.secret).await;
+            let secret_ok = pair_token_ok || enrollment_token_ok;
+
+            tracing::info!(
+                "[WS] message type=register agent_id={} ok={}",
+                payload.agent_id,
+                secret_ok
+            );
+
+            if !secret_ok {
+                let error = if state
+                    .manager
+                    .enrollment_token_expired(&payload.secret)
+                    .await
+                {
+                    "token expired"
+                } else {
+                    "invalid shared secret"
+                };
+                let _ = tx.send(Message::Text(
+                    json!({
+                        "type": "registered",
+                        "ts": state.manager.now(),
+                        "agent_id": payload.agent_id,
+                        "payload": {"ok": false, "error": error, "server_time": state.manager.now()}
+                    })
+                    .to_string()
+                    .into(),
+                ));
+                state
+                    .manager
+                    .record_secret_failure(&*state.app, &remote)
+                    .await;
+                break;
+            }
+
+            if blocked {
+                let _ = tx.send(Message::Text(
+                    json!({
+                        "type": "registered",
+                        "ts": state.manager.now(),
+                        "agent_id": payload.agent_id,
+                        "payload": {"ok": false, "error": "agent blocked", "server_time": state.manager.now()}
+                    })
+                    .to_string(),
+                ));
+                state
+                    .manager
+                    .emit_log(
+                        &*state.app,
+                        Some(payload.agent_id.clone()),
+                        "WARN",
+                        format!("Blocked agent attempted to register from {}", remote),
+                    )
+                    .await;
+                break;
+            }
+
+            if !allowed {
+                let _ = tx.send(Message::Text(
+                    json!({
+                        "type": "registered",
+                        "ts": state.manager.now(),
+                        "agent_id": payload.agent_id,
+                        "payload": {"ok": false, "error": "agent not allowed", "server_time": state.manager.now()}
+                    })
+                    .to_string(),
+                ));
+                state
+                    .manager
+                    .emit_log(
+                        &*state.app,
+                        Some(payload.agent_id.clone()),
+                        "WARN",
+                        format!(
+                            "Agent not on allowlist attempted to register from {}",
+                            remote
+                        ),
+                    )
+                    .await;
+                break;
+            }
+
+            if enrollment_token_ok
+                && !state
+                    .manager
+                    .consume_enrollment_token(&payload.secret)
+                    .await
+            {
+                // Another registration redeemed it since the check above.
+                let _ = tx.send(Message::Text(
+                    json!({
+                        "type": "registered",
+                        "ts": state.manager.now(),
+                        "agent_id": payload.agent_id,
+                        "payload": {"ok": false, "error": "token already used", "server_time": state.manager.now()}
+                    })
+                    .to_string(),
+                ));
+                break;
+            }
+            state
+                .manager
+                .clear_secret_failures(&*state.app, &remote)
+                .await;
+            session_key = payload.secret.clone();
+
+            let now = state.manager.now();
+            let (
+                device,
+                was_new,
+                old_status,
+                adopted_old_agent,
+                nat_detected,
+                bad_network,
+                took_over,
+            ) = {
+                let mut guard = state.manager.inner.lock().await;
+                let fingerprint = clean_non_empty_owned(&payload.fingerprint);
+
+                let adopt_key = fingerprint.as_ref().and_then(|fp| {
+                    guard.fingerprint_index.get(fp).cloned().or_else(|| {
+                        guard
+                            .devices
+                            .iter()
+                            .find(|(_, d)| d.fingerprint.as_deref() == Some(fp.as_str()))
+                            .map(|(key, _)| key.clone())
+                    })
+                });
+
+                let mut adopted_old_agent: Option<String> = None;
+                if let Some(old_key) = adopt_key {
+                    if old_key != payload.agent_id {
+                        if let Some(mut adopted) = guard.devices.remove(&old_key) {
+                            adopted_old_agent = Some(adopted.agent_id.clone());
+                            adopted.agent_id = payload.agent_id.clone();
+                            guard.devices.insert(payload.agent_id.clone(), adopted);
+                            guard.connections.remove(&old_key);
+                            guard.last_device_emit_ms.remove(&old_key);
+                            guard.emitted_devices.remove(&old_key);
+                            if let Some(slot) =
+                                guard.device_order.iter_mut().find(|id| **id == old_key)
+                            {
+                                *slot = payload.agent_id.clone();
+                            }
+                        }
+                    }
+                }
+
+                // A second socket for a connected id (a reconnect racing its stale
+                // socket, or a cloned agent) takes the slot; the old socket is closed.
+                let took_over = match guard
+                    .connections
+                    .insert(payload.agent_id.clone(), tx.clone())
+                {
+                    Some(previous) if !previous.same_channel(&tx) => {
+                        let _ = previous.send(Message::Close(None));
+                        true
+                    }
+                    _ => false,
+                };
+                let was_new = !guard.devices.contains_key(&payload.agent_id);
+                if was_new {
+                    guard.device_order.push(payload.agent_id.clone());
+                }
+                guard.mock_agents.remove(&payload.agent_id);
+
+                let pending_approval = guard.config.require_agent_approval
+                    && guard.approvals.get(&payload.agent_id).map(String::as_str)
+                        != Some("approved");
+                let fp_for_index = fingerprint.clone();
+                let default_key = fingerprint
+                    .as_ref()
+                    .map(|fp| format!("fp:{}", fp))
+                    .unwrap_or_else(|| format!("agent:{}", payload.agent_id));
+                let new_record = DeviceRecord {
+                    device_key: default_key.clone(),
+                    agent_id: payload.agent_id.clone(),
+                    fingerprint: fingerprint.clone(),
+                    hostname: payload.hostname.clone(),
+                    ips: payload.ips.clone(),
+                    os: payload.os.clone(),
+                    version: payload.version.clone(),
+                    status: "online".to_string(),
+                    last_seen_ms: now,
+                    internet_reachable: None,
+                    dns_ok: None,
+                    gateway_reachable: None,
+                    latency_ms: None,
+                    last_internet_change_ms: None,
+                    last_dns_change_ms: None,
+                    first_seen_ms: now,
+                    ip: None,
+                    subnet_cidr: None,
+                    default_gateway_ip: None,
+                    interface_type: None,
+                    mac: None,
+                    gateway_mac: None,
+                    dhcp_server_ip: None,
+                    ssid: None,
+                    arp_snapshot: Vec::new(),
+                    update_notified_version: None,
+                    update_notified_ms: None,
+                    remote_ip: None,
+                    behind_nat: false,
+                    gateway_arp_conflict: false,
+                    local_ports: None,
+                    duplicate_mac: false,
+                    last_control_action: None,
+                    last_control_sent_ms: None,
+                    last_control_ack_ms: None,
+                    last_control_ok: None,
+                    pending_approval: false,
+                    ipv6: None,
+                    ipv6_cidr: None,
+                    timezone: None,
+                    locale: None,
+                    vlan_id: None,
+                    connection_quality: None,
+                    connection_band: None,
+                    peripherals: None,
+                    overridden_network_fields: Vec::new(),
+                    alias: None,
+                    tags: Vec::new(),
+                    location: None,
+                    alias_rule_id: None,
+                    extra_metrics: serde_json::Map::new(),
+                    metric_schema: Vec::new(),
+                    bad_network_detection: false,
+                    is_mock: false,
+                };
+                let correction = guard.network_overrides.get(&payload.agent_id).cloned();
+                let alias_rules = guard.alias_rules.clone();
+                let device = {
+                    let entry = guard
+                        .devices
+                        .entry(payload.agent_id.clone())
+                        .or_insert(new_record);
+
+                    if entry.device_key.is_empty() {
+                        entry.device_key = default_key;
+                    }
+                    if let Some(fp) = fingerprint.clone() {
+                        entry.fingerprint = Some(fp);
+                    }
+
+                    let old_status = entry.status.clone();
+                    let was_bad_network = entry.bad_network_detection;
+                    entry.hostname = payload.hostname;
+                    entry.ips = payload.ips;
+                    entry.os = payload.os;
+                    entry.version = payload.version;
+                    entry.timezone = payload.timezone.as_deref().and_then(sanitize::identifier);
+                    entry.locale = payload.locale.as_deref().and_then(sanitize::identifier);
+                    entry.metric_schema = normalize_metric_schema(payload.metric_schema);
+                    entry.status = "online".to_string();
+                    entry.last_seen_ms = now;
+                    entry.pending_approval = pending_approval;
+                    // A real agent takes over a mock that happened to use its id.
+                    entry.is_mock = false;
+                    apply_network_payload(entry, &payload.network, correction.as_ref());
+                    apply_alias_rules(&alias_rules, entry);
+
+                    let was_behind_nat = entry.behind_nat;
+                    entry.remote_ip = Some(remote.ip().to_canonical().to_string());
+                    entry.behind_nat = detect_nat(&remote, entry);
+                    (
+                        entry.clone(),
+                        old_status,
+                        !was_behind_nat && entry.behind_nat,
+                        !was_bad_network && entry.bad_network_detection,
+                    )
+                };
+
+                if let Some(fp) = fp_for_index {
+                    guard.fingerprint_index.insert(fp, payload.agent_id.clone());
+                }
+                (
+                    device.0,
+                    was_new,
+                    device.1,
+                    adopted_old_agent,
+                    device.2,
+                    device.3,
+                    took_over,
+                )
+            };
+
+            registered_agent_id = Some(device.agent_id.clone());
+            let _ = trace_id.set(device.agent_id.clone());
+            state.manager.persist_device(&device);
+
+            let _ = tx.send(Message::Text(
+                json!({
+                    "type": "registered",
+                    "ts": state.manager.now(),
+                    "agent_id": device.agent_id,
+                    "payload": {"ok": true, "server_time": state.manager.now(), "canonical_id": device.device_key}
+                })
+                .to_string()
+                .into(),
+            ));
+
+            state
+                .manager
+                .emit_device_upsert_if_needed(&*state.app, device.clone(), true)
+                .await;
+
+            if let Some(old_agent) = adopted_old_agent {
+                state
+                    .manager
+                    .emit_device_remove(&*state.app, old_agent, REMOVE_REASON_MERGED)
+                    .await;
+            }
+
+            if took_over {
+                state
+                    .manager
+                    .emit_log(
+                        &*state.app,
+                        Some(device.agent_id.clone()),
+                        "WARN",
+                        format!(
+                            "[WS] agent_id={} registered again from {}; previous connection closed",
+                            device.agent_id, remote
+                        ),
+                    )
+                    .await;
+                state
+                    .manager
+                    .emit_activity(
+                        &*state.app,
+                        "agent_takeover",
+                        Some(device.agent_id.clone()),
+                        format!(
+                            "{} connected again from {}; closed its previous connection",
+                            device.hostname,
+                            remote.ip()
+                        ),
+                    )
+                    .await;
+            }
+            let auth = if enrollment_token_ok {
+                "an enrollment token"
+            } else {
+                "the pair token"
+            };
+            if was_new {
+                state
+                    .manager
+                    .emit_activity(
+                        &*state.app,
+                        "device_connected",
+                        Some(device.agent_id.clone()),
+                        format!("{} connected using {}", device.hostname, auth),
+                    )
+                    .await;
+            }
+            if enrollment_token_ok {
+                state
+                    .manager
+                    .emit_activity(
+                        &*state.app,
+                        "enrollment_token_used",
+                        Some(device.agent_id.clone()),
+                        format!("{} registered using {}", device.hostname, auth),
+                    )
+                    .await;
+            }
+            if old_status != "online" {
+                state
+                    .manager
+                    .emit_activity(
+                        &*state.app,
+                        "device_status_changed",
+                        Some(device.agent_id.clone()),
+                        format!("{} status {} -> online", device.hostname, old_status),
+                    )
+                    .await;
+            }
+            if bad_network {
+                state
+                    .manager
+                    .report_bad_network_detection(&*state.app, &device)
+                    .await;
+            }
+            if nat_detected {
+                state
+                    .manager
+                    .emit_log(
+                        &*state.app,
+                        Some(device.agent_id.clone()),
+                        "INFO",
+                        format!(
+                            "NAT suspected: reported ip {:?} but connected from {}",
+                            device.ip, remote
+                        ),
+                    )
+                    .await;
+                state
+                    .manager
+                    .emit_activity(
+                        &*state.app,
+                        "device_behind_nat",
+                        Some(device.agent_id.clone()),
+                        format!(
+                            "{} appears to be behind NAT (seen from {})",
+                            device.hostname,
+                            remote.ip()
+                        ),
+                    )
+                    .await;
+            }
+            if device.pending_approval {
+                state
+                    .manager
+                    .emit_log(
+                        &*state.app,
+                        Some(device.agent_id.clone()),
+                        "WARN",
+                        format!("{} registered and is awaiting approval", device.hostname),
+                    )
+                    .await;
+            }
+            state
+                .manager
+                .redeliver_pending_tasks(&*state.app, &device.agent_id)
+                .await;
+            state.manager.refresh_duplicate_macs(&*state.app).await;
+            state.manager.rebuild_topology_if_changed(&*state.app).await;
+            continue;
+        }
+
+        let agent_id = match &registered_agent_id {
+            Some(id) => id.clone(),
+            None => continue,
+        };
+        if let Err(reason) = verify_wire(&session_key, &wire, &text, state.manager.now()) {
+            tracing::warn!(
+                "[WS] rejected type={} agent_id={}: {}",
+                wire.message_type,
+                agent_id,
+                reason
+            );
+            state
+                .manager
+                .emit_activity(
+                    &*state.app,
+                    "auth_failure",
+                    Some(agent_id.clone()),
+                    format!(
+                        "Rejected {} from {}: {}",
+                        wire.message_type,
+                        remote.ip(),
+                        reason
+                    ),
+                )
+                .await;
+            continue;
+        }
+
+        match wire.message_type.as_str() {
+            "heartbeat" => {
+                if let Ok(payload) = serde_json::from_value::<HeartbeatPayload>(wire.payload) {
+                    tracing::debug!("[WS] heartbeat agent_id={}", agent_id);
+                    let now = state.manager.now();
+                    let (
+                        device_opt,
+                        status_changed,
+                        internet_changed,
+                        dns_changed,
+                        arp_conflict,
+                        ports_changed,
+                        peripherals_changed,
+                        mac_changed,
+                        bad_network,
+                    ) = {
+                        let mut guard = state.manager.inner.lock().await;
+                        let runtime = &mut *guard;
+                        if let Some(device) = runtime.devices.get_mut(&agent_id) {
+                            let old_status = device.status.clone();
+                            let old_internet = device.internet_reachable;
+                            let old_dns = device.dns_ok;
+                            let old_mac = device.mac.clone();
+                            let was_bad_network = device.bad_network_detection;
+
+                            device.last_seen_ms = if payload.last_seen > 0 {
+                                payload.last_seen
+                            } else {
+                                now
+                            };
+                            if !payload.status.is_empty() {
+                                device.status = payload.status;
+                            }
+                            if let Some(metrics) = payload.metrics {
+                                if let Some(v) = metrics.get("internet_reachable") {
+                                    device.internet_reachable = v.as_bool();
+                                }
+                                if let Some(v) = metrics.get("dns_ok") {
+                                    device.dns_ok = v.as_bool();
+                                }
+                                if let Some(v) = metrics.get("gateway_reachable") {
+                                    device.gateway_reachable = v.as_bool();
+                                }
+                                if let Some(v) = metrics.get("latency_ms") {
+                                    device.latency_ms = v.as_i64();
+                                }
+                                device.extra_metrics = extra_metrics(&metrics);
+                            }
+
+                            apply_network_payload(
+                                device,
+                                &payload.network,
+                                runtime.network_overrides.get(&agent_id),
+                            );
+                            apply_alias_rules(&runtime.alias_rules, device);
+
+                            let signals = runtime
+                                .connection_signals
+                                .entry(agent_id.clone())
+                                .or_default();
+                            signals.record_heartbeat(now, payload.last_seen, device.latency_ms);
+                            let score = quality::connection_quality(&signals.signals(now));
+                            device.connection_quality = Some(score);
+                            device.connection_band = Some(quality::quality_band(score).to_string());
+
+                            let ports_changed = match payload.local_ports {
+                                Some(mut ports) => {
+                                    ports.sort_unstable();
+                                    ports.dedup();
+                                    ports.truncate(MAX_LOCAL_PORTS);
+                                    let previous = device.local_ports.replace(ports.clone());
+                                    match previous {
+                                        Some(old) if old != ports => Some((old, ports)),
+                                        _ => None,
+                                    }
+                                }
+                                None => None,
+                            };
+
+                            let peripherals_changed = match payload.peripherals {
+                                Some(reported) => {
+                                    let current = normalize_peripherals(reported);
+                                    match device.peripherals.replace(current.clone()) {
+                                        Some(old) if old != current => {
+                                            Some(diff_peripherals(&old, &current))
+                                        }
+                                        _ => None,
+                                    }
+                                }
+                                None => None,
+                            };
+
+                            let conflict_mac = gateway_arp_contradiction(device);
+                            let arp_conflict =
+                                if conflict_mac.is_some() != device.gateway_arp_conflict {
+                                    device.gateway_arp_conflict = conflict_mac.is_some();
+                                    Some(conflict_mac)
+                                } else {
+                                    None
+                                };
+
+                            let status_changed = if old_status != device.status {
+                                Some((old_status, device.status.clone()))
+                            } else {
+                                None
+                            };
+
+                            let internet_changed = if old_internet != device.internet_reachable {
+                                device.last_internet_change_ms = Some(now);
+                                Some((old_internet, device.internet_reachable))
+                            } else {
+                                None
+                            };
+
+                            let dns_changed = if old_dns != device.dns_ok {
+                                device.last_dns_change_ms = Some(now);
+                                Some((old_dns, device.dns_ok))
+                            } else {
+                                None
+                            };
+
+                            (
+                                Some(device.clone()),
+                                status_changed,
+                                internet_changed,
+                                dns_changed,
+                                arp_conflict,
+                                ports_changed,
+                                peripherals_changed,
+                                old_mac != device.mac,
+                                !was_bad_network && device.bad_network_detection,
+                            )
+                        } else {
+                            (None, None, None, None, None, None, None, false, false)
+                        }
+                    };
+
+                    if let Some(device) = device_opt {
+                        state.manager.persist_heartbeat(&device);
+                        state
+                            .manager
+                            .emit_device_upsert_if_needed(&*state.app, device.clone(), false)
+                            .await;
+
+                        if let Some((old, new)) = ports_changed {
+                            let opened: Vec<u16> =
+                                new.iter().filter(|p| !old.contains(p)).copied().collect();
+                            let closed: Vec<u16> =
+                                old.iter().filter(|p| !new.contains(p)).copied().collect();
+                            state
+                                .manager
+                                .emit_activity(
+                                    &*state.app,
+                                    "ports_changed",
+                                    Some(device.agent_id.clone()),
+                                    format!(
+                                        "{} listening ports opened {:?} closed {:?}",
+                                        device.hostname, opened, closed
+                                    ),
+                                )
+                                .await;
+                        }
+
+                        if let Some((added, removed)) = peripherals_changed {
+                            let describe = |list: &[Peripheral]| {
+                                list.iter()
+                                    .map(|p| format!("{} {}", p.kind, p.name))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            };
+                            state
+                                .manager
+                                .emit_activity(
+                                    &*state.app,
+                                    "peripherals_changed",
+                                    Some(device.agent_id.clone()),
+                                    format!(
+                                        "{} peripherals added [{}] removed [{}]",
+                                        device.hostname,
+                                        describe(&added),
+                                        describe(&removed)
+                                    ),
+                                )
+                                .await;
+                        }
+
+                        if let Some((old, new)) = status_changed {
+                            state
+                                .manager
+                                .emit_activity(
+                                    &*state.app,
+                                    "device_status_changed",
+                                    Some(device.agent_id.clone()),
+                                    format!("{} status {} -> {}", device.hostname, old, new),
+                                )
+                                .await;
+                        }
+                        if let Some((old, new)) = internet_changed {
+                            state
+                                .manager
+                                .emit_log(
+                                    &*state.app,
+                                    Some(device.agent_id.clone()),
+                                    "INFO",
+                                    format!("internet_reachable changed {:?} -> {:?}", old, new),
+                                )
+                                .await;
+                            state
+                                .manager
+                                .emit_activity(
+                                    &*state.app,
+                                    "internet_status_changed",
+                                    Some(device.agent_id.clone()),
+                                    format!("{} internet {:?} -> {:?}", device.hostname, old, new),
+                                )
+                                .await;
+                        }
+                        match arp_conflict {
+                            Some(Some(mac)) => {
+                                state
+                                    .manager
+                                    .emit_log(
+                                        &*state.app,
+                                        Some(device.agent_id.clone()),
+                                        "WARN",
+                                        format!(
+                                            "{} reports gateway {} unreachable but it is in the ARP table ({})",
+                                            device.hostname,
+                                            device.default_gateway_ip.clone().unwrap_or_default(),
+                                            mac
+                                        ),
+                                    )
+                                    .await;
+                            }
+                            Some(None) => {
+                                state
+                                    .manager
+                                    .emit_log(
+                                        &*state.app,
+                                        Some(device.agent_id.clone()),
+                                        "INFO",
+                                        format!(
+                                            "{} gateway reachability consistent with ARP again",
+                                            device.hostname
+                                        ),
+                                    )
+                                    .await;
+                            }
+                            None => {}
+                        }
+                        if let Some((old, new)) = dns_changed {
+                            state
+                                .manager
+                                .emit_log(
+                                    &*state.app,
+                                    Some(device.agent_id.clone()),
+                                    "INFO",
+                                    format!("dns_ok changed {:?} -> {:?}", old, new),
+                                )
+                                .await;
+                            state
+                                .manager
+                                .emit_activity(
+                                    &*state.app,
+                                    "dns_status_changed",
+                                    Some(device.agent_id.clone()),
+                                    format!("{} dns {:?} -> {:?}", device.hostname, old, new),
+                                )
+                                .await;
+                        }
+                        if bad_network {
+                            state
+                                .manager
+                                .report_bad_network_detection(&*state.app, &device)
+                                .await;
+                        }
+                        if mac_changed {
+                            state.manager.refresh_duplicate_macs(&*state.app).await;
+                        }
+                        state.manager.rebuild_topology_if_changed(&*state.app).await;
+                    }
+                }
+            }
+            "task_ack" => {
+                if let Ok(payload) = serde_json::from_value::<TaskAckPayload>(wire.payload) {
+                    let mut state_guard = state.manager.inner.lock().await;
+                    if let Some(task) = state_guard.tasks.get_mut(&payload.task_id) {
+                        mark_task_acked(task, &agent_id);
+                    }
+                }
+            }
+            "task_result" => {
+                if let Ok(mut payload) = serde_json::from_value::<TaskResultPayload>(wire.payload) {
+                    payload.error = payload.error.map(|e| sanitize::message(&e));
+                    let (maybe_task, route_mismatch, arp_change, completed) = {
+                        let mut guard = state.manager.inner.lock().await;
+                        let runtime = &mut *guard;
+                        if let Some(task) = runtime.tasks.get_mut(&payload.task_id) {
+                            mark_task_acked(task, &agent_id);
+                            let was_open = task.ended_at.is_none();
+                            let mut route_mismatch = None;
+                            let mut arp_change = None;
+                            let result = if task.kind == "route_table" {
+                                let routes = normalize_route_table(payload.result);
+                                let inferred = runtime
+                                    .devices
+                                    .get(&agent_id)
+                                    .and_then(|d| d.default_gateway_ip.clone());
+                                if let Some(reported) = default_route_gateway(&routes) {
+                                    if payload.ok && inferred.as_deref() != Some(reported.as_str())
+                                    {
+                                        route_mismatch = Some((reported, inferred));
+                                    }
+                                }
+                                serde_json::to_value(&routes).unwrap_or_else(|_| json!([]))
+                            } else if task.kind == "arp_snapshot" && payload.ok {
+                                let mut result = payload.result;
+                                let entries: Vec<ArpEntry> = result
+                                    .get("entries")
+                                    .and_then(Value::as_array)
+                                    .map(|lines| {
+                                        lines
+                                            .iter()
+                                            .filter_map(Value::as_str)
+                                            .filter_map(parse_neighbor_line)
+                                            .collect()
+                                    })
+                                    .unwrap_or_default();
+                                if let Some(device) = runtime.devices.get_mut(&agent_id) {
+                                    let (added, removed) =
+                                        diff_arp_entries(&device.arp_snapshot, &entries);
+                                    // The first table has nothing to compare against.
+                                    if !device.arp_snapshot.is_empty()
+                                        && (!added.is_empty() || !removed.is_empty())
+                                    {
+                                        arp_change = Some((
+                                            device.hostname.clone(),
+                                            added.len(),
+                                            removed.len(),
+                                        ));
+                                    }
+                                    if let Value::Object(fields) = &mut result {
+                                        fields.insert("added".to_string(), json!(added));
+                                        fields.insert("removed".to_string(), json!(removed));
+                                    }
+                                    if !entries.is_empty() {
+                                        device.arp_snapshot = entries;
+                                    }
+                                }
+                                result
+                            } else {
+                                payload.result
+                            };
+                            task.results.retain(|r| r.agent_id != agent_id);
+                            task.results.push(TaskResultRecord {
+                                agent_id: agent_id.clone(),
+                                ok: payload.ok,
+                                result,
+                                error_code: normalize_task_error_code(
+                                    payload.ok,
+                                    payload.error_code.as_deref(),
+                                ),
+                                error: payload.error,
+                                ts: state.manager.now(),
+                            });
+                            refresh_pending_agents(task);
+                            // Late results are kept, but a cancelled or timed-out task keeps its status.
+                            if task.pending_agents.is_empty()
+                                && !matches!(task.status.as_str(), "cancelled" | "timeout")
+                            {
+                                task.ended_at = Some(state.manager.now());
+                                task.status = if task.results.iter().all(|r| r.ok) {
+                                    "done".to_string()
+                                } else {
+                                    "failed".to_string()
+                                };
+                            }
+                            let completed = was_open && task.ended_at.is_some();
+                            (Some(task.clone()), route_mismatch, arp_change, completed)
+                        } else {
+                            (None, None, None, false)
+                        }
+                    };
+
+                    if let Some((hostname, added, removed)) = arp_change {
+                        state
+                            .manager
+                            .emit_activity(
+                                &*state.app,
+                                "arp_change",
+                                Some(agent_id.clone()),
+                                format!(
+                                    "{}: {} neighbor(s) appeared, {} gone since last ARP snapshot",
+                                    hostname, added, removed
+                                ),
+                            )
+                            .await;
+                    }
+
+                    if let Some((reported, inferred)) = route_mismatch {
+                        state
+                            .manager
+                            .emit_log(
+                                &*state.app,
+                                Some(agent_id.clone()),
+                                "WARN",
+                                format!(
+                                    "route_table default gateway {} disagrees with inferred gateway {:?}",
+                                    reported, inferred
+                                ),
+                            )
+                            .await;
+                        state
+                            .manager
+                            .emit_activity(
+                                &*state.app,
+                                "route_mismatch",
+                                Some(agent_id.clone()),
+                                format!(
+                                    "Default route via {} but topology uses {}",
+                                    reported,
+                                    inferred.unwrap_or_else(|| "no gateway".to_string())
+                                ),
+                            )
+                            .await;
+                    }
+
+                    if let Some(task) = maybe_task {
+                        state
+                            .manager
+                            .emit_task_update(&*state.app, task.clone())
+                            .await;
+                        if completed {
+                            let _ = state.manager.task_completions.send(task.clone());
+                        }
+                        let kind = if task.status == "failed" {
+                            "task_failed"
+                        } else if task.status == "done" {
+                            "task_completed"
+                        } else {
+                            "task_started"
+                        };
+                        state
+                            .manager
+                            .emit_activity(
+                                &*state.app,
+                                kind,
+                                Some(agent_id),
+                                format!("Task {} status {}", task.task_id, task.status),
+                            )
+                            .await;
+                    }
+                }
+            }
+            "ping_alive" => {
+                let payload =
+                    serde_json::from_value::<PingAlivePayload>(wire.payload).unwrap_or_default();
+                let now = state.manager.now();
+                let updated = {
+                    let mut guard = state.manager.inner.lock().await;
+                    guard.devices.get_mut(&agent_id).map(|device| {
+                        let old_status = device.status.clone();
+                        device.last_seen_ms = if payload.last_seen > 0 {
+                            payload.last_seen
+                        } else {
+                            now
+                        };
+                        device.status = if payload.status.is_empty() {
+                            "online".to_string()
+                        } else {
+                            payload.status
+                        };
+                        (device.clone(), old_status)
+                    })
+                };
+                if let Some((device, old_status)) = updated {
+                    let device_id = device.agent_id.clone();
+                    let last_seen = device.last_seen_ms;
+                    state
+                        .manager
+                        .persist(move |db| db.update_device_last_seen(&device_id, last_seen));
+                    let status_changed = old_status != device.status;
+                    state
+                        .manager
+                        .emit_device_upsert_if_needed(&*state.app, device.clone(), status_changed)
+                        .await;
+                    if status_changed {
+                        state
+                            .manager
+                            .emit_activity(
+                                &*state.app,
+                                "device_status_changed",
+                                Some(device.agent_id.clone()),
+                                format!(
+                                    "{} status {} -> {}",
+                                    device.hostname, old_status, device.status
+                                ),
+                            )
+                            .await;
+                    }
+                }
+            }
+            "control_ack" => {
+                if let Ok(mut payload) = serde_json::from_value::<ControlAckPayload>(wire.payload) {
+                    payload.action = sanitize::field(&payload.action);
+                    payload.error = payload.error.map(|e| sanitize::message(&e));
+                    let device = {
+                        let mut guard = state.manager.inner.lock().await;
+                        guard.devices.get_mut(&agent_id).map(|device| {
+                            device.last_control_action = Some(payload.action.clone());
+                            device.last_control_ack_ms = Some(state.manager.now());
+                            device.last_control_ok = Some(payload.ok);
+                            device.clone()
+                        })
+                    };
+                    if let Some(device) = device {
+                        state
+                            .manager
+                            .emit_device_upsert_if_needed(&*state.app, device, true)
+                            .await;
+                    }
+                    let (level, message) = if payload.ok {
+                        (
+                            "INFO",
+                            format!(
+                                "Control action '{}' acknowledged ({})",
+                                payload.action, payload.request_id
+                            ),
+                        )
+                    } else {
+                        (
+                            "WARN",
+                            format!(
+                                "Control action '{}' failed ({}): {}",
+                                payload.action,
+                                payload.request_id,
+                                payload.error.unwrap_or_default()
+                            ),
+                        )
+                    };
+                    state
+                        .manager
+                        .emit_log(&*state.app, Some(agent_id), level, message)
+                        .await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    write_task.abort();
+    state.manager.untrack_socket(socket_id).await;
+    if let Some(agent_id) = registered_agent_id {
+        tracing::info!("[WS] disconnect agent_id={}", agent_id);
+        state
+            .manager
+            .on_agent_disconnect(&*state.app, agent_id, &tx)
+            .await;
+    }
+}
+
+fn normalize_task_error_code(ok: bool, code: Option<&str>) -> Option<String> {
+    if ok {
+        return None;
+    }
+    let code = code
+        .map(|c| c.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    if TASK_ERROR_CODES.contains(&code.as_str()) {
+        Some(code)
+    } else {
+        Some(TASK_ERROR_CODE_OTHER.to_string())
+    }
+}
+
+fn disconnect_agent(state: &mut RuntimeState, agent_id: &str) -> bool {
+    match state.connections.remove(agent_id) {
+        Some(sender) => {
+            let _ = sender.send(Message::Close(None));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Top-level fields of `current` that differ from `previous`, including ones that vanished (as null).
+fn changed_fields(previous: &Value, current: &Value) -> serde_json::Map<String, Value> {
+    let mut changes = serde_json::Map::new();
+    let (Some(before), Some(after)) = (previous.as_object(), current.as_object()) else {
+        return changes;
+    };
+    for (key, value) in after {
+        if before.get(key) != Some(value) {
+            changes.insert(key.clone(), value.clone());
+        }
+    }
+    for key in before.keys() {
+        if !after.contains_key(key) {
+            changes.insert(key.clone(), Value::Null);
+        }
+    }
+    changes
+}
+
+fn accepts_pair_token(state: &RuntimeState, secret: &str, now: i64) -> bool {
+    secret == state.pair_token
+        || state
+            .previous_pair_token
+            .as_ref()
+            .is_some_and(|(token, until)| secret == token && now < *until)
+}
+
+fn emit_followed<T: Serialize>(
+    app: &dyn EventSink,
+    agent_id: &str,
+    kind: &str,
+    payload: &T,
+    ts: i64,
+) {
+    app.emit(
+        EVENT_FOLLOWED_DEVICE,
+        FollowedDeviceEvent {
+            agent_id: agent_id.to_string(),
+            kind: kind.to_string(),
+            ts,
+            payload: serde_json::to_value(payload).unwrap_or(Value::Null),
+        },
+    );
+}
+
+fn format_duration_ms(ms: i64) -> String {
+    let minutes = ms.max(0) / 60_000;
+    if minutes < 60 {
+        format!("{}m", minutes)
+    } else if minutes < 48 * 60 {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}d {}h", minutes / 1440, minutes % 1440 / 60)
+    }
+}
+
+fn nearest_rank(sorted: &[i64], pct: usize) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+fn record_check(
+    checks: &mut Vec<ProvisioningCheck>,
+    step: &str,
+    started: i64,
+    finished: i64,
+    result: std::result::Result<String, String>,
+) -> Result<()> {
+    let (ok, detail) = match &result {
+        Ok(detail) => (true, detail.clone()),
+        Err(detail) => (false, detail.clone()),
+    };
+    checks.push(ProvisioningCheck {
+        step: step.to_string(),
+        ok,
+        detail,
+        elapsed_ms: finished - started,
+    });
+    result.map(|_| ()).map_err(ServerError::Unavailable)
+}
+
+fn device_facts(device: &DeviceRecord) -> DeviceFacts {
+    DeviceFacts {
+        ip: device.ip.clone(),
+        subnet_cidr: device.subnet_cidr.clone(),
+        default_gateway_ip: device.default_gateway_ip.clone(),
+        dns_ok: device.dns_ok,
+        local_ports: device.local_ports.clone(),
+        status: device.status.clone(),
+    }
+}
+
+fn device_row(device: &DeviceRecord) -> Device {
+    Device {
+        id: device.agent_id.clone(),
+        hostname: device.hostname.clone(),
+        os: device.os.clone(),
+        arch: String::new(),
+        agent_version: device.version.clone(),
+        local_ip: device
+            .ip
+            .clone()
+            .or_else(|| device.ips.first().cloned())
+            .unwrap_or_default(),
+        mac_address: device.mac.clone(),
+        gateway_ip: device.default_gateway_ip.clone(),
+        dns_servers: None,
+        registered_at: device.first_seen_ms,
+        last_seen: device.last_seen_ms,
+        is_online: device.status != "offline",
+        record: serde_json::to_string(device).ok(),
+        record_version: DEVICE_RECORD_VERSION,
+    }
+}
+
+/// Rows from builds that predate the `record` column only have the summary
+/// columns to go on.
+fn device_from_row(row: Device) -> DeviceRecord {
+    if let Some(device) = row
+        .record
+        .as_deref()
+        .filter(|_| row.record_version <= DEVICE_RECORD_VERSION)
+        .and_then(|raw| serde_json::from_str::<DeviceRecord>(raw).ok())
+    {
+        return device;
+    }
+    let ip = clean_non_empty_owned(&row.local_ip);
+    DeviceRecord {
+        device_key: format!("agent:{}", row.id),
+        agent_id: row.id,
+        hostname: row.hostname,
+        ips: ip.iter().cloned().collect(),
+        os: row.os,
+        version: row.agent_version,
+        status: "offline".to_string(),
+        last_seen_ms: row.last_seen,
+        first_seen_ms: row.registered_at,
+        subnet_cidr: ip.as_deref().and_then(netutil::guess_subnet),
+        ip,
+        default_gateway_ip: row.gateway_ip,
+        mac: row.mac_address,
+        ..Default::default()
+    }
+}
+
+fn task_matches(task: &TaskRecord, needle: &str) -> bool {
+    if needle.is_empty() {
+        return true;
+    }
+    task.task_id.to_lowercase().contains(needle)
+        || task.kind.to_lowercase().contains(needle)
+        || task
+            .label
+            .as_deref()
+            .is_some_and(|l| l.to_lowercase().contains(needle))
+        || (!task.metadata.is_null() && task.metadata.to_string().to_lowercase().contains(needle))
+}
+
+fn normalize_route_table(result: Value) -> Vec<RouteEntry> {
+    let rows = match result {
+        Value::Array(rows) => rows,
+        Value::Object(mut obj) => match obj.remove("routes") {
+            Some(Value::Array(rows)) => rows,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+    rows.into_iter()
+        .filter_map(|row| serde_json::from_value::<RouteEntry>(row).ok())
+        .take(MAX_ROUTE_TABLE_ENTRIES)
+        .collect()
+}
+
+fn default_route_gateway(routes: &[RouteEntry]) -> Option<String> {
+    routes
+        .iter()
+        .filter(|r| matches!(r.destination.trim(), "0.0.0.0/0" | "0.0.0.0" | "default"))
+        .filter(|r| ip_to_u32(r.gateway.trim()).is_some_and(|gw| gw != 0))
+        .min_by_key(|r| r.metric.unwrap_or(i64::MAX))
+        .map(|r| r.gateway.trim().to_string())
+}
+
+fn gateway_arp_contradiction(device: &DeviceRecord) -> Option<String> {
+    if device.gateway_reachable != Some(false) {
+        return None;
+    }
+    let gateway = device.default_gateway_ip.as_deref()?;
+    device
+        .arp_snapshot
+        .iter()
+        .find(|entry| entry.ip.trim() == gateway)
+        .and_then(|entry| usable_mac(&entry.mac))
+}
+
+pub(crate) fn usable_mac(raw: &str) -> Option<String> {
+    let mac = raw.trim().to_lowercase().replace('-', ":");
+    if mac.is_empty()
+        || mac == "00:00:00:00:00:00"
+        || mac == "ff:ff:ff:ff:ff:ff"
+        || mac.contains("incomplete")
+    {
+        return None;
+    }
+    Some(mac)
+}
+
+// Only non-empty incoming facts overwrite the record, so a heartbeat from a
+// failed detection cycle keeps the last known good values.
+fn apply_network_payload(
+    device: &mut DeviceRecord,
+    network: &NetworkFactsPayload,
+    correction: Option<&NetworkFactsPayload>,
+) {
+    let reported_ip = clean_non_empty_owned(&network.ip);
+    let bad_ips = device
+        .ips
+        .iter()
+        .any(|ip| netutil::is_unroutable_host_ip(ip));
+    // A failed detection cycle reports no ip; that alone doesn't clear the flag.
+    device.bad_network_detection = match &reported_ip {
+        Some(ip) => netutil::is_unroutable_host_ip(ip) || bad_ips,
+        None => device.bad_network_detection || bad_ips,
+    };
+
+    let usable = |ip: &String| !netutil::is_unroutable_host_ip(ip);
+    let previous_ip = device
+        .ip
+        .take()
+        .filter(|ip| device.ips.is_empty() || device.ips.contains(ip))
+        .filter(usable);
+    device.ip = reported_ip.filter(usable).or(previous_ip).or_else(|| {
+        device
+            .ips
+            .iter()
+            .filter_map(|candidate| clean_non_empty_owned(candidate))
+            .find(usable)
+    });
+
+    // Agents may report the host address with its prefix; store the network it masks to.
+    let reported_subnet = clean_non_empty_owned(&network.subnet_cidr)
+        .filter(|subnet| subnet.contains('/'))
+        .and_then(|subnet| {
+            let (addr, prefix) = netutil::parse_cidr(&subnet)?;
+            netutil::network_cidr(&addr.to_string(), prefix)
+        })
+        .filter(|subnet| {
+            netutil::network_address(subnet)
+                .is_some_and(|network| !netutil::is_unroutable_host_ip(&network.to_string()))
+        });
+    match reported_subnet {
+        Some(subnet) => device.subnet_cidr = Some(subnet),
+        None => {
+            let still_valid = match (&device.subnet_cidr, &device.ip) {
+                (Some(subnet), Some(ip)) => netutil::contains(subnet, ip),
+                _ => false,
+            };
+            if !still_valid {
+                let known_prefix = device
+                    .subnet_cidr
+                    .as_deref()
+                    .filter(|subnet| subnet.contains('/'))
+                    .and_then(netutil::parse_cidr)
+                    .map(|(_, prefix)| prefix);
+                device.subnet_cidr = device.ip.as_deref().and_then(|ip| match known_prefix {
+                    Some(prefix) => netutil::network_cidr(ip, prefix),
+                    None => netutil::guess_subnet(ip),
+                });
+            }
+        }
+    }
+
+    keep_non_empty(
+        &mut device.default_gateway_ip,
+        Some(&network.default_gateway_ip),
+    );
+    keep_non_empty(&mut device.interface_type, Some(&network.interface_type));
+    keep_non_empty(&mut device.mac, network.mac.as_ref());
+    keep_non_empty(&mut device.gateway_mac, network.gateway_mac.as_ref());
+    keep_non_empty(&mut device.dhcp_server_ip, network.dhcp_server_ip.as_ref());
+    keep_non_empty(&mut device.ssid, network.ssid.as_ref());
+    if !network.arp_snapshot.is_empty() {
+        device.arp_snapshot = network.arp_snapshot.clone();
+    }
+    // Unlike the facts above, a tag that stops being reported is gone (e.g. the
+    // port was moved to an untagged VLAN), so it isn't kept.
+    device.vlan_id = network.vlan_id.filter(|v| (1..=4094).contains(v));
+    if let Some(ipv6) = network
+        .ipv6
+        .as_deref()
+        .and_then(clean_non_empty_owned)
+        .filter(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok())
+        .filter(|ip| !netutil::is_unroutable_host_ip(ip))
+    {
+        let prefix = network
+            .ipv6_cidr
+            .as_deref()
+            .and_then(|cidr| cidr.split_once('/'))
+            .map(|(_, prefix)| prefix.trim().to_string());
+        device.ipv6_cidr = match prefix {
+            Some(prefix) => netutil::network_cidr_v6(&format!("{}/{}", ipv6, prefix)),
+            None => netutil::network_cidr_v6(&ipv6),
+        };
+        device.ipv6 = Some(ipv6);
+    }
+    apply_network_override(device, correction);
+}
+
+/// Writes every field the operator set over whatever the agent reported,
+/// and records which ones those were.
+fn apply_network_override(device: &mut DeviceRecord, correction: Option<&NetworkFactsPayload>) {
+    device.overridden_network_fields.clear();
+    let Some(correction) = correction else {
+        return;
+    };
+    let mut set = |name: &str, field: &mut Option<String>, value: Option<&String>| {
+        if let Some(value) = value.and_then(|v| clean_non_empty_owned(v)) {
+            *field = Some(value);
+            device.overridden_network_fields.push(name.to_string());
+        }
+    };
+    set("ip", &mut device.ip, Some(&correction.ip));
+    set(
+        "subnet_cidr",
+        &mut device.subnet_cidr,
+        Some(&correction.subnet_cidr),
+    );
+    set(
+        "default_gateway_ip",
+        &mut device.default_gateway_ip,
+        Some(&correction.default_gateway_ip),
+    );
+    set(
+        "interface_type",
+        &mut device.interface_type,
+        Some(&correction.interface_type),
+    );
+    set("mac", &mut device.mac, correction.mac.as_ref());
+    set(
+        "gateway_mac",
+        &mut device.gateway_mac,
+        correction.gateway_mac.as_ref(),
+    );
+    set(
+        "dhcp_server_ip",
+        &mut device.dhcp_server_ip,
+        correction.dhcp_server_ip.as_ref(),
+    );
+    set("ssid", &mut device.ssid, correction.ssid.as_ref());
+    if let Some(vlan) = correction.vlan_id {
+        device.vlan_id = Some(vlan);
+        device.overridden_network_fields.push("vlan_id".to_string());
+    }
+}
+
+fn validate_network_override(facts: &NetworkFactsPayload) -> Result<()> {
+    let invalid = |msg: String| Err(ServerError::InvalidInput(msg));
+    let ipv4_fields = [
+        ("ip", Some(&facts.ip)),
+        ("default_gateway_ip", Some(&facts.default_gateway_ip)),
+        ("dhcp_server_ip", facts.dhcp_server_ip.as_ref()),
+    ];
+    for (name, value) in ipv4_fields {
+        if let Some(value) = value.and_then(|v| clean_non_empty_owned(v)) {
+            if netutil::ip_to_u32(&value).is_none() {
+                return invalid(format!("{} is not an IPv4 address: {}", name, value));
+            }
+        }
+    }
+    if let Some(subnet) = clean_non_empty_owned(&facts.subnet_cidr) {
+        if !subnet.contains('/') || netutil::parse_cidr(&subnet).is_none() {
+            return invalid(format!("subnet_cidr is not a CIDR: {}", subnet));
+        }
+    }
+    if let Some(vlan) = facts.vlan_id {
+        if !(1..=4094).contains(&vlan) {
+            return invalid(format!("vlan_id must be between 1 and 4094: {}", vlan));
+        }
+    }
+    let mut probe = DeviceRecord::default();
+    apply_network_override(&mut probe, Some(facts));
+    if probe.overridden_network_fields.is_empty() {
+        return invalid("override sets no fields".to_string());
+    }
+    Ok(())
+}
+
+fn keep_non_empty(field: &mut Option<String>, incoming: Option<&String>) {
+    if let Some(value) = incoming.and_then(|v| clean_non_empty_owned(&sanitize::field(v))) {
+        *field = Some(value);
+    }
+}
+
+fn devices_key(devices: &HashMap<String, DeviceRecord>) -> String {
+    let mut parts: Vec<String> = devices
+        .values()
+        .map(|d| {
+            format!(
+                "{}|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+                d.agent_id,
+                d.device_key,
+                d.status,
+                d.hostname,
+                d.version,
+                d.ip.clone().unwrap_or_default(),
+                d.subnet_cidr.clone().unwrap_or_default(),
+                d.default_gateway_ip.clone().unwrap_or_default(),
+                d.mac.clone().unwrap_or_default(),
+                d.vlan_id.map(|v| v.to_string()).unwrap_or_default()
+            )
+        })
+        .collect();
+    parts.sort();
+    parts.join(";")
+}
+
+fn detect_admin_network_facts() -> NetworkFactsPayload {
+    let ip = detect_local_ipv4_string().unwrap_or_else(|| "127.0.0.1".to_string());
+    let default_gateway_ip = detect_default_gateway_ip().unwrap_or_default();
+    let subnet_cidr = netutil::guess_subnet(&ip).unwrap_or_else(|| "127.0.0.0/24".to_string());
+    let arp_snapshot = detect_neighbor_table();
+    let gateway_mac = arp_snapshot
+        .iter()
+        .find(|entry| !default_gateway_ip.is_empty() && entry.ip == default_gateway_ip)
+        .map(|entry| entry.mac.clone());
+
+    NetworkFactsPayload {
+        mac: detect_interface_mac(&ip),
+        ip,
+        subnet_cidr,
+        default_gateway_ip,
+        interface_type: detect_interface_type(),
+        gateway_mac,
+        dhcp_server_ip: None,
+        ssid: detect_ssid(),
+        arp_snapshot,
+        vlan_id: None,
+        ipv6: None,
+        ipv6_cidr: None,
+    }
+}
+
+fn detect_interface_mac(ip: &str) -> Option<String> {
+    if cfg!(target_os = "windows") {
+        let out = command_output("getmac", &["/v", "/fo", "csv", "/nh"])?;
+        for line in out.lines() {
+            let fields: Vec<&str> = line
+                .split(',')
+                .map(|f| f.trim().trim_matches('"'))
+                .collect();
+            if fields.len() >= 4 && fields[3].starts_with("\\Device\\Tcpip") {
+                if let Some(mac) = usable_mac(fields[2]) {
+                    return Some(mac);
+                }
+            }
+        }
+        return None;
+    }
+
+    if cfg!(target_os = "linux") {
+        let addrs = command_output("ip", &["-o", "-4", "addr", "show"])?;
+        let iface = addrs.lines().find_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let inet = parts.iter().position(|p| *p == "inet")?;
+            let addr = parts.get(inet + 1)?.split('/').next()?;
+            if addr == ip {
+                parts
+                    .get(1)
+                    .map(|name| name.trim_end_matches(':').to_string())
+            } else {
+                None
+            }
+        })?;
+        let link = command_output("ip", &["-o", "link", "show", "dev", &iface])?;
+        let parts: Vec<&str> = link.split_whitespace().collect();
+        let idx = parts.iter().position(|p| *p == "link/ether")?;
+        return parts.get(idx + 1).and_then(|mac| usable_mac(mac));
+    }
+
+    let out = command_output("ifconfig", &[])?;
+    let mut block_mac: Option<String> = None;
+    let mut block_has_ip = false;
+    for line in out.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            if block_has_ip && block_mac.is_some() {
+                return block_mac;
+            }
+            block_mac = None;
+            block_has_ip = false;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.first().copied() {
+            Some("ether") => block_mac = parts.get(1).and_then(|mac| usable_mac(mac)),
+            Some("inet") => block_has_ip |= parts.get(1) == Some(&ip),
+            _ => {}
+        }
+    }
+    if block_has_ip {
+        block_mac
+    } else {
+        None
+    }
+}
+
+fn detect_neighbor_table() -> Vec<ArpEntry> {
+    let out = if cfg!(target_os = "linux") {
+        command_output("ip", &["neigh", "show"])
+    } else {
+        command_output("arp", &["-a", "-n"]).or_else(|| command_output("arp", &["-a"]))
+    };
+    out.map(|out| out.lines().filter_map(parse_neighbor_line).collect())
+        .unwrap_or_default()
+}
+
+fn parse_neighbor_line(line: &str) -> Option<ArpEntry> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    // Linux: "192.168.1.1 dev eth0 lladdr aa:bb:... REACHABLE";
+    // Windows: "192.168.1.1  aa-bb-cc-dd-ee-ff  dynamic"; BSD: "? (192.168.1.1) at aa:bb:... on en0"
+    let (ip, mac) = if let Some(i) = parts.iter().position(|p| *p == "lladdr") {
+        (parts.first().copied(), parts.get(i + 1))
+    } else if parts.get(2) == Some(&"at") {
+        (
+            parts.get(1).map(|p| p.trim_matches(['(', ')'])),
+            parts.get(3),
+        )
+    } else {
+        (parts.first().copied(), parts.get(1))
+    };
+    let ip = ip.filter(|ip| ip_to_u32(ip).is_some())?;
+    let mac = usable_mac(mac?)?;
+    let octets: Vec<&str> = mac.split(':').collect();
+    if octets.len() != 6 || !octets.iter().all(|o| u8::from_str_radix(o, 16).is_ok()) {
+        return None;
+    }
+    Some(ArpEntry {
+        ip: ip.to_string(),
+        mac,
+    })
+}
+
+/// Neighbors present now but not before, and before but not now, by IP and MAC.
+fn diff_arp_entries(previous: &[ArpEntry], current: &[ArpEntry]) -> (Vec<ArpEntry>, Vec<ArpEntry>) {
+    let key = |e: &ArpEntry| (e.ip.clone(), e.mac.clone());
+    let before: HashSet<(String, String)> = previous.iter().map(key).collect();
+    let after: HashSet<(String, String)> = current.iter().map(key).collect();
+    let added = current
+        .iter()
+        .filter(|e| !before.contains(&key(e)))
+        .cloned()
+        .collect();
+    let removed = previous
+        .iter()
+        .filter(|e| !after.contains(&key(e)))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+/// Sanitized, de-duplicated and capped at [`MAX_PERIPHERALS`] so a chatty agent can't bloat records.
+fn normalize_peripherals(reported: Vec<Peripheral>) -> Vec<Peripheral> {
+    let mut peripherals: Vec<Peripheral> = reported
+        .into_iter()
+        .map(|p| Peripheral {
+            kind: sanitize::field(&p.kind).to_ascii_lowercase(),
+            name: sanitize::field(&p.name),
+            id: sanitize::field(&p.id),
+        })
+        .filter(|p| !p.kind.is_empty() && (!p.name.is_empty() || !p.id.is_empty()))
+        .collect();
+    peripherals.sort();
+    peripherals.dedup();
+    peripherals.truncate(MAX_PERIPHERALS);
+    peripherals
+}
+
+/// Unrecognized heartbeat metrics, keyed by identifier-safe names; oversized
+/// values are dropped and at most [`MAX_EXTRA_METRICS`] are kept.
+fn extra_metrics(metrics: &Value) -> serde_json::Map<String, Value> {
+    let Some(reported) = metrics.as_object() else {
+        return serde_json::Map::new();
+    };
+    reported
+        .iter()
+        .filter(|(key, _)| !KNOWN_METRICS.contains(&key.as_str()))
+        .filter_map(|(key, value)| {
+            let key = sanitize::identifier(key)?;
+            let size = serde_json::to_string(value).map(|v| v.len()).ok()?;
+            (size <= MAX_EXTRA_METRIC_BYTES).then(|| (key, value.clone()))
+        })
+        .take(MAX_EXTRA_METRICS)
+        .collect()
+}
+
+fn normalize_metric_schema(declared: Vec<MetricSchema>) -> Vec<MetricSchema> {
+    let mut schema: Vec<MetricSchema> = Vec::new();
+    for entry in declared {
+        let Some(name) = sanitize::identifier(&entry.name) else {
+            continue;
+        };
+        if KNOWN_METRICS.contains(&name.as_str()) || schema.iter().any(|s| s.name == name) {
+            continue;
+        }
+        schema.push(MetricSchema {
+            name,
+            unit: entry
+                .unit
+                .and_then(|v| clean_non_empty_owned(&sanitize::field(&v))),
+            display: entry.display.as_deref().and_then(sanitize::identifier),
+        });
+        if schema.len() == MAX_EXTRA_METRICS {
+            break;
+        }
+    }
+    schema
+}
+
+fn diff_peripherals(
+    previous: &[Peripheral],
+    current: &[Peripheral],
+) -> (Vec<Peripheral>, Vec<Peripheral>) {
+    let added = current
+        .iter()
+        .filter(|p| !previous.contains(p))
+        .cloned()
+        .collect();
+    let removed = previous
+        .iter()
+        .filter(|p| !current.contains(p))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+pub(crate) fn detect_local_ipv4_string() -> Option<String> {
+    detect_local_ipv4().map(|ip| ip.to_string())
+}
+
+fn detect_default_gateway_ip() -> Option<String> {
+    if cfg!(target_os = "windows") {
+        let out = command_output("route", &["print", "-4"])?;
+        for line in out.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() >= 3
+                && fields[0] == "0.0.0.0"
+                && fields[1] == "0.0.0.0"
+                && ip_to_u32(fields[2]).is_some()
+            {
+                return Some(fields[2].to_string());
+            }
+        }
+    }
+
+    if cfg!(target_os = "linux") {
+        let out = command_output("ip", &["route", "show", "default"])?;
+        let parts: Vec<&str> = out.split_whitespace().collect();
+        for i in 0..parts.len().saturating_sub(1) {
+            if parts[i] == "via" && ip_to_u32(parts[i + 1]).is_some() {
+                return Some(parts[i + 1].to_string());
+            }
+        }
+    }
+    None
+}
+
+fn detect_interface_type() -> String {
+    if cfg!(target_os = "windows") {
+        if let Some(out) = command_output("netsh", &["wlan", "show", "interfaces"]) {
+            let lower = out.to_lowercase();
+            if lower.contains("ssid") && !lower.contains("there is no wireless interface") {
+                return "wifi".to_string();
+            }
+        }
+    }
+    "ethernet".to_string()
+}
+
+fn detect_ssid() -> Option<String> {
+    if !cfg!(target_os = "windows") {
+        return None;
+    }
+    let out = command_output("netsh", &["wlan", "show", "interfaces"])?;
+    for line in out.lines() {
+        let trimmed = line.trim();
+        let lower = trimmed.to_lowercase();
+        if lower.starts_with("ssid") && !lower.starts_with("bssid") {
+            let parts: Vec<&str> = trimmed.splitn(2, ':').collect();
+            if parts.len() == 2 {
+                let ssid = parts[1].trim();
+                if !ssid.is_empty() {
+                    return Some(ssid.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn command_output(command: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(command).args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8(out.stdout).ok()
+}
+
+// Sends the task to assigned agents that have neither acknowledged nor answered it.
+fn send_task_to_agents(
+    task: &mut TaskRecord,
+    connections: &HashMap<String, mpsc::UnboundedSender<Message>>,
+    only_agent: Option<&str>,
+    now: i64,
+) -> usize {
+    let payload = serde_json::to_value(TaskDispatchPayload {
+        task_id: task.task_id.clone(),
+        kind: task.kind.clone(),
+        params: task.params.clone(),
+        timeout_ms: task.timeout_ms,
+    })
+    .unwrap_or_else(|_| json!({}));
+    let mut sent = 0;
+    for agent in task.assigned_agents.clone() {
+        if only_agent.is_some_and(|only| only != agent)
+            || task.acked_by.contains(&agent)
+            || task.results.iter().any(|r| r.agent_id == agent)
+        {
+            continue;
+        }
+        let Some(sender) = connections.get(&agent) else {
+            continue;
+        };
+        if let Some(msg) = wire_frame("task", &agent, payload.clone(), now) {
+            if sender.send(msg).is_ok() {
+                if !task.delivered_to.contains(&agent) {
+                    task.delivered_to.push(agent);
+                }
+                sent += 1;
+            }
+        }
+    }
+    sent
+}
+
+fn mark_task_acked(task: &mut TaskRecord, agent_id: &str) {
+    if !task.acked_by.iter().any(|a| a == agent_id) {
+        task.acked_by.push(agent_id.to_string());
+    }
+}
+
+fn fact_changes(before: Option<&DeviceFacts>, after: Option<&DeviceFacts>) -> Vec<FieldDiff> {
+    let before = serde_json::to_value(before).unwrap_or(Value::Null);
+    let after = serde_json::to_value(after).unwrap_or(Value::Null);
+    DEVICE_FACT_FIELDS
+        .iter()
+        .filter_map(|field| {
+            let before = before.get(field).cloned().unwrap_or(Value::Null);
+            let after = after.get(field).cloned().unwrap_or(Value::Null);
+            (before != after).then(|| FieldDiff {
+                field: field.to_string(),
+                before,
+                after,
+            })
+        })
+        .collect()
+}
+
+/// Rejects params an agent could not act on. A `traceroute` result is an
+/// array of `{hop, ip, rtt_ms}` objects, with `ip`/`rtt_ms` null for hops
+/// that didn't answer. A `dns_lookup` result is
+/// `{resolved: [String], resolver_ip: String, elapsed_ms: i64}`.
+fn validate_task_params(kind: &str, params: &Value) -> Result<()> {
+    let invalid = |message: String| Err(ServerError::InvalidInput(message));
+    let field = |name: &str| params.get(name).filter(|v| !v.is_null());
+    let target = field("target")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .unwrap_or_default();
+    let is_port = |v: &Value| v.as_u64().is_some_and(|p| (1..=65535).contains(&p));
+
+    match kind {
+        "ping" => {
+            match field("targets") {
+                Some(targets) => {
+                    let Some(targets) = targets
+                        .as_array()
+                        .filter(|t| (1..=MAX_PING_TARGETS).contains(&t.len()))
+                    else {
+                        return invalid(format!(
+                            "targets must be a list of 1 to {} hosts",
+                            MAX_PING_TARGETS
+                        ));
+                    };
+                    if targets
+                        .iter()
+                        .any(|t| t.as_str().is_none_or(|t| t.trim().is_empty()))
+                    {
+                        return invalid("targets must be non-empty host names".to_string());
+                    }
+                }
+                None if target.is_empty() => {
+                    return invalid("ping needs a target or targets".to_string());
+                }
+                None => {}
+            }
+            if let Some(count) = field("count") {
+                if !count.as_u64().is_some_and(|c| (1..=100).contains(&c)) {
+                    return invalid("count must be between 1 and 100".to_string());
+                }
+            }
+        }
+        "port_scan" => match (field("ports"), field("port_range")) {
+            (Some(ports), _) => {
+                let Some(ports) = ports.as_array().filter(|p| !p.is_empty()) else {
+                    return invalid("ports must be a non-empty list".to_string());
+                };
+                if let Some(bad) = ports.iter().find(|p| !is_port(p)) {
+                    return invalid(format!("port {} is not between 1 and 65535", bad));
+                }
+            }
+            (None, Some(range)) => {
+                let (Some(start), Some(end)) = (range.get("start"), range.get("end")) else {
+                    return invalid("port_range needs start and end".to_string());
+                };
+                if !is_port(start) || !is_port(end) {
+                    return invalid("port_range must be between 1 and 65535".to_string());
+                }
+                if start.as_u64() > end.as_u64() {
+                    return invalid(format!("port_range start {} is after end {}", start, end));
+                }
+            }
+            (None, None) => {
+                return invalid("port_scan needs ports or port_range".to_string());
+            }
+        },
+        "traceroute" => {
+            if target.is_empty() {
+                return invalid("traceroute needs a target".to_string());
+            }
+            if target.parse::<std::net::Ipv4Addr>().is_err() && !netutil::is_hostname(target) {
+                return invalid(format!(
+                    "traceroute target is not an IPv4 address or hostname: {}",
+                    target
+                ));
+            }
+            if let Some(max_hops) = field("max_hops") {
+                if !max_hops
+                    .as_u64()
+                    .is_some_and(|v| (1..=u64::from(u8::MAX)).contains(&v))
+                {
+                    return invalid("max_hops must be between 1 and 255".to_string());
+                }
+            }
+        }
+        "dns_lookup" => {
+            let hostname = field("hostname")
+                .and_then(Value::as_str)
+                .map(str::trim)
+                .unwrap_or_default();
+            if hostname.is_empty() {
+                return invalid("dns_lookup needs a hostname".to_string());
+            }
+            if !netutil::is_hostname(hostname) {
+                return invalid(format!("not a valid hostname: {}", hostname));
+            }
+            if let Some(record_type) = field("record_type") {
+                if !record_type
+                    .as_str()
+                    .is_some_and(|t| matches!(t, "A" | "AAAA" | "CNAME" | "MX"))
+                {
+                    return invalid("record_type must be one of A, AAAA, CNAME or MX".to_string());
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn refresh_pending_agents(task: &mut TaskRecord) {
+    task.pending_agents = task
+        .assigned_agents
+        .iter()
+        .filter(|id| !task.results.iter().any(|r| &r.agent_id == *id))
+        .cloned()
+        .collect();
+}
+
+fn wire_frame(message_type: &str, agent_id: &str, payload: Value, ts: i64) -> Option<Message> {
+    let msg = WireMessage {
+        message_type: message_type.to_string(),
+        ts,
+        agent_id: agent_id.to_string(),
+        payload,
+        sig: None,
+    };
+    serde_json::to_string(&msg).ok().map(Message::Text)
+}
+
+fn wire_mac(key: &str, message_type: &str, ts: i64, agent_id: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac accepts any key length");
+    mac.update(format!("{}.{}.{}.{}", message_type, ts, agent_id, payload).as_bytes());
+    mac
+}
+
+/// Hex HMAC-SHA256 an agent puts in `sig`, keyed by the secret it registered with.
+fn wire_signature(key: &str, message_type: &str, ts: i64, agent_id: &str, payload: &str) -> String {
+    hex::encode(
+        wire_mac(key, message_type, ts, agent_id, payload)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Checks `sig` against the raw frame and bounds replay to `WIRE_MAX_SKEW_MS`.
+fn verify_wire(
+    key: &str,
+    wire: &WireMessage,
+    text: &str,
+    now: i64,
+) -> std::result::Result<(), &'static str> {
+    if (now - wire.ts).abs() > WIRE_MAX_SKEW_MS {
+        return Err("timestamp outside the replay window");
+    }
+    let sig = wire.sig.as_deref().ok_or("missing signature")?;
+    let sig = hex::decode(sig).map_err(|_| "malformed signature")?;
+    let raw = serde_json::from_str::<WirePayloadText>(text).map_err(|_| "unreadable payload")?;
+    wire_mac(
+        key,
+        &wire.message_type,
+        wire.ts,
+        &wire.agent_id,
+        raw.payload.get(),
+    )
+    .verify_slice(&sig)
+    .map_err(|_| "signature mismatch")
+}
+
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parse = |value: &str| -> Vec<u64> {
+        value
+            .trim()
+            .trim_start_matches(['v', 'V'])
+            .split(['.', '-', '+'])
+            .map(|part| {
+                part.chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect::<String>()
+                    .parse::<u64>()
+                    .unwrap_or(0)
+            })
+            .collect()
+    };
+    let a_parts = parse(a);
+    let b_parts = parse(b);
+    for i in 0..a_parts.len().max(b_parts.len()) {
+        let x = a_parts.get(i).copied().unwrap_or(0);
+        let y = b_parts.get(i).copied().unwrap_or(0);
+        if x != y {
+            return x.cmp(&y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn detect_nat(remote: &SocketAddr, device: &DeviceRecord) -> bool {
+    let observed = match remote.ip().to_canonical() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return false,
+    };
+    if observed.is_loopback() {
+        return false;
+    }
+    let observed = observed.to_string();
+    if device.ip.as_deref() == Some(observed.as_str())
+        || device.ips.iter().any(|ip| ip.trim() == observed)
+    {
+        return false;
+    }
+    match (device.subnet_cidr.as_deref(), device.ip.as_deref()) {
+        (Some(cidr), Some(reported)) => !netutil::same_subnet(&observed, reported, cidr),
+        (Some(cidr), None) => !netutil::contains(cidr, &observed),
+        // Without a reported subnet there's nothing to compare against.
+        (None, _) => false,
+    }
+}
+
+pub(crate) fn clean_non_empty_owned(value: &str) -> Option<String> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+fn detect_local_ipv4() -> Option<std::net::Ipv4Addr> {
+    let udp = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    udp.connect("8.8.8.8:80").ok()?;
+    match udp.local_addr().ok()?.ip() {
+        std::net::IpAddr::V4(ip) => Some(ip),
+        _ => None,
+    }
+}
+
+/// Global broadcast plus each up, non-loopback IPv4 interface's directed broadcast.
+/// Falls back to the global address alone when interfaces can't be listed.
+fn broadcast_targets() -> (Vec<std::net::Ipv4Addr>, Option<String>) {
+    let mut targets = vec![std::net::Ipv4Addr::BROADCAST];
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(err) => return (targets, Some(err.to_string())),
+    };
+    for iface in interfaces {
+        if let if_addrs::IfAddr::V4(v4) = iface.addr {
+            let directed = v4
+                .broadcast
+                .filter(|b| !b.is_broadcast() && !b.is_unspecified())
+                .or_else(|| netutil::directed_broadcast(v4.ip, v4.netmask));
+            if let Some(addr) = directed.filter(|_| !v4.ip.is_loopback()) {
+                if !targets.contains(&addr) {
+                    targets.push(addr);
+                }
+            }
+        }
+    }
+    (targets, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn test_manager() -> ServerManager {
+        clocked_manager(Arc::new(SystemClock))
+    }
+
+    fn clocked_manager(clock: Arc<dyn Clock>) -> ServerManager {
+        let db = Database::new(rusqlite::Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        ServerManager::with_clock(Arc::new(Mutex::new(db)), LabScanConfig::default(), clock)
+            .unwrap()
+    }
+
+    #[test]
+    fn startup_fails_instead_of_skipping_saved_state() {
+        let db = Database::new(rusqlite::Connection::open_in_memory().unwrap());
+        db.create_tables().unwrap();
+        let pool: DbPool = Arc::new(Mutex::new(db));
+        let held = pool.try_lock().unwrap();
+        let result = ServerManager::new(pool.clone(), LabScanConfig::default());
+        assert!(matches!(result, Err(ServerError::Unavailable(_))));
+        drop(held);
+        assert!(ServerManager::new(pool, LabScanConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn arp_lines_parse_across_platforms_and_diff_by_ip_and_mac() {
+        let previous: Vec<ArpEntry> = [
+            "192.168.1.1 dev eth0 lladdr aa:bb:cc:dd:ee:01 REACHABLE",
+            "? (192.168.1.20) at aa:bb:cc:dd:ee:14 on en0 ifscope [ethernet]",
+            "192.168.1.51 aa-bb-cc-dd-ee-51 dynamic",
+        ]
+        .into_iter()
+        .filter_map(parse_neighbor_line)
+        .collect();
+        assert_eq!(previous.len(), 3);
+        assert_eq!(previous[1].ip, "192.168.1.20");
+        assert_eq!(previous[2].mac, "aa:bb:cc:dd:ee:51");
+        assert!(parse_neighbor_line("192.168.1.9 dev eth0 FAILED").is_none());
+        assert!(parse_neighbor_line("Interface: 192.168.1.10 --- 0x4").is_none());
+
+        let current: Vec<ArpEntry> = [
+            "192.168.1.1 aa-bb-cc-dd-ee-01 dynamic",
+            "192.168.1.20 aa-bb-cc-dd-ee-99 dynamic",
+            "192.168.1.77 aa-bb-cc-dd-ee-77 dynamic",
+        ]
+        .into_iter()
+        .filter_map(parse_neighbor_line)
+        .collect();
+        let (added, removed) = diff_arp_entries(&previous, &current);
+        let ips = |entries: &[ArpEntry]| entries.iter().map(|e| e.ip.clone()).collect::<Vec<_>>();
+        assert_eq!(ips(&added), vec!["192.168.1.20", "192.168.1.77"]);
+        assert_eq!(ips(&removed), vec!["192.168.1.20", "192.168.1.51"]);
+    }
+
+    #[test]
+    fn route_tables_normalize_and_yield_the_preferred_default_gateway() {
+        let routes = normalize_route_table(json!({
+            "routes": [
+                { "destination": "0.0.0.0/0", "gateway": "10.0.0.254", "interface": "eth1", "metric": 600 },
+                { "destination": "0.0.0.0/0", "gateway": "10.0.0.1", "interface": "eth0", "metric": 100 },
+                { "destination": "default", "gateway": "0.0.0.0", "interface": "tun0", "metric": 1 },
+                { "destination": "10.0.0.0/24", "gateway": "0.0.0.0", "interface": "eth0" },
+                "not a route",
+            ]
+        }));
+        assert_eq!(routes.len(), 4);
+        assert_eq!(routes[3].metric, None);
+        assert_eq!(default_route_gateway(&routes).as_deref(), Some("10.0.0.1"));
+
+        // A bare array is accepted too, bounded to the table limit.
+        let flood: Vec<Value> = (0..MAX_ROUTE_TABLE_ENTRIES + 10)
+            .map(|i| json!({ "destination": format!("10.{}.{}.0/24", i / 256, i % 256), "gateway": "10.0.0.1" }))
+            .collect();
+        let routes = normalize_route_table(Value::Array(flood));
+        assert_eq!(routes.len(), MAX_ROUTE_TABLE_ENTRIES);
+        assert_eq!(default_route_gateway(&routes), None);
+        assert!(normalize_route_table(json!("garbage")).is_empty());
+    }
+
+    #[test]
+    fn nat_is_detected_only_from_outside_the_reported_subnet() {
+        let remote = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 40_000);
+        let device = DeviceRecord {
+            ip: Some("192.168.1.20".to_string()),
+            ips: vec!["192.168.1.20".to_string()],
+            subnet_cidr: Some("192.168.1.0/24".to_string()),
+            ..Default::default()
+        };
+        assert!(!detect_nat(&remote("192.168.1.20"), &device));
+        assert!(!detect_nat(&remote("192.168.1.99"), &device));
+        assert!(detect_nat(&remote("10.0.0.5"), &device));
+        assert!(detect_nat(&remote("::ffff:10.0.0.5"), &device));
+        assert!(!detect_nat(&remote("127.0.0.1"), &device));
+        assert!(!detect_nat(&remote("fd00::5"), &device));
+
+        let subnet_only = DeviceRecord {
+            ip: None,
+            ..device.clone()
+        };
+        assert!(detect_nat(&remote("10.0.0.5"), &subnet_only));
+        assert!(!detect_nat(&remote("192.168.1.30"), &subnet_only));
+
+        let no_subnet = DeviceRecord {
+            subnet_cidr: None,
+            ..device
+        };
+        assert!(!detect_nat(&remote("10.0.0.5"), &no_subnet));
+        assert!(!detect_nat(&remote("10.0.0.5"), &DeviceRecord::default()));
+    }
+
+    #[tokio::test]
+    async fn sparse_upserts_send_only_changed_fields_after_the_first() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        manager.inner.lock().await.config.sparse_device_upserts = true;
+        let mut device = DeviceRecord {
+            agent_id: "lab-1".to_string(),
+            hostname: "lab-1".to_string(),
+            arp_snapshot: vec![ArpEntry {
+                ip: "10.0.0.1".to_string(),
+                mac: "aa:bb:cc:dd:ee:01".to_string(),
+            }],
+            last_seen_ms: 1,
+            ..Default::default()
+        };
+        manager
+            .emit_device_upsert_if_needed(&sink, device.clone(), true)
+            .await;
+        device.last_seen_ms = 2;
+        manager
+            .emit_device_upsert_if_needed(&sink, device.clone(), true)
+            .await;
+        manager
+            .emit_device_upsert_if_needed(&sink, device.clone(), true)
+            .await;
+
+        let events = sink.events();
+        assert_eq!(
+            events
+                .iter()
+                .filter(|(name, _)| name == EVENT_DEVICE_UPSERT)
+                .count(),
+            1
+        );
+        let patches: Vec<&Value> = events
+            .iter()
+            .filter(|(name, _)| name == EVENT_DEVICE_PATCH)
+            .map(|(_, payload)| payload)
+            .collect();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0]["agent_id"], "lab-1");
+        assert_eq!(patches[0]["changes"], json!({"last_seen_ms": 2}));
+    }
+
+    #[test]
+    fn peripherals_are_normalized_bounded_and_diffed() {
+        let usb = |name: &str, id: &str| Peripheral {
+            kind: "USB".to_string(),
+            name: name.to_string(),
+            id: id.to_string(),
+        };
+        let reported = vec![
+            usb("Keyboard", "046d:c31c"),
+            usb("Keyboard", "046d:c31c"),
+            usb("  ", ""),
+            usb("Mouse\n[ERROR]", "046d:c077"),
+        ];
+        let previous = normalize_peripherals(reported);
+        assert_eq!(previous.len(), 2);
+        assert_eq!(previous[0].kind, "usb");
+        assert_eq!(previous[1].name, "Mouse [ERROR]");
+
+        let flood = (0..500).map(|i| usb("Hub", &i.to_string())).collect();
+        assert_eq!(normalize_peripherals(flood).len(), MAX_PERIPHERALS);
+
+        let current = normalize_peripherals(vec![
+            usb("Keyboard", "046d:c31c"),
+            usb("Webcam", "046d:0825"),
+        ]);
+        let (added, removed) = diff_peripherals(&previous, &current);
+        assert_eq!(
+            added.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+            ["Webcam"]
+        );
+        assert_eq!(
+            removed.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+            ["046d:c077"]
+        );
+    }
+
+    #[tokio::test]
+    async fn wire_traces_are_bounded_and_logged_only_for_traced_agents() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        {
+            let mut state = manager.inner.lock().await;
+            state.config.wire_trace.max_agents = 1;
+            for id in ["a", "b"] {
+                state
+                    .devices
+                    .insert(id.to_string(), DeviceRecord::default());
+            }
+        }
+
+        assert!(manager
+            .set_wire_trace(&sink, "ghost".to_string(), true)
+            .await
+            .is_err());
+        let status = manager
+            .set_wire_trace(&sink, "a".to_string(), true)
+            .await
+            .unwrap();
+        assert!(status.expires_at.is_some());
+        assert!(manager
+            .set_wire_trace(&sink, "b".to_string(), true)
+            .await
+            .is_err());
+
+        let frame = r#"{"type":"heartbeat","payload":{}}"#;
+        manager.trace_wire(&sink, "a", "in", frame).await;
+        manager.trace_wire(&sink, "b", "in", frame).await;
+        let traced = sink
+            .events()
+            .into_iter()
+            .filter(|(_, payload)| {
+                payload["message"]
+                    .as_str()
+                    .is_some_and(|m| m.starts_with("[WIRE] in type=heartbeat"))
+            })
+            .count();
+        assert_eq!(traced, 1);
+
+        let long = format!(r#"{{"type":"result","payload":"{}"}}"#, "x".repeat(5000));
+        manager.trace_wire(&sink, "a", "in", &long).await;
+        let message = sink.events().last().unwrap().1["message"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(message.starts_with("[WIRE] in type=result bytes=5030 "));
+        assert!(message.ends_with('…'));
+        assert!(message.chars().count() <= sanitize::MAX_MESSAGE_CHARS);
+
+        let register =
+            r#"{"type":"register","payload":{"agent_id":"a","secret":"pair-token-123"}}"#;
+        manager.trace_wire(&sink, "a", "in", register).await;
+        let message = sink.events().last().unwrap().1["message"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(message.starts_with("[WIRE] in type=register"));
+        assert!(message.contains("[redacted]"));
+        assert!(!message.contains("pair-token-123"));
+
+        manager
+            .inner
+            .lock()
+            .await
+            .wire_traces
+            .insert("a".to_string(), 0);
+        assert!(manager
+            .set_wire_trace(&sink, "b".to_string(), true)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn topology_changes_wait_out_the_stability_window() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        manager.commit_topology(&sink, true).await;
+        let baseline = manager.get_topology_snapshot().await.revision;
+        manager.inner.lock().await.config.topology_stability_ms = 60_000;
+
+        let add_host = || async {
+            let mut state = manager.inner.lock().await;
+            let device = DeviceRecord {
+                device_key: "flappy".to_string(),
+                agent_id: "flappy".to_string(),
+                hostname: "flappy".to_string(),
+                ip: Some("10.9.9.9".to_string()),
+                status: "online".to_string(),
+                ..Default::default()
+            };
+            state.devices.insert("flappy".to_string(), device);
+            state.device_order.push("flappy".to_string());
+        };
+
+        add_host().await;
+        manager.rebuild_topology_if_changed(&sink).await;
+        assert_eq!(manager.get_topology_snapshot().await.revision, baseline);
+        assert!(manager.inner.lock().await.topology_candidate_key.is_some());
+
+        {
+            let mut state = manager.inner.lock().await;
+            state.devices.remove("flappy");
+            state.device_order.retain(|id| id != "flappy");
+        }
+        manager.rebuild_topology_if_changed(&sink).await;
+        assert!(manager.inner.lock().await.topology_candidate_key.is_none());
+        assert!(!sink.names().contains(&EVENT_TOPOLOGY_SNAPSHOT.to_string()));
+
+        add_host().await;
+        manager.force_full_refresh(&sink).await;
+        assert_eq!(manager.get_topology_snapshot().await.revision, baseline + 1);
+        assert!(sink.names().contains(&EVENT_TOPOLOGY_SNAPSHOT.to_string()));
+        let diff = sink
+            .events()
+            .into_iter()
+            .rfind(|(name, _)| name == EVENT_TOPOLOGY_DIFF)
+            .map(|(_, payload)| payload)
+            .unwrap();
+        assert_eq!(diff["rev_to"], baseline + 1);
+        assert!(diff["added_nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|n| n["id"] == "host:flappy"));
+    }
+
+    #[tokio::test]
+    async fn rotate_pair_token_emits_status_then_log() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+
+        let token = manager.rotate_pair_token(&sink).await.unwrap();
+
+        assert_eq!(token, manager.get_pair_token().await);
+        assert_eq!(sink.names(), vec![EVENT_SERVER_STATUS, EVENT_LOG]);
+        let events = sink.events();
+        assert_eq!(
+            events[0].1["port_ws"],
+            LabScanConfig::default().server.port_ws
+        );
+        assert_eq!(events[1].1["message"], "Pair token rotated");
+    }
+
+    #[tokio::test]
+    async fn scheduled_rotation_honours_the_previous_token_during_grace() {
+        let manager = test_manager();
+        let old = manager.get_pair_token().await;
+
+        let new = manager.replace_pair_token(60_000).await;
+        {
+            let state = manager.inner.lock().await;
+            let now = manager.now();
+            assert!(accepts_pair_token(&state, &new, now));
+            assert!(accepts_pair_token(&state, &old, now));
+            assert!(!accepts_pair_token(&state, &old, now + 60_000));
+            assert!(!accepts_pair_token(&state, "guess", now));
+        }
+
+        let sink = CaptureSink::default();
+        manager.rotate_pair_token(&sink).await.unwrap();
+        let state = manager.inner.lock().await;
+        assert!(!accepts_pair_token(&state, &new, manager.now()));
+    }
+
+    #[test]
+    fn apply_network_payload_keeps_last_known_good_facts() {
+        let mut device = DeviceRecord {
+            ips: vec!["192.168.1.20".to_string()],
+            ..Default::default()
+        };
+        apply_network_payload(
+            &mut device,
+            &NetworkFactsPayload {
+                ip: "192.168.1.20".to_string(),
+                subnet_cidr: "192.168.1.0/24".to_string(),
+                default_gateway_ip: "192.168.1.1".to_string(),
+                interface_type: "ethernet".to_string(),
+                mac: Some("aa:bb:cc:dd:ee:01".to_string()),
+                gateway_mac: Some("aa:bb:cc:dd:ee:ff".to_string()),
+                vlan_id: Some(10),
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(device.vlan_id, Some(10));
+
+        apply_network_payload(
+            &mut device,
+            &NetworkFactsPayload {
+                mac: Some("  ".to_string()),
+                ..Default::default()
+            },
+            None,
+        );
+
+        assert_eq!(device.ip.as_deref(), Some("192.168.1.20"));
+        assert_eq!(device.subnet_cidr.as_deref(), Some("192.168.1.0/24"));
+        assert_eq!(device.default_gateway_ip.as_deref(), Some("192.168.1.1"));
+        assert_eq!(device.interface_type.as_deref(), Some("ethernet"));
+        assert_eq!(device.mac.as_deref(), Some("aa:bb:cc:dd:ee:01"));
+        assert_eq!(device.gateway_mac.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        assert_eq!(device.vlan_id, None);
+
+        device.ips = vec!["10.0.0.7".to_string()];
+        apply_network_payload(&mut device, &NetworkFactsPayload::default(), None);
+        assert_eq!(device.ip.as_deref(), Some("10.0.0.7"));
+        assert_eq!(device.subnet_cidr.as_deref(), Some("10.0.0.0/24"));
+
+        apply_network_payload(
+            &mut device,
+            &NetworkFactsPayload {
+                ip: "10.0.5.7".to_string(),
+                subnet_cidr: "10.0.5.7/22".to_string(),
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(device.subnet_cidr.as_deref(), Some("10.0.4.0/22"));
+        // A move without a reported subnet keeps the last known prefix length.
+        device.ips = vec!["10.0.9.1".to_string()];
+        apply_network_payload(&mut device, &NetworkFactsPayload::default(), None);
+        assert_eq!(device.subnet_cidr.as_deref(), Some("10.0.8.0/22"));
+    }
+
+    #[test]
+    fn apply_network_payload_skips_loopback_and_link_local_addresses() {
+        let mut device = DeviceRecord {
+            ips: vec!["169.254.3.4".to_string(), "192.168.1.20".to_string()],
+            ..Default::default()
+        };
+        apply_network_payload(
+            &mut device,
+            &NetworkFactsPayload {
+                ip: "127.0.0.1".to_string(),
+                subnet_cidr: "127.0.0.0/8".to_string(),
+                ..Default::default()
+            },
+            None,
+        );
+        assert!(device.bad_network_detection);
+        assert_eq!(device.ip.as_deref(), Some("192.168.1.20"));
+        assert_eq!(device.subnet_cidr.as_deref(), Some("192.168.1.0/24"));
+
+        device.ips = vec!["192.168.1.20".to_string()];
+        apply_network_payload(&mut device, &NetworkFactsPayload::default(), None);
+        assert!(device.bad_network_detection);
+        apply_network_payload(
+            &mut device,
+            &NetworkFactsPayload {
+                ip: "192.168.1.20".to_string(),
+                ..Default::default()
+            },
+            None,
+        );
+        assert!(!device.bad_network_detection);
+
+        let v6 = |ipv6: &str, cidr: Option<&str>| NetworkFactsPayload {
+            ipv6: Some(ipv6.to_string()),
+            ipv6_cidr: cidr.map(str::to_string),
+            ..Default::default()
+        };
+        apply_network_payload(&mut device, &v6("fe80::1", Some("fe80::/64")), None);
+        assert_eq!(device.ipv6, None);
+        apply_network_payload(
+            &mut device,
+            &v6("2001:db8:7::42", Some("2001:db8:7::42/56")),
+            None,
+        );
+        assert_eq!(device.ipv6.as_deref(), Some("2001:db8:7::42"));
+        assert_eq!(device.ipv6_cidr.as_deref(), Some("2001:db8:7::/56"));
+    }
+
+    #[tokio::test]
+    async fn outages_escalate_once_per_threshold_and_clear_on_return() {
+        let went_offline = 1_000_000_000;
+        let clock = Arc::new(ManualClock::new(went_offline));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let alerts = |sink: &CaptureSink| {
+            sink.events()
+                .into_iter()
+                .filter(|(name, payload)| {
+                    name == EVENT_LOG
+                        && payload["message"].as_str().unwrap().contains("offline for")
+                })
+                .map(|(_, payload)| payload["level"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        manager.inner.lock().await.devices.insert(
+            "lab-1".to_string(),
+            DeviceRecord {
+                agent_id: "lab-1".to_string(),
+                hostname: "lab-1".to_string(),
+                status: "offline".to_string(),
+                last_seen_ms: went_offline,
+                ..Default::default()
+            },
+        );
+
+        clock.set(went_offline + 60_000);
+        manager.escalate_outages(&sink).await;
+        assert!(alerts(&sink).is_empty());
+
+        clock.set(went_offline + 10 * 60_000);
+        manager.escalate_outages(&sink).await;
+        manager.escalate_outages(&sink).await;
+        assert_eq!(alerts(&sink), ["WARN"]);
+
+        clock.set(went_offline + 2 * 60 * 60_000);
+        manager.escalate_outages(&sink).await;
+        assert_eq!(alerts(&sink), ["WARN", "ERROR"]);
+
+        // Back online, past the per-device activity rate limit.
+        clock.advance(DEVICE_ACTIVITY_RATE_MS);
+        if let Some(device) = manager.inner.lock().await.devices.get_mut("lab-1") {
+            device.status = "online".to_string();
+            device.last_seen_ms = manager.now();
+        }
+        manager.escalate_outages(&sink).await;
+        assert!(manager.inner.lock().await.outage_levels.is_empty());
+        assert!(sink
+            .events()
+            .iter()
+            .any(|(_, payload)| payload["kind"] == "outage_cleared"));
+    }
+
+    #[tokio::test]
+    async fn network_overrides_survive_heartbeats_until_cleared() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let vpn_facts = NetworkFactsPayload {
+            ip: "10.8.0.5".to_string(),
+            subnet_cidr: "10.8.0.0/24".to_string(),
+            default_gateway_ip: "10.8.0.1".to_string(),
+            ..Default::default()
+        };
+        {
+            let mut state = manager.inner.lock().await;
+            let mut device = DeviceRecord {
+                agent_id: "lab-1".to_string(),
+                ..Default::default()
+            };
+            apply_network_payload(&mut device, &vpn_facts, None);
+            state.devices.insert("lab-1".to_string(), device);
+        }
+
+        let bad = NetworkFactsPayload {
+            default_gateway_ip: "gateway".to_string(),
+            ..Default::default()
+        };
+        assert!(manager
+            .override_device_network(&sink, "lab-1".to_string(), bad)
+            .await
+            .is_err());
+        assert!(manager
+            .override_device_network(&sink, "lab-1".to_string(), NetworkFactsPayload::default())
+            .await
+            .is_err());
+
+        let correction = NetworkFactsPayload {
+            subnet_cidr: "192.168.1.0/24".to_string(),
+            default_gateway_ip: "192.168.1.1".to_string(),
+            ..Default::default()
+        };
+        let device = manager
+            .override_device_network(&sink, "lab-1".to_string(), correction)
+            .await
+            .unwrap();
+        assert_eq!(
+            device.overridden_network_fields,
+            vec!["subnet_cidr", "default_gateway_ip"]
+        );
+
+        {
+            let mut state = manager.inner.lock().await;
+            let runtime = &mut *state;
+            let device = runtime.devices.get_mut("lab-1").unwrap();
+            apply_network_payload(device, &vpn_facts, runtime.network_overrides.get("lab-1"));
+            assert_eq!(device.ip.as_deref(), Some("10.8.0.5"));
+            assert_eq!(device.default_gateway_ip.as_deref(), Some("192.168.1.1"));
+        }
+
+        let device = manager
+            .clear_device_network_override(&sink, "lab-1".to_string())
+            .await
+            .unwrap();
+        assert!(device.overridden_network_fields.is_empty());
+        assert!(manager
+            .clear_device_network_override(&sink, "lab-1".to_string())
+            .await
+            .is_err());
+    }
+
+    async fn activity_counts(manager: &ServerManager) -> Vec<(String, Option<u32>)> {
+        manager
+            .inner
+            .lock()
+            .await
+            .activity
+            .iter()
+            .map(|e| (e.kind.clone(), e.count))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn activity_coalesces_within_the_dedupe_window() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let agent = Some("lab-1".to_string());
+
+        manager
+            .emit_activity(&sink, "dns_changed", agent.clone(), "dns down".to_string())
+            .await;
+        clock.advance(DEVICE_ACTIVITY_RATE_MS);
+        manager
+            .emit_activity(&sink, "dns_changed", agent.clone(), "dns down".to_string())
+            .await;
+        assert_eq!(
+            activity_counts(&manager).await,
+            vec![("dns_changed".to_string(), Some(2))]
+        );
+        let emitted = sink.events();
+        assert_eq!(emitted.len(), 2);
+        assert_eq!(emitted[1].1["count"], 2);
+        assert_eq!(emitted[1].1["ts"], 1_000_000 + DEVICE_ACTIVITY_RATE_MS);
+
+        // The window is measured from the last bump, so it slides with repeats.
+        clock.advance(ACTIVITY_DEDUPE_MS + 1);
+        manager
+            .emit_activity(&sink, "dns_changed", agent.clone(), "dns down".to_string())
+            .await;
+        assert_eq!(
+            activity_counts(&manager).await,
+            vec![
+                ("dns_changed".to_string(), None),
+                ("dns_changed".to_string(), Some(2)),
+            ]
+        );
+        assert_ne!(emitted[0].1["id"], sink.events()[2].1["id"]);
+    }
+
+    #[tokio::test]
+    async fn activity_rate_limit_drops_bursts_per_agent_only() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+
+        manager
+            .emit_activity(
+                &sink,
+                "ports_changed",
+                Some("lab-1".to_string()),
+                "a".to_string(),
+            )
+            .await;
+        clock.advance(DEVICE_ACTIVITY_RATE_MS - 1);
+        manager
+            .emit_activity(
+                &sink,
+                "peripherals_changed",
+                Some("lab-1".to_string()),
+                "b".to_string(),
+            )
+            .await;
+        assert_eq!(sink.events().len(), 1);
+        assert_eq!(activity_counts(&manager).await.len(), 1);
+
+        // Another agent has its own budget.
+        manager
+            .emit_activity(
+                &sink,
+                "ports_changed",
+                Some("lab-2".to_string()),
+                "c".to_string(),
+            )
+            .await;
+        assert_eq!(sink.events().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn activity_without_agent_skips_the_rate_limit() {
+        let manager = clocked_manager(Arc::new(ManualClock::new(1_000_000)));
+        let sink = CaptureSink::default();
+
+        for _ in 0..3 {
+            manager
+                .emit_activity(&sink, "server_started", None, "up".to_string())
+                .await;
+        }
+        manager
+            .emit_activity(&sink, "config_reloaded", None, "reloaded".to_string())
+            .await;
+        assert_eq!(sink.events().len(), 4);
+        assert_eq!(
+            activity_counts(&manager).await,
+            vec![
+                ("config_reloaded".to_string(), None),
+                ("server_started".to_string(), Some(3)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn saved_devices_are_loaded_offline_at_startup() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let saved = DeviceRecord {
+            agent_id: "lab-1".to_string(),
+            hostname: "lab-1-pc".to_string(),
+            status: "online".to_string(),
+            first_seen_ms: 1_000,
+            last_seen_ms: 5_000,
+            ..Default::default()
+        };
+        {
+            let mut db = manager.db.lock().await;
+            db.register_device(device_row(&saved)).unwrap();
+            db.register_device(Device {
+                record: None,
+                record_version: 0,
+                registered_at: 500,
+                ..device_row(&DeviceRecord {
+                    agent_id: "legacy".to_string(),
+                    hostname: "old-pc".to_string(),
+                    ip: Some("10.0.0.9".to_string()),
+                    ..Default::default()
+                })
+            })
+            .unwrap();
+        }
+
+        manager.load_persisted_devices(&sink).await;
+        let state = manager.inner.lock().await;
+        assert_eq!(state.device_order, ["legacy", "lab-1"]);
+        let device = &state.devices["lab-1"];
+        assert_eq!(device.status, "offline");
+        assert_eq!(device.first_seen_ms, 1_000);
+        let legacy = &state.devices["legacy"];
+        assert_eq!(legacy.hostname, "old-pc");
+        assert_eq!(legacy.subnet_cidr.as_deref(), Some("10.0.0.0/24"));
+    }
+
+    #[tokio::test]
+    async fn stopped_server_reports_reconfigured_ports() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let config = ServerConfig {
+            port_ws: 9148,
+            port_udp: 9870,
+            ..manager.get_config().await
+        };
+        manager.set_config(&sink, config).await.unwrap();
+        let status = manager.get_status().await;
+        assert_eq!((status.port_ws, status.port_udp), (9148, 9870));
+        assert_eq!(manager.get_effective_config().await.server.port_ws, 9148);
+        assert!(manager.stop_server(&sink).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_listener_presents_the_advertised_fingerprint() {
+        let dir = std::env::temp_dir().join(format!("labscan-wss-{}", Uuid::new_v4()));
+        let manager = test_manager().with_data_dir(&dir);
+        let sink = CaptureSink::default();
+        let config = manager.load_tls_config(&sink).await.unwrap();
+        let fingerprint = manager.get_tls_fingerprint().await.unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("wss://{}/ws/agent", listener.local_addr().unwrap());
+        let router = Router::new()
+            .route("/ws/agent", get(ws_agent_handler))
+            .with_state(HttpState {
+                manager: manager.clone(),
+                app: Arc::new(sink.clone()),
+            });
+        tokio::spawn(
+            axum_server::from_tcp_rustls(listener, config)
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+        let connect = |pin: &str| {
+            let connector = Connector::Rustls(tls::pinned_client_config(pin));
+            tokio_tungstenite::connect_async_tls_with_config(
+                url.clone(),
+                None,
+                false,
+                Some(connector),
+            )
+        };
+        assert!(connect(&fingerprint).await.is_ok());
+        assert!(connect(&"0".repeat(64)).await.is_err());
+
+        let rotated = manager.regenerate_tls_certificate(&sink).await.unwrap();
+        assert_ne!(rotated, fingerprint);
+        assert!(connect(&fingerprint).await.is_err());
+        assert!(connect(&rotated).await.is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn second_register_takes_over_the_connection() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let router = Router::new()
+            .route("/ws/agent", get(ws_agent_handler))
+            .with_state(HttpState {
+                manager: manager.clone(),
+                app: Arc::new(sink.clone()),
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws/agent", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await;
+        });
+
+        let register = json!({
+            "type": "register",
+            "ts": 0,
+            "agent_id": "lab-1",
+            "payload": {
+                "agent_id": "lab-1",
+                "secret": manager.get_pair_token().await,
+                "hostname": "lab-pc",
+                "ips": ["10.0.0.5"],
+                "os": "linux",
+                "version": "1.0.0",
+            },
+        })
+        .to_string();
+        let mut sockets = Vec::new();
+        for _ in 0..2 {
+            // Past the per-device rate limit left by the first registration.
+            clock.advance(DEVICE_ACTIVITY_RATE_MS);
+            let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+                .await
+                .unwrap();
+            socket
+                .send(ClientMessage::Text(register.clone()))
+                .await
+                .unwrap();
+            loop {
+                let frame = timeout(Duration::from_secs(5), socket.next())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                if frame.to_text().is_ok_and(|t| t.contains("\"registered\"")) {
+                    break;
+                }
+            }
+            sockets.push(socket);
+        }
+
+        let mut first = sockets.remove(0);
+        let closed = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(frame)) = first.next().await {
+                if frame.is_close() {
+                    return true;
+                }
+            }
+            true
+        })
+        .await;
+        assert_eq!(closed, Ok(true));
+        drop(first);
+        timeout(Duration::from_secs(5), async {
+            while manager.open_sockets.load(AtomicOrdering::Relaxed) > 1 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let state = manager.inner.lock().await;
+        assert_eq!(state.connections.len(), 1);
+        assert_eq!(state.devices["lab-1"].status, "online");
+        assert!(sink
+            .events()
+            .iter()
+            .any(|(name, payload)| name == EVENT_ACTIVITY && payload["kind"] == "agent_takeover"));
+    }
+
+    #[tokio::test]
+    async fn refused_registrations_keep_their_enrollment_token() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let router = Router::new()
+            .route("/ws/agent", get(ws_agent_handler))
+            .with_state(HttpState {
+                manager: manager.clone(),
+                app: Arc::new(sink.clone()),
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws/agent", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await;
+        });
+
+        let token = manager.mint_enrollment_token(&sink, 600).await.unwrap();
+        manager
+            .block_agent(&sink, "lab-1".to_string())
+            .await
+            .unwrap();
+        let register = || async {
+            let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+                .await
+                .unwrap();
+            let frame = json!({
+                "type": "register",
+                "ts": 0,
+                "agent_id": "lab-1",
+                "payload": {
+                    "agent_id": "lab-1",
+                    "secret": token.token,
+                    "hostname": "lab-pc",
+                    "ips": ["10.0.0.5"],
+                    "os": "linux",
+                    "version": "1.0.0",
+                },
+            });
+            socket
+                .send(ClientMessage::Text(frame.to_string()))
+                .await
+                .unwrap();
+            // Refusals close the socket, which may beat the reply to the client.
+            while let Some(Ok(frame)) = timeout(Duration::from_secs(5), socket.next())
+                .await
+                .unwrap()
+            {
+                let reply: Value =
+                    serde_json::from_str(frame.to_text().unwrap_or_default()).unwrap_or_default();
+                if reply["type"] == "registered" && reply["payload"]["ok"] == true {
+                    return true;
+                }
+            }
+            false
+        };
+
+        assert!(!register().await);
+        assert!(manager.enrollment_token_valid(&token.token).await);
+
+        manager
+            .unblock_agent(&sink, "lab-1".to_string())
+            .await
+            .unwrap();
+        assert!(register().await);
+        assert!(!manager.enrollment_token_valid(&token.token).await);
+    }
+
+    #[tokio::test]
+    async fn late_results_leave_a_cancelled_task_cancelled() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let router = Router::new()
+            .route("/ws/agent", get(ws_agent_handler))
+            .with_state(HttpState {
+                manager: manager.clone(),
+                app: Arc::new(sink.clone()),
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws/agent", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await;
+        });
+
+        let secret = manager.get_pair_token().await;
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .unwrap();
+        let register = json!({
+            "type": "register",
+            "ts": 0,
+            "agent_id": "lab-1",
+            "payload": {
+                "agent_id": "lab-1",
+                "secret": secret,
+                "hostname": "lab-pc",
+                "ips": ["10.0.0.5"],
+                "os": "linux",
+                "version": "1.0.0",
+            },
+        });
+        socket
+            .send(ClientMessage::Text(register.to_string()))
+            .await
+            .unwrap();
+        loop {
+            let frame = timeout(Duration::from_secs(5), socket.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if frame.to_text().is_ok_and(|t| t.contains("\"registered\"")) {
+                break;
+            }
+        }
+
+        let task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string()],
+                "port_scan".to_string(),
+                json!({ "target": "10.0.0.9", "ports": [22] }),
+                TaskOptions::default(),
+            )
+            .await
+            .unwrap();
+        manager
+            .cancel_task(&sink, task.task_id.clone())
+            .await
+            .unwrap();
+
+        let ts = manager.now();
+        let payload =
+            json!({ "task_id": task.task_id, "ok": true, "result": { "open_ports": [22] } })
+                .to_string();
+        let sig = wire_signature(&secret, "task_result", ts, "lab-1", &payload);
+        let frame = format!(
+            r#"{{"type":"task_result","ts":{},"agent_id":"lab-1","payload":{},"sig":"{}"}}"#,
+            ts, payload, sig
+        );
+        socket.send(ClientMessage::Text(frame)).await.unwrap();
+
+        let late = timeout(Duration::from_secs(5), async {
+            loop {
+                let task = manager.inner.lock().await.tasks[&task.task_id].clone();
+                if !task.results.is_empty() {
+                    return task;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(late.status, "cancelled");
+        assert!(late.ended_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn blocking_a_source_closes_its_open_sockets() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let router = Router::new()
+            .route("/ws/agent", get(ws_agent_handler))
+            .with_state(HttpState {
+                manager: manager.clone(),
+                app: Arc::new(sink.clone()),
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws/agent", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await;
+        });
+
+        // Never registers, so only the socket registry knows about it.
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), async {
+            while manager.inner.lock().await.socket_sources.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        manager
+            .block_source(&sink, "10.0.0.0/8".to_string())
+            .await
+            .unwrap();
+        assert_eq!(manager.inner.lock().await.socket_sources.len(), 1);
+
+        manager
+            .block_source(&sink, "127.0.0.0/8".to_string())
+            .await
+            .unwrap();
+        let closed = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(frame)) = socket.next().await {
+                if frame.is_close() {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+        timeout(Duration::from_secs(5), async {
+            while !manager.inner.lock().await.socket_sources.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(sink.events().iter().any(|(name, payload)| name == EVENT_LOG
+            && payload["message"] == "Source 127.0.0.0/8 blocked; closed 1 connection(s)"));
     }
 
-    write_task.abort();
-    if let Some(agent_id) = registered_agent_id {
-        tracing::info!("[WS] disconnect agent_id={}", agent_id);
-        state
-            .manager
-            .on_agent_disconnect(&state.app, agent_id)
-            .await;
+    #[test]
+    fn wire_signatures_bind_the_frame_and_its_timestamp() {
+        let now = 1_000_000;
+        let frame = |ts: i64, payload: &str, sig: &str| {
+            format!(
+                r#"{{"type":"heartbeat","ts":{},"agent_id":"lab-1","payload":{},"sig":"{}"}}"#,
+                ts, payload, sig
+            )
+        };
+        let check = |text: String| {
+            let wire: WireMessage = serde_json::from_str(&text).unwrap();
+            verify_wire("secret", &wire, &text, now)
+        };
+        let payload = r#"{"status":"online","metrics":{"latency_ms":4}}"#;
+        let sig = wire_signature("secret", "heartbeat", now, "lab-1", payload);
+
+        assert_eq!(check(frame(now, payload, &sig)), Ok(()));
+        let forged = r#"{"status":"online","metrics":{"latency_ms":5}}"#;
+        assert_eq!(check(frame(now, forged, &sig)), Err("signature mismatch"));
+        let stale = now - WIRE_MAX_SKEW_MS - 1;
+        let stale_sig = wire_signature("secret", "heartbeat", stale, "lab-1", payload);
+        assert_eq!(
+            check(frame(stale, payload, &stale_sig)),
+            Err("timestamp outside the replay window")
+        );
+        let wrong_key = wire_signature("other", "heartbeat", now, "lab-1", payload);
+        assert_eq!(
+            check(frame(now, payload, &wrong_key)),
+            Err("signature mismatch")
+        );
+        let unsigned = format!(
+            r#"{{"type":"heartbeat","ts":{},"agent_id":"lab-1","payload":{}}}"#,
+            now, payload
+        );
+        assert_eq!(check(unsigned), Err("missing signature"));
     }
-}
 
-fn apply_network_payload(device: &mut DeviceRecord, network: &NetworkFactsPayload) {
-    let ip = clean_non_empty_owned(&network.ip).or_else(|| {
-        device
-            .ips
-            .iter()
-            .find_map(|candidate| clean_non_empty_owned(candidate))
-    });
-    device.ip = ip;
-    device.subnet_cidr = clean_non_empty_owned(&network.subnet_cidr)
-        .or_else(|| device.ip.as_deref().and_then(guess_subnet_from_ip));
-    device.default_gateway_ip = clean_non_empty_owned(&network.default_gateway_ip);
-    device.interface_type = clean_non_empty_owned(&network.interface_type);
-    device.mac = network.mac.clone().and_then(|v| clean_non_empty_owned(&v));
-    device.gateway_mac = network
-        .gateway_mac
-        .clone()
-        .and_then(|v| clean_non_empty_owned(&v));
-    device.dhcp_server_ip = network
-        .dhcp_server_ip
-        .clone()
-        .and_then(|v| clean_non_empty_owned(&v));
-    device.ssid = network.ssid.clone().and_then(|v| clean_non_empty_owned(&v));
-    if !network.arp_snapshot.is_empty() {
-        device.arp_snapshot = network.arp_snapshot.clone();
+    #[test]
+    fn origin_allowlist_ignores_case_and_trailing_slash() {
+        assert!(!ServerConfig::default().validate_ws_origin);
+        let allowed = vec!["http://localhost:1420/".to_string()];
+        assert!(origin_allowed("HTTP://localhost:1420", &allowed));
+        assert!(!origin_allowed("http://evil.example", &allowed));
+        assert!(!origin_allowed("http://localhost:1420", &[]));
     }
-}
-
-fn build_topology_snapshot(
-    devices: &HashMap<String, DeviceRecord>,
-    device_order: &[String],
-    admin_network: &NetworkFactsPayload,
-    revision: u64,
-) -> TopologySnapshot {
-    let mut nodes: Vec<TopologyNode> = Vec::new();
-    let mut edges: Vec<TopologyEdge> = Vec::new();
-
-    let mut host_records: Vec<DeviceRecord> = device_order
-        .iter()
-        .filter_map(|id| devices.get(id).cloned())
-        .collect();
-    host_records.sort_by(compare_device_topology_order);
-
-    let admin_ip = clean_non_empty_owned(&admin_network.ip)
-        .or_else(detect_local_ipv4_string)
-        .unwrap_or_else(|| "127.0.0.1".to_string());
-    let admin_subnet = clean_non_empty_owned(&admin_network.subnet_cidr)
-        .or_else(|| guess_subnet_from_ip(&admin_ip));
-    let admin_gateway = clean_non_empty_owned(&admin_network.default_gateway_ip);
 
-    let mut gateway_by_key: HashMap<String, String> = HashMap::new();
-    let mut subnet_nodes: HashMap<String, String> = HashMap::new();
-    let mut gateway_specs: Vec<(String, Option<String>)> = Vec::new();
-    let mut attachment_count: HashMap<String, usize> = HashMap::new();
-
-    let mut observed_subnets: Vec<String> = host_records
-        .iter()
-        .filter_map(|d| d.subnet_cidr.clone())
-        .collect();
-    if let Some(s) = admin_subnet.clone() {
-        observed_subnets.push(s);
-    }
-    observed_subnets.sort();
-    observed_subnets.dedup();
-    let use_subnet_nodes = observed_subnets.len() > 1;
-
-    if use_subnet_nodes {
-        for subnet in &observed_subnets {
-            let subnet_id = format!("subnet:{}", subnet);
-            subnet_nodes.insert(subnet.clone(), subnet_id.clone());
-            nodes.push(TopologyNode {
-                id: subnet_id,
-                node_type: "subnet".to_string(),
-                label: subnet.clone(),
-                ip: None,
-                subnet_cidr: Some(subnet.clone()),
-                gateway_ip: None,
-                agent_id: None,
-                interface_type: None,
-                attached_count: None,
-            });
+    #[tokio::test]
+    async fn remove_device_refuses_connected_agents() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        {
+            let mut state = manager.inner.lock().await;
+            for id in ["lab-1", "lab-2"] {
+                state.devices.insert(
+                    id.to_string(),
+                    DeviceRecord {
+                        agent_id: id.to_string(),
+                        ..Default::default()
+                    },
+                );
+                state.device_order.push(id.to_string());
+            }
+            let (tx, _rx) = mpsc::unbounded_channel();
+            state.connections.insert("lab-2".to_string(), tx);
         }
-    }
 
-    if let Some(gw) = admin_gateway.clone() {
-        gateway_specs.push((gw, admin_subnet.clone()));
+        manager
+            .remove_device(&sink, "lab-1".to_string())
+            .await
+            .unwrap();
+        assert!(manager
+            .remove_device(&sink, "lab-2".to_string())
+            .await
+            .is_err());
+        assert!(manager
+            .remove_device(&sink, "lab-1".to_string())
+            .await
+            .is_err());
+        let state = manager.inner.lock().await;
+        assert_eq!(state.device_order, ["lab-2"]);
+        assert!(sink
+            .events()
+            .iter()
+            .any(|(name, payload)| name == EVENT_DEVICE_REMOVE && payload["agent_id"] == "lab-1"));
     }
-    for host in &host_records {
-        if let Some(gw) = host
-            .default_gateway_ip
-            .clone()
-            .and_then(|v| clean_non_empty_owned(&v))
+
+    #[tokio::test]
+    async fn enabling_the_allowlist_disconnects_unlisted_agents() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let mut receivers = HashMap::new();
         {
-            gateway_specs.push((gw, host.subnet_cidr.clone()));
+            let mut state = manager.inner.lock().await;
+            for id in ["lab-1", "lab-2"] {
+                let (tx, rx) = mpsc::unbounded_channel();
+                state.connections.insert(id.to_string(), tx);
+                receivers.insert(id, rx);
+            }
         }
+
+        manager
+            .allow_agent(&sink, "lab-1".to_string())
+            .await
+            .unwrap();
+        assert!(!manager.get_agent_allowlist().await.enabled);
+        assert_eq!(manager.inner.lock().await.connections.len(), 2);
+
+        let allowlist = manager
+            .set_agent_allowlist_enabled(&sink, true)
+            .await
+            .unwrap();
+        assert!(allowlist.enabled);
+        assert_eq!(allowlist.agents, ["lab-1"]);
+        assert!(matches!(
+            receivers.get_mut("lab-2").unwrap().try_recv(),
+            Ok(Message::Close(None))
+        ));
+        assert!(receivers.get_mut("lab-1").unwrap().try_recv().is_err());
+
+        // Emptying the list keeps it enforced instead of reopening registration.
+        manager
+            .deny_agent(&sink, "lab-1".to_string())
+            .await
+            .unwrap();
+        let allowlist = manager.get_agent_allowlist().await;
+        assert!(allowlist.enabled && allowlist.agents.is_empty());
+        assert!(manager.inner.lock().await.connections.is_empty());
     }
-    gateway_specs.sort_by(|a, b| {
-        let subnet_cmp = a.1.cmp(&b.1);
-        if subnet_cmp != Ordering::Equal {
-            return subnet_cmp;
-        }
-        let a_ip = ip_to_u32(&a.0);
-        let b_ip = ip_to_u32(&b.0);
-        match (a_ip, b_ip) {
-            (Some(x), Some(y)) if x != y => x.cmp(&y),
-            (Some(_), None) => Ordering::Less,
-            (None, Some(_)) => Ordering::Greater,
-            _ => a.0.cmp(&b.0),
-        }
-    });
-    gateway_specs.dedup();
-
-    for (gateway_ip, subnet) in gateway_specs {
-        let subnet_key = subnet.clone().unwrap_or_default();
-        let key = format!("{}|{}", subnet_key, gateway_ip);
-        let gateway_id = format!("gw:{}", gateway_ip);
-        gateway_by_key.insert(key, gateway_id.clone());
-        nodes.push(TopologyNode {
-            id: gateway_id.clone(),
-            node_type: "gateway".to_string(),
-            label: format!("Gateway {}", gateway_ip),
-            ip: Some(gateway_ip.clone()),
-            subnet_cidr: subnet.clone(),
-            gateway_ip: Some(gateway_ip.clone()),
-            agent_id: None,
-            interface_type: None,
-            attached_count: None,
-        });
 
-        if use_subnet_nodes {
-            if let Some(subnet_value) = subnet {
-                if let Some(subnet_id) = subnet_nodes.get(&subnet_value) {
-                    edges.push(TopologyEdge {
-                        id: format!("{}->{}", gateway_id, subnet_id),
-                        child_id: gateway_id,
-                        parent_id: subnet_id.clone(),
-                        method: "evidence".to_string(),
-                        confidence: 1.0,
-                    });
-                }
+    #[tokio::test]
+    async fn reconnect_mid_task_resends_only_unacknowledged_work() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let connect = |agent: &str| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let manager = manager.clone();
+            let agent = agent.to_string();
+            async move {
+                manager.inner.lock().await.connections.insert(agent, tx);
+                rx
             }
+        };
+        let mut first = connect("lab-1").await;
+        let mut second = connect("lab-2").await;
+        let task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string(), "lab-2".to_string()],
+                "port_scan".to_string(),
+                json!({ "target": "10.0.0.5", "ports": [22] }),
+                TaskOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+        {
+            let mut state = manager.inner.lock().await;
+            mark_task_acked(state.tasks.get_mut(&task.task_id).unwrap(), "lab-1");
         }
-    }
 
-    let mut unknown_hub_ids: HashMap<String, String> = HashMap::new();
-
-    let admin_id = "admin:self".to_string();
-    nodes.push(TopologyNode {
-        id: admin_id.clone(),
-        node_type: "admin".to_string(),
-        label: "Admin".to_string(),
-        ip: Some(admin_ip.clone()),
-        subnet_cidr: admin_subnet.clone(),
-        gateway_ip: admin_gateway.clone(),
-        agent_id: None,
-        interface_type: clean_non_empty_owned(&admin_network.interface_type),
-        attached_count: None,
-    });
-    let admin_parent = if let Some(gw) = admin_gateway.clone() {
-        let key = format!("{}|{}", admin_subnet.clone().unwrap_or_default(), gw);
-        let parent_id = gateway_by_key
-            .get(&key)
-            .cloned()
-            .unwrap_or_else(|| format!("gw:{}", gw));
-        (parent_id, "evidence".to_string(), 0.9)
-    } else {
-        (
-            ensure_unknown_hub_node(
-                &mut nodes,
-                &mut edges,
-                &subnet_nodes,
-                &mut unknown_hub_ids,
-                admin_subnet.clone(),
-                use_subnet_nodes,
-            ),
-            "heuristic".to_string(),
-            0.5,
-        )
-    };
-    edges.push(TopologyEdge {
-        id: format!("{}->{}", admin_id, admin_parent.0),
-        child_id: admin_id,
-        parent_id: admin_parent.0.clone(),
-        method: admin_parent.1,
-        confidence: admin_parent.2,
-    });
-    *attachment_count.entry(admin_parent.0).or_insert(0) += 1;
-
-    for host in host_records {
-        let node_id = format!("host:{}", host.device_key);
-        nodes.push(TopologyNode {
-            id: node_id.clone(),
-            node_type: "host".to_string(),
-            label: host.hostname.clone(),
-            ip: host.ip.clone().or_else(|| host.ips.first().cloned()),
-            subnet_cidr: host.subnet_cidr.clone(),
-            gateway_ip: host.default_gateway_ip.clone(),
-            agent_id: Some(host.agent_id.clone()),
-            interface_type: host.interface_type.clone(),
-            attached_count: None,
-        });
+        // Both drop mid-scan; only lab-2 never acknowledged the task.
+        let mut first = connect("lab-1").await;
+        let mut second = connect("lab-2").await;
+        manager.redeliver_pending_tasks(&sink, "lab-1").await;
+        manager.redeliver_pending_tasks(&sink, "lab-2").await;
+        assert!(first.try_recv().is_err());
+        let Ok(Message::Text(frame)) = second.try_recv() else {
+            panic!("unacknowledged task was not resent");
+        };
+        let frame: Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["type"], "task");
+        assert_eq!(frame["payload"]["task_id"], task.task_id.as_str());
 
-        let (parent_id, method, confidence) = if let Some(gw) = host
-            .default_gateway_ip
-            .clone()
-            .and_then(|v| clean_non_empty_owned(&v))
         {
-            let key = format!("{}|{}", host.subnet_cidr.clone().unwrap_or_default(), gw);
-            let parent_id = gateway_by_key
-                .get(&key)
-                .cloned()
-                .unwrap_or_else(|| format!("gw:{}", gw));
-            (parent_id, "evidence".to_string(), 0.9)
-        } else {
-            (
-                ensure_unknown_hub_node(
-                    &mut nodes,
-                    &mut edges,
-                    &subnet_nodes,
-                    &mut unknown_hub_ids,
-                    host.subnet_cidr.clone(),
-                    use_subnet_nodes,
-                ),
-                "heuristic".to_string(),
-                0.45,
+            let mut state = manager.inner.lock().await;
+            mark_task_acked(state.tasks.get_mut(&task.task_id).unwrap(), "lab-2");
+        }
+        manager.redeliver_pending_tasks(&sink, "lab-2").await;
+        assert!(second.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn agent_control_goes_out_as_a_wire_frame() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager
+            .inner
+            .lock()
+            .await
+            .connections
+            .insert("lab-1".to_string(), tx);
+
+        let request_id = manager
+            .send_agent_control(&sink, "lab-1".to_string(), " Reconnect ".to_string())
+            .await
+            .unwrap();
+        let Ok(Message::Text(frame)) = rx.try_recv() else {
+            panic!("no control frame");
+        };
+        let frame: WireMessage = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame.message_type, "control");
+        assert_eq!(frame.agent_id, "lab-1");
+        assert_eq!(
+            frame.payload,
+            json!({ "action": "reconnect", "request_id": request_id })
+        );
+
+        assert!(matches!(
+            manager
+                .send_agent_control(&sink, "lab-1".to_string(), "shutdown".to_string())
+                .await,
+            Err(ServerError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            manager
+                .send_agent_control(&sink, "lab-2".to_string(), "refresh".to_string())
+                .await,
+            Err(ServerError::Unavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancel_task_notifies_pending_agents() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager
+            .inner
+            .lock()
+            .await
+            .connections
+            .insert("lab-1".to_string(), tx);
+        let task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string(), "lab-2".to_string()],
+                "port_scan".to_string(),
+                json!({ "target": "10.0.0.5", "ports": [22, 443] }),
+                TaskOptions::default(),
             )
+            .await
+            .unwrap();
+        assert!(matches!(rx.try_recv(), Ok(Message::Text(_))));
+
+        let cancelled = manager
+            .cancel_task(&sink, task.task_id.clone())
+            .await
+            .unwrap();
+        assert_eq!(cancelled.status, "cancelled");
+        assert!(cancelled.ended_at.is_some());
+        let Ok(Message::Text(frame)) = rx.try_recv() else {
+            panic!("no task_cancel frame");
         };
+        let frame: Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["type"], "task_cancel");
+        assert_eq!(frame["payload"]["task_id"], task.task_id.as_str());
+        assert!(sink
+            .events()
+            .iter()
+            .any(|(name, payload)| name == EVENT_ACTIVITY && payload["kind"] == "task_cancelled"));
 
-        edges.push(TopologyEdge {
-            id: format!("{}->{}", node_id, parent_id),
-            child_id: node_id,
-            parent_id: parent_id.clone(),
-            method,
-            confidence,
-        });
-        *attachment_count.entry(parent_id).or_insert(0) += 1;
+        assert!(manager.cancel_task(&sink, task.task_id).await.is_err());
+        assert!(manager
+            .cancel_task(&sink, "missing".to_string())
+            .await
+            .is_err());
     }
 
-    for node in &mut nodes {
-        if node.node_type == "gateway" || node.node_type == "unknown_hub" {
-            node.attached_count = Some(*attachment_count.get(&node.id).unwrap_or(&0));
-        }
+    #[test]
+    fn task_params_are_validated_per_kind() {
+        let check = |kind: &str, params: Value| validate_task_params(kind, &params);
+        assert!(check("port_scan", json!({})).is_err());
+        assert!(check("port_scan", json!({ "ports": [] })).is_err());
+        assert!(check("port_scan", json!({ "ports": [22, 70000] })).is_err());
+        assert!(check("port_scan", json!({ "ports": [0] })).is_err());
+        assert!(check(
+            "port_scan",
+            json!({ "port_range": { "start": 100, "end": 10 } })
+        )
+        .is_err());
+        assert!(check(
+            "port_scan",
+            json!({ "port_range": { "start": 1, "end": 65536 } })
+        )
+        .is_err());
+        assert!(check("port_scan", json!({ "ports": [22, 80, 443] })).is_ok());
+        assert!(check(
+            "port_scan",
+            json!({ "port_range": { "start": 1, "end": 1024 } })
+        )
+        .is_ok());
+
+        assert!(check("ping", json!({})).is_err());
+        assert!(check("ping", json!({ "targets": [] })).is_err());
+        assert!(check("ping", json!({ "targets": ["10.0.0.1", " "] })).is_err());
+        assert!(check("ping", json!({ "targets": ["10.0.0.1", "10.0.0.2"] })).is_ok());
+        assert!(check("ping", json!({ "target": "10.0.0.1", "count": 0 })).is_err());
+        assert!(check("ping", json!({ "target": "10.0.0.1", "count": 101 })).is_err());
+        assert!(check("ping", json!({ "target": "10.0.0.1", "count": 4 })).is_ok());
+
+        assert!(check("traceroute", json!({ "target": " " })).is_err());
+        assert!(check("traceroute", json!({ "target": "gw.lab", "max_hops": 0 })).is_err());
+        assert!(check("traceroute", json!({ "target": "8.8.8.8" })).is_ok());
+
+        assert!(check("dns_lookup", json!({ "hostname": "" })).is_err());
+        assert!(check(
+            "dns_lookup",
+            json!({ "hostname": "lab.local", "record_type": "TXT" })
+        )
+        .is_err());
+        assert!(check("dns_lookup", json!({ "hostname": "lab.local" })).is_ok());
+        assert!(check(
+            "dns_lookup",
+            json!({ "hostname": "lab.local", "record_type": "MX" })
+        )
+        .is_ok());
     }
 
-    nodes.sort_by(compare_topology_nodes);
-    edges.sort_by(|a, b| {
-        let child = a.child_id.cmp(&b.child_id);
-        if child != Ordering::Equal {
-            return child;
-        }
-        a.parent_id.cmp(&b.parent_id)
-    });
-
-    TopologySnapshot {
-        revision,
-        updated_at: now_ms(),
-        nodes,
-        edges,
-    }
-}
-
-fn ensure_unknown_hub_node(
-    nodes: &mut Vec<TopologyNode>,
-    edges: &mut Vec<TopologyEdge>,
-    subnet_nodes: &HashMap<String, String>,
-    unknown_hub_ids: &mut HashMap<String, String>,
-    subnet: Option<String>,
-    use_subnet_nodes: bool,
-) -> String {
-    let subnet_key = subnet.clone().unwrap_or_else(|| "unknown".to_string());
-    if let Some(existing) = unknown_hub_ids.get(&subnet_key) {
-        return existing.clone();
-    }
-
-    let hub_id = format!("hub:{}", subnet_key);
-    nodes.push(TopologyNode {
-        id: hub_id.clone(),
-        node_type: "unknown_hub".to_string(),
-        label: "Unknown Hub".to_string(),
-        ip: None,
-        subnet_cidr: subnet.clone(),
-        gateway_ip: None,
-        agent_id: None,
-        interface_type: None,
-        attached_count: None,
-    });
+    #[tokio::test]
+    async fn journaled_events_replay_without_snapshots() {
+        let manager = test_manager();
+        let sink = CaptureSink::journaled(&manager);
+        manager
+            .emit_log(&sink, None, "INFO", "first".to_string())
+            .await;
+        manager.emit_devices_snapshot(&sink).await;
+        manager
+            .emit_log(&sink, None, "INFO", "second".to_string())
+            .await;
 
-    if use_subnet_nodes {
-        if let Some(subnet_value) = subnet {
-            if let Some(subnet_id) = subnet_nodes.get(&subnet_value) {
-                edges.push(TopologyEdge {
-                    id: format!("{}->{}", hub_id, subnet_id),
-                    child_id: hub_id.clone(),
-                    parent_id: subnet_id.clone(),
-                    method: "heuristic".to_string(),
-                    confidence: 0.5,
-                });
+        let stamped: Vec<(String, u64)> = sink
+            .events()
+            .into_iter()
+            .filter_map(|(name, payload)| Some((name, payload["event_seq"].as_u64()?)))
+            .collect();
+        assert_eq!(
+            stamped,
+            [(EVENT_LOG.to_string(), 1), (EVENT_LOG.to_string(), 2)]
+        );
+
+        // Journal writes go through the background writer.
+        let replay = timeout(Duration::from_secs(5), async {
+            loop {
+                let replay = manager.get_events_since(0).await.unwrap();
+                if replay.events.len() == 2 {
+                    return replay;
+                }
+                sleep(Duration::from_millis(10)).await;
             }
-        }
+        })
+        .await
+        .unwrap();
+        assert_eq!(replay.latest_seq, 2);
+        assert!(replay.events.iter().all(|e| e.event == EVENT_LOG));
+        assert_eq!(replay.events[1].payload["message"], "second");
+
+        let tail = manager.get_events_since(1).await.unwrap();
+        assert_eq!(tail.events.len(), 1);
+        assert_eq!(tail.events[0].seq, 2);
+        assert!(manager.get_events_since(2).await.unwrap().events.is_empty());
+        assert!(
+            manager
+                .get_events_since(u64::MAX)
+                .await
+                .unwrap()
+                .snapshot_required
+        );
     }
 
-    unknown_hub_ids.insert(subnet_key, hub_id.clone());
-    hub_id
-}
-
-fn compare_device_topology_order(a: &DeviceRecord, b: &DeviceRecord) -> Ordering {
-    let a_ip =
-        a.ip.as_deref()
-            .or_else(|| a.ips.first().map(String::as_str))
-            .and_then(ip_to_u32);
-    let b_ip =
-        b.ip.as_deref()
-            .or_else(|| b.ips.first().map(String::as_str))
-            .and_then(ip_to_u32);
-    match (a_ip, b_ip) {
-        (Some(x), Some(y)) if x != y => x.cmp(&y),
-        (Some(_), None) => Ordering::Less,
-        (None, Some(_)) => Ordering::Greater,
-        _ => {
-            let host_cmp = a.hostname.cmp(&b.hostname);
-            if host_cmp != Ordering::Equal {
-                host_cmp
-            } else {
-                a.device_key.cmp(&b.device_key)
+    #[tokio::test]
+    async fn mock_devices_stay_out_of_storage_reaping_and_alerts() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        manager.inner.lock().await.devices.insert(
+            "mock-0001".to_string(),
+            DeviceRecord {
+                agent_id: "mock-0001".to_string(),
+                hostname: "real-pc".to_string(),
+                status: "online".to_string(),
+                last_seen_ms: clock.now_ms(),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(manager.inject_mock_devices(&sink, 5).await.unwrap(), 5);
+        let mocks: Vec<DeviceRecord> = {
+            let state = manager.inner.lock().await;
+            assert_eq!(state.devices["mock-0001"].hostname, "real-pc");
+            assert!(!state.mock_agents.contains("mock-0001"));
+            state
+                .devices
+                .values()
+                .filter(|d| d.is_mock)
+                .cloned()
+                .collect()
+        };
+        assert_eq!(mocks.len(), 5);
+        assert!(mocks.iter().any(|d| d.status == "offline"));
+
+        // Well past every outage threshold and the reap window.
+        clock.advance(30 * 24 * 60 * 60 * 1000);
+        manager.escalate_outages(&sink).await;
+        manager.reap_stale_devices(&sink).await;
+        assert_eq!(
+            manager
+                .inner
+                .lock()
+                .await
+                .devices
+                .values()
+                .filter(|d| d.is_mock)
+                .count(),
+            5
+        );
+        assert!(!sink
+            .events()
+            .iter()
+            .any(|(name, payload)| name == EVENT_ACTIVITY
+                && payload["agent_id"]
+                    .as_str()
+                    .is_some_and(|id| id != "mock-0001")));
+
+        // Writes are ordered, so once the real device's row lands the mock's would have too.
+        let real = manager.inner.lock().await.devices["mock-0001"].clone();
+        manager.persist_facts(&mocks[0]);
+        manager.persist_facts(&real);
+        timeout(Duration::from_secs(5), async {
+            while manager
+                .db
+                .lock()
+                .await
+                .get_device_history("mock-0001", 0)
+                .unwrap()
+                .is_empty()
+            {
+                sleep(Duration::from_millis(10)).await;
             }
-        }
-    }
-}
+        })
+        .await
+        .unwrap();
+        assert!(manager
+            .db
+            .lock()
+            .await
+            .get_device_history(&mocks[0].agent_id, 0)
+            .unwrap()
+            .is_empty());
+
+        let config = ServerConfig {
+            allow_mock_devices: true,
+            ..manager.get_config().await
+        };
+        manager.set_config(&sink, config).await.unwrap();
+        assert!(!manager.get_config().await.allow_mock_devices);
 
-fn compare_topology_nodes(a: &TopologyNode, b: &TopologyNode) -> Ordering {
-    let rank = |node_type: &str| match node_type {
-        "subnet" => 0,
-        "gateway" => 1,
-        "switch" => 2,
-        "unknown_hub" => 3,
-        "admin" => 4,
-        "host" => 5,
-        _ => 6,
-    };
-    let r = rank(&a.node_type).cmp(&rank(&b.node_type));
-    if r != Ordering::Equal {
-        return r;
+        assert_eq!(manager.clear_mock_devices(&sink).await.unwrap(), 5);
+        let state = manager.inner.lock().await;
+        assert_eq!(state.devices.len(), 1);
+        assert_eq!(state.devices["mock-0001"].hostname, "real-pc");
     }
 
-    let a_ip = a
-        .gateway_ip
-        .as_deref()
-        .and_then(ip_to_u32)
-        .or_else(|| ip_to_u32(&a.label));
-    let b_ip = b
-        .gateway_ip
-        .as_deref()
-        .and_then(ip_to_u32)
-        .or_else(|| ip_to_u32(&b.label));
-    match (a_ip, b_ip) {
-        (Some(x), Some(y)) if x != y => x.cmp(&y),
-        (Some(_), None) => Ordering::Less,
-        (None, Some(_)) => Ordering::Greater,
-        _ => a.id.cmp(&b.id),
+    #[tokio::test]
+    async fn overdue_tasks_time_out_missing_agents() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        manager
+            .inner
+            .lock()
+            .await
+            .connections
+            .insert("lab-1".to_string(), tx);
+        let task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string(), "lab-2".to_string()],
+                "ping".to_string(),
+                json!({ "target": "10.0.0.1" }),
+                TaskOptions {
+                    timeout_ms: Some(5_000),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(task.status, "running");
+
+        clock.advance(5_000);
+        manager.expire_overdue_tasks(&sink).await;
+        assert_eq!(
+            manager.inner.lock().await.tasks[&task.task_id].status,
+            "running"
+        );
+
+        clock.advance(1);
+        manager.expire_overdue_tasks(&sink).await;
+        let expired = manager.inner.lock().await.tasks[&task.task_id].clone();
+        assert_eq!(expired.status, "timeout");
+        assert_eq!(expired.ended_at, Some(1_005_001));
+        assert_eq!(expired.results.len(), 2);
+        assert!(expired
+            .results
+            .iter()
+            .all(|r| !r.ok && r.error_code.as_deref() == Some("timeout")));
+        assert!(sink
+            .events()
+            .iter()
+            .any(|(name, payload)| name == EVENT_ACTIVITY && payload["kind"] == "task_failed"));
     }
-}
 
-fn topology_key(snapshot: &TopologySnapshot) -> String {
-    let mut node_parts: Vec<String> = snapshot
-        .nodes
-        .iter()
-        .map(|n| {
-            format!(
-                "{}|{}|{}|{}|{}",
-                n.id,
-                n.node_type,
-                n.ip.clone().unwrap_or_default(),
-                n.subnet_cidr.clone().unwrap_or_default(),
-                n.gateway_ip.clone().unwrap_or_default()
+    #[tokio::test]
+    async fn tasks_for_offline_agents_time_out_from_creation() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string()],
+                "ping".to_string(),
+                json!({ "target": "10.0.0.1" }),
+                TaskOptions {
+                    timeout_ms: Some(5_000),
+                    ..Default::default()
+                },
             )
-        })
-        .collect();
-    node_parts.sort();
-    let mut edge_parts: Vec<String> = snapshot
-        .edges
-        .iter()
-        .map(|e| format!("{}|{}|{}", e.child_id, e.parent_id, e.method))
-        .collect();
-    edge_parts.sort();
-    format!("{}#{}", node_parts.join(";"), edge_parts.join(";"))
-}
-
-fn detect_admin_network_facts() -> NetworkFactsPayload {
-    let ip = detect_local_ipv4_string().unwrap_or_else(|| "127.0.0.1".to_string());
-    let default_gateway_ip = detect_default_gateway_ip().unwrap_or_default();
-    let subnet_cidr = guess_subnet_from_ip(&ip).unwrap_or_else(|| "127.0.0.0/24".to_string());
-
-    NetworkFactsPayload {
-        ip,
-        subnet_cidr,
-        default_gateway_ip,
-        interface_type: detect_interface_type(),
-        mac: None,
-        gateway_mac: None,
-        dhcp_server_ip: None,
-        ssid: detect_ssid(),
-        arp_snapshot: Vec::new(),
+            .await
+            .unwrap();
+        assert_eq!(task.status, "queued");
+        assert_eq!(task.started_at, None);
+
+        clock.advance(5_001);
+        manager.expire_overdue_tasks(&sink).await;
+        let expired = manager.inner.lock().await.tasks[&task.task_id].clone();
+        assert_eq!(expired.status, "timeout");
+        assert_eq!(expired.results.len(), 1);
+        assert_eq!(expired.results[0].error_code.as_deref(), Some("timeout"));
     }
-}
 
-fn detect_local_ipv4_string() -> Option<String> {
-    detect_local_ipv4().map(|ip| ip.to_string())
-}
+    #[tokio::test]
+    async fn retry_failed_waits_for_the_task_to_finish() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        manager
+            .inner
+            .lock()
+            .await
+            .connections
+            .insert("lab-1".to_string(), tx);
+        let task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string(), "lab-2".to_string()],
+                "ping".to_string(),
+                json!({ "target": "10.0.0.1" }),
+                TaskOptions {
+                    timeout_ms: Some(5_000),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            manager.retry_failed(&sink, task.task_id.clone()).await,
+            Err(ServerError::Conflict(_))
+        ));
+
+        clock.advance(5_001);
+        manager.expire_overdue_tasks(&sink).await;
+        let rerun = manager
+            .retry_failed(&sink, task.task_id.clone())
+            .await
+            .unwrap();
+        assert_eq!(rerun.assigned_agents, ["lab-1", "lab-2"]);
+        assert_eq!(rerun.origin_task_id.as_deref(), Some(task.task_id.as_str()));
+    }
 
-fn detect_default_gateway_ip() -> Option<String> {
-    if cfg!(target_os = "windows") {
-        let out = command_output("route", &["print", "-4"])?;
-        for line in out.lines() {
-            let fields: Vec<&str> = line.split_whitespace().collect();
-            if fields.len() >= 3
-                && fields[0] == "0.0.0.0"
-                && fields[1] == "0.0.0.0"
-                && ip_to_u32(fields[2]).is_some()
+    #[tokio::test]
+    async fn rerun_depth_is_kept_on_the_task_after_its_origin_is_evicted() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let mut task = manager
+            .dispatch_task(
+                &sink,
+                vec!["lab-1".to_string()],
+                "ping".to_string(),
+                json!({ "target": "10.0.0.1" }),
+                TaskOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(task.rerun_depth, 0);
+        for depth in 1..=3 {
             {
-                return Some(fields[2].to_string());
+                let mut state = manager.inner.lock().await;
+                let record = state.tasks.get_mut(&task.task_id).unwrap();
+                record.status = "failed".to_string();
+                record.ended_at = Some(manager.now());
             }
+            let rerun = manager
+                .retry_failed(&sink, task.task_id.clone())
+                .await
+                .unwrap();
+            assert_eq!(rerun.rerun_depth, depth);
+            manager.inner.lock().await.tasks.remove(&task.task_id);
+            task = rerun;
         }
+        let stored: TaskRecord =
+            serde_json::from_value(serde_json::to_value(&task).unwrap()).unwrap();
+        assert_eq!(stored.rerun_depth, 3);
     }
 
-    if cfg!(target_os = "linux") {
-        let out = command_output("ip", &["route", "show", "default"])?;
-        let parts: Vec<&str> = out.split_whitespace().collect();
-        for i in 0..parts.len().saturating_sub(1) {
-            if parts[i] == "via" && ip_to_u32(parts[i + 1]).is_some() {
-                return Some(parts[i + 1].to_string());
-            }
+    #[tokio::test]
+    async fn failed_registrations_ban_a_source_only_within_the_window() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let remote: SocketAddr = "10.0.0.66:50000".parse().unwrap();
+        let policy = manager.inner.lock().await.config.secret_backoff.clone();
+
+        // Failures spread wider than the window never accumulate to a ban.
+        for _ in 0..policy.threshold {
+            manager.record_secret_failure(&sink, &remote).await;
+            clock.advance(policy.window_ms);
+        }
+        clock.advance(1);
+        assert_eq!(manager.secret_ban_remaining(&remote).await, None);
+
+        for _ in 0..policy.threshold {
+            manager.record_secret_failure(&sink, &remote).await;
         }
+        assert_eq!(
+            manager.secret_ban_remaining(&remote).await,
+            Some(policy.base_ban_ms)
+        );
+        let limited = sink
+            .events()
+            .into_iter()
+            .filter(|(name, payload)| {
+                name == EVENT_ACTIVITY && payload["kind"] == "auth_rate_limited"
+            })
+            .count();
+        assert_eq!(limited, 1);
+
+        manager.clear_secret_failures(&sink, &remote).await;
+        assert_eq!(manager.secret_ban_remaining(&remote).await, None);
     }
-    None
-}
 
-fn detect_interface_type() -> String {
-    if cfg!(target_os = "windows") {
-        if let Some(out) = command_output("netsh", &["wlan", "show", "interfaces"]) {
-            let lower = out.to_lowercase();
-            if lower.contains("ssid") && !lower.contains("there is no wireless interface") {
-                return "wifi".to_string();
+    #[tokio::test]
+    async fn repeated_bans_escalate_to_the_cap_until_a_clean_period() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let manager = clocked_manager(clock.clone());
+        let sink = CaptureSink::default();
+        let remote: SocketAddr = "10.0.0.66:50000".parse().unwrap();
+        let policy = manager.inner.lock().await.config.secret_backoff.clone();
+        let burst = || async {
+            for _ in 0..policy.threshold {
+                manager.record_secret_failure(&sink, &remote).await;
+            }
+            manager.secret_ban_remaining(&remote).await.unwrap()
+        };
+
+        // A steady attacker retries as soon as each ban lifts.
+        let mut expected = policy.base_ban_ms;
+        let mut bans = 0;
+        loop {
+            let ban = burst().await;
+            assert_eq!(ban, expected);
+            bans += 1;
+            clock.advance(ban);
+            if ban == policy.max_ban_ms {
+                break;
             }
+            expected = (expected * 2).min(policy.max_ban_ms);
         }
+        assert!(bans > 2);
+        assert_eq!(burst().await, policy.max_ban_ms);
+
+        clock.advance(policy.max_ban_ms * 2 + 1);
+        assert_eq!(burst().await, policy.base_ban_ms);
     }
-    "ethernet".to_string()
-}
 
-fn detect_ssid() -> Option<String> {
-    if !cfg!(target_os = "windows") {
-        return None;
+    #[tokio::test]
+    async fn await_task_returns_on_completion_or_timeout() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let ping = || json!({ "target": "10.0.0.1" });
+        assert!(manager
+            .await_task(
+                &sink,
+                vec!["lab-1".to_string()],
+                "ping".to_string(),
+                ping(),
+                0
+            )
+            .await
+            .is_err());
+
+        let awaited = manager
+            .await_task(
+                &sink,
+                vec!["lab-1".to_string()],
+                "ping".to_string(),
+                ping(),
+                20,
+            )
+            .await
+            .unwrap();
+        assert!(awaited.timed_out);
+        assert!(awaited.task.ended_at.is_none());
+
+        let finisher = manager.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(20)).await;
+            let task = {
+                let mut state = finisher.inner.lock().await;
+                let task = state
+                    .tasks
+                    .values_mut()
+                    .find(|t| t.ended_at.is_none() && t.assigned_agents == ["lab-2"])
+                    .unwrap();
+                task.status = "done".to_string();
+                task.ended_at = Some(finisher.now());
+                task.clone()
+            };
+            let _ = finisher.task_completions.send(task);
+        });
+        let awaited = manager
+            .await_task(
+                &sink,
+                vec!["lab-2".to_string()],
+                "ping".to_string(),
+                ping(),
+                5_000,
+            )
+            .await
+            .unwrap();
+        assert!(!awaited.timed_out);
+        assert_eq!(awaited.task.status, "done");
     }
-    let out = command_output("netsh", &["wlan", "show", "interfaces"])?;
-    for line in out.lines() {
-        let trimmed = line.trim();
-        let lower = trimmed.to_lowercase();
-        if lower.starts_with("ssid") && !lower.starts_with("bssid") {
-            let parts: Vec<&str> = trimmed.splitn(2, ':').collect();
-            if parts.len() == 2 {
-                let ssid = parts[1].trim();
-                if !ssid.is_empty() {
-                    return Some(ssid.to_string());
-                }
+
+    #[tokio::test]
+    async fn mesh_ping_sends_one_task_per_source_and_fails_offline_sources() {
+        let manager = test_manager();
+        let sink = CaptureSink::default();
+        let mut receivers = HashMap::new();
+        {
+            let mut state = manager.inner.lock().await;
+            for (id, ip) in [
+                ("lab-1", "10.0.0.1"),
+                ("lab-2", "10.0.0.2"),
+                ("lab-3", "10.0.0.3"),
+            ] {
+                state.devices.insert(
+                    id.to_string(),
+                    DeviceRecord {
+                        agent_id: id.to_string(),
+                        ip: Some(ip.to_string()),
+                        ..Default::default()
+                    },
+                );
+            }
+            for id in ["lab-1", "lab-2"] {
+                let (tx, rx) = mpsc::unbounded_channel();
+                state.connections.insert(id.to_string(), tx);
+                receivers.insert(id, rx);
             }
         }
-    }
-    None
-}
 
-fn command_output(command: &str, args: &[&str]) -> Option<String> {
-    let out = Command::new(command).args(args).output().ok()?;
-    if !out.status.success() {
-        return None;
-    }
-    String::from_utf8(out.stdout).ok()
-}
+        let finisher = manager.clone();
+        tokio::spawn(async move {
+            let tasks = loop {
+                let tasks: Vec<TaskRecord> = {
+                    let state = finisher.inner.lock().await;
+                    state.tasks.values().cloned().collect()
+                };
+                if tasks.len() == 2 {
+                    break tasks;
+                }
+                sleep(Duration::from_millis(5)).await;
+            };
+            for mut task in tasks {
+                let source = task.assigned_agents[0].clone();
+                let results: Vec<Value> = task.params["targets"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|target| {
+                        // lab-1 reaches everything, lab-2 reaches nothing.
+                        json!({ "target": target, "ok": source == "lab-1", "latency_ms": 3 })
+                    })
+                    .collect();
+                task.results.push(TaskResultRecord {
+                    agent_id: source,
+                    ok: true,
+                    result: json!({ "results": results }),
+                    error_code: None,
+                    error: None,
+                    ts: finisher.now(),
+                });
+                task.status = "done".to_string();
+                task.ended_at = Some(finisher.now());
+                let _ = finisher.task_completions.send(task);
+            }
+        });
 
-fn clean_non_empty_owned(value: &str) -> Option<String> {
-    let trimmed = value.trim();
-    if trimmed.is_empty() {
-        None
-    } else {
-        Some(trimmed.to_string())
+        let matrix = manager
+            .dispatch_mesh_ping(
+                &sink,
+                vec![
+                    "lab-1".to_string(),
+                    "lab-2".to_string(),
+                    "lab-3".to_string(),
+                ],
+            )
+            .await
+            .unwrap();
+        assert!(matrix.complete);
+        assert_eq!(matrix.cells.len(), 6);
+        assert_eq!(manager.inner.lock().await.tasks.len(), 2);
+        let Ok(Message::Text(frame)) = receivers.get_mut("lab-1").unwrap().try_recv() else {
+            panic!("lab-1 got no task");
+        };
+        let frame: Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(
+            frame["payload"]["params"]["targets"],
+            json!(["10.0.0.2", "10.0.0.3"])
+        );
+
+        let cell = |source: &str, destination: &str| {
+            matrix
+                .cells
+                .iter()
+                .find(|c| c.source == source && c.destination == destination)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(cell("lab-1", "lab-2").reachable, Some(true));
+        assert_eq!(cell("lab-1", "lab-2").latency_ms, Some(3));
+        assert_eq!(cell("lab-2", "lab-1").reachable, Some(false));
+        assert_eq!(cell("lab-3", "lab-1").task_id, None);
+        assert_eq!(
+            cell("lab-3", "lab-1").error.as_deref(),
+            Some("agent offline")
+        );
+        assert!(matrix
+            .asymmetric
+            .contains(&("lab-1".to_string(), "lab-2".to_string())));
     }
-}
 
-fn guess_subnet_from_ip(ip: &str) -> Option<String> {
-    let parts: Vec<&str> = ip.split('.').collect();
-    if parts.len() == 4 {
-        Some(format!("{}.{}.{}.0/24", parts[0], parts[1], parts[2]))
-    } else {
-        None
+    #[test]
+    fn extra_metrics_keep_unknown_keys_within_bounds() {
+        let mut metrics = json!({
+            "latency_ms": 12,
+            "cpu_temp": 61.5,
+            "gpu": {"usage": 0.4},
+            "bad key\n": 1,
+            "blob": "x".repeat(MAX_EXTRA_METRIC_BYTES),
+        });
+        for i in 0..MAX_EXTRA_METRICS {
+            metrics[format!("m{:02}", i)] = json!(i);
+        }
+        let extra = extra_metrics(&metrics);
+        assert_eq!(extra.len(), MAX_EXTRA_METRICS);
+        assert_eq!(extra.get("cpu_temp"), Some(&json!(61.5)));
+        assert!(extra.contains_key("gpu"));
+        assert!(!extra.contains_key("latency_ms"));
+        assert!(!extra.contains_key("blob"));
+
+        let schema = normalize_metric_schema(vec![
+            MetricSchema {
+                name: "cpu_temp".to_string(),
+                unit: Some(" celsius ".to_string()),
+                display: Some("gauge".to_string()),
+            },
+            MetricSchema {
+                name: "cpu_temp".to_string(),
+                ..Default::default()
+            },
+            MetricSchema {
+                name: "dns_ok".to_string(),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(schema.len(), 1);
+        assert_eq!(schema[0].unit.as_deref(), Some("celsius"));
     }
-}
 
-fn ip_to_u32(value: &str) -> Option<u32> {
-    let ip: Ipv4Addr = value.parse().ok()?;
-    Some(u32::from(ip))
-}
+    #[tokio::test]
+    async fn runtime_state_round_trips_and_keeps_live_agents() {
+        let source = test_manager();
+        let sink = CaptureSink::default();
+        {
+            let mut state = source.inner.lock().await;
+            for id in ["lab-1", "lab-2"] {
+                state.devices.insert(
+                    id.to_string(),
+                    DeviceRecord {
+                        agent_id: id.to_string(),
+                        hostname: format!("{}-pc", id),
+                        status: "online".to_string(),
+                        ..Default::default()
+                    },
+                );
+                state.device_order.push(id.to_string());
+            }
+        }
+        let rule = AliasRule {
+            hostname_pattern: Some("lab-1-*".to_string()),
+            alias: Some("front desk".to_string()),
+            ..Default::default()
+        };
+        source.add_alias_rule(&sink, rule).await.unwrap();
+        let doc = source.export_runtime_state().await;
+        assert_eq!(doc.devices.len(), 2);
+        let raw = serde_json::to_string(&doc).unwrap();
+
+        let target = test_manager();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        target
+            .inner
+            .lock()
+            .await
+            .connections
+            .insert("lab-2".to_string(), tx);
+        let doc: RuntimeStateDocument = serde_json::from_str(&raw).unwrap();
+        assert!(target
+            .import_runtime_state(&sink, doc.clone(), false)
+            .await
+            .is_err());
+        let future = RuntimeStateDocument {
+            version: RUNTIME_STATE_VERSION + 1,
+            ..doc.clone()
+        };
+        assert!(target
+            .import_runtime_state(&sink, future, true)
+            .await
+            .is_err());
+
+        let summary = target.import_runtime_state(&sink, doc, true).await.unwrap();
+        assert_eq!(summary.devices, 1);
+        assert_eq!(summary.live_kept, vec!["lab-2"]);
+        let device = target.get_device("lab-1").await.unwrap();
+        assert_eq!(device.status, "offline");
+        assert_eq!(device.alias.as_deref(), Some("front desk"));
+        assert_eq!(target.list_alias_rules().await.len(), 1);
+        assert!(target.get_device("lab-2").await.is_err());
+    }
 
-fn detect_local_ipv4() -> Option<std::net::Ipv4Addr> {
-    let udp = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
-    udp.connect("8.8.8.8:80").ok()?;
-    match udp.local_addr().ok()?.ip() {
-        std::net::IpAddr::V4(ip) => Some(ip),
-        _ => None,
+    #[test]
+    fn broadcast_budget_skips_cycles_over_the_rate() {
+        let mut budget = BroadcastBudget::default();
+        assert!(budget.try_spend(600, 0, 1_000));
+        assert!(budget.try_spend(600, 1000, 1_000));
+        assert!(!budget.try_spend(600, 1000, 1_100));
+        assert!(budget.try_spend(600, 1000, 1_800));
+
+        // A cycle bigger than a second's budget goes out once enough has accrued.
+        let mut budget = BroadcastBudget::default();
+        assert!(budget.try_spend(2500, 1000, 1_000));
+        assert!(!budget.try_spend(2500, 1000, 2_000));
+        assert!(budget.try_spend(2500, 1000, 3_500));
     }
-}
 
-fn now_ms() -> i64 {
-    chrono::Utc::now().timestamp_millis()
+    #[test]
+    fn device_record_reads_older_json() {
+        let device: DeviceRecord = serde_json::from_str(
+            r#"{"device_key":"k1","agent_id":"agent-1","hostname":"lab-pc-01","status":"online"}"#,
+        )
+        .unwrap();
+        assert_eq!(device.agent_id, "agent-1");
+        assert!(device.ips.is_empty());
+        assert!(!device.pending_approval);
+        assert_eq!(device.local_ports, None);
+    }
 }
@@ -0,0 +1,206 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::{fs, sync::Arc};
+
+const CERT_FILE: &str = "labscan-cert.pem";
+const KEY_FILE: &str = "labscan-key.pem";
+
+const SUBJECT_ALT_NAMES: [&str; 2] = ["labscan-admin", "localhost"];
+
+/// The admin certificate and private key, kept in the app data dir.
+#[derive(Debug, Clone)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertPaths {
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            cert: dir.join(CERT_FILE),
+            key: dir.join(KEY_FILE),
+        }
+    }
+}
+
+struct GeneratedCert {
+    cert_pem: String,
+    key_pem: String,
+    fingerprint: String,
+}
+
+/// Loads the admin certificate from disk, generating a self-signed one on first run,
+/// and returns its SHA-256 fingerprint.
+pub fn load_or_generate(paths: &CertPaths) -> Result<String, String> {
+    match fs::read_to_string(&paths.cert) {
+        Ok(pem) if paths.key.exists() => fingerprint_pem(&pem),
+        _ => regenerate(paths),
+    }
+}
+
+/// Replaces the certificate and key on disk and returns the new fingerprint.
+pub fn regenerate(paths: &CertPaths) -> Result<String, String> {
+    let generated = generate()?;
+    if let Some(dir) = paths.key.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("create {}: {}", dir.display(), e))?;
+    }
+    write_private(&paths.key, &generated.key_pem)
+        .map_err(|e| format!("write {}: {}", paths.key.display(), e))?;
+    fs::write(&paths.cert, &generated.cert_pem)
+        .map_err(|e| format!("write {}: {}", paths.cert.display(), e))?;
+    Ok(generated.fingerprint)
+}
+
+/// Owner read/write only, also when an older key file already exists.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+pub fn fingerprint_pem(pem: &str) -> Result<String, String> {
+    let der = rustls_pemfile::certs(&mut pem.as_bytes())
+        .next()
+        .ok_or_else(|| "no certificate found in PEM".to_string())?
+        .map_err(|e| format!("invalid certificate PEM: {}", e))?;
+    Ok(fingerprint_der(der.as_ref()))
+}
+
+fn fingerprint_der(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+fn generate() -> Result<GeneratedCert, String> {
+    let names: Vec<String> = SUBJECT_ALT_NAMES.iter().map(|n| n.to_string()).collect();
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| format!("certificate generation failed: {}", e))?;
+    Ok(GeneratedCert {
+        cert_pem: certified.cert.pem(),
+        key_pem: certified.key_pair.serialize_pem(),
+        fingerprint: fingerprint_der(certified.cert.der()),
+    })
+}
+
+/// Client config that trusts exactly the certificate with this fingerprint,
+/// the way agents pin the admin.
+pub fn pinned_client_config(fingerprint: &str) -> Arc<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = PinnedCert {
+        fingerprint: fingerprint.to_lowercase(),
+        provider: provider.clone(),
+    };
+    Arc::new(
+        ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions are supported")
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth(),
+    )
+}
+
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint_der(end_entity.as_ref()) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate fingerprint mismatch".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_matches_between_der_and_pem() {
+        let generated = generate().unwrap();
+        assert_eq!(generated.fingerprint.len(), 64);
+        assert_eq!(
+            fingerprint_pem(&generated.cert_pem).unwrap(),
+            generated.fingerprint
+        );
+        assert!(generated.key_pem.contains("PRIVATE KEY"));
+
+        let other = generate().unwrap();
+        assert_ne!(other.fingerprint, generated.fingerprint);
+        assert!(fingerprint_pem("not a pem").is_err());
+    }
+
+    #[test]
+    fn certificates_live_in_the_data_dir_with_a_private_key() {
+        let dir = std::env::temp_dir().join(format!("labscan-tls-{}", uuid::Uuid::new_v4()));
+        let paths = CertPaths::in_dir(&dir.join("nested"));
+        let fingerprint = load_or_generate(&paths).unwrap();
+        assert_eq!(load_or_generate(&paths).unwrap(), fingerprint);
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&paths.key).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_ne!(regenerate(&paths).unwrap(), fingerprint);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
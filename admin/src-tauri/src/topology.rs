@@ -0,0 +1,1584 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+};
+
+use crate::config::{ConfidenceWeights, ServerConfig};
+use crate::netutil::{self, ip_to_u32};
+use crate::server::{
+    clean_non_empty_owned, detect_local_ipv4_string, usable_mac, DeviceRecord, NetworkFactsPayload,
+    SubnetUtilization, TopologyEdge, TopologyNode, TopologySnapshot,
+};
+
+/// Operator-documented wiring: a device (by MAC or agent id) plugged into a named switch port.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopologySeedEntry {
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    pub switch: String,
+    #[serde(default)]
+    pub port: Option<String>,
+}
+
+impl TopologySeedEntry {
+    pub fn normalize(mut self) -> Result<Self, String> {
+        self.switch = clean_non_empty_owned(&self.switch)
+            .ok_or_else(|| "seed entry switch is required".to_string())?;
+        self.agent_id = self.agent_id.and_then(|v| clean_non_empty_owned(&v));
+        self.port = self.port.and_then(|v| clean_non_empty_owned(&v));
+        self.mac = match self.mac.and_then(|v| clean_non_empty_owned(&v)) {
+            Some(raw) => {
+                Some(usable_mac(&raw).ok_or_else(|| format!("invalid seed mac: {}", raw))?)
+            }
+            None => None,
+        };
+        if self.mac.is_none() && self.agent_id.is_none() {
+            return Err("seed entry needs a mac or agent_id".to_string());
+        }
+        Ok(self)
+    }
+
+    fn matches(&self, host: &DeviceRecord) -> bool {
+        if self.agent_id.as_deref() == Some(host.agent_id.as_str()) {
+            return true;
+        }
+        match (&self.mac, host.mac.as_deref().and_then(usable_mac)) {
+            (Some(seed_mac), Some(host_mac)) => *seed_mac == host_mac,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopologyQuality {
+    pub revision: u64,
+    pub edge_count: usize,
+    pub edges_by_method: BTreeMap<String, usize>,
+    pub edge_fraction_by_method: BTreeMap<String, f64>,
+    pub unknown_hub_attachments: usize,
+    pub device_count: usize,
+    pub devices_missing_subnet: usize,
+    pub devices_missing_gateway: usize,
+    /// Mean of the share of edges backed by evidence or configuration and the
+    /// share of devices reporting both subnet and gateway, as 0–100.
+    pub completeness_pct: f64,
+}
+
+/// What changed between two committed revisions, matched by node and edge id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopologyDiff {
+    pub rev_from: u64,
+    pub rev_to: u64,
+    pub added_nodes: Vec<TopologyNode>,
+    pub removed_nodes: Vec<String>,
+    pub added_edges: Vec<TopologyEdge>,
+    pub removed_edges: Vec<String>,
+}
+
+pub(crate) fn topology_diff(before: &TopologySnapshot, after: &TopologySnapshot) -> TopologyDiff {
+    let before_nodes: HashSet<&str> = before.nodes.iter().map(|n| n.id.as_str()).collect();
+    let after_nodes: HashSet<&str> = after.nodes.iter().map(|n| n.id.as_str()).collect();
+    let before_edges: HashSet<&str> = before.edges.iter().map(|e| e.id.as_str()).collect();
+    let after_edges: HashSet<&str> = after.edges.iter().map(|e| e.id.as_str()).collect();
+    TopologyDiff {
+        rev_from: before.revision,
+        rev_to: after.revision,
+        added_nodes: after
+            .nodes
+            .iter()
+            .filter(|n| !before_nodes.contains(n.id.as_str()))
+            .cloned()
+            .collect(),
+        removed_nodes: before
+            .nodes
+            .iter()
+            .filter(|n| !after_nodes.contains(n.id.as_str()))
+            .map(|n| n.id.clone())
+            .collect(),
+        added_edges: after
+            .edges
+            .iter()
+            .filter(|e| !before_edges.contains(e.id.as_str()))
+            .cloned()
+            .collect(),
+        removed_edges: before
+            .edges
+            .iter()
+            .filter(|e| !after_edges.contains(e.id.as_str()))
+            .map(|e| e.id.clone())
+            .collect(),
+    }
+}
+
+pub(crate) fn topology_quality(
+    snapshot: &TopologySnapshot,
+    devices: &HashMap<String, DeviceRecord>,
+) -> TopologyQuality {
+    let mut edges_by_method: BTreeMap<String, usize> = BTreeMap::new();
+    for edge in &snapshot.edges {
+        *edges_by_method.entry(edge.method.clone()).or_insert(0) += 1;
+    }
+    let edge_count = snapshot.edges.len();
+    let share = |part: usize, whole: usize| {
+        if whole == 0 {
+            0.0
+        } else {
+            part as f64 / whole as f64
+        }
+    };
+    let edge_fraction_by_method = edges_by_method
+        .iter()
+        .map(|(method, count)| (method.clone(), share(*count, edge_count)))
+        .collect();
+
+    let hub_ids: HashSet<&str> = snapshot
+        .nodes
+        .iter()
+        .filter(|n| n.node_type == "unknown_hub")
+        .map(|n| n.id.as_str())
+        .collect();
+    let unknown_hub_attachments = snapshot
+        .edges
+        .iter()
+        .filter(|e| hub_ids.contains(e.parent_id.as_str()))
+        .count();
+
+    let missing =
+        |value: &Option<String>| value.as_deref().and_then(clean_non_empty_owned).is_none();
+    let devices_missing_subnet = devices.values().filter(|d| missing(&d.subnet_cidr)).count();
+    let devices_missing_gateway = devices
+        .values()
+        .filter(|d| missing(&d.default_gateway_ip))
+        .count();
+    let devices_complete = devices
+        .values()
+        .filter(|d| !missing(&d.subnet_cidr) && !missing(&d.default_gateway_ip))
+        .count();
+
+    let backed_edges = snapshot
+        .edges
+        .iter()
+        .filter(|e| matches!(e.method.as_str(), "evidence" | "configured" | "manual"))
+        .count();
+    let mut parts = Vec::new();
+    if edge_count > 0 {
+        parts.push(share(backed_edges, edge_count));
+    }
+    if !devices.is_empty() {
+        parts.push(share(devices_complete, devices.len()));
+    }
+    let completeness_pct = if parts.is_empty() {
+        0.0
+    } else {
+        (parts.iter().sum::<f64>() / parts.len() as f64 * 1000.0).round() / 10.0
+    };
+
+    TopologyQuality {
+        revision: snapshot.revision,
+        edge_count,
+        edges_by_method,
+        edge_fraction_by_method,
+        unknown_hub_attachments,
+        device_count: devices.len(),
+        devices_missing_subnet,
+        devices_missing_gateway,
+        completeness_pct,
+    }
+}
+
+pub(crate) fn build_topology_snapshot(
+    devices: &HashMap<String, DeviceRecord>,
+    device_order: &[String],
+    admin_network: &NetworkFactsPayload,
+    config: &ServerConfig,
+    seed: &[TopologySeedEntry],
+    revision: u64,
+    now: i64,
+) -> TopologySnapshot {
+    let weights = &config.confidence;
+    let mut nodes: Vec<TopologyNode> = Vec::new();
+    let mut edges: Vec<TopologyEdge> = Vec::new();
+
+    // v6-only hosts group under their v6 prefix wherever a subnet is used.
+    let mut host_records: Vec<DeviceRecord> = device_order
+        .iter()
+        .filter_map(|id| devices.get(id).cloned())
+        .map(|mut host| {
+            if host.subnet_cidr.is_none() {
+                host.subnet_cidr = host.ipv6_cidr.clone();
+            }
+            host
+        })
+        .collect();
+    host_records.sort_by(compare_device_topology_order);
+
+    let admin_ip = clean_non_empty_owned(&admin_network.ip)
+        .or_else(detect_local_ipv4_string)
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+    let admin_subnet = clean_non_empty_owned(&admin_network.subnet_cidr)
+        .or_else(|| netutil::guess_subnet(&admin_ip));
+    let admin_gateway = clean_non_empty_owned(&admin_network.default_gateway_ip);
+
+    let mut gateway_by_key: HashMap<String, String> = HashMap::new();
+    let mut subnet_nodes: HashMap<String, String> = HashMap::new();
+    let mut gateway_specs: Vec<(String, Option<String>)> = Vec::new();
+    let mut attachment_count: HashMap<String, usize> = HashMap::new();
+
+    let mut observed_subnets: Vec<String> = host_records
+        .iter()
+        .filter_map(|d| d.subnet_cidr.clone())
+        .collect();
+    if let Some(s) = admin_subnet.clone() {
+        observed_subnets.push(s);
+    }
+    observed_subnets.sort();
+    observed_subnets.dedup();
+
+    // VLAN nodes sit above subnets, so several VLANs force subnet nodes on too.
+    // Only a subnet's majority tag gets a node; minority tags would sit empty.
+    let mut vlan_by_subnet = majority_vlan_by_subnet(&host_records);
+    let mut observed_vlans: Vec<u16> = vlan_by_subnet.values().copied().collect();
+    observed_vlans.sort_unstable();
+    observed_vlans.dedup();
+    let use_vlan_nodes = observed_vlans.len() > 1;
+    if !use_vlan_nodes {
+        vlan_by_subnet.clear();
+    }
+    let use_subnet_nodes = observed_subnets.len() > 1 || use_vlan_nodes;
+
+    let mut subnet_host_counts: HashMap<String, usize> = HashMap::new();
+    for host in &host_records {
+        if let Some(subnet) = &host.subnet_cidr {
+            *subnet_host_counts.entry(subnet.clone()).or_insert(0) += 1;
+        }
+    }
+    let mut subnet_group_ids: HashMap<String, String> = HashMap::new();
+    let mut switch_ids: HashMap<String, String> = HashMap::new();
+
+    if use_subnet_nodes {
+        let mut seen_ips: Vec<&str> = host_records
+            .iter()
+            .flat_map(|d| d.ip.iter().chain(d.ips.iter()))
+            .chain(
+                host_records
+                    .iter()
+                    .flat_map(|d| d.arp_snapshot.iter())
+                    .chain(admin_network.arp_snapshot.iter())
+                    .filter(|e| usable_mac(&e.mac).is_some())
+                    .map(|e| &e.ip),
+            )
+            .map(|ip| ip.trim())
+            .chain(std::iter::once(admin_ip.as_str()))
+            .collect();
+        seen_ips.sort_unstable();
+        seen_ips.dedup();
+
+        for vlan in &observed_vlans {
+            nodes.push(TopologyNode {
+                id: vlan_node_id(*vlan),
+                node_type: "vlan".to_string(),
+                label: format!("VLAN {}", vlan),
+                ip: None,
+                subnet_cidr: None,
+                gateway_ip: None,
+                agent_id: None,
+                interface_type: None,
+                attached_count: None,
+                utilization: None,
+                behind_nat: false,
+            });
+        }
+
+        for subnet in &observed_subnets {
+            let subnet_id = format!("subnet:{}", subnet);
+            subnet_nodes.insert(subnet.clone(), subnet_id.clone());
+            let utilization = SubnetUtilization {
+                managed_hosts: *subnet_host_counts.get(subnet).unwrap_or(&0),
+                observed_ips: seen_ips
+                    .iter()
+                    .filter(|ip| netutil::contains(subnet, ip))
+                    .count(),
+                capacity: netutil::host_count(subnet).unwrap_or(0),
+            };
+            if let Some(vlan) = vlan_by_subnet.get(subnet) {
+                let vlan_id = vlan_node_id(*vlan);
+                edges.push(TopologyEdge {
+                    id: format!("{}->{}", subnet_id, vlan_id),
+                    child_id: subnet_id.clone(),
+                    parent_id: vlan_id.clone(),
+                    method: "evidence".to_string(),
+                    confidence: weights.evidence,
+                    port: None,
+                });
+                *attachment_count.entry(vlan_id).or_insert(0) += 1;
+            }
+            nodes.push(TopologyNode {
+                id: subnet_id,
+                node_type: "subnet".to_string(),
+                label: subnet.clone(),
+                ip: None,
+                subnet_cidr: Some(subnet.clone()),
+                gateway_ip: None,
+                agent_id: None,
+                interface_type: None,
+                attached_count: None,
+                utilization: Some(utilization),
+                behind_nat: false,
+            });
+        }
+    }
+
+    if let Some(gw) = admin_gateway.clone() {
+        gateway_specs.push((gw, admin_subnet.clone()));
+    }
+    for host in &host_records {
+        if let Some(gw) = host
+            .default_gateway_ip
+            .clone()
+            .and_then(|v| clean_non_empty_owned(&v))
+        {
+            gateway_specs.push((gw, host.subnet_cidr.clone()));
+        }
+    }
+    gateway_specs.sort_by(|a, b| {
+        let subnet_cmp = a.1.cmp(&b.1);
+        if subnet_cmp != Ordering::Equal {
+            return subnet_cmp;
+        }
+        let a_ip = ip_to_u32(&a.0);
+        let b_ip = ip_to_u32(&b.0);
+        match (a_ip, b_ip) {
+            (Some(x), Some(y)) if x != y => x.cmp(&y),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            _ => a.0.cmp(&b.0),
+        }
+    });
+    gateway_specs.dedup();
+
+    for (gateway_ip, subnet) in gateway_specs {
+        let subnet_key = subnet.clone().unwrap_or_default();
+        let key = format!("{}|{}", subnet_key, gateway_ip);
+        let gateway_id = format!("gw:{}", gateway_ip);
+        gateway_by_key.insert(key, gateway_id.clone());
+        nodes.push(TopologyNode {
+            id: gateway_id.clone(),
+            node_type: "gateway".to_string(),
+            label: format!("Gateway {}", gateway_ip),
+            ip: Some(gateway_ip.clone()),
+            subnet_cidr: subnet.clone(),
+            gateway_ip: Some(gateway_ip.clone()),
+            agent_id: None,
+            interface_type: None,
+            attached_count: None,
+            utilization: None,
+            behind_nat: false,
+        });
+
+        if use_subnet_nodes {
+            if let Some(subnet_value) = subnet {
+                if let Some(subnet_id) = subnet_nodes.get(&subnet_value) {
+                    edges.push(TopologyEdge {
+                        id: format!("{}->{}", gateway_id, subnet_id),
+                        child_id: gateway_id,
+                        parent_id: subnet_id.clone(),
+                        method: "evidence".to_string(),
+                        confidence: weights.gateway_subnet,
+                        port: None,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut unknown_hub_ids: HashMap<String, String> = HashMap::new();
+
+    let admin_id = "admin:self".to_string();
+    nodes.push(TopologyNode {
+        id: admin_id.clone(),
+        node_type: "admin".to_string(),
+        label: "Admin".to_string(),
+        ip: Some(admin_ip.clone()),
+        subnet_cidr: admin_subnet.clone(),
+        gateway_ip: admin_gateway.clone(),
+        agent_id: None,
+        interface_type: clean_non_empty_owned(&admin_network.interface_type),
+        attached_count: None,
+        utilization: None,
+        behind_nat: false,
+    });
+    let admin_parent = if let Some(gw) = admin_gateway.clone() {
+        let key = format!("{}|{}", admin_subnet.clone().unwrap_or_default(), gw);
+        let parent_id = gateway_by_key
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| format!("gw:{}", gw));
+        (parent_id, "evidence".to_string(), weights.evidence)
+    } else {
+        (
+            ensure_unknown_hub_node(
+                &mut nodes,
+                &mut edges,
+                &subnet_nodes,
+                &mut unknown_hub_ids,
+                admin_subnet.clone(),
+                use_subnet_nodes,
+                weights.hub_heuristic,
+            ),
+            "heuristic".to_string(),
+            weights.admin_heuristic,
+        )
+    };
+    edges.push(TopologyEdge {
+        id: format!("{}->{}", admin_id, admin_parent.0),
+        child_id: admin_id,
+        parent_id: admin_parent.0.clone(),
+        method: admin_parent.1,
+        confidence: admin_parent.2,
+        port: None,
+    });
+    *attachment_count.entry(admin_parent.0).or_insert(0) += 1;
+
+    let inferred_switches = infer_shared_switches(&host_records);
+    for host in host_records {
+        let node_id = format!("host:{}", host.device_key);
+        nodes.push(TopologyNode {
+            id: node_id.clone(),
+            node_type: "host".to_string(),
+            label: host.hostname.clone(),
+            ip: host
+                .ip
+                .clone()
+                .or_else(|| host.ips.first().cloned())
+                .or_else(|| host.ipv6.clone()),
+            subnet_cidr: host.subnet_cidr.clone(),
+            gateway_ip: host.default_gateway_ip.clone(),
+            agent_id: Some(host.agent_id.clone()),
+            interface_type: host.interface_type.clone(),
+            attached_count: None,
+            utilization: None,
+            behind_nat: host.behind_nat,
+        });
+
+        let (parent_id, method, confidence) = if let Some(gw) = host
+            .default_gateway_ip
+            .clone()
+            .and_then(|v| clean_non_empty_owned(&v))
+        {
+            let key = format!("{}|{}", host.subnet_cidr.clone().unwrap_or_default(), gw);
+            let parent_id = gateway_by_key
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| format!("gw:{}", gw));
+            (parent_id, "evidence".to_string(), weights.evidence)
+        } else {
+            (
+                ensure_unknown_hub_node(
+                    &mut nodes,
+                    &mut edges,
+                    &subnet_nodes,
+                    &mut unknown_hub_ids,
+                    host.subnet_cidr.clone(),
+                    use_subnet_nodes,
+                    weights.hub_heuristic,
+                ),
+                "heuristic".to_string(),
+                weights.host_heuristic,
+            )
+        };
+
+        let (method, confidence) = if host.behind_nat {
+            (
+                "heuristic".to_string(),
+                f64::min(confidence, weights.nat_cap),
+            )
+        } else {
+            (method, confidence)
+        };
+
+        if let Some(entry) = seed.iter().find(|entry| entry.matches(&host)) {
+            let switch_id = format!("switch:{}", entry.switch);
+            if !switch_ids.contains_key(&switch_id) {
+                // The switch uplink is still inferred from the first seeded host behind it.
+                nodes.push(TopologyNode {
+                    id: switch_id.clone(),
+                    node_type: "switch".to_string(),
+                    label: entry.switch.clone(),
+                    ip: None,
+                    subnet_cidr: host.subnet_cidr.clone(),
+                    gateway_ip: host.default_gateway_ip.clone(),
+                    agent_id: None,
+                    interface_type: None,
+                    attached_count: None,
+                    utilization: None,
+                    behind_nat: false,
+                });
+                edges.push(TopologyEdge {
+                    id: format!("{}->{}", switch_id, parent_id),
+                    child_id: switch_id.clone(),
+                    parent_id: parent_id.clone(),
+                    method,
+                    confidence,
+                    port: None,
+                });
+                *attachment_count.entry(parent_id).or_insert(0) += 1;
+                switch_ids.insert(switch_id.clone(), entry.switch.clone());
+            }
+            edges.push(TopologyEdge {
+                id: format!("{}->{}", node_id, switch_id),
+                child_id: node_id,
+                parent_id: switch_id.clone(),
+                method: "configured".to_string(),
+                confidence: weights.configured,
+                port: entry.port.clone(),
+            });
+            *attachment_count.entry(switch_id).or_insert(0) += 1;
+            continue;
+        }
+
+        if let Some(inferred) = inferred_switches.get(&host.device_key) {
+            let switch_id = format!("switch:{}", inferred.mac);
+            if !switch_ids.contains_key(&switch_id) {
+                let subnet_id = inferred
+                    .subnet
+                    .as_ref()
+                    .filter(|_| use_subnet_nodes)
+                    .and_then(|subnet| subnet_nodes.get(subnet));
+                let (uplink_id, uplink_method, uplink_confidence) =
+                    match (&inferred.gateway_ip, subnet_id) {
+                        (Some(gw), _) => {
+                            let key =
+                                format!("{}|{}", inferred.subnet.clone().unwrap_or_default(), gw);
+                            let gateway_id = gateway_by_key
+                                .get(&key)
+                                .cloned()
+                                .unwrap_or_else(|| format!("gw:{}", gw));
+                            (gateway_id, "evidence", weights.evidence)
+                        }
+                        (None, Some(subnet_id)) => {
+                            (subnet_id.clone(), "heuristic", weights.hub_heuristic)
+                        }
+                        (None, None) => (
+                            ensure_unknown_hub_node(
+                                &mut nodes,
+                                &mut edges,
+                                &subnet_nodes,
+                                &mut unknown_hub_ids,
+                                inferred.subnet.clone(),
+                                use_subnet_nodes,
+                                weights.hub_heuristic,
+                            ),
+                            "heuristic",
+                            weights.hub_heuristic,
+                        ),
+                    };
+                nodes.push(TopologyNode {
+                    id: switch_id.clone(),
+                    node_type: "switch".to_string(),
+                    label: format!("Switch {}", inferred.mac),
+                    ip: None,
+                    subnet_cidr: inferred.subnet.clone(),
+                    gateway_ip: inferred.gateway_ip.clone(),
+                    agent_id: None,
+                    interface_type: None,
+                    attached_count: None,
+                    utilization: None,
+                    behind_nat: false,
+                });
+                edges.push(TopologyEdge {
+                    id: format!("{}->{}", switch_id, uplink_id),
+                    child_id: switch_id.clone(),
+                    parent_id: uplink_id.clone(),
+                    method: uplink_method.to_string(),
+                    confidence: uplink_confidence,
+                    port: None,
+                });
+                *attachment_count.entry(uplink_id).or_insert(0) += 1;
+                switch_ids.insert(switch_id.clone(), inferred.mac.clone());
+            }
+            let (method, confidence) = if host.behind_nat {
+                (
+                    "heuristic".to_string(),
+                    f64::min(weights.evidence, weights.nat_cap),
+                )
+            } else {
+                ("evidence".to_string(), weights.evidence)
+            };
+            edges.push(TopologyEdge {
+                id: format!("{}->{}", node_id, switch_id),
+                child_id: node_id,
+                parent_id: switch_id.clone(),
+                method,
+                confidence,
+                port: None,
+            });
+            *attachment_count.entry(switch_id).or_insert(0) += 1;
+            continue;
+        }
+
+        let parent_id = match subnet_group_for(&host, &subnet_host_counts, config) {
+            Some(group_cidr) => {
+                let group_id = format!("group:{}|{}", group_cidr, parent_id);
+                if !subnet_group_ids.contains_key(&group_id) {
+                    nodes.push(TopologyNode {
+                        id: group_id.clone(),
+                        node_type: "subnet_group".to_string(),
+                        label: group_cidr.clone(),
+                        ip: None,
+                        subnet_cidr: Some(group_cidr.clone()),
+                        gateway_ip: host.default_gateway_ip.clone(),
+                        agent_id: None,
+                        interface_type: None,
+                        attached_count: None,
+                        utilization: None,
+                        behind_nat: false,
+                    });
+                    edges.push(TopologyEdge {
+                        id: format!("{}->{}", group_id, parent_id),
+                        child_id: group_id.clone(),
+                        parent_id: parent_id.clone(),
+                        method: method.clone(),
+                        confidence,
+                        port: None,
+                    });
+                    *attachment_count.entry(parent_id).or_insert(0) += 1;
+                    subnet_group_ids.insert(group_id.clone(), group_cidr);
+                }
+                group_id
+            }
+            None => parent_id,
+        };
+
+        edges.push(TopologyEdge {
+            id: format!("{}->{}", node_id, parent_id),
+            child_id: node_id,
+            parent_id: parent_id.clone(),
+            method,
+            confidence,
+            port: None,
+        });
+        *attachment_count.entry(parent_id).or_insert(0) += 1;
+    }
+
+    for node in &mut nodes {
+        if matches!(
+            node.node_type.as_str(),
+            "vlan" | "gateway" | "switch" | "unknown_hub" | "subnet_group"
+        ) {
+            node.attached_count = Some(*attachment_count.get(&node.id).unwrap_or(&0));
+        }
+    }
+
+    nodes.sort_by(compare_topology_nodes);
+    edges.sort_by(|a, b| {
+        let child = a.child_id.cmp(&b.child_id);
+        if child != Ordering::Equal {
+            return child;
+        }
+        a.parent_id.cmp(&b.parent_id)
+    });
+
+    TopologySnapshot {
+        revision,
+        updated_at: now,
+        nodes,
+        edges,
+    }
+}
+
+/// Adds hosts seen in any ARP table but running no agent as leaves under the
+/// subnet that contains them (or its gateway/hub when subnet nodes are off).
+/// One node per MAC across all observers; IPs already on the graph are skipped.
+pub(crate) fn with_unmanaged_hosts(
+    mut snapshot: TopologySnapshot,
+    devices: &HashMap<String, DeviceRecord>,
+    admin_network: &NetworkFactsPayload,
+    weights: &ConfidenceWeights,
+) -> TopologySnapshot {
+    let mut known_ips: HashSet<String> =
+        snapshot.nodes.iter().filter_map(|n| n.ip.clone()).collect();
+    known_ips.extend(devices.values().flat_map(|d| d.ips.iter().cloned()));
+    let known_macs: HashSet<String> = devices
+        .values()
+        .filter_map(|d| d.mac.as_deref().and_then(usable_mac))
+        .chain(admin_network.mac.as_deref().and_then(usable_mac))
+        .collect();
+
+    let mut ips_by_mac: BTreeMap<String, BTreeSet<u32>> = BTreeMap::new();
+    let mut claimed: HashSet<u32> = HashSet::new();
+    let mut observed: Vec<(String, u32)> = devices
+        .values()
+        .flat_map(|d| d.arp_snapshot.iter())
+        .chain(admin_network.arp_snapshot.iter())
+        .filter(|e| !known_ips.contains(e.ip.trim()))
+        .filter_map(|e| Some((usable_mac(&e.mac)?, ip_to_u32(e.ip.trim())?)))
+        .filter(|(mac, _)| !known_macs.contains(mac))
+        .collect();
+    observed.sort_unstable();
+    observed.dedup();
+    for (mac, ip) in observed {
+        if claimed.insert(ip) {
+            ips_by_mac.entry(mac).or_default().insert(ip);
+        }
+    }
+
+    let parent_types = ["subnet", "gateway", "unknown_hub"];
+    let mut added = 0;
+    for (mac, ips) in ips_by_mac {
+        let Some(first) = ips.first() else {
+            continue;
+        };
+        let ip = std::net::Ipv4Addr::from(*first).to_string();
+        let parent = parent_types.iter().find_map(|kind| {
+            snapshot.nodes.iter().find(|n| {
+                n.node_type == *kind
+                    && n.subnet_cidr
+                        .as_deref()
+                        .is_some_and(|cidr| netutil::contains(cidr, &ip))
+            })
+        });
+        let Some(parent) = parent else {
+            continue;
+        };
+        let (parent_id, subnet_cidr) = (parent.id.clone(), parent.subnet_cidr.clone());
+        let node_id = format!("unmanaged:{}", mac);
+        snapshot.nodes.push(TopologyNode {
+            id: node_id.clone(),
+            node_type: "unmanaged".to_string(),
+            label: ip.clone(),
+            ip: Some(ip),
+            subnet_cidr,
+            gateway_ip: None,
+            agent_id: None,
+            interface_type: None,
+            attached_count: None,
+            utilization: None,
+            behind_nat: false,
+        });
+        snapshot.edges.push(TopologyEdge {
+            id: format!("{}->{}", node_id, parent_id),
+            child_id: node_id,
+            parent_id: parent_id.clone(),
+            method: "heuristic".to_string(),
+            confidence: weights.unmanaged,
+            port: None,
+        });
+        if let Some(count) = snapshot
+            .nodes
+            .iter_mut()
+            .find(|n| n.id == parent_id)
+            .and_then(|n| n.attached_count.as_mut())
+        {
+            *count += 1;
+        }
+        added += 1;
+    }
+
+    if added > 0 {
+        snapshot.nodes.sort_by(compare_topology_nodes);
+        snapshot.edges.sort_by(|a, b| {
+            a.child_id
+                .cmp(&b.child_id)
+                .then_with(|| a.parent_id.cmp(&b.parent_id))
+        });
+    }
+    snapshot
+}
+
+fn ensure_unknown_hub_node(
+    nodes: &mut Vec<TopologyNode>,
+    edges: &mut Vec<TopologyEdge>,
+    subnet_nodes: &HashMap<String, String>,
+    unknown_hub_ids: &mut HashMap<String, String>,
+    subnet: Option<String>,
+    use_subnet_nodes: bool,
+    confidence: f64,
+) -> String {
+    let subnet_key = subnet.clone().unwrap_or_else(|| "unknown".to_string());
+    if let Some(existing) = unknown_hub_ids.get(&subnet_key) {
+        return existing.clone();
+    }
+
+    let hub_id = format!("hub:{}", subnet_key);
+    nodes.push(TopologyNode {
+        id: hub_id.clone(),
+        node_type: "unknown_hub".to_string(),
+        label: "Unknown Hub".to_string(),
+        ip: None,
+        subnet_cidr: subnet.clone(),
+        gateway_ip: None,
+        agent_id: None,
+        interface_type: None,
+        attached_count: None,
+        utilization: None,
+        behind_nat: false,
+    });
+
+    if use_subnet_nodes {
+        if let Some(subnet_value) = subnet {
+            if let Some(subnet_id) = subnet_nodes.get(&subnet_value) {
+                edges.push(TopologyEdge {
+                    id: format!("{}->{}", hub_id, subnet_id),
+                    child_id: hub_id.clone(),
+                    parent_id: subnet_id.clone(),
+                    method: "heuristic".to_string(),
+                    confidence,
+                    port: None,
+                });
+            }
+        }
+    }
+
+    unknown_hub_ids.insert(subnet_key, hub_id.clone());
+    hub_id
+}
+
+struct InferredSwitch {
+    mac: String,
+    subnet: Option<String>,
+    /// Set only when every member that reports a gateway reports this one.
+    gateway_ip: Option<String>,
+}
+
+/// Hosts in one subnet that resolve their gateway to the same MAC sit behind
+/// one layer-2 segment. A shared switch is only inferred when that can't be
+/// read straight off the gateway IPs: some members report none, or they
+/// disagree. Keyed by device key.
+fn infer_shared_switches(hosts: &[DeviceRecord]) -> HashMap<String, InferredSwitch> {
+    let mut groups: BTreeMap<(Option<String>, String), Vec<&DeviceRecord>> = BTreeMap::new();
+    for host in hosts {
+        if let Some(mac) = host.gateway_mac.as_deref().and_then(usable_mac) {
+            groups
+                .entry((host.subnet_cidr.clone(), mac))
+                .or_default()
+                .push(host);
+        }
+    }
+
+    let mut inferred = HashMap::new();
+    for ((subnet, mac), members) in groups {
+        if members.len() < 2 {
+            continue;
+        }
+        let mut gateways: Vec<String> = members
+            .iter()
+            .filter_map(|h| {
+                h.default_gateway_ip
+                    .as_deref()
+                    .and_then(clean_non_empty_owned)
+            })
+            .collect();
+        let reported = gateways.len();
+        gateways.sort();
+        gateways.dedup();
+        if reported == members.len() && gateways.len() == 1 {
+            continue;
+        }
+        let gateway_ip = if gateways.len() == 1 {
+            gateways.pop()
+        } else {
+            None
+        };
+        for host in members {
+            inferred.insert(
+                host.device_key.clone(),
+                InferredSwitch {
+                    mac: mac.clone(),
+                    subnet: subnet.clone(),
+                    gateway_ip: gateway_ip.clone(),
+                },
+            );
+        }
+    }
+    inferred
+}
+
+fn vlan_node_id(vlan: u16) -> String {
+    format!("vlan:{}", vlan)
+}
+
+/// Each subnet goes under the VLAN most of its hosts report (lowest tag on ties).
+fn majority_vlan_by_subnet(hosts: &[DeviceRecord]) -> HashMap<String, u16> {
+    let mut counts: HashMap<String, BTreeMap<u16, usize>> = HashMap::new();
+    for host in hosts {
+        if let (Some(subnet), Some(vlan)) = (&host.subnet_cidr, host.vlan_id) {
+            *counts
+                .entry(subnet.clone())
+                .or_default()
+                .entry(vlan)
+                .or_insert(0) += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .filter_map(|(subnet, by_vlan)| {
+            by_vlan
+                .into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+                .map(|(vlan, _)| (subnet, vlan))
+        })
+        .collect()
+}
+
+fn subnet_group_for(
+    host: &DeviceRecord,
+    subnet_host_counts: &HashMap<String, usize>,
+    config: &ServerConfig,
+) -> Option<String> {
+    if config.subnet_aggregation_threshold == 0 {
+        return None;
+    }
+    let subnet = host.subnet_cidr.as_deref()?;
+    if subnet_host_counts.get(subnet).copied().unwrap_or(0) <= config.subnet_aggregation_threshold {
+        return None;
+    }
+    let (_, subnet_prefix) = netutil::parse_cidr(subnet)?;
+    let group_prefix = config.subnet_aggregation_prefix;
+    if group_prefix <= subnet_prefix || group_prefix > 32 {
+        return None;
+    }
+    let ip = host
+        .ip
+        .as_deref()
+        .or_else(|| host.ips.first().map(String::as_str))?;
+    let network = netutil::network_address(&format!("{}/{}", ip.trim(), group_prefix))?;
+    Some(format!("{}/{}", network, group_prefix))
+}
+
+fn compare_device_topology_order(a: &DeviceRecord, b: &DeviceRecord) -> Ordering {
+    let a_ip =
+        a.ip.as_deref()
+            .or_else(|| a.ips.first().map(String::as_str))
+            .and_then(ip_to_u32);
+    let b_ip =
+        b.ip.as_deref()
+            .or_else(|| b.ips.first().map(String::as_str))
+            .and_then(ip_to_u32);
+    // Hosts without any v4 address fall back to comparing their v6 address.
+    let v6 = |d: &DeviceRecord| {
+        d.ipv6
+            .as_deref()
+            .and_then(|ip| ip.parse::<std::net::Ipv6Addr>().ok())
+    };
+    let (a_v6, b_v6) = match (a_ip, b_ip) {
+        (None, None) => (v6(a), v6(b)),
+        _ => (None, None),
+    };
+    match (a_ip, b_ip, a_v6, b_v6) {
+        (Some(x), Some(y), _, _) if x != y => x.cmp(&y),
+        (Some(_), None, _, _) => Ordering::Less,
+        (None, Some(_), _, _) => Ordering::Greater,
+        (_, _, Some(x), Some(y)) if x != y => x.cmp(&y),
+        (_, _, Some(_), None) => Ordering::Less,
+        (_, _, None, Some(_)) => Ordering::Greater,
+        _ => {
+            let host_cmp = a.hostname.cmp(&b.hostname);
+            if host_cmp != Ordering::Equal {
+                host_cmp
+            } else {
+                a.device_key.cmp(&b.device_key)
+            }
+        }
+    }
+}
+
+fn compare_topology_nodes(a: &TopologyNode, b: &TopologyNode) -> Ordering {
+    let rank = |node_type: &str| match node_type {
+        "vlan" => 0,
+        "subnet" => 1,
+        "gateway" => 2,
+        "switch" => 3,
+        "unknown_hub" => 4,
+        "subnet_group" => 5,
+        "admin" => 6,
+        "host" => 7,
+        "unmanaged" => 8,
+        _ => 9,
+    };
+    let r = rank(&a.node_type).cmp(&rank(&b.node_type));
+    if r != Ordering::Equal {
+        return r;
+    }
+
+    let a_ip = a
+        .gateway_ip
+        .as_deref()
+        .and_then(ip_to_u32)
+        .or_else(|| ip_to_u32(&a.label));
+    let b_ip = b
+        .gateway_ip
+        .as_deref()
+        .and_then(ip_to_u32)
+        .or_else(|| ip_to_u32(&b.label));
+    match (a_ip, b_ip) {
+        (Some(x), Some(y)) if x != y => x.cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        _ => a.id.cmp(&b.id),
+    }
+}
+
+pub(crate) fn topology_key(snapshot: &TopologySnapshot) -> String {
+    let mut node_parts: Vec<String> = snapshot
+        .nodes
+        .iter()
+        .map(|n| {
+            format!(
+                "{}|{}|{}|{}|{}|{}",
+                n.id,
+                n.node_type,
+                n.ip.clone().unwrap_or_default(),
+                n.subnet_cidr.clone().unwrap_or_default(),
+                n.gateway_ip.clone().unwrap_or_default(),
+                n.utilization
+                    .as_ref()
+                    .map(|u| format!("{}/{}", u.managed_hosts, u.observed_ips))
+                    .unwrap_or_default()
+            )
+        })
+        .collect();
+    node_parts.sort();
+    let mut edge_parts: Vec<String> = snapshot
+        .edges
+        .iter()
+        .map(|e| {
+            format!(
+                "{}|{}|{}|{:.3}|{}",
+                e.child_id,
+                e.parent_id,
+                e.method,
+                e.confidence,
+                e.port.clone().unwrap_or_default()
+            )
+        })
+        .collect();
+    edge_parts.sort();
+    format!("{}#{}", node_parts.join(";"), edge_parts.join(";"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ArpEntry;
+
+    struct TopologyTestCase {
+        devices: HashMap<String, DeviceRecord>,
+        order: Vec<String>,
+        admin: NetworkFactsPayload,
+        config: ServerConfig,
+        seed: Vec<TopologySeedEntry>,
+    }
+
+    impl TopologyTestCase {
+        fn new(admin_ip: &str, admin_subnet: &str) -> Self {
+            Self {
+                devices: HashMap::new(),
+                order: Vec::new(),
+                admin: NetworkFactsPayload {
+                    ip: admin_ip.to_string(),
+                    subnet_cidr: admin_subnet.to_string(),
+                    ..Default::default()
+                },
+                config: ServerConfig::default(),
+                seed: Vec::new(),
+            }
+        }
+
+        fn admin_gateway(mut self, gateway: &str) -> Self {
+            self.admin.default_gateway_ip = gateway.to_string();
+            self
+        }
+
+        fn host(mut self, key: &str, ip: &str, subnet: &str, gateway: Option<&str>) -> Self {
+            let device = DeviceRecord {
+                device_key: key.to_string(),
+                agent_id: key.to_string(),
+                hostname: key.to_string(),
+                ips: vec![ip.to_string()],
+                ip: Some(ip.to_string()),
+                subnet_cidr: Some(subnet.to_string()),
+                default_gateway_ip: gateway.map(str::to_string),
+                status: "online".to_string(),
+                ..Default::default()
+            };
+            self.order.push(key.to_string());
+            self.devices.insert(key.to_string(), device);
+            self
+        }
+
+        fn mac(mut self, key: &str, mac: &str) -> Self {
+            if let Some(device) = self.devices.get_mut(key) {
+                device.mac = Some(mac.to_string());
+            }
+            self
+        }
+
+        fn gateway_mac(mut self, key: &str, mac: &str) -> Self {
+            if let Some(device) = self.devices.get_mut(key) {
+                device.gateway_mac = Some(mac.to_string());
+            }
+            self
+        }
+
+        fn arp(mut self, key: &str, ip: &str, mac: &str) -> Self {
+            if let Some(device) = self.devices.get_mut(key) {
+                device.arp_snapshot.push(ArpEntry {
+                    ip: ip.to_string(),
+                    mac: mac.to_string(),
+                });
+            }
+            self
+        }
+
+        fn vlan(mut self, key: &str, vlan: u16) -> Self {
+            if let Some(device) = self.devices.get_mut(key) {
+                device.vlan_id = Some(vlan);
+            }
+            self
+        }
+
+        fn behind_nat(mut self, key: &str) -> Self {
+            if let Some(device) = self.devices.get_mut(key) {
+                device.behind_nat = true;
+            }
+            self
+        }
+
+        fn seed(
+            mut self,
+            mac: Option<&str>,
+            agent_id: Option<&str>,
+            switch: &str,
+            port: &str,
+        ) -> Self {
+            let entry = TopologySeedEntry {
+                mac: mac.map(str::to_string),
+                agent_id: agent_id.map(str::to_string),
+                switch: switch.to_string(),
+                port: Some(port.to_string()),
+            };
+            self.seed.push(entry.normalize().unwrap());
+            self
+        }
+
+        fn config(mut self, apply: impl FnOnce(&mut ServerConfig)) -> Self {
+            apply(&mut self.config);
+            self
+        }
+
+        fn build(&self) -> TopologySnapshot {
+            build_topology_snapshot(
+                &self.devices,
+                &self.order,
+                &self.admin,
+                &self.config,
+                &self.seed,
+                1,
+                0,
+            )
+        }
+    }
+
+    fn node<'a>(snapshot: &'a TopologySnapshot, id: &str) -> &'a TopologyNode {
+        snapshot
+            .nodes
+            .iter()
+            .find(|n| n.id == id)
+            .unwrap_or_else(|| panic!("missing node {}", id))
+    }
+
+    fn parent_edge<'a>(snapshot: &'a TopologySnapshot, child: &str) -> &'a TopologyEdge {
+        snapshot
+            .edges
+            .iter()
+            .find(|e| e.child_id == child)
+            .unwrap_or_else(|| panic!("missing parent edge for {}", child))
+    }
+
+    fn count_type(snapshot: &TopologySnapshot, node_type: &str) -> usize {
+        snapshot
+            .nodes
+            .iter()
+            .filter(|n| n.node_type == node_type)
+            .count()
+    }
+
+    #[test]
+    fn single_subnet_attaches_hosts_to_shared_gateway() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .build();
+
+        assert_eq!(count_type(&snapshot, "subnet"), 0);
+        assert_eq!(count_type(&snapshot, "gateway"), 1);
+        assert_eq!(node(&snapshot, "gw:192.168.1.1").attached_count, Some(3));
+
+        let edge = parent_edge(&snapshot, "host:a");
+        assert_eq!(edge.parent_id, "gw:192.168.1.1");
+        assert_eq!(edge.method, "evidence");
+        assert_eq!(
+            parent_edge(&snapshot, "admin:self").parent_id,
+            "gw:192.168.1.1"
+        );
+
+        let mut ids: Vec<&str> = snapshot.edges.iter().map(|e| e.id.as_str()).collect();
+        let total = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), total);
+    }
+
+    #[test]
+    fn hosts_behind_nat_are_flagged_and_attached_with_low_confidence() {
+        let case = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .behind_nat("b");
+        let snapshot = case.build();
+
+        assert!(!node(&snapshot, "host:a").behind_nat);
+        assert!(node(&snapshot, "host:b").behind_nat);
+        assert!(!node(&snapshot, "gw:192.168.1.1").behind_nat);
+        let edge = parent_edge(&snapshot, "host:b");
+        assert_eq!(edge.parent_id, "gw:192.168.1.1");
+        assert_eq!(edge.method, "heuristic");
+        assert!(edge.confidence <= case.config.confidence.nat_cap);
+        assert_eq!(parent_edge(&snapshot, "host:a").method, "evidence");
+    }
+
+    #[test]
+    fn multiple_subnets_add_subnet_nodes_above_gateways() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "10.0.0.5", "10.0.0.0/24", Some("10.0.0.1"))
+            .build();
+
+        assert_eq!(count_type(&snapshot, "subnet"), 2);
+        assert_eq!(count_type(&snapshot, "gateway"), 2);
+        assert_eq!(
+            parent_edge(&snapshot, "gw:10.0.0.1").parent_id,
+            "subnet:10.0.0.0/24"
+        );
+        assert_eq!(parent_edge(&snapshot, "host:b").parent_id, "gw:10.0.0.1");
+
+        let utilization = node(&snapshot, "subnet:10.0.0.0/24")
+            .utilization
+            .as_ref()
+            .unwrap();
+        assert_eq!(utilization.managed_hosts, 1);
+        assert_eq!(utilization.capacity, 254);
+    }
+
+    #[test]
+    fn multiple_vlans_group_subnets_beneath_vlan_nodes() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("c", "192.168.1.22", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("d", "10.0.0.5", "10.0.0.0/24", Some("10.0.0.1"))
+            .vlan("a", 10)
+            .vlan("b", 10)
+            .vlan("c", 30)
+            .vlan("d", 20)
+            .build();
+
+        assert_eq!(count_type(&snapshot, "vlan"), 2);
+        assert_eq!(snapshot.nodes[0].id, "vlan:10");
+        assert_eq!(node(&snapshot, "vlan:10").label, "VLAN 10");
+        assert_eq!(
+            parent_edge(&snapshot, "subnet:192.168.1.0/24").parent_id,
+            "vlan:10"
+        );
+        assert_eq!(
+            parent_edge(&snapshot, "subnet:10.0.0.0/24").parent_id,
+            "vlan:20"
+        );
+        assert_eq!(node(&snapshot, "vlan:10").attached_count, Some(1));
+        // A minority tag neither pulls its subnet away nor gets an empty node.
+        assert!(snapshot.nodes.iter().all(|n| n.id != "vlan:30"));
+        assert!(snapshot
+            .nodes
+            .iter()
+            .filter(|n| n.node_type == "vlan")
+            .all(|n| n.attached_count.unwrap_or(0) > 0));
+        assert_eq!(parent_edge(&snapshot, "host:d").parent_id, "gw:10.0.0.1");
+
+        // Two tags in one subnet are still a single VLAN.
+        let minority_only = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .vlan("a", 10)
+            .vlan("b", 10)
+            .host("c", "192.168.1.22", "192.168.1.0/24", Some("192.168.1.1"))
+            .vlan("c", 30)
+            .build();
+        assert_eq!(count_type(&minority_only, "vlan"), 0);
+        assert_eq!(count_type(&minority_only, "subnet"), 0);
+
+        let single = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .vlan("a", 10)
+            .build();
+        assert_eq!(count_type(&single, "vlan"), 0);
+        assert_eq!(count_type(&single, "subnet"), 0);
+    }
+
+    #[test]
+    fn unmanaged_hosts_are_opt_in_deduped_leaves_under_their_subnet() {
+        let case = TopologyTestCase::new("10.0.0.10", "10.0.0.0/24")
+            .admin_gateway("10.0.0.1")
+            .host("a", "10.0.0.20", "10.0.0.0/24", Some("10.0.0.1"))
+            .mac("a", "aa:aa:aa:aa:aa:01")
+            .host("b", "10.0.1.20", "10.0.1.0/24", Some("10.0.1.1"))
+            // Printer seen by both observers, plus the gateway and a managed peer.
+            .arp("a", "10.0.0.50", "bb:bb:bb:bb:bb:01")
+            .arp("b", "10.0.0.50", "BB-BB-BB-BB-BB-01")
+            .arp("a", "10.0.0.1", "cc:cc:cc:cc:cc:01")
+            .arp("b", "10.0.0.20", "aa:aa:aa:aa:aa:01")
+            .arp("b", "10.0.1.77", "bb:bb:bb:bb:bb:02")
+            .arp("b", "172.16.0.5", "bb:bb:bb:bb:bb:03");
+        let clean = case.build();
+        assert_eq!(count_type(&clean, "unmanaged"), 0);
+
+        let full = with_unmanaged_hosts(
+            clean.clone(),
+            &case.devices,
+            &case.admin,
+            &case.config.confidence,
+        );
+        assert_eq!(count_type(&full, "unmanaged"), 2);
+        let printer = node(&full, "unmanaged:bb:bb:bb:bb:bb:01");
+        assert_eq!(printer.ip.as_deref(), Some("10.0.0.50"));
+        let edge = parent_edge(&full, "unmanaged:bb:bb:bb:bb:bb:01");
+        assert_eq!(edge.parent_id, "subnet:10.0.0.0/24");
+        assert_eq!(edge.confidence, case.config.confidence.unmanaged);
+        assert_eq!(
+            parent_edge(&full, "unmanaged:bb:bb:bb:bb:bb:02").parent_id,
+            "subnet:10.0.1.0/24"
+        );
+        assert_eq!(full.nodes.len(), clean.nodes.len() + 2);
+    }
+
+    #[test]
+    fn hosts_without_gateway_share_an_unknown_hub() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .host("a", "192.168.1.20", "192.168.1.0/24", None)
+            .host("b", "192.168.1.21", "192.168.1.0/24", None)
+            .build();
+        let weights = ServerConfig::default().confidence;
+
+        assert_eq!(count_type(&snapshot, "unknown_hub"), 1);
+        let hub = node(&snapshot, "hub:192.168.1.0/24");
+        assert_eq!(hub.attached_count, Some(3));
+
+        let edge = parent_edge(&snapshot, "host:a");
+        assert_eq!(edge.parent_id, hub.id);
+        assert_eq!(edge.method, "heuristic");
+        assert_eq!(edge.confidence, weights.host_heuristic);
+        assert_eq!(
+            parent_edge(&snapshot, "admin:self").confidence,
+            weights.admin_heuristic
+        );
+    }
+
+    #[test]
+    fn quality_counts_guesswork_and_missing_facts() {
+        let case = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", None);
+        let quality = topology_quality(&case.build(), &case.devices);
+
+        // admin->gw and a->gw are evidence; b->hub is heuristic.
+        assert_eq!(quality.edge_count, 3);
+        assert_eq!(quality.edges_by_method.get("evidence"), Some(&2));
+        assert_eq!(quality.edges_by_method.get("heuristic"), Some(&1));
+        assert_eq!(quality.unknown_hub_attachments, 1);
+        assert_eq!(quality.devices_missing_subnet, 0);
+        assert_eq!(quality.devices_missing_gateway, 1);
+        // (2/3 + 1/2) / 2
+        assert_eq!(quality.completeness_pct, 58.3);
+    }
+
+    #[test]
+    fn busy_subnets_are_aggregated_into_groups() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("c", "192.168.1.200", "192.168.1.0/24", Some("192.168.1.1"))
+            .config(|config| {
+                config.subnet_aggregation_threshold = 2;
+                config.subnet_aggregation_prefix = 25;
+            })
+            .build();
+
+        assert_eq!(count_type(&snapshot, "subnet_group"), 2);
+        let low = node(&snapshot, "group:192.168.1.0/25|gw:192.168.1.1");
+        assert_eq!(low.attached_count, Some(2));
+        assert_eq!(
+            parent_edge(&snapshot, "host:c").parent_id,
+            "group:192.168.1.128/25|gw:192.168.1.1"
+        );
+        // Admin plus the two group nodes hang off the gateway.
+        assert_eq!(node(&snapshot, "gw:192.168.1.1").attached_count, Some(3));
+    }
+
+    #[test]
+    fn seeded_hosts_attach_to_configured_switch_ports() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("c", "192.168.1.22", "192.168.1.0/24", Some("192.168.1.1"))
+            .mac("a", "AA-BB-CC-00-00-01")
+            .seed(Some("aa:bb:cc:00:00:01"), None, "lab-sw1", "Gi0/1")
+            .seed(None, Some("b"), "lab-sw1", "Gi0/2")
+            .build();
+
+        assert_eq!(count_type(&snapshot, "switch"), 1);
+        assert_eq!(node(&snapshot, "switch:lab-sw1").attached_count, Some(2));
+        assert_eq!(
+            parent_edge(&snapshot, "switch:lab-sw1").parent_id,
+            "gw:192.168.1.1"
+        );
+
+        let edge = parent_edge(&snapshot, "host:a");
+        assert_eq!(edge.parent_id, "switch:lab-sw1");
+        assert_eq!(edge.method, "configured");
+        assert_eq!(edge.port.as_deref(), Some("Gi0/1"));
+        assert_eq!(
+            parent_edge(&snapshot, "host:b").port.as_deref(),
+            Some("Gi0/2")
+        );
+
+        // Unseeded hosts keep the inferred attachment.
+        assert_eq!(parent_edge(&snapshot, "host:c").parent_id, "gw:192.168.1.1");
+        assert_eq!(node(&snapshot, "gw:192.168.1.1").attached_count, Some(3));
+    }
+
+    #[test]
+    fn hosts_sharing_a_gateway_mac_are_grouped_under_an_inferred_switch() {
+        let snapshot = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", None)
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("c", "192.168.1.22", "192.168.1.0/24", None)
+            .host("d", "192.168.1.23", "192.168.1.0/24", None)
+            .gateway_mac("a", "AA-BB-CC-00-00-FE")
+            .gateway_mac("b", "aa:bb:cc:00:00:fe")
+            .gateway_mac("c", "aa:bb:cc:00:00:fe")
+            .gateway_mac("d", "aa:bb:cc:00:00:99")
+            .build();
+        let weights = ServerConfig::default().confidence;
+
+        assert_eq!(count_type(&snapshot, "switch"), 1);
+        let switch = node(&snapshot, "switch:aa:bb:cc:00:00:fe");
+        assert_eq!(switch.attached_count, Some(3));
+        let uplink = parent_edge(&snapshot, "switch:aa:bb:cc:00:00:fe");
+        assert_eq!(uplink.parent_id, "gw:192.168.1.1");
+        assert_eq!(uplink.method, "evidence");
+
+        for host in ["host:a", "host:b", "host:c"] {
+            let edge = parent_edge(&snapshot, host);
+            assert_eq!(edge.parent_id, switch.id);
+            assert_eq!(edge.method, "evidence");
+            assert_eq!(edge.confidence, weights.evidence);
+        }
+        // A lone host behind some other gateway MAC keeps the hub fallback.
+        assert_eq!(
+            parent_edge(&snapshot, "host:d").parent_id,
+            "hub:192.168.1.0/24"
+        );
+
+        // Agreeing gateway IPs are already direct evidence; no switch is invented.
+        let agreeing = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"))
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .gateway_mac("a", "aa:bb:cc:00:00:fe")
+            .gateway_mac("b", "aa:bb:cc:00:00:fe")
+            .build();
+        assert_eq!(count_type(&agreeing, "switch"), 0);
+    }
+
+    #[test]
+    fn adding_a_host_diffs_to_one_node_and_one_edge() {
+        let case = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"));
+        let before = case.build();
+        let mut after = case
+            .host("b", "192.168.1.21", "192.168.1.0/24", Some("192.168.1.1"))
+            .build();
+        after.revision = 2;
+
+        let diff = topology_diff(&before, &after);
+        assert_eq!((diff.rev_from, diff.rev_to), (1, 2));
+        assert_eq!(diff.added_nodes.len(), 1);
+        assert_eq!(diff.added_nodes[0].id, "host:b");
+        assert_eq!(diff.added_edges.len(), 1);
+        assert_eq!(diff.added_edges[0].id, "host:b->gw:192.168.1.1");
+        assert!(diff.removed_nodes.is_empty());
+        assert!(diff.removed_edges.is_empty());
+
+        let reverse = topology_diff(&after, &before);
+        assert!(reverse.added_nodes.is_empty());
+        assert_eq!(reverse.removed_nodes, vec!["host:b"]);
+        assert_eq!(reverse.removed_edges, vec!["host:b->gw:192.168.1.1"]);
+    }
+
+    #[test]
+    fn v6_only_hosts_sort_by_address_under_a_v6_subnet_node() {
+        let mut case = TopologyTestCase::new("192.168.1.10", "192.168.1.0/24")
+            .admin_gateway("192.168.1.1")
+            .host("a", "192.168.1.20", "192.168.1.0/24", Some("192.168.1.1"));
+        for (key, ipv6) in [("v6-b", "2001:db8:5::20"), ("v6-a", "2001:db8:5::9")] {
+            case.devices.insert(
+                key.to_string(),
+                DeviceRecord {
+                    device_key: key.to_string(),
+                    agent_id: key.to_string(),
+                    hostname: key.to_string(),
+                    ipv6: Some(ipv6.to_string()),
+                    ipv6_cidr: Some("2001:db8:5::/64".to_string()),
+                    status: "online".to_string(),
+                    ..Default::default()
+                },
+            );
+            case.order.push(key.to_string());
+        }
+        let snapshot = case.build();
+
+        assert_eq!(count_type(&snapshot, "subnet"), 2);
+        let hub = parent_edge(&snapshot, "host:v6-a").parent_id.clone();
+        assert_eq!(hub, "hub:2001:db8:5::/64");
+        assert_eq!(
+            parent_edge(&snapshot, &hub).parent_id,
+            "subnet:2001:db8:5::/64"
+        );
+        assert_eq!(
+            node(&snapshot, "host:v6-a").ip.as_deref(),
+            Some("2001:db8:5::9")
+        );
+        let hosts: Vec<&str> = snapshot
+            .nodes
+            .iter()
+            .filter(|n| n.node_type == "host")
+            .map(|n| n.id.as_str())
+            .collect();
+        assert_eq!(hosts, vec!["host:a", "host:v6-a", "host:v6-b"]);
+        // The v4 side is laid out exactly as before.
+        assert_eq!(parent_edge(&snapshot, "host:a").parent_id, "gw:192.168.1.1");
+    }
+}
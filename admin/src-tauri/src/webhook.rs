@@ -0,0 +1,170 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub const SIGNATURE_HEADER: &str = "X-LabScan-Signature";
+pub const EVENT_HEADER: &str = "X-LabScan-Event";
+
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+const RETRY_BACKOFF_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Activity kinds, `alert` (WARN/ERROR logs) or `*`. Empty matches everything.
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+impl WebhookConfig {
+    pub fn matches(&self, kind: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == "*" || e == kind)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let url = self.url.trim();
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(format!("webhook url must be http(s): {}", self.url));
+        }
+        if self.max_attempts == 0 || self.max_attempts > 10 {
+            return Err("webhook max_attempts must be between 1 and 10".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct WebhookStats {
+    pub queued: u64,
+    pub delivered: u64,
+    pub failed: u64,
+    pub retries: u64,
+    pub last_error: Option<String>,
+    pub last_delivery_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub kind: String,
+    pub ts: i64,
+    pub agent_id: Option<String>,
+    pub level: Option<String>,
+    pub message: String,
+    pub data: Value,
+}
+
+#[derive(Debug)]
+pub struct WebhookDelivery {
+    pub hook: WebhookConfig,
+    pub event: WebhookEvent,
+}
+
+pub struct DeliveryOutcome {
+    pub attempts: u32,
+    pub result: Result<(), String>,
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_millis(REQUEST_TIMEOUT_MS))
+        .build()
+        .unwrap_or_default()
+}
+
+pub async fn deliver(client: &reqwest::Client, delivery: &WebhookDelivery) -> DeliveryOutcome {
+    let body = match serde_json::to_vec(&delivery.event) {
+        Ok(body) => body,
+        Err(err) => {
+            return DeliveryOutcome {
+                attempts: 0,
+                result: Err(format!("serialize failed: {}", err)),
+            }
+        }
+    };
+    let signature = delivery
+        .hook
+        .secret
+        .as_deref()
+        .filter(|s| !s.is_empty())
+        .map(|secret| sign(secret, &body));
+
+    let mut last_error = String::new();
+    for attempt in 1..=delivery.hook.max_attempts {
+        let mut request = client
+            .post(delivery.hook.url.trim())
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, delivery.event.kind.as_str())
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature.as_str());
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                return DeliveryOutcome {
+                    attempts: attempt,
+                    result: Ok(()),
+                }
+            }
+            Ok(response) => last_error = format!("HTTP {}", response.status()),
+            Err(err) => last_error = err.to_string(),
+        }
+        if attempt < delivery.hook.max_attempts {
+            sleep(Duration::from_millis(RETRY_BACKOFF_MS * u64::from(attempt))).await;
+        }
+    }
+    DeliveryOutcome {
+        attempts: delivery.hook.max_attempts,
+        result: Err(last_error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_matches_rfc4231_vector() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn matches_kinds_and_wildcards() {
+        let mut hook = WebhookConfig {
+            url: "https://example.test/hook".to_string(),
+            events: Vec::new(),
+            secret: None,
+            max_attempts: 3,
+        };
+        assert!(hook.matches("alert"));
+
+        hook.events = vec!["alert".to_string(), "device_reaped".to_string()];
+        assert!(hook.matches("alert"));
+        assert!(hook.matches("device_reaped"));
+        assert!(!hook.matches("device_connected"));
+
+        hook.events = vec!["*".to_string()];
+        assert!(hook.matches("device_connected"));
+    }
+}
@@ -5,7 +5,7 @@ import { cn } from "@/lib/utils";
 
 type NodeData = {
   label: string;
-  nodeType: "gateway" | "admin" | "host" | "switch" | "subnet" | "unknown_hub";
+  nodeType: "gateway" | "admin" | "host" | "switch" | "subnet" | "unknown_hub" | "vlan" | "unmanaged";
   ip?: string;
   status?: "online" | "idle" | "scanning" | "offline";
   internet?: boolean | null;
@@ -14,6 +14,7 @@ type NodeData = {
   subnet?: string | null;
   lastSeenMs?: number;
   attachedCount?: number | null;
+  behindNat?: boolean;
   handleCount?: number;
   selected?: boolean;
   onHover?: (id?: string) => void;
@@ -75,6 +76,7 @@ function LabscanNodeComponent({ id, data }: NodeProps<NodeData>) {
           "flex items-center justify-center backdrop-blur-sm transition-all",
           data.selected && "ring-2 ring-cyan/70 shadow-[0_0_28px_rgba(56,189,248,0.32)]",
           isSubnet && "rounded-md border-dashed",
+          data.behindNat && "border-dashed border-amber-300/70",
           offline && "opacity-65 saturate-50",
         )}
       >
@@ -117,6 +119,7 @@ function LabscanNodeComponent({ id, data }: NodeProps<NodeData>) {
       <div className="absolute top-full left-1/2 -translate-x-1/2 mt-1.5 text-center min-w-0 w-[120px]">
         <p className="text-[10px] text-foreground truncate">{data.label}</p>
         {(isGateway || isHub) && <p className="text-[9px] text-muted-foreground">{data.attachedCount ?? 0} attached</p>}
+        {data.behindNat && <p className="text-[9px] text-amber-300">behind NAT</p>}
       </div>
     </div>
   );
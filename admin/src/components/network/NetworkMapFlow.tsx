@@ -134,6 +134,14 @@ export function NetworkMapFlow() {
       nextPositions[subnetId] = { x: 110 + i * 520, y: 70 };
     });
 
+    const vlanIds = topology.nodes
+      .filter((node) => node.node_type === "vlan")
+      .map((node) => node.id)
+      .sort((a, b) => a.localeCompare(b, undefined, { numeric: true }));
+    vlanIds.forEach((vlanId, i) => {
+      nextPositions[vlanId] = { x: 110 + i * 520, y: -80 };
+    });
+
     const fallbackParent = gateways[0];
     for (const node of topology.nodes) {
       if (node.node_type === "admin") {
@@ -204,6 +212,7 @@ export function NetworkMapFlow() {
           subnet: device?.subnet_cidr ?? node.subnet_cidr,
           lastSeenMs: device?.last_seen_ms,
           attachedCount: node.attached_count,
+          behindNat: node.behind_nat,
           handleCount: node.node_type === "gateway" ? handleCountByGateway.get(node.id) ?? 16 : undefined,
           selected: focusedAgentId === (node.agent_id ?? node.id),
           onHover: setHoveredId,
@@ -312,12 +321,19 @@ export function NetworkMapFlow() {
             <p>Gateway: {hoveredDevice?.default_gateway_ip ?? hoveredNode.gateway_ip ?? "Unknown"}</p>
             <p>Subnet: {hoveredDevice?.subnet_cidr ?? hoveredNode.subnet_cidr ?? "Unknown"}</p>
             <p>Interface: {hoveredDevice?.interface_type ?? hoveredNode.interface_type ?? "Unknown"}</p>
+            {hoveredNode.behind_nat && <p className="text-amber-300">Behind NAT: placement is a guess</p>}
             {hoveredDevice && <p>Internet: {hoveredDevice.internet_reachable === null ? "Unknown" : hoveredDevice.internet_reachable ? "Reachable" : "Down"}</p>}
             {hoveredDevice && <p>DNS: {hoveredDevice.dns_ok === null ? "Unknown" : hoveredDevice.dns_ok ? "OK" : "Fail"}</p>}
             {hoveredDevice && <p>Last seen: {formatSince(hoveredDevice.last_seen_ms)}</p>}
             {(hoveredNode.node_type === "gateway" || hoveredNode.node_type === "unknown_hub") && (
               <p>Attached devices: {hoveredNode.attached_count ?? 0}</p>
             )}
+            {hoveredNode.utilization && (
+              <p>
+                Utilization: {hoveredNode.utilization.observed_ips}/{hoveredNode.utilization.capacity} IPs ·{" "}
+                {hoveredNode.utilization.managed_hosts} managed
+              </p>
+            )}
           </div>
         </div>
       )}
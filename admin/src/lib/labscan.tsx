@@ -30,11 +30,40 @@ export interface DeviceRecord {
   gateway_mac?: string | null;
   dhcp_server_ip?: string | null;
   ssid?: string | null;
+  ipv6?: string | null;
+  ipv6_cidr?: string | null;
+  timezone?: string | null;
+  locale?: string | null;
+  vlan_id?: number | null;
+  connection_quality?: number | null;
+  connection_band?: "good" | "fair" | "poor" | null;
+  peripherals?: Peripheral[] | null;
+  overridden_network_fields?: string[];
+  alias?: string | null;
+  tags?: string[];
+  location?: string | null;
+  alias_rule_id?: string | null;
+  extra_metrics?: Record<string, unknown>;
+  metric_schema?: MetricSchema[];
+  bad_network_detection?: boolean;
+  is_mock?: boolean;
+}
+
+export interface MetricSchema {
+  name: string;
+  unit?: string | null;
+  display?: string | null;
+}
+
+export interface Peripheral {
+  kind: string;
+  name: string;
+  id: string;
 }
 
 export interface TopologyNode {
   id: string;
-  node_type: "gateway" | "admin" | "host" | "switch" | "subnet" | "unknown_hub";
+  node_type: "gateway" | "admin" | "host" | "switch" | "subnet" | "unknown_hub" | "vlan" | "unmanaged";
   label: string;
   ip?: string | null;
   subnet_cidr?: string | null;
@@ -42,14 +71,23 @@ export interface TopologyNode {
   agent_id?: string | null;
   interface_type?: string | null;
   attached_count?: number | null;
+  utilization?: SubnetUtilization | null;
+  behind_nat?: boolean;
+}
+
+export interface SubnetUtilization {
+  managed_hosts: number;
+  observed_ips: number;
+  capacity: number;
 }
 
 export interface TopologyEdge {
   id: string;
   child_id: string;
   parent_id: string;
-  method: "evidence" | "heuristic" | "manual";
+  method: "evidence" | "heuristic" | "manual" | "configured";
   confidence: number;
+  port?: string;
 }
 
 export interface TopologySnapshot {
@@ -59,24 +97,46 @@ export interface TopologySnapshot {
   edges: TopologyEdge[];
 }
 
+export interface TopologyDiff {
+  rev_from: number;
+  rev_to: number;
+  added_nodes: TopologyNode[];
+  removed_nodes: string[];
+  added_edges: TopologyEdge[];
+  removed_edges: string[];
+}
+
+export interface TraceHop {
+  hop: number;
+  ip: string | null;
+  rtt_ms: number | null;
+}
+
 export interface TaskResultRecord {
   agent_id: string;
   ok: boolean;
   result: Record<string, unknown>;
   error?: string;
+  error_code?: "timeout" | "unreachable" | "permission_denied" | "unsupported" | "other";
   ts: number;
 }
 
 export interface TaskRecord {
   task_id: string;
-  kind: "ping" | "port_scan" | "arp_snapshot";
+  kind: "ping" | "port_scan" | "arp_snapshot" | "traceroute" | "dns_lookup";
   params: Record<string, unknown>;
   assigned_agents: string[];
-  status: "queued" | "running" | "done" | "failed";
+  status: "queued" | "running" | "done" | "failed" | "cancelled" | "timeout";
   created_at: number;
   started_at?: number;
   ended_at?: number;
   results: TaskResultRecord[];
+  origin_task_id?: string;
+  rerun_depth?: number;
+  delivered_to?: string[];
+  acked_by?: string[];
+  pending_agents?: string[];
+  timeout_ms?: number;
 }
 
 export interface LogRecord {
@@ -96,10 +156,27 @@ export interface ActivityEvent {
   count?: number;
 }
 
+export type DeviceRemoveReason = "manual" | "reaped" | "evicted" | "blocked" | "merged";
+
+export interface DeviceRemoveEvent {
+  agent_id: string;
+  reason: DeviceRemoveReason;
+}
+
+export interface LoopStatus {
+  udp_provision: boolean;
+  multicast_beacon: boolean;
+  watchdog: boolean;
+  topology_rebuild: boolean;
+}
+
 export interface ServerStatus {
   online: boolean;
   port_ws: number;
   port_udp: number;
+  loops?: LoopStatus;
+  connection_count?: number;
+  max_connections?: number;
 }
 
 export interface LabStateSnapshot {
@@ -280,12 +357,24 @@ export function LabScanProvider({ children }: { children: ReactNode }) {
           }));
         });
 
-        const unlistenDeviceRemove = await listen<{ agent_id: string }>("device_remove", (event) => {
+        const unlistenDevicePatch = await listen<{ agent_id: string; changes: Partial<DeviceRecord> }>(
+          "device_patch",
+          (event) => {
+            setState((prev) => ({
+              ...prev,
+              devices: prev.devices.map((device) =>
+                device.agent_id === event.payload.agent_id ? { ...device, ...event.payload.changes } : device,
+              ),
+            }));
+          },
+        );
+
+        const unlistenDeviceRemove = await listen<DeviceRemoveEvent>("device_remove", (event) => {
           setState((prev) => ({
             ...prev,
             devices: prev.devices.filter((device) => device.agent_id !== event.payload.agent_id),
           }));
-          void logger.info("[UI] device_remove", { id: event.payload.agent_id });
+          void logger.info("[UI] device_remove", { id: event.payload.agent_id, reason: event.payload.reason });
         });
 
         const unlistenTaskUpdate = await listen<{ task: TaskRecord }>("task_update", (event) => {
@@ -296,6 +385,10 @@ export function LabScanProvider({ children }: { children: ReactNode }) {
           });
         });
 
+        const unlistenTasksSnapshot = await listen<{ tasks: TaskRecord[] }>("tasks_snapshot", (event) => {
+          setState((prev) => ({ ...prev, tasks: event.payload.tasks }));
+        });
+
         const unlistenLog = await listen<LogRecord>("log_event", (event) => {
           setState((prev) => ({
             ...prev,
@@ -315,20 +408,27 @@ export function LabScanProvider({ children }: { children: ReactNode }) {
           });
         });
 
+        const unlistenActivitySnapshot = await listen<{ events: ActivityEvent[] }>("activity_snapshot", (event) => {
+          setState((prev) => ({ ...prev, activity: event.payload.events }));
+        });
+
         unsubscribers = [
           unlistenServer,
           unlistenDevices,
           unlistenDeviceUpsert,
+          unlistenDevicePatch,
           unlistenDeviceRemove,
           unlistenTaskUpdate,
+          unlistenTasksSnapshot,
           unlistenLog,
           unlistenActivity,
+          unlistenActivitySnapshot,
           unlistenTopologySnapshot,
           unlistenTopologyChanged,
         ];
 
         void logger.info("[UI] subscribed events", {
-          events: ["server_status", "devices_snapshot", "device_upsert", "device_remove", "task_update", "activity_event"],
+          events: ["server_status", "devices_snapshot", "device_upsert", "device_patch", "device_remove", "task_update", "activity_event"],
         });
       } catch (err) {
         setError(err instanceof Error ? err.message : "failed to initialize LabScan runtime");
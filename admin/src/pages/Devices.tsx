@@ -42,14 +42,16 @@ const Devices = () => {
     setSelected((prev) => ({ ...prev, [focusedAgentId]: true }));
   }, [focusedAgentId]);
 
-  const runTask = async (kind: "ping" | "port_scan" | "arp_snapshot") => {
+  const runTask = async (kind: "ping" | "port_scan" | "arp_snapshot" | "traceroute") => {
     if (selectedAgentIds.length === 0) return;
     const params =
       kind === "ping"
         ? { target: "8.8.8.8", timeout_ms: 1200 }
         : kind === "port_scan"
           ? { target: "127.0.0.1", ports: [22, 80, 443], timeout_ms: 600 }
-          : {};
+          : kind === "traceroute"
+            ? { target: "8.8.8.8", max_hops: 30 }
+            : {};
     await startTask(kind, selectedAgentIds, params);
   };
 
@@ -119,6 +121,7 @@ const Devices = () => {
           <button onClick={() => runTask("ping")} disabled={!selectedAgentIds.length} className="h-8 px-3 rounded-md bg-primary/10 border border-primary/20 text-xs text-primary disabled:opacity-40">Ping</button>
           <button onClick={() => runTask("port_scan")} disabled={!selectedAgentIds.length} className="h-8 px-3 rounded-md bg-amber/10 border border-amber/20 text-xs text-amber disabled:opacity-40">Port Scan</button>
           <button onClick={() => runTask("arp_snapshot")} disabled={!selectedAgentIds.length} className="h-8 px-3 rounded-md bg-cyan/10 border border-cyan/20 text-xs text-cyan disabled:opacity-40">ARP Snapshot</button>
+          <button onClick={() => runTask("traceroute")} disabled={!selectedAgentIds.length} className="h-8 px-3 rounded-md bg-primary/10 border border-primary/20 text-xs text-primary disabled:opacity-40">Traceroute</button>
         </div>
       </div>
 
@@ -1,6 +1,6 @@
 import { useMemo } from "react";
-import { CheckCircle2, Clock3, Loader2, XCircle } from "lucide-react";
-import { formatSince, useLabScan } from "@/lib/labscan";
+import { Ban, CheckCircle2, Clock3, Loader2, XCircle } from "lucide-react";
+import { formatSince, type TraceHop, useLabScan } from "@/lib/labscan";
 import { cn } from "@/lib/utils";
 
 const statusStyle = {
@@ -8,6 +8,8 @@ const statusStyle = {
   running: { icon: Loader2, color: "text-cyan", label: "Running" },
   done: { icon: CheckCircle2, color: "text-green", label: "Done" },
   failed: { icon: XCircle, color: "text-red", label: "Failed" },
+  cancelled: { icon: Ban, color: "text-muted-foreground", label: "Cancelled" },
+  timeout: { icon: Clock3, color: "text-red", label: "Timed out" },
 };
 
 const Tasks = () => {
@@ -63,6 +65,9 @@ const Tasks = () => {
                     <p key={result.agent_id} className="text-xs font-mono text-muted-foreground">
                       <span className={result.ok ? "text-green" : "text-red"}>{result.ok ? "OK" : "ERR"}</span>{" "}<span className="selectable">{result.agent_id}</span>
                       {result.error ? ` - ${result.error}` : ""}
+                      {task.kind === "traceroute" && Array.isArray(result.result)
+                        ? ` - ${(result.result as unknown as TraceHop[]).map((hop) => hop.ip ?? "*").join(" > ")}`
+                        : ""}
                     </p>
                   ))}
                 </div>
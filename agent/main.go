@@ -3,7 +3,10 @@ package main
 import (
 	"bufio"
 	"context"
+	"crypto/hmac"
 	"crypto/sha256"
+	"crypto/tls"
+	"crypto/x509"
 	"encoding/hex"
 	"encoding/json"
 	"errors"
@@ -33,12 +36,29 @@ const (
 	configPath       = "agent_config.json"
 	agentVersion     = "0.3.0"
 	fakeAgentCount   = 4
+	maxSeenTasks     = 256
+	maxRouteEntries  = 512
+
+	// Matches the admin's cap; peripherals are re-enumerated at most this often.
+	maxPeripherals      = 64
+	peripheralRefreshMS = 120000
 )
 
 type PersistedConfig struct {
-	AdminIP       string `json:"admin_ip"`
-	Secret        string `json:"secret"`
-	ProvisionedAt int64  `json:"provisioned_at"`
+	AdminIP        string `json:"admin_ip"`
+	Secret         string `json:"secret"`
+	ProvisionedAt  int64  `json:"provisioned_at"`
+	WSPort         int    `json:"ws_port,omitempty"`
+	TLSFingerprint string `json:"tls_fingerprint,omitempty"`
+}
+
+// adminPort is the admin's WS port, falling back to the default for
+// configs saved before the admin announced it.
+func (cfg *PersistedConfig) adminPort() int {
+	if cfg.WSPort > 0 {
+		return cfg.WSPort
+	}
+	return wsPort
 }
 
 type AgentIdentity struct {
@@ -48,11 +68,13 @@ type AgentIdentity struct {
 }
 
 type ProvisionMessage struct {
-	Type    string `json:"type"`
-	V       int    `json:"v"`
-	AdminIP string `json:"admin_ip"`
-	Secret  string `json:"secret"`
-	Nonce   string `json:"nonce"`
+	Type           string `json:"type"`
+	V              int    `json:"v"`
+	AdminIP        string `json:"admin_ip"`
+	Secret         string `json:"secret"`
+	Nonce          string `json:"nonce"`
+	WSPort         int    `json:"ws_port"`
+	TLSFingerprint string `json:"tls_fingerprint,omitempty"`
 }
 
 type ProvisionAck struct {
@@ -69,6 +91,7 @@ type WireMessage struct {
 	TS      int64       `json:"ts"`
 	AgentID string      `json:"agent_id"`
 	Payload interface{} `json:"payload"`
+	Sig     string      `json:"sig,omitempty"`
 }
 
 type RegisterPayload struct {
@@ -86,10 +109,18 @@ type RegisterPayload struct {
 }
 
 type HeartbeatPayload struct {
-	Status   string                 `json:"status"`
-	LastSeen int64                  `json:"last_seen"`
-	Metrics  map[string]interface{} `json:"metrics,omitempty"`
-	Network  NetworkFacts           `json:"network"`
+	Status      string                 `json:"status"`
+	LastSeen    int64                  `json:"last_seen"`
+	Metrics     map[string]interface{} `json:"metrics,omitempty"`
+	Network     NetworkFacts           `json:"network"`
+	Peripherals *[]Peripheral          `json:"peripherals,omitempty"`
+}
+
+// Peripheral is a USB device or monitor attached to this machine.
+type Peripheral struct {
+	Kind string `json:"kind"`
+	Name string `json:"name"`
+	ID   string `json:"id"`
 }
 
 type ArpEntry struct {
@@ -107,6 +138,8 @@ type NetworkFacts struct {
 	ARPSnapshot      []ArpEntry `json:"arp_snapshot,omitempty"`
 	DHCPServerIP     string     `json:"dhcp_server_ip,omitempty"`
 	SSID             string     `json:"ssid,omitempty"`
+	IPv6             string     `json:"ipv6,omitempty"`
+	IPv6CIDR         string     `json:"ipv6_cidr,omitempty"`
 }
 
 type TaskPayload struct {
@@ -115,6 +148,33 @@ type TaskPayload struct {
 	Params map[string]interface{} `json:"params"`
 }
 
+type RouteEntry struct {
+	Destination string `json:"destination"`
+	Gateway     string `json:"gateway"`
+	Interface   string `json:"interface"`
+	Metric      *int   `json:"metric,omitempty"`
+}
+
+type ControlPayload struct {
+	Action    string `json:"action"`
+	RequestID string `json:"request_id"`
+}
+
+type ControlAckPayload struct {
+	Action    string  `json:"action"`
+	RequestID string  `json:"request_id"`
+	OK        bool    `json:"ok"`
+	Error     *string `json:"error,omitempty"`
+}
+
+type TaskAckPayload struct {
+	TaskID string `json:"task_id"`
+}
+
+type TaskCancelPayload struct {
+	TaskID string `json:"task_id"`
+}
+
 type TaskResultPayload struct {
 	TaskID string      `json:"task_id"`
 	OK     bool        `json:"ok"`
@@ -123,8 +183,9 @@ type TaskResultPayload struct {
 }
 
 type RegisteredResponse struct {
-	OK    bool   `json:"ok"`
-	Error string `json:"error,omitempty"`
+	OK         bool   `json:"ok"`
+	Error      string `json:"error,omitempty"`
+	ServerTime int64  `json:"server_time,omitempty"`
 }
 
 type AgentProfile struct {
@@ -140,6 +201,7 @@ type AgentProfile struct {
 type AgentClient struct {
 	profile   AgentProfile
 	adminIP   string
+	adminPort int
 	secret    string
 	heartbeat time.Duration
 	conn      *websocket.Conn
@@ -149,6 +211,26 @@ type AgentClient struct {
 	networkMu sync.Mutex
 	network   NetworkFacts
 	lastARPMS int64
+
+	// tlsFingerprint pins the admin certificate; empty means plain ws.
+	tlsFingerprint string
+
+	// clockOffsetMS keeps signed timestamps inside the admin's replay window.
+	clockOffsetMS atomic.Int64
+
+	// seenTasks outlives a session so a task the admin redelivers after a
+	// reconnect is answered from its stored result instead of run again.
+	// taskCancels stops a running task when the admin cancels it.
+	tasksMu     sync.Mutex
+	seenTasks   map[string]*TaskResultPayload
+	seenOrder   []string
+	taskCancels map[string]context.CancelFunc
+
+	// peripherals stays nil where they can't be enumerated, so heartbeats
+	// leave the field out instead of reporting everything as unplugged.
+	peripheralsMu     sync.Mutex
+	peripherals       []Peripheral
+	lastPeripheralsMS int64
 }
 
 type ProbeState struct {
@@ -164,17 +246,22 @@ type ProbeState struct {
 func main() {
 	fake := flag.Bool("fake", false, "Run in fake provisioning mode")
 	identityPath := flag.String("identity", "", "Override identity file path")
+	provisionPort := flag.Int("provision-port", provisionUDPPort, "UDP port to wait for admin provisioning on (the admin's port_udp)")
 	flag.Parse()
 
+	if *provisionPort <= 0 || *provisionPort > 65535 {
+		log.Fatalf("invalid -provision-port %d", *provisionPort)
+	}
+
 	if *fake {
-		runFakeMode(*identityPath)
+		runFakeMode(*identityPath, *provisionPort)
 		return
 	}
 
-	runNormalMode(*identityPath)
+	runNormalMode(*identityPath, *provisionPort)
 }
 
-func runNormalMode(identityPath string) {
+func runNormalMode(identityPath string, provisionPort int) {
 	hostname, _ := os.Hostname()
 	identity, err := loadOrCreateIdentity(resolveIdentityPath(identityPath), "")
 	if err != nil {
@@ -182,7 +269,7 @@ func runNormalMode(identityPath string) {
 	}
 
 	for {
-		cfg, err := waitForProvision(identity.AgentID, hostname)
+		cfg, err := waitForProvision(identity.AgentID, hostname, provisionPort)
 		if err != nil {
 			log.Printf("provisioning listener error: %v", err)
 			time.Sleep(2 * time.Second)
@@ -198,12 +285,12 @@ func runNormalMode(identityPath string) {
 			StartedAt:   nowMS(),
 			IsFake:      false,
 		}
-		client := newAgentClient(profile, cfg.AdminIP, cfg.Secret, jitterDuration(5, 10))
+		client := newAgentClient(profile, cfg, jitterDuration(5, 10))
 		_ = client.runWithSleepLifecycle(context.Background())
 	}
 }
 
-func runFakeMode(identityPath string) {
+func runFakeMode(identityPath string, provisionPort int) {
 	hostname, _ := os.Hostname()
 	controllerIdentity, err := loadOrCreateIdentity(resolveIdentityPath(identityPath), "")
 	if err != nil {
@@ -215,7 +302,7 @@ func runFakeMode(identityPath string) {
 	}
 
 	for {
-		cfg, err := waitForProvision(controllerIdentity.AgentID, hostname)
+		cfg, err := waitForProvision(controllerIdentity.AgentID, hostname, provisionPort)
 		if err != nil {
 			log.Printf("failed provisioning in fake mode: %v", err)
 			time.Sleep(2 * time.Second)
@@ -242,7 +329,7 @@ func runFakeMode(identityPath string) {
 				IsFake:      true,
 			}
 
-			client := newAgentClient(profile, cfg.AdminIP, cfg.Secret, jitterDuration(5, 10))
+			client := newAgentClient(profile, cfg, jitterDuration(5, 10))
 			go func(c *AgentClient) {
 				_ = c.runWithSleepLifecycle(ctx)
 				if atomic.CompareAndSwapInt32(&doneOnce, 0, 1) {
@@ -257,15 +344,15 @@ func runFakeMode(identityPath string) {
 	}
 }
 
-func waitForProvision(agentID, hostname string) (*PersistedConfig, error) {
-	listenAddr := fmt.Sprintf(":%d", provisionUDPPort)
+func waitForProvision(agentID, hostname string, port int) (*PersistedConfig, error) {
+	listenAddr := fmt.Sprintf(":%d", port)
 	conn, err := net.ListenPacket("udp4", listenAddr)
 	if err != nil {
 		return nil, err
 	}
 	defer conn.Close()
 
-	log.Printf("Sleep mode: waiting for admin provisioning on UDP 8870...")
+	log.Printf("Sleep mode: waiting for admin provisioning on UDP %d...", port)
 
 	buffer := make([]byte, 4096)
 	for {
@@ -292,9 +379,11 @@ func waitForProvision(agentID, hostname string) (*PersistedConfig, error) {
 		}
 
 		cfg := &PersistedConfig{
-			AdminIP:       provision.AdminIP,
-			Secret:        provision.Secret,
-			ProvisionedAt: nowMS(),
+			AdminIP:        provision.AdminIP,
+			Secret:         provision.Secret,
+			ProvisionedAt:  nowMS(),
+			WSPort:         provision.WSPort,
+			TLSFingerprint: strings.ToLower(strings.TrimSpace(provision.TLSFingerprint)),
 		}
 
 		if err := saveConfig(cfg); err != nil {
@@ -313,16 +402,25 @@ func waitForProvision(agentID, hostname string) (*PersistedConfig, error) {
 			_, _ = conn.WriteTo(raw, sender)
 		}
 
-		log.Printf("Provisioned by %s, connecting to WS 8148...", provision.AdminIP)
+		log.Printf("Provisioned by %s, connecting to WS %d...", provision.AdminIP, cfg.adminPort())
 		return cfg, nil
 	}
 }
 
-func newAgentClient(profile AgentProfile, adminIP, secret string, heartbeat time.Duration) *AgentClient {
+func newAgentClient(profile AgentProfile, cfg *PersistedConfig, heartbeat time.Duration) *AgentClient {
 	if heartbeat <= 0 {
 		heartbeat = 8 * time.Second
 	}
-	return &AgentClient{profile: profile, adminIP: adminIP, secret: secret, heartbeat: heartbeat}
+	return &AgentClient{
+		profile:        profile,
+		adminIP:        cfg.AdminIP,
+		adminPort:      cfg.adminPort(),
+		secret:         cfg.Secret,
+		tlsFingerprint: cfg.TLSFingerprint,
+		heartbeat:      heartbeat,
+		seenTasks:      make(map[string]*TaskResultPayload),
+		taskCancels:    make(map[string]context.CancelFunc),
+	}
 }
 
 func (c *AgentClient) runWithSleepLifecycle(ctx context.Context) error {
@@ -369,9 +467,13 @@ func (c *AgentClient) runSession(parent context.Context) (bool, error) {
 	default:
 	}
 
-	url := fmt.Sprintf("ws://%s:%d/ws/agent", c.adminIP, wsPort)
+	scheme, dialer := "ws", websocket.DefaultDialer
+	if c.tlsFingerprint != "" {
+		scheme, dialer = "wss", pinnedDialer(c.tlsFingerprint)
+	}
+	url := fmt.Sprintf("%s://%s:%d/ws/agent", scheme, c.adminIP, c.adminPort)
 	log.Printf("WS dial url=%s", url)
-	conn, _, err := websocket.DefaultDialer.Dial(url, nil)
+	conn, _, err := dialer.Dial(url, nil)
 	if err != nil {
 		log.Printf("WS dial failed err=%v", err)
 		return false, fmt.Errorf("dial failed: %w", err)
@@ -420,6 +522,7 @@ func (c *AgentClient) runSession(parent context.Context) (bool, error) {
 		return false, errors.New("register timeout")
 	}
 
+	c.refreshPeripherals()
 	go c.heartbeatLoop(ctx)
 	go c.probeLoop(ctx)
 	go c.networkFactsLoop(ctx)
@@ -454,6 +557,9 @@ func (c *AgentClient) readLoop(ctx context.Context, registered chan<- bool) erro
 				continue
 			}
 			log.Printf("WS registered response agent_id=%s ok=%v", c.profile.AgentID, payload.OK)
+			if payload.ServerTime > 0 {
+				c.clockOffsetMS.Store(payload.ServerTime - nowMS())
+			}
 			if !registeredSent {
 				registered <- payload.OK
 				registeredSent = true
@@ -467,10 +573,24 @@ func (c *AgentClient) readLoop(ctx context.Context, registered chan<- bool) erro
 			if err := json.Unmarshal(message.Payload, &payload); err != nil {
 				continue
 			}
-			go c.executeTask(payload)
+			_ = c.send("task_ack", TaskAckPayload{TaskID: payload.TaskID})
+			if c.claimTask(payload.TaskID) {
+				go c.executeTask(c.trackTask(payload.TaskID), payload)
+			}
 
 		case "task_cancel":
-			continue
+			var payload TaskCancelPayload
+			if err := json.Unmarshal(message.Payload, &payload); err != nil {
+				continue
+			}
+			c.cancelTask(payload.TaskID)
+
+		case "control":
+			var payload ControlPayload
+			if err := json.Unmarshal(message.Payload, &payload); err != nil {
+				continue
+			}
+			c.handleControl(payload)
 		}
 
 		select {
@@ -489,26 +609,88 @@ func (c *AgentClient) heartbeatLoop(ctx context.Context) {
 		case <-ctx.Done():
 			return
 		case <-time.After(wait):
-			internet, dns, gateway, latency := c.probeSnapshot()
-			payload := HeartbeatPayload{
-				Status:   "idle",
-				LastSeen: nowMS(),
-				Network:  c.networkSnapshot(),
-				Metrics: map[string]interface{}{
-					"goroutines":         runtime.NumGoroutine(),
-					"internet_reachable": internet,
-					"dns_ok":             dns,
-					"gateway_reachable":  gateway,
-					"latency_ms":         latency,
-				},
-			}
-			if err := c.send("heartbeat", payload); err != nil {
+			if err := c.send("heartbeat", c.heartbeatPayload()); err != nil {
 				return
 			}
 		}
 	}
 }
 
+func (c *AgentClient) heartbeatPayload() HeartbeatPayload {
+	internet, dns, gateway, latency := c.probeSnapshot()
+	payload := HeartbeatPayload{
+		Status:   "idle",
+		LastSeen: nowMS(),
+		Network:  c.networkSnapshot(),
+		Metrics: map[string]interface{}{
+			"goroutines":         runtime.NumGoroutine(),
+			"internet_reachable": internet,
+			"dns_ok":             dns,
+			"gateway_reachable":  gateway,
+			"latency_ms":         latency,
+		},
+	}
+	if peripherals := c.peripheralsSnapshot(); peripherals != nil {
+		payload.Peripherals = &peripherals
+	}
+	return payload
+}
+
+// handleControl runs an operator lifecycle action. The ack goes out first
+// because reconnect and restart tear down the connection it would travel on.
+func (c *AgentClient) handleControl(control ControlPayload) {
+	ack := ControlAckPayload{Action: control.Action, RequestID: control.RequestID, OK: true}
+	fail := func(err error) {
+		errText := err.Error()
+		ack.OK = false
+		ack.Error = &errText
+	}
+
+	switch control.Action {
+	case "refresh":
+		c.collectAndStoreNetworkFacts(true)
+		c.refreshPeripherals()
+		if err := c.send("heartbeat", c.heartbeatPayload()); err != nil {
+			fail(err)
+		}
+		_ = c.send("control_ack", ack)
+
+	case "reconnect":
+		_ = c.send("control_ack", ack)
+		log.Printf("[%s] reconnect requested by admin", c.profile.Hostname)
+		_ = c.conn.Close()
+
+	case "restart":
+		// Fake agents share one process, so restarting one means reconnecting it.
+		if c.profile.IsFake {
+			_ = c.send("control_ack", ack)
+			_ = c.conn.Close()
+			return
+		}
+		exe, err := os.Executable()
+		if err != nil {
+			fail(err)
+			_ = c.send("control_ack", ack)
+			return
+		}
+		cmd := exec.Command(exe, os.Args[1:]...)
+		cmd.Stdout = os.Stdout
+		cmd.Stderr = os.Stderr
+		if err := cmd.Start(); err != nil {
+			fail(err)
+			_ = c.send("control_ack", ack)
+			return
+		}
+		_ = c.send("control_ack", ack)
+		log.Printf("[%s] restart requested by admin, handing over to pid %d", c.profile.Hostname, cmd.Process.Pid)
+		os.Exit(0)
+
+	default:
+		fail(fmt.Errorf("unsupported control action: %s", control.Action))
+		_ = c.send("control_ack", ack)
+	}
+}
+
 func (c *AgentClient) probeLoop(ctx context.Context) {
 	probeAndStore := func() {
 		internetOK, latency := probeInternet()
@@ -554,6 +736,9 @@ func (c *AgentClient) networkFactsLoop(ctx context.Context) {
 		case <-ticker.C:
 			includeARP := nowMS()-c.lastARPMS >= 120000
 			c.collectAndStoreNetworkFacts(includeARP)
+			if nowMS()-c.lastPeripheralsMS >= peripheralRefreshMS {
+				c.refreshPeripherals()
+			}
 		}
 	}
 }
@@ -575,6 +760,30 @@ func (c *AgentClient) networkSnapshot() NetworkFacts {
 	return c.network
 }
 
+// refreshPeripherals keeps the last good list when enumeration fails.
+func (c *AgentClient) refreshPeripherals() {
+	var peripherals []Peripheral
+	var err error
+	if c.profile.IsFake {
+		peripherals = fakePeripherals(c.profile)
+	} else {
+		peripherals, err = collectPeripherals()
+	}
+
+	c.peripheralsMu.Lock()
+	defer c.peripheralsMu.Unlock()
+	c.lastPeripheralsMS = nowMS()
+	if err == nil {
+		c.peripherals = peripherals
+	}
+}
+
+func (c *AgentClient) peripheralsSnapshot() []Peripheral {
+	c.peripheralsMu.Lock()
+	defer c.peripheralsMu.Unlock()
+	return c.peripherals
+}
+
 func (c *AgentClient) probeSnapshot() (*bool, *bool, *bool, *int64) {
 	c.probeMu.Lock()
 	defer c.probeMu.Unlock()
@@ -603,23 +812,92 @@ func (c *AgentClient) probeSnapshot() (*bool, *bool, *bool, *int64) {
 	return internet, dns, gateway, latency
 }
 
-func (c *AgentClient) executeTask(task TaskPayload) {
-	result, err := runTask(c.profile.IsFake, task.Kind, task.Params)
+// claimTask reports whether taskID is new. A repeat resends the stored
+// result if the task already finished; one still running answers when done.
+func (c *AgentClient) claimTask(taskID string) bool {
+	c.tasksMu.Lock()
+	defer c.tasksMu.Unlock()
+
+	if result, seen := c.seenTasks[taskID]; seen {
+		if result != nil {
+			go func(r TaskResultPayload) { _ = c.send("task_result", r) }(*result)
+		}
+		return false
+	}
+	c.seenTasks[taskID] = nil
+	c.seenOrder = append(c.seenOrder, taskID)
+	if len(c.seenOrder) > maxSeenTasks {
+		delete(c.seenTasks, c.seenOrder[0])
+		c.seenOrder = c.seenOrder[1:]
+	}
+	return true
+}
+
+// trackTask returns the context a task runs under until it finishes or
+// the admin cancels it.
+func (c *AgentClient) trackTask(taskID string) context.Context {
+	ctx, cancel := context.WithCancel(context.Background())
+	c.tasksMu.Lock()
+	c.taskCancels[taskID] = cancel
+	c.tasksMu.Unlock()
+	return ctx
+}
+
+func (c *AgentClient) cancelTask(taskID string) {
+	c.tasksMu.Lock()
+	cancel, running := c.taskCancels[taskID]
+	c.tasksMu.Unlock()
+	if running {
+		log.Printf("Task %s cancelled by admin", taskID)
+		cancel()
+	}
+}
+
+func (c *AgentClient) executeTask(ctx context.Context, task TaskPayload) {
+	defer func() {
+		c.tasksMu.Lock()
+		cancel := c.taskCancels[task.TaskID]
+		delete(c.taskCancels, task.TaskID)
+		c.tasksMu.Unlock()
+		if cancel != nil {
+			cancel()
+		}
+	}()
+
+	result, err := runTask(ctx, c.profile.IsFake, task.Kind, task.Params)
+	if ctx.Err() != nil {
+		// The admin already ended the task as cancelled, so there is nothing to report.
+		return
+	}
 	response := TaskResultPayload{TaskID: task.TaskID, OK: err == nil, Result: result}
 	if err != nil {
 		errText := err.Error()
 		response.Error = &errText
 	}
+
+	c.tasksMu.Lock()
+	if _, tracked := c.seenTasks[task.TaskID]; tracked {
+		c.seenTasks[task.TaskID] = &response
+	}
+	c.tasksMu.Unlock()
+
 	_ = c.send("task_result", response)
 }
 
-func runTask(fake bool, kind string, params map[string]interface{}) (interface{}, error) {
+func runTask(ctx context.Context, fake bool, kind string, params map[string]interface{}) (interface{}, error) {
 	if fake {
 		switch kind {
 		case "ping":
+			if targets := asStringSlice(params["targets"]); len(targets) > 0 {
+				results := make([]map[string]interface{}, 0, len(targets))
+				for _, target := range targets {
+					results = append(results, map[string]interface{}{"target": target, "ok": true, "latency_ms": 5 + rand.Intn(25)})
+				}
+				return map[string]interface{}{"results": results}, nil
+			}
 			return map[string]interface{}{"ok": true, "latency_ms": 5 + rand.Intn(25)}, nil
 		case "port_scan":
-			ports := asIntSlice(params["ports"], []int{22, 80, 443})
+			ports := scanPorts(params)
 			openPorts := make([]int, 0)
 			for _, p := range ports {
 				if p%2 == 0 || p == 443 {
@@ -634,6 +912,37 @@ func runTask(fake bool, kind string, params map[string]interface{}) (interface{}
 				"192.168.1.51 aa-bb-cc-dd-ee-51 dynamic",
 			}
 			return map[string]interface{}{"entries": entries, "count": len(entries)}, nil
+		case "traceroute":
+			hops := make([]map[string]interface{}, 0)
+			for hop := 1; hop <= 3 && hop <= asInt(params["max_hops"], 30); hop++ {
+				hops = append(hops, map[string]interface{}{
+					"hop":    hop,
+					"ip":     fmt.Sprintf("10.%d.0.1", hop),
+					"rtt_ms": float64(hop*4 + rand.Intn(5)),
+				})
+			}
+			return hops, nil
+		case "dns_lookup":
+			resolved := []string{"10.0.0.10"}
+			switch asString(params["record_type"], "A") {
+			case "AAAA":
+				resolved = []string{"fd00::10"}
+			case "CNAME":
+				resolved = []string{"lab-gw.local."}
+			case "MX":
+				resolved = []string{"mail.lab.local."}
+			}
+			return map[string]interface{}{
+				"resolved":    resolved,
+				"resolver_ip": "10.0.0.2",
+				"elapsed_ms":  1 + rand.Intn(20),
+			}, nil
+		case "route_table":
+			metric := 100
+			return map[string]interface{}{"routes": []RouteEntry{
+				{Destination: "0.0.0.0/0", Gateway: "192.168.1.1", Interface: "eth0", Metric: &metric},
+				{Destination: "192.168.1.0/24", Gateway: "0.0.0.0", Interface: "eth0", Metric: &metric},
+			}}, nil
 		default:
 			return nil, fmt.Errorf("unsupported task kind: %s", kind)
 		}
@@ -643,23 +952,47 @@ func runTask(fake bool, kind string, params map[string]interface{}) (interface{}
 	case "ping":
 		return runRealPing(params)
 	case "port_scan":
-		return runRealPortScan(params)
+		return runRealPortScan(ctx, params)
 	case "arp_snapshot":
 		return runRealARPSnapshot()
+	case "traceroute":
+		return runRealTraceroute(ctx, params)
+	case "dns_lookup":
+		return runRealDNSLookup(params)
+	case "route_table":
+		return runRealRouteTable()
 	default:
 		return nil, fmt.Errorf("unsupported task kind: %s", kind)
 	}
 }
 
+// runRealPing checks "target", or every host in "targets" in parallel and
+// returns one entry per host under "results".
 func runRealPing(params map[string]interface{}) (interface{}, error) {
-	target := asString(params["target"], "8.8.8.8")
 	timeoutMS := asInt(params["timeout_ms"], 1200)
+	if targets := asStringSlice(params["targets"]); len(targets) > 0 {
+		results := make([]map[string]interface{}, len(targets))
+		var wg sync.WaitGroup
+		for i, target := range targets {
+			wg.Add(1)
+			go func(i int, target string) {
+				defer wg.Done()
+				results[i] = pingTarget(target, timeoutMS)
+			}(i, target)
+		}
+		wg.Wait()
+		return map[string]interface{}{"results": results}, nil
+	}
+	return pingTarget(asString(params["target"], "8.8.8.8"), timeoutMS), nil
+}
+
+func pingTarget(target string, timeoutMS int) map[string]interface{} {
 	addr := net.JoinHostPort(target, "80")
 
 	start := time.Now()
 	conn, err := net.DialTimeout("tcp", addr, time.Duration(timeoutMS)*time.Millisecond)
 	if err != nil {
-		return map[string]interface{}{"target": target, "ok": false}, nil
+		return map[string]interface{}{"target": target, "ok": false}
 	}
 	_ = conn.Close()
 
@@ -667,16 +1000,19 @@ func runRealPing(params map[string]interface{}) (interface{}, error) {
 		"target":     target,
 		"ok":         true,
 		"latency_ms": time.Since(start).Milliseconds(),
-	}, nil
+	}
 }
 
-func runRealPortScan(params map[string]interface{}) (interface{}, error) {
+func runRealPortScan(ctx context.Context, params map[string]interface{}) (interface{}, error) {
 	target := asString(params["target"], "127.0.0.1")
-	ports := asIntSlice(params["ports"], []int{22, 80, 443})
+	ports := scanPorts(params)
 	timeoutMS := asInt(params["timeout_ms"], 700)
 
 	openPorts := make([]int, 0)
 	for _, port := range ports {
+		if err := ctx.Err(); err != nil {
+			return nil, err
+		}
 		addr := fmt.Sprintf("%s:%d", target, port)
 		conn, err := net.DialTimeout("tcp", addr, time.Duration(timeoutMS)*time.Millisecond)
 		if err == nil {
@@ -688,6 +1024,28 @@ func runRealPortScan(params map[string]interface{}) (interface{}, error) {
 	return map[string]interface{}{"target": target, "open_ports": openPorts, "scanned": len(ports)}, nil
 }
 
+// scanPorts prefers an explicit "ports" list and falls back to "port_range".
+func scanPorts(params map[string]interface{}) []int {
+	fallback := []int{22, 80, 443}
+	if _, ok := params["ports"]; ok {
+		return asIntSlice(params["ports"], fallback)
+	}
+	portRange, ok := params["port_range"].(map[string]interface{})
+	if !ok {
+		return fallback
+	}
+	start := asInt(portRange["start"], 0)
+	end := asInt(portRange["end"], 0)
+	if start < 1 || end > 65535 || start > end {
+		return fallback
+	}
+	ports := make([]int, 0, end-start+1)
+	for port := start; port <= end; port++ {
+		ports = append(ports, port)
+	}
+	return ports
+}
+
 func runRealARPSnapshot() (interface{}, error) {
 	var cmd *exec.Cmd
 	if runtime.GOOS == "windows" {
@@ -705,12 +1063,360 @@ func runRealARPSnapshot() (interface{}, error) {
 	return map[string]interface{}{"entries": lines, "count": len(lines)}, nil
 }
 
+func runRealTraceroute(ctx context.Context, params map[string]interface{}) (interface{}, error) {
+	target := asString(params["target"], "")
+	if target == "" {
+		return nil, errors.New("traceroute needs a target")
+	}
+	maxHops := strconv.Itoa(asInt(params["max_hops"], 30))
+
+	var cmd *exec.Cmd
+	if runtime.GOOS == "windows" {
+		cmd = exec.CommandContext(ctx, "tracert", "-d", "-h", maxHops, "-w", "1000", target)
+	} else {
+		cmd = exec.CommandContext(ctx, "traceroute", "-n", "-q", "1", "-w", "1", "-m", maxHops, target)
+	}
+	out, err := cmd.CombinedOutput()
+
+	hopPattern := regexp.MustCompile(`^\s*(\d+)\s+(.*)$`)
+	ipPattern := regexp.MustCompile(`\b(?:\d{1,3}\.){3}\d{1,3}\b`)
+	rttPattern := regexp.MustCompile(`(<?[\d.]+)\s*ms`)
+	hops := make([]map[string]interface{}, 0)
+	for _, line := range strings.Split(string(out), "\n") {
+		match := hopPattern.FindStringSubmatch(line)
+		if match == nil {
+			continue
+		}
+		hop, _ := strconv.Atoi(match[1])
+		entry := map[string]interface{}{"hop": hop, "ip": nil, "rtt_ms": nil}
+		if ip := ipPattern.FindString(match[2]); ip != "" {
+			entry["ip"] = ip
+		}
+		if rtt := rttPattern.FindStringSubmatch(match[2]); rtt != nil {
+			if value, parseErr := strconv.ParseFloat(strings.TrimPrefix(rtt[1], "<"), 64); parseErr == nil {
+				entry["rtt_ms"] = value
+			}
+		}
+		hops = append(hops, entry)
+	}
+	if len(hops) == 0 && err != nil {
+		return nil, fmt.Errorf("traceroute failed: %w", err)
+	}
+	return hops, nil
+}
+
+func runRealDNSLookup(params map[string]interface{}) (interface{}, error) {
+	hostname := asString(params["hostname"], "")
+	if hostname == "" {
+		return nil, errors.New("dns_lookup needs a hostname")
+	}
+	ctx, cancel := context.WithTimeout(context.Background(), 5*time.Second)
+	defer cancel()
+
+	resolver := net.Resolver{}
+	resolved := make([]string, 0)
+	start := time.Now()
+	var err error
+	switch recordType := asString(params["record_type"], "A"); recordType {
+	case "A", "AAAA":
+		network := "ip4"
+		if recordType == "AAAA" {
+			network = "ip6"
+		}
+		var ips []net.IP
+		ips, err = resolver.LookupIP(ctx, network, hostname)
+		for _, ip := range ips {
+			resolved = append(resolved, ip.String())
+		}
+	case "CNAME":
+		var cname string
+		cname, err = resolver.LookupCNAME(ctx, hostname)
+		if cname != "" {
+			resolved = append(resolved, cname)
+		}
+	case "MX":
+		var records []*net.MX
+		records, err = resolver.LookupMX(ctx, hostname)
+		for _, mx := range records {
+			resolved = append(resolved, mx.Host)
+		}
+	default:
+		return nil, fmt.Errorf("unsupported record_type: %s", recordType)
+	}
+	elapsed := time.Since(start).Milliseconds()
+	if err != nil {
+		return nil, fmt.Errorf("dns lookup failed: %w", err)
+	}
+
+	return map[string]interface{}{
+		"resolved":    resolved,
+		"resolver_ip": systemResolverIP(),
+		"elapsed_ms":  elapsed,
+	}, nil
+}
+
+// runRealRouteTable reports the IPv4 routing table, capped at maxRouteEntries.
+func runRealRouteTable() (interface{}, error) {
+	var routes []RouteEntry
+	var err error
+	switch runtime.GOOS {
+	case "linux":
+		routes, err = linuxRouteTable()
+	case "windows":
+		routes, err = windowsRouteTable()
+	default:
+		routes, err = netstatRouteTable()
+	}
+	if err != nil {
+		return nil, fmt.Errorf("route table failed: %w", err)
+	}
+	if len(routes) > maxRouteEntries {
+		routes = routes[:maxRouteEntries]
+	}
+	return map[string]interface{}{"routes": routes}, nil
+}
+
+// linuxRouteTable reads /proc/net/route: Iface Destination Gateway Flags
+// RefCnt Use Metric Mask, with addresses in little-endian hex.
+func linuxRouteTable() ([]RouteEntry, error) {
+	data, err := os.ReadFile("/proc/net/route")
+	if err != nil {
+		return nil, err
+	}
+	routes := make([]RouteEntry, 0)
+	for _, line := range strings.Split(string(data), "\n")[1:] {
+		fields := strings.Fields(line)
+		if len(fields) < 8 {
+			continue
+		}
+		dest, destErr := hexLittleEndianToIPv4(fields[1])
+		gateway, gwErr := hexLittleEndianToIPv4(fields[2])
+		mask, maskErr := hexLittleEndianToIPv4(fields[7])
+		metric, metricErr := strconv.Atoi(fields[6])
+		if destErr != nil || gwErr != nil || maskErr != nil || metricErr != nil {
+			continue
+		}
+		prefix, _ := net.IPMask(net.ParseIP(mask).To4()).Size()
+		routes = append(routes, RouteEntry{
+			Destination: fmt.Sprintf("%s/%d", dest, prefix),
+			Gateway:     gateway,
+			Interface:   fields[0],
+			Metric:      &metric,
+		})
+	}
+	return routes, nil
+}
+
+// windowsRouteTable parses the IPv4 rows of `route print -4`: Network
+// Destination, Netmask, Gateway ("On-link" for connected routes), Interface, Metric.
+func windowsRouteTable() ([]RouteEntry, error) {
+	out, err := exec.Command("route", "print", "-4").CombinedOutput()
+	if err != nil {
+		return nil, err
+	}
+	routes := make([]RouteEntry, 0)
+	for _, line := range strings.Split(string(out), "\n") {
+		fields := strings.Fields(line)
+		if len(fields) != 5 || !isIPv4(fields[0]) || !isIPv4(fields[1]) || !isIPv4(fields[3]) {
+			continue
+		}
+		metric, metricErr := strconv.Atoi(fields[4])
+		if metricErr != nil {
+			continue
+		}
+		gateway := fields[2]
+		if !isIPv4(gateway) {
+			gateway = "0.0.0.0"
+		}
+		prefix, _ := net.IPMask(net.ParseIP(fields[1]).To4()).Size()
+		routes = append(routes, RouteEntry{
+			Destination: fmt.Sprintf("%s/%d", fields[0], prefix),
+			Gateway:     gateway,
+			Interface:   fields[3],
+			Metric:      &metric,
+		})
+	}
+	return routes, nil
+}
+
+// netstatRouteTable covers macOS and the BSDs: Destination Gateway Flags Netif.
+func netstatRouteTable() ([]RouteEntry, error) {
+	out, err := exec.Command("netstat", "-rn", "-f", "inet").CombinedOutput()
+	if err != nil {
+		return nil, err
+	}
+	routes := make([]RouteEntry, 0)
+	for _, line := range strings.Split(string(out), "\n") {
+		fields := strings.Fields(line)
+		if len(fields) < 4 || fields[0] == "Destination" || strings.HasSuffix(fields[0], ":") {
+			continue
+		}
+		gateway := fields[1]
+		if !isIPv4(gateway) {
+			gateway = "0.0.0.0"
+		}
+		routes = append(routes, RouteEntry{Destination: fields[0], Gateway: gateway, Interface: fields[3]})
+	}
+	return routes, nil
+}
+
+func fakePeripherals(profile AgentProfile) []Peripheral {
+	return []Peripheral{
+		{Kind: "usb", Name: "LabScan Fake Keyboard", ID: "feed:0001"},
+		{Kind: "monitor", Name: "LabScan Fake Display", ID: profile.Hostname + "-HDMI-1"},
+	}
+}
+
+// collectPeripherals lists attached USB devices and connected monitors,
+// capped at maxPeripherals.
+func collectPeripherals() ([]Peripheral, error) {
+	var peripherals []Peripheral
+	var err error
+	switch runtime.GOOS {
+	case "linux":
+		peripherals, err = linuxUSBDevices()
+		if err == nil {
+			peripherals = append(peripherals, linuxMonitors()...)
+		}
+	case "windows":
+		peripherals, err = windowsPeripherals()
+	default:
+		return nil, fmt.Errorf("peripherals are not supported on %s", runtime.GOOS)
+	}
+	if err != nil {
+		return nil, err
+	}
+	if len(peripherals) > maxPeripherals {
+		peripherals = peripherals[:maxPeripherals]
+	}
+	return peripherals, nil
+}
+
+// linuxUSBDevices reads /sys/bus/usb/devices, skipping interfaces (names
+// with a colon) and the kernel's own root hubs (vendor 1d6b).
+func linuxUSBDevices() ([]Peripheral, error) {
+	const root = "/sys/bus/usb/devices"
+	entries, err := os.ReadDir(root)
+	if err != nil {
+		return nil, err
+	}
+	devices := make([]Peripheral, 0)
+	for _, entry := range entries {
+		if strings.Contains(entry.Name(), ":") {
+			continue
+		}
+		dir := filepath.Join(root, entry.Name())
+		vendor := readSysfsValue(filepath.Join(dir, "idVendor"))
+		if vendor == "" || vendor == "1d6b" {
+			continue
+		}
+		product := readSysfsValue(filepath.Join(dir, "idProduct"))
+		name := strings.TrimSpace(readSysfsValue(filepath.Join(dir, "manufacturer")) + " " + readSysfsValue(filepath.Join(dir, "product")))
+		devices = append(devices, Peripheral{Kind: "usb", Name: name, ID: vendor + ":" + product})
+	}
+	return devices, nil
+}
+
+// linuxMonitors lists connected DRM connectors, named from the EDID when
+// the monitor reports a model name.
+func linuxMonitors() []Peripheral {
+	monitors := make([]Peripheral, 0)
+	connectors, _ := filepath.Glob("/sys/class/drm/card*-*")
+	for _, dir := range connectors {
+		if readSysfsValue(filepath.Join(dir, "status")) != "connected" {
+			continue
+		}
+		connector := strings.SplitN(filepath.Base(dir), "-", 2)[1]
+		name := connector
+		if edid, err := os.ReadFile(filepath.Join(dir, "edid")); err == nil {
+			if model := edidMonitorName(edid); model != "" {
+				name = model
+			}
+		}
+		monitors = append(monitors, Peripheral{Kind: "monitor", Name: name, ID: connector})
+	}
+	return monitors
+}
+
+// edidMonitorName returns the display product name descriptor (tag 0xFC)
+// from the four 18-byte descriptors of a base EDID block.
+func edidMonitorName(edid []byte) string {
+	for offset := 54; offset <= 108 && offset+18 <= len(edid); offset += 18 {
+		block := edid[offset : offset+18]
+		if block[0] == 0 && block[1] == 0 && block[3] == 0xFC {
+			return strings.TrimSpace(strings.SplitN(string(block[5:]), "\n", 2)[0])
+		}
+	}
+	return ""
+}
+
+func readSysfsValue(path string) string {
+	data, err := os.ReadFile(path)
+	if err != nil {
+		return ""
+	}
+	return strings.TrimSpace(string(data))
+}
+
+// windowsPeripherals asks PnP for present USB devices and monitors as
+// "class<TAB>name<TAB>instance id" lines, leaving out hubs and controllers.
+func windowsPeripherals() ([]Peripheral, error) {
+	script := "Get-PnpDevice -PresentOnly -Class USB,Monitor | ForEach-Object { \"$($_.Class)`t$($_.FriendlyName)`t$($_.InstanceId)\" }"
+	out, err := exec.Command("powershell", "-NoProfile", "-NonInteractive", "-Command", script).CombinedOutput()
+	if err != nil {
+		return nil, err
+	}
+	peripherals := make([]Peripheral, 0)
+	for _, line := range strings.Split(string(out), "\n") {
+		fields := strings.Split(strings.TrimRight(line, "\r"), "\t")
+		if len(fields) != 3 {
+			continue
+		}
+		instance := strings.ToUpper(fields[2])
+		if strings.HasPrefix(instance, `USB\ROOT_HUB`) || strings.HasPrefix(instance, `PCI\`) {
+			continue
+		}
+		kind := "usb"
+		if strings.EqualFold(fields[0], "Monitor") {
+			kind = "monitor"
+		}
+		peripherals = append(peripherals, Peripheral{Kind: kind, Name: fields[1], ID: fields[2]})
+	}
+	return peripherals, nil
+}
+
+// systemResolverIP reports the first nameserver from resolv.conf; Windows
+// has no equivalent file, so it reports an empty string there.
+func systemResolverIP() string {
+	file, err := os.Open("/etc/resolv.conf")
+	if err != nil {
+		return ""
+	}
+	defer file.Close()
+	scanner := bufio.NewScanner(file)
+	for scanner.Scan() {
+		fields := strings.Fields(scanner.Text())
+		if len(fields) >= 2 && fields[0] == "nameserver" {
+			return fields[1]
+		}
+	}
+	return ""
+}
+
 func (c *AgentClient) send(messageType string, payload interface{}) error {
 	if c.conn == nil {
 		return errors.New("connection unavailable")
 	}
 
-	wire := WireMessage{Type: messageType, TS: nowMS(), AgentID: c.profile.AgentID, Payload: payload}
+	body, err := json.Marshal(payload)
+	if err != nil {
+		return err
+	}
+	ts := nowMS() + c.clockOffsetMS.Load()
+	wire := WireMessage{Type: messageType, TS: ts, AgentID: c.profile.AgentID, Payload: json.RawMessage(body)}
+	if messageType != "register" {
+		wire.Sig = signWire(c.secret, messageType, ts, c.profile.AgentID, body)
+	}
 	raw, err := json.Marshal(wire)
 	if err != nil {
 		return err
@@ -721,6 +1427,32 @@ func (c *AgentClient) send(messageType string, payload interface{}) error {
 	return c.conn.WriteMessage(websocket.TextMessage, raw)
 }
 
+// pinnedDialer trusts only the self-signed admin certificate whose SHA-256
+// matches the fingerprint the admin announced while provisioning.
+func pinnedDialer(fingerprint string) *websocket.Dialer {
+	verify := func(rawCerts [][]byte, _ [][]*x509.Certificate) error {
+		if len(rawCerts) == 0 {
+			return errors.New("admin presented no certificate")
+		}
+		sum := sha256.Sum256(rawCerts[0])
+		if hex.EncodeToString(sum[:]) != fingerprint {
+			return errors.New("admin certificate does not match the pinned fingerprint")
+		}
+		return nil
+	}
+	dialer := *websocket.DefaultDialer
+	dialer.TLSClientConfig = &tls.Config{InsecureSkipVerify: true, VerifyPeerCertificate: verify}
+	return &dialer
+}
+
+// signWire is the HMAC-SHA256 the admin checks on every message after register.
+func signWire(secret, messageType string, ts int64, agentID string, payload []byte) string {
+	mac := hmac.New(sha256.New, []byte(secret))
+	fmt.Fprintf(mac, "%s.%d.%s.", messageType, ts, agentID)
+	mac.Write(payload)
+	return hex.EncodeToString(mac.Sum(nil))
+}
+
 func loadConfig() (*PersistedConfig, error) {
 	data, err := os.ReadFile(configPath)
 	if err != nil {
@@ -993,6 +1725,13 @@ func collectNetworkFacts(includeARP bool) NetworkFacts {
 	dhcpServer := detectDHCPServerIP()
 	ssid := detectSSID(ifaceType)
 
+	ipv6 := ""
+	ipv6CIDR := ""
+	if v6Net := primaryIPv6(iface); v6Net != nil {
+		ipv6 = v6Net.IP.String()
+		ipv6CIDR = (&net.IPNet{IP: v6Net.IP.Mask(v6Net.Mask), Mask: v6Net.Mask}).String()
+	}
+
 	return NetworkFacts{
 		IP:               ip,
 		SubnetCIDR:       subnet,
@@ -1003,9 +1742,38 @@ func collectNetworkFacts(includeARP bool) NetworkFacts {
 		ARPSnapshot:      arpEntries,
 		DHCPServerIP:     dhcpServer,
 		SSID:             ssid,
+		IPv6:             ipv6,
+		IPv6CIDR:         ipv6CIDR,
 	}
 }
 
+// primaryIPv6 returns the first global unicast v6 address on iface, or on any
+// up interface when the host has no IPv4 primary.
+func primaryIPv6(iface *net.Interface) *net.IPNet {
+	candidates := []net.Interface{}
+	if iface != nil {
+		candidates = append(candidates, *iface)
+	} else if interfaces, err := net.Interfaces(); err == nil {
+		candidates = interfaces
+	}
+	for _, candidate := range candidates {
+		if candidate.Flags&net.FlagUp == 0 || candidate.Flags&net.FlagLoopback != 0 {
+			continue
+		}
+		addrs, err := candidate.Addrs()
+		if err != nil {
+			continue
+		}
+		for _, addr := range addrs {
+			netAddr, ok := addr.(*net.IPNet)
+			if ok && netAddr.IP.To4() == nil && netAddr.IP.IsGlobalUnicast() {
+				return netAddr
+			}
+		}
+	}
+	return nil
+}
+
 func pickPrimaryInterface(gatewayIP string) (*net.Interface, *net.IPNet) {
 	interfaces, err := net.Interfaces()
 	if err != nil {
@@ -1346,6 +2114,21 @@ func asInt(v interface{}, fallback int) int {
 	}
 }
 
+func asStringSlice(v interface{}) []string {
+	values, ok := v.([]interface{})
+	if !ok {
+		return nil
+	}
+
+	out := make([]string, 0, len(values))
+	for _, raw := range values {
+		if s := asString(raw, ""); s != "" {
+			out = append(out, s)
+		}
+	}
+	return out
+}
+
 func asIntSlice(v interface{}, fallback []int) []int {
 	values, ok := v.([]interface{})
 	if !ok {